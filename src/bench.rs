@@ -0,0 +1,144 @@
+//! Synthetic load generation for capacity testing.
+//!
+//! [`run_load`] drives a running server with a mix of GETs and SETs from
+//! several client threads and reports throughput and latency, so performance
+//! changes can be measured without an external tool.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use keystonelight::bench::{run_load, OpMix};
+//!
+//! let report = run_load("127.0.0.1:7878", 4, 10_000, OpMix::new(9, 1)).unwrap();
+//! println!(
+//!     "{:.0} ops/sec, p50 {:?}, p99 {:?}, {} errors",
+//!     report.ops_per_sec, report.p50, report.p99, report.errors
+//! );
+//! ```
+
+use crate::client::Client;
+use rand::Rng;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of distinct keys each client reads and writes
+const KEYS_PER_CLIENT: usize = 100;
+
+/// The relative weights of reads and writes in a load.
+///
+/// `OpMix::new(9, 1)` issues nine GETs for every SET, on average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpMix {
+    /// Weight of GET operations
+    pub reads: u32,
+    /// Weight of SET operations
+    pub writes: u32,
+}
+
+impl OpMix {
+    pub fn new(reads: u32, writes: u32) -> Self {
+        Self { reads, writes }
+    }
+}
+
+impl Default for OpMix {
+    /// An even split between reads and writes.
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+/// The results of a [`run_load`] run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchReport {
+    /// Operations completed per second, across all clients
+    pub ops_per_sec: f64,
+    /// Median latency of a single operation
+    pub p50: Duration,
+    /// 99th percentile latency of a single operation
+    pub p99: Duration,
+    /// Operations that failed or got an `ERROR` reply
+    pub errors: usize,
+}
+
+/// Runs `ops` operations against the server at `addr`, split across
+/// `clients` connections, each working on its own set of keys.
+///
+/// Every operation waits for its reply, so latencies are full round trips.
+/// A GET of a key that hasn't been written yet counts as a success. Fails
+/// if a client can't connect or the mix has no weight at all.
+pub fn run_load(addr: &str, clients: usize, ops: usize, mix: OpMix) -> io::Result<BenchReport> {
+    let total_weight = mix.reads + mix.writes;
+    if clients == 0 || total_weight == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a load needs at least one client and a non-zero op mix",
+        ));
+    }
+
+    // Connect everyone up front so connection setup isn't timed
+    let connections = (0..clients)
+        .map(|_| Client::connect(addr))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let started = Instant::now();
+    let handles: Vec<_> = connections
+        .into_iter()
+        .enumerate()
+        .map(|(id, mut client)| {
+            // Spread the remainder over the first few clients
+            let client_ops = ops / clients + usize::from(id < ops % clients);
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut latencies = Vec::with_capacity(client_ops);
+                let mut errors = 0;
+                for i in 0..client_ops {
+                    let key = format!("bench:{}:{}", id, i % KEYS_PER_CLIENT);
+                    let command = if rng.gen_range(0..total_weight) < mix.reads {
+                        format!("GET {}", key)
+                    } else {
+                        format!("SET {} value-{}", key, i)
+                    };
+
+                    let sent = Instant::now();
+                    match client.send_command(&command) {
+                        Ok(response) if !response.starts_with("ERROR") => {
+                            latencies.push(sent.elapsed())
+                        }
+                        _ => errors += 1,
+                    }
+                }
+                (latencies, errors)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(ops);
+    let mut errors = 0;
+    for handle in handles {
+        let (client_latencies, client_errors) = handle
+            .join()
+            .map_err(|_| io::Error::other("benchmark client panicked"))?;
+        latencies.extend(client_latencies);
+        errors += client_errors;
+    }
+    let elapsed = started.elapsed();
+
+    latencies.sort();
+    Ok(BenchReport {
+        ops_per_sec: latencies.len() as f64 / elapsed.as_secs_f64(),
+        p50: percentile(&latencies, 50),
+        p99: percentile(&latencies, 99),
+        errors,
+    })
+}
+
+/// Returns the `pct`th percentile of sorted `latencies`, or zero if empty.
+fn percentile(latencies: &[Duration], pct: usize) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (latencies.len() * pct).div_ceil(100);
+    latencies[rank.saturating_sub(1)]
+}
@@ -1,5 +1,59 @@
-use keystonelight::client::run_interactive;
+use keystonelight::client::{replay_file, run_file, run_interactive};
+use std::env;
+use std::process;
+use std::time::Duration;
 
 fn main() -> std::io::Result<()> {
-    run_interactive()
+    let args: Vec<String> = env::args().collect();
+    let mut file = None;
+    let mut replay = None;
+    let mut continue_on_error = false;
+    let mut idle_timeout = None;
+
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--file" => match rest.next() {
+                Some(path) => file = Some(path),
+                None => usage(&args[0]),
+            },
+            "--replay" => match rest.next() {
+                Some(path) => replay = Some(path),
+                None => usage(&args[0]),
+            },
+            "--continue-on-error" => continue_on_error = true,
+            "--idle-timeout" => match rest.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => idle_timeout = Some(Duration::from_secs(secs)),
+                None => usage(&args[0]),
+            },
+            _ => usage(&args[0]),
+        }
+    }
+
+    match (file, replay) {
+        (Some(path), None) => {
+            // Exit non-zero if any command in the script failed
+            if run_file(path, continue_on_error)? > 0 {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        (None, Some(path)) => {
+            // Exit non-zero if any response differed from the capture
+            if replay_file(path)? > 0 {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        (None, None) => run_interactive(idle_timeout),
+        (Some(_), Some(_)) => usage(&args[0]),
+    }
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--file <path> [--continue-on-error] | --replay <capture>] [--idle-timeout <secs>]",
+        program
+    );
+    process::exit(2);
 }
@@ -44,16 +44,38 @@
 //! assert!(response.contains("base64:"));
 //! ```
 
-use crate::protocol::parse_command;
+use crate::protocol::{encode_key, hash_slot, key_hash};
+use crate::server::{read_capture, CapturedExchange};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use std::env;
 use std::io::{self, BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 /// The server address to connect to
 const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:7878";
+/// Default capacity of the buffer responses are read through
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+/// Points each server gets on a [`ShardedClient`]'s hash ring
+const VIRTUAL_NODES: usize = 100;
+/// Commands whose responses span several lines ending with `END`
+const MULTILINE_COMMANDS: [&str; 10] = [
+    "GETALL",
+    "SCANVALUES",
+    "INFO",
+    "MMETA",
+    "MGET",
+    "SLOWLOG",
+    "RANDOMKEYS",
+    "LRUKEYS",
+    "CHILDREN",
+    "CHANGES",
+];
 
 /// A client connection to the key-value database server.
 ///
@@ -78,6 +100,41 @@ const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:7878";
 pub struct Client {
     stream: TcpStream,
     reader: BufReader<TcpStream>,
+    limits: ServerLimits,
+}
+
+/// The limits a server enforces on clients, as reported by `LIMITS`.
+///
+/// `None` means the server has no such limit, or didn't say.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Longest request line the server accepts, in bytes
+    pub max_request_bytes: Option<usize>,
+    /// Commands the server runs back to back for one connection
+    pub max_pipeline_depth: Option<usize>,
+    /// Cap on the number of keys the server stores
+    pub max_keys: Option<usize>,
+}
+
+impl ServerLimits {
+    /// Parses a `LIMITS` reply, leaving out any field that is missing or
+    /// `none`.
+    fn parse(reply: &str) -> Self {
+        let mut limits = Self::default();
+        for field in reply.split_whitespace() {
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = value.parse().ok();
+            match name {
+                "max_request_bytes" => limits.max_request_bytes = value,
+                "max_pipeline_depth" => limits.max_pipeline_depth = value,
+                "max_keys" => limits.max_keys = value,
+                _ => {}
+            }
+        }
+        limits
+    }
 }
 
 impl Client {
@@ -92,12 +149,78 @@ impl Client {
     /// println!("Connected to server successfully!");
     /// ```
     pub fn new() -> io::Result<Self> {
-        let server_addr = env::var("KEYSTONELIGHT_SERVER_ADDR")
-            .unwrap_or_else(|_| DEFAULT_SERVER_ADDR.to_string());
+        let server_addr = server_addr();
         println!("Connecting to database server at {}...", server_addr);
-        let stream = TcpStream::connect(server_addr)?;
-        let reader = BufReader::new(stream.try_clone()?);
-        Ok(Client { stream, reader })
+        Self::connect(&server_addr)
+    }
+
+    /// Connect to the server at `addr`.
+    ///
+    /// The server's [`ServerLimits`] are fetched as part of connecting, so
+    /// requests over them can be refused without a round trip.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let client = Client::connect("127.0.0.1:7878").unwrap();
+    /// ```
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        Self::connect_with_buffer_size(addr, DEFAULT_READ_BUFFER_SIZE)
+    }
+
+    /// Connect to the server at `addr`, reading responses through a buffer of
+    /// `read_buffer_size` bytes.
+    ///
+    /// A larger buffer means fewer reads from the socket when fetching large
+    /// values; the default is 8KB. Like [`Client::connect`], this asks the
+    /// server for its [`ServerLimits`] before returning. Fails with
+    /// `InvalidInput` if `read_buffer_size` is 0, since an empty buffer would
+    /// read every response as the connection closing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let client = Client::connect_with_buffer_size("127.0.0.1:7878", 1024 * 1024).unwrap();
+    /// ```
+    pub fn connect_with_buffer_size(addr: &str, read_buffer_size: usize) -> io::Result<Self> {
+        if read_buffer_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read buffer size must be at least 1 byte",
+            ));
+        }
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::with_capacity(read_buffer_size, stream.try_clone()?);
+        let mut client = Client {
+            stream,
+            reader,
+            limits: ServerLimits::default(),
+        };
+        // A server that doesn't know `LIMITS` replies with an error, which
+        // parses as no limits at all
+        let reply = client.send_command("LIMITS")?;
+        client.limits = ServerLimits::parse(&reply);
+        Ok(client)
+    }
+
+    /// Returns the limits the server reported when this client connected.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let client = Client::new().unwrap();
+    /// if let Some(max) = client.limits().max_request_bytes {
+    ///     println!("requests are limited to {} bytes", max);
+    /// }
+    /// ```
+    pub fn limits(&self) -> ServerLimits {
+        self.limits
     }
 
     /// Send a command to the server and receive the response.
@@ -129,6 +252,467 @@ impl Client {
         Ok(response)
     }
 
+    /// Send a command whose response spans several lines ending with `END`,
+    /// such as `GETALL`, and receive the lines before `END`.
+    ///
+    /// An `ERROR` reply is returned as a single line.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let mut client = Client::new().unwrap();
+    ///
+    /// for line in client.send_multiline_command("GETALL user:*").unwrap() {
+    ///     println!("{}", line);
+    /// }
+    /// ```
+    pub fn send_multiline_command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        writeln!(&mut self.stream, "{}", command)?;
+        self.stream.flush()?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before END",
+                ));
+            }
+            let line = line.trim_end_matches(['\r', '\n']).to_string();
+            if line == "END" {
+                break;
+            }
+            let is_error = line.starts_with("ERROR");
+            lines.push(line);
+            if is_error {
+                break;
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Fetches the value of `key` straight into `sink`, decoding base64 on
+    /// the fly. Returns `false`, and writes nothing, if the key is missing.
+    ///
+    /// The value is moved through the read buffer one chunk at a time, so it
+    /// never has to fit in memory at once. An `ERROR` reply is returned as
+    /// an error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    /// use std::fs::File;
+    ///
+    /// let mut client = Client::new().unwrap();
+    /// let file = File::create("backup.bin").unwrap();
+    /// if !client.get_to("large_key", file).unwrap() {
+    ///     println!("large_key does not exist");
+    /// }
+    /// ```
+    pub fn get_to<W: Write>(&mut self, key: &str, mut sink: W) -> io::Result<bool> {
+        writeln!(&mut self.stream, "GET {}", encode_key(key))?;
+        self.stream.flush()?;
+
+        let (status, ended) = self.read_token()?;
+        match (status.as_slice(), ended) {
+            (b"NOT_FOUND", true) => return Ok(false),
+            (b"VALUE", false) => {}
+            (b"ERROR", false) => {
+                let mut message = String::new();
+                self.reader.read_line(&mut message)?;
+                return Err(io::Error::other(message.trim_end().to_string()));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected reply {}", String::from_utf8_lossy(&status)),
+                ))
+            }
+        }
+
+        // The payload is either plain text or `base64:` followed by the
+        // encoded bytes; decide once the first few bytes are in
+        const PREFIX: &[u8] = b"base64:";
+        let mut head = Vec::with_capacity(PREFIX.len());
+        let mut ended = false;
+        while head.len() < PREFIX.len() && !ended {
+            ended = self.read_chunk(PREFIX.len() - head.len(), |chunk| {
+                head.extend_from_slice(chunk);
+                Ok(())
+            })?;
+        }
+
+        if head != PREFIX {
+            sink.write_all(&head)?;
+            while !ended {
+                ended = self.read_chunk(usize::MAX, |chunk| sink.write_all(chunk))?;
+            }
+            sink.flush()?;
+            return Ok(true);
+        }
+
+        // Base64 decodes in groups of four characters, so carry any partial
+        // group over to the next chunk
+        let mut pending = Vec::new();
+        while !ended {
+            ended = self.read_chunk(usize::MAX, |chunk| {
+                pending.extend_from_slice(chunk);
+                let whole = pending.len() / 4 * 4;
+                let decoded = BASE64
+                    .decode(&pending[..whole])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                sink.write_all(&decoded)?;
+                pending.drain(..whole);
+                Ok(())
+            })?;
+        }
+        if !pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated base64 value",
+            ));
+        }
+        sink.flush()?;
+        Ok(true)
+    }
+
+    /// Fetches the value of `key`, or `None` if it is missing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let mut client = Client::new().unwrap();
+    /// client.set("greeting", b"hello").unwrap();
+    /// assert_eq!(client.get("greeting").unwrap(), Some(b"hello".to_vec()));
+    /// ```
+    pub fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(self.get_to(key, &mut value)?.then_some(value))
+    }
+
+    /// Fetches the values of `keys` in one round trip, in order, with `None`
+    /// for each key that is missing.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let mut client = Client::new().unwrap();
+    /// client.set("a", b"1").unwrap();
+    /// assert_eq!(
+    ///     client.mget(&["a", "missing"]).unwrap(),
+    ///     vec![Some(b"1".to_vec()), None]
+    /// );
+    /// ```
+    pub fn mget<K: AsRef<str>>(&mut self, keys: &[K]) -> io::Result<Vec<Option<Vec<u8>>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let encoded: Vec<String> = keys.iter().map(|key| encode_key(key.as_ref())).collect();
+        let lines = self.send_multiline_command(&format!("MGET {}", encoded.join(" ")))?;
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected MGET reply line '{}'", line),
+            )
+        };
+        let values = lines
+            .iter()
+            .map(|line| {
+                if line == "NOT_FOUND" {
+                    return Ok(None);
+                }
+                if line.starts_with("ERROR") {
+                    return Err(io::Error::other(line.clone()));
+                }
+                let payload = line.strip_prefix("VALUE ").ok_or_else(|| invalid(line))?;
+                match payload.strip_prefix("base64:") {
+                    Some(encoded) => BASE64.decode(encoded).map(Some).map_err(|_| invalid(line)),
+                    None => Ok(Some(payload.as_bytes().to_vec())),
+                }
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if values.len() != keys.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "MGET replied {} values for {} keys",
+                    values.len(),
+                    keys.len()
+                ),
+            ));
+        }
+        Ok(values)
+    }
+
+    /// Stores `value` under `key`. Any bytes may be stored; they are sent
+    /// base64-encoded.
+    ///
+    /// Fails with `InvalidInput`, without contacting the server, if the
+    /// request would be longer than the server's `max_request_bytes`.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        let command = format!("SET {} base64:{}", encode_key(key), BASE64.encode(value));
+        // Caught here rather than by the server, which would disconnect us
+        if let Some(max) = self.limits.max_request_bytes {
+            if command.len() > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "value for '{}' is too large: the request would be {} bytes, the server accepts {}",
+                        key,
+                        command.len(),
+                        max
+                    ),
+                ));
+            }
+        }
+        self.expect_ok(&command)
+    }
+
+    /// Deletes `key`. Deleting a missing key is not an error.
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.expect_ok(&format!("DELETE {}", encode_key(key)))
+    }
+
+    /// Asks the server for the hash and hash slot of `key`, as `SLOT`
+    /// reports them; see [`hash_slot`](crate::protocol::hash_slot).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    ///
+    /// let mut client = Client::new().unwrap();
+    /// let (hash, slot) = client.slot("user:1").unwrap();
+    /// println!("user:1 hashes to {:016x}, slot {}", hash, slot);
+    /// ```
+    pub fn slot(&mut self, key: &str) -> io::Result<(u64, u16)> {
+        let response = self.send_command(&format!("SLOT {}", encode_key(key)))?;
+        let reply = response.trim_end();
+        if let Some(message) = reply.strip_prefix("ERROR ") {
+            return Err(io::Error::other(message.to_string()));
+        }
+        let parsed = reply
+            .strip_prefix("SLOT ")
+            .and_then(|fields| fields.split_once(' '))
+            .and_then(|(hash, slot)| Some((hash.parse().ok()?, slot.parse().ok()?)));
+        parsed.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected reply {}", reply),
+            )
+        })
+    }
+
+    /// Sends `command`, turning any reply but `OK` into an error.
+    fn expect_ok(&mut self, command: &str) -> io::Result<()> {
+        let response = self.send_command(command)?;
+        match response.trim_end() {
+            "OK" => Ok(()),
+            "" => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before reply",
+            )),
+            reply => Err(io::Error::other(
+                reply.strip_prefix("ERROR ").unwrap_or(reply).to_string(),
+            )),
+        }
+    }
+
+    /// Reads up to the next space or newline, consuming it. Returns the
+    /// bytes before it and whether it was the end of the line.
+    fn read_token(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        let mut token = Vec::new();
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed mid-reply",
+                ));
+            }
+            match buf.iter().position(|&b| b == b' ' || b == b'\n') {
+                Some(pos) => {
+                    let ended = buf[pos] == b'\n';
+                    token.extend_from_slice(&buf[..pos]);
+                    self.reader.consume(pos + 1);
+                    return Ok((token, ended));
+                }
+                None => {
+                    let len = buf.len();
+                    token.extend_from_slice(buf);
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    /// Passes up to `max` buffered bytes of the current line to `f`, reading
+    /// more from the socket if the buffer is empty. Returns whether the end
+    /// of the line was reached; the newline itself is consumed, not passed.
+    fn read_chunk<F>(&mut self, max: usize, mut f: F) -> io::Result<bool>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        let buf = self.reader.fill_buf()?;
+        if buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed mid-reply",
+            ));
+        }
+        let len = buf.len().min(max);
+        let (chunk, ended) = match buf[..len].iter().position(|&b| b == b'\n') {
+            Some(pos) => (&buf[..pos], true),
+            None => (&buf[..len], false),
+        };
+        // A CR before the newline is part of the line ending, not the value
+        let chunk_len = chunk.len();
+        let value = if ended {
+            chunk.strip_suffix(b"\r").unwrap_or(chunk)
+        } else {
+            chunk
+        };
+        f(value)?;
+        self.reader.consume(chunk_len + usize::from(ended));
+        Ok(ended)
+    }
+
+    /// Sends each command in `script`, one per line, writing every response
+    /// to `out`. Returns how many commands got an `ERROR` reply.
+    ///
+    /// Blank lines and lines starting with `#` are skipped. Unless
+    /// `continue_on_error` is set, the script stops after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    /// use std::io;
+    ///
+    /// let mut client = Client::connect("127.0.0.1:7878").unwrap();
+    /// let script = "SET a 1\nGET a\n".as_bytes();
+    /// let failed = client.run_script(script, &mut io::stdout(), false).unwrap();
+    /// assert_eq!(failed, 0);
+    /// ```
+    pub fn run_script<R: BufRead, W: Write>(
+        &mut self,
+        script: R,
+        out: &mut W,
+        continue_on_error: bool,
+    ) -> io::Result<usize> {
+        let mut failed = 0;
+        for line in script.lines() {
+            let line = line?;
+            let command = line.trim();
+            if command.is_empty() || command.starts_with('#') {
+                continue;
+            }
+
+            let name = command.split(' ').next().unwrap_or_default().to_uppercase();
+            let lines = if MULTILINE_COMMANDS.contains(&name.as_str()) {
+                self.send_multiline_command(command)?
+            } else {
+                vec![self.send_command(command)?.trim_end().to_string()]
+            };
+            for line in &lines {
+                writeln!(out, "{}", line)?;
+            }
+
+            if lines.iter().any(|line| line.starts_with("ERROR")) {
+                failed += 1;
+                if !continue_on_error {
+                    break;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(failed)
+    }
+
+    /// Sends the requests of a capture again, as recorded by a server with
+    /// [`with_capture_file`](crate::server::Server::with_capture_file), and
+    /// compares the responses. Returns how many responses differed from the
+    /// recorded ones, reporting each of them to `out`.
+    ///
+    /// Requests are sent in their original order over this one connection.
+    /// `NOREPLY` requests are sent without waiting, and `SYNC` requests,
+    /// which would take the connection over, are skipped. Responses that
+    /// depend on the server, such as `INFO` or versions, are expected to
+    /// differ.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::client::Client;
+    /// use keystonelight::server::read_capture;
+    /// use std::io;
+    ///
+    /// let exchanges = read_capture("capture.log").unwrap();
+    /// let mut client = Client::connect("127.0.0.1:7879").unwrap();
+    /// let mismatched = client.replay_capture(&exchanges, &mut io::stdout()).unwrap();
+    /// println!("{} responses differed", mismatched);
+    /// ```
+    pub fn replay_capture<W: Write>(
+        &mut self,
+        exchanges: &[CapturedExchange],
+        out: &mut W,
+    ) -> io::Result<usize> {
+        let mut mismatched = 0;
+        for exchange in exchanges {
+            let name = exchange
+                .request
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_uppercase();
+            if name == "SYNC" {
+                writeln!(out, "SKIPPED {}", exchange.request)?;
+                continue;
+            }
+
+            writeln!(&mut self.stream, "{}", exchange.request)?;
+            self.stream.flush()?;
+            if exchange.response.is_empty() {
+                continue;
+            }
+            let response = self.read_response(MULTILINE_COMMANDS.contains(&name.as_str()))?;
+            if response != exchange.response {
+                mismatched += 1;
+                writeln!(out, "MISMATCH {}", exchange.request)?;
+                writeln!(out, "  expected: {:?}", exchange.response)?;
+                writeln!(out, "  got:      {:?}", response)?;
+            }
+        }
+        out.flush()?;
+        Ok(mismatched)
+    }
+
+    /// Reads one response exactly as sent: a single line, or for a
+    /// `multiline` command every line up to `END` or an error.
+    fn read_response(&mut self, multiline: bool) -> io::Result<String> {
+        let mut response = String::new();
+        loop {
+            let start = response.len();
+            if self.reader.read_line(&mut response)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before reply",
+                ));
+            }
+            let line = &response[start..];
+            if !multiline || line == "END\n" || line.starts_with("ERROR") {
+                return Ok(response);
+            }
+        }
+    }
+
     /// Run the client in interactive mode
     pub fn run_interactive(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         println!("KeystoneLight Client - Interactive Mode");
@@ -143,7 +727,7 @@ impl Client {
             let readline = rl.readline(">> ");
             match readline {
                 Ok(line) => {
-                    rl.add_history_entry(line.as_str());
+                    let _ = rl.add_history_entry(line.as_str());
                     let line = line.trim();
                     if line.is_empty() {
                         continue;
@@ -188,6 +772,155 @@ impl Client {
     }
 }
 
+/// A client spreading keys across several servers.
+///
+/// Each key is routed to one server by a consistent-hash ring, so a key
+/// always lands on the same server and adding a server moves only a share
+/// of the keys. Connections are opened the first time a server is needed;
+/// if a server can't be reached, only the keys mapped to it fail.
+///
+/// # Examples
+///
+/// ```no_run
+/// use keystonelight::client::ShardedClient;
+///
+/// let mut client = ShardedClient::new(&["127.0.0.1:7878", "127.0.0.1:7879"]).unwrap();
+/// client.set("user:1", b"alice").unwrap();
+/// println!("user:1 is on {}", client.server_for("user:1"));
+/// assert_eq!(client.get("user:1").unwrap(), Some(b"alice".to_vec()));
+/// client.delete("user:1").unwrap();
+/// ```
+pub struct ShardedClient {
+    /// Server addresses, indexed by shard
+    servers: Vec<String>,
+    /// Open connection to each server, if any
+    connections: Vec<Option<Client>>,
+    /// `(hash, shard)` points, sorted by hash
+    ring: Vec<(u64, usize)>,
+}
+
+impl ShardedClient {
+    /// Creates a client for the servers at `addrs`. No connections are made
+    /// until a key needs one; fails only if `addrs` is empty.
+    pub fn new<S: AsRef<str>>(addrs: &[S]) -> io::Result<Self> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a sharded client needs at least one server",
+            ));
+        }
+        let servers: Vec<String> = addrs.iter().map(|addr| addr.as_ref().to_string()).collect();
+        let mut ring: Vec<(u64, usize)> = servers
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, addr)| {
+                (0..VIRTUAL_NODES).map(move |i| (key_hash(&format!("{}#{}", addr, i)), shard))
+            })
+            .collect();
+        ring.sort_unstable();
+        Ok(Self {
+            connections: servers.iter().map(|_| None).collect(),
+            servers,
+            ring,
+        })
+    }
+
+    /// Returns the address of the server `key` is stored on.
+    pub fn server_for(&self, key: &str) -> &str {
+        &self.servers[self.shard_for(key)]
+    }
+
+    /// Returns the hash and hash slot of `key`, computed locally; they match
+    /// what [`Client::slot`] gets from any server.
+    pub fn slot(&self, key: &str) -> (u64, u16) {
+        (key_hash(key), hash_slot(key))
+    }
+
+    /// Fetches the value of `key` from its server; see [`Client::get`].
+    pub fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        self.with_shard(key, |client| client.get(key))
+    }
+
+    /// Stores `value` under `key` on its server; see [`Client::set`].
+    pub fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.with_shard(key, |client| client.set(key, value))
+    }
+
+    /// Deletes `key` from its server; see [`Client::delete`].
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.with_shard(key, |client| client.delete(key))
+    }
+
+    /// The first ring point at or after the key's hash, wrapping around.
+    fn shard_for(&self, key: &str) -> usize {
+        let hash = key_hash(key);
+        let index = self.ring.partition_point(|&(point, _)| point < hash);
+        self.ring[index % self.ring.len()].1
+    }
+
+    /// Runs `f` on the connection to `key`'s server, connecting first if
+    /// needed. A connection that fails is dropped, so the next call for
+    /// that server reconnects.
+    fn with_shard<T, F>(&mut self, key: &str, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Client) -> io::Result<T>,
+    {
+        let shard = self.shard_for(key);
+        let addr = &self.servers[shard];
+        let unreachable = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("server {} for key '{}' is unreachable: {}", addr, key, e),
+            )
+        };
+
+        let slot = &mut self.connections[shard];
+        let client = match slot {
+            Some(client) => client,
+            None => slot.insert(Client::connect(addr).map_err(unreachable)?),
+        };
+        match f(client) {
+            // `Other` is an `ERROR` reply and `InvalidInput` a request
+            // refused before sending; the connection is still fine either way
+            Err(e) if !matches!(e.kind(), io::ErrorKind::Other | io::ErrorKind::InvalidInput) => {
+                *slot = None;
+                Err(unreachable(e))
+            }
+            result => result,
+        }
+    }
+}
+
+/// Runs the commands in the file at `path` against the server, printing each
+/// response to stdout. Returns how many commands got an `ERROR` reply; see
+/// [`Client::run_script`].
+///
+/// The server address is taken from `KEYSTONELIGHT_SERVER_ADDR`, as for
+/// [`Client::new`], but nothing besides the responses is printed.
+pub fn run_file<P: AsRef<Path>>(path: P, continue_on_error: bool) -> io::Result<usize> {
+    let script = std::fs::File::open(path)?;
+    let mut client = Client::connect(&server_addr())?;
+    client.run_script(BufReader::new(script), &mut io::stdout(), continue_on_error)
+}
+
+/// Replays the capture file at `path` against the server, printing each
+/// response that differs from the recorded one. Returns how many did; see
+/// [`Client::replay_capture`].
+///
+/// The server address is taken from `KEYSTONELIGHT_SERVER_ADDR`, as for
+/// [`Client::new`].
+pub fn replay_file<P: AsRef<Path>>(path: P) -> io::Result<usize> {
+    let exchanges = read_capture(path)?;
+    let mut client = Client::connect(&server_addr())?;
+    client.replay_capture(&exchanges, &mut io::stdout())
+}
+
+/// The address of the server to connect to, from `KEYSTONELIGHT_SERVER_ADDR`
+/// or the default.
+fn server_addr() -> String {
+    env::var("KEYSTONELIGHT_SERVER_ADDR").unwrap_or_else(|_| DEFAULT_SERVER_ADDR.to_string())
+}
+
 /// Run the client in interactive mode.
 ///
 /// This function starts an interactive session where users can:
@@ -196,15 +929,20 @@ impl Client {
 /// - Get help with the 'help' command
 /// - Exit with 'quit' or 'exit'
 ///
+/// With an `idle_timeout`, the session prints a message and disconnects
+/// once that long passes without any input, so an abandoned terminal doesn't
+/// hold a server connection forever.
+///
 /// # Examples
 ///
 /// ```no_run
 /// use keystonelight::client::run_interactive;
+/// use std::time::Duration;
 ///
-/// // Start an interactive session
-/// run_interactive().unwrap();
+/// // Start an interactive session that ends after five idle minutes
+/// run_interactive(Some(Duration::from_secs(300))).unwrap();
 /// ```
-pub fn run_interactive() -> io::Result<()> {
+pub fn run_interactive(idle_timeout: Option<Duration>) -> io::Result<()> {
     println!(
         "Connecting to database server at {}...",
         DEFAULT_SERVER_ADDR
@@ -213,17 +951,22 @@ pub fn run_interactive() -> io::Result<()> {
     println!("Connected successfully!");
     println!("Enter commands (type 'help' for usage, 'quit' to exit):");
 
-    let stdin = io::stdin();
-    let mut reader = stdin.lock();
-    let mut line = String::new();
+    // Stdin has no timed read, so lines are read on their own thread
+    let (lines, input) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
 
     print!("> ");
     io::stdout().flush()?;
 
-    while reader.read_line(&mut line)? > 0 {
+    while let Some(line) = next_line(&input, idle_timeout)? {
         let trimmed = line.trim();
         if trimmed.is_empty() {
-            line.clear();
             print!("> ");
             io::stdout().flush()?;
             continue;
@@ -239,7 +982,21 @@ pub fn run_interactive() -> io::Result<()> {
                 println!("  SET <key> <value>  - Set a key-value pair");
                 println!("  GET <key>         - Get the value for a key");
                 println!("  DELETE <key>      - Delete a key-value pair");
+                println!("  SETHEX <key> <hex> | SETB64 <key> <base64> - Set raw bytes");
+                println!("  GETHEX <key> | GETB64 <key> - Get a value's exact bytes");
+                println!("  GETALL <pattern>  - Get all pairs whose key matches a glob");
+                println!("  SCANVALUES <cursor> COUNT <n> [MATCH <pattern>] - Page through pairs");
                 println!("  COMPACT           - Trigger log compaction");
+                println!("  HEALTHCHECK       - Check that the server can write to disk");
+                println!("  INFO              - Show server build and runtime information");
+                println!("  DATAINFO          - Show the log file's path and size");
+                println!("  LIMITS            - Show the limits the server enforces on clients");
+                println!("  REPLICAOF <host:port> | NO ONE - Follow a primary, or stop");
+                println!("  REPLSTATUS        - Show replication role and lag");
+                println!("  ROLE              - Show whether this is a primary or a replica");
+                println!("  SLOT <key>        - Show the hash and hash slot a key maps to");
+                println!("  SLOWLOG           - List recent slow commands");
+                println!("  CHANGES SINCE <version> - List the changes made after a version");
                 println!("  quit/exit         - Exit the client");
             }
             _ => {
@@ -256,9 +1013,8 @@ pub fn run_interactive() -> io::Result<()> {
                             Ok(response) => {
                                 if response.starts_with("VALUE ") {
                                     let value = response.trim().split_once(' ').unwrap().1;
-                                    if value.starts_with("base64:") {
+                                    if let Some(encoded) = value.strip_prefix("base64:") {
                                         // Handle base64-encoded binary data
-                                        let encoded = &value[7..]; // Skip "base64:" prefix
                                         if let Ok(bytes) = BASE64.decode(encoded) {
                                             println!("<binary data of {} bytes>", bytes.len());
                                         } else {
@@ -275,12 +1031,53 @@ pub fn run_interactive() -> io::Result<()> {
                             Err(e) => println!("Error: {}", e),
                         }
                     }
+                    [cmd, key, encoded]
+                        if ["SETHEX", "SETB64"].contains(&cmd.to_uppercase().as_str()) =>
+                    {
+                        // Decoded here and sent as base64, so any bytes survive
+                        let (value, encoding) = if cmd.eq_ignore_ascii_case("SETHEX") {
+                            (decode_hex(encoded), "hex")
+                        } else {
+                            (BASE64.decode(encoded).ok(), "base64")
+                        };
+                        match value {
+                            Some(value) => match client.set(key, &value) {
+                                Ok(()) => println!("OK"),
+                                Err(e) => println!("Error: {}", e),
+                            },
+                            None => {
+                                println!("Error: Invalid {} encoding", encoding)
+                            }
+                        }
+                    }
+                    [cmd, key] if ["GETHEX", "GETB64"].contains(&cmd.to_uppercase().as_str()) => {
+                        match client.get(key) {
+                            Ok(Some(value)) if cmd.eq_ignore_ascii_case("GETHEX") => {
+                                let hex: String =
+                                    value.iter().map(|b| format!("{:02x}", b)).collect();
+                                println!("{}", hex);
+                            }
+                            Ok(Some(value)) => println!("{}", BASE64.encode(value)),
+                            Ok(None) => println!("NOT_FOUND"),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
                     [cmd, key] if cmd.to_uppercase() == "DELETE" => {
                         match client.send_command(&format!("DELETE {}", key)) {
                             Ok(response) => print!("{}", response),
                             Err(e) => println!("Error: {}", e),
                         }
                     }
+                    [cmd, ..] if MULTILINE_COMMANDS.contains(&cmd.to_uppercase().as_str()) => {
+                        match client.send_multiline_command(trimmed) {
+                            Ok(lines) => {
+                                for line in lines {
+                                    println!("{}", line);
+                                }
+                            }
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
                     [cmd] if cmd.to_uppercase() == "COMPACT" => {
                         match client.send_command("COMPACT") {
                             Ok(response) => print!("{}", response),
@@ -288,16 +1085,50 @@ pub fn run_interactive() -> io::Result<()> {
                         }
                     }
                     _ => {
-                        println!("Error: Invalid command. Type 'help' for usage.");
+                        // Let the server validate anything else
+                        match client.send_command(trimmed) {
+                            Ok(response) => print!("{}", response),
+                            Err(e) => println!("Error: {}", e),
+                        }
                     }
                 }
             }
         }
 
-        line.clear();
         print!("> ");
         io::stdout().flush()?;
     }
 
     Ok(())
 }
+
+/// Decodes a string of hex digit pairs, in either case.
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Waits for the next line of input. Returns `None` at the end of input, or
+/// once `idle_timeout` passes without a line.
+fn next_line(
+    input: &Receiver<io::Result<String>>,
+    idle_timeout: Option<Duration>,
+) -> io::Result<Option<String>> {
+    let received = match idle_timeout {
+        Some(timeout) => match input.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                println!();
+                println!("No input for {:?}, disconnecting", timeout);
+                return Ok(None);
+            }
+            received => received.ok(),
+        },
+        None => input.recv().ok(),
+    };
+    received.transpose()
+}
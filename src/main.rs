@@ -1,44 +1,55 @@
-mod client;
-mod protocol;
-mod server;
-mod storage;
-mod thread_pool;
-
+use keystonelight::{client, server};
 use std::env;
+use std::path::Path;
 use std::process;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("Usage: {} [serve|client] [num_threads]", args[0]);
+        eprintln!(
+            "Usage: {} [serve|client|status] [num_threads | --config <file>]",
+            args[0]
+        );
         process::exit(1);
     }
 
     match args[1].as_str() {
         "serve" => {
-            let num_threads = if args.len() > 2 {
-                args[2].parse().unwrap_or(4)
+            let server = if args.len() > 3 && args[2] == "--config" {
+                server::Server::from_config_file(&args[3])
             } else {
-                4
-            };
-            if let Err(e) =
+                let num_threads = if args.len() > 2 {
+                    args[2].parse().unwrap_or(4)
+                } else {
+                    4
+                };
                 server::Server::with_paths("keystonelight.pid", "keystonelight.log", num_threads)
-                    .and_then(|server| server.run())
-            {
+            };
+            if let Err(e) = server.and_then(|server| server.run()) {
                 eprintln!("Server error: {}", e);
                 process::exit(1);
             }
         }
+        "status" => match server::Server::running_pid(Path::new("keystonelight.pid")) {
+            Some(pid) => println!("running (PID {})", pid),
+            None => {
+                println!("not running");
+                process::exit(1);
+            }
+        },
         "client" => {
-            if let Err(e) = client::run_interactive() {
+            if let Err(e) = client::run_interactive(None) {
                 eprintln!("Client error: {}", e);
                 process::exit(1);
             }
         }
         _ => {
             eprintln!("Unknown command: {}", args[1]);
-            eprintln!("Usage: {} [serve|client] [num_threads]", args[0]);
+            eprintln!(
+                "Usage: {} [serve|client|status] [num_threads | --config <file>]",
+                args[0]
+            );
             process::exit(1);
         }
     }
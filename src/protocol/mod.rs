@@ -47,7 +47,20 @@
 //! ```
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+lazy_static! {
+    /// Built-in command aliases accepted by [`parse_command`], mapping the
+    /// alias to its canonical command name.
+    pub static ref DEFAULT_ALIASES: HashMap<String, String> = [("DEL", "DELETE"), ("PUT", "SET")]
+        .iter()
+        .map(|(alias, canonical)| (alias.to_string(), canonical.to_string()))
+        .collect();
+}
 
 /// Commands that can be sent from the client to the server.
 ///
@@ -72,14 +85,272 @@ use std::fmt;
 pub enum Command {
     /// Get the value associated with a key
     Get(String),
+    /// Get the value associated with a key along with its version
+    GetWithVersion(String),
+    /// Get the value associated with a key in the requested encoding
+    GetAs(String, ValueEncoding),
+    /// Get the value associated with a key along with its content type
+    GetWithContentType(String),
+    /// Get the value a key had when the given read snapshot was taken
+    GetAt(String, u64),
+    /// Open a point-in-time read snapshot
+    Snapshot,
+    /// Release a read snapshot
+    EndSnapshot(u64),
     /// Set a key-value pair
     Set(String, Vec<u8>),
+    /// Set a key-value pair only if the key is still at the given version
+    SetIfVersion(String, Vec<u8>, u64),
+    /// Set a key-value pair along with the value's content type
+    SetWithContentType(String, Vec<u8>, String),
+    /// Set a key-value pair only if the key already exists
+    SetXx(String, Vec<u8>),
+    /// Set a key-value pair unless it already holds that value, returning
+    /// the old value either way
+    SetGetIf(String, Vec<u8>),
+    /// Get the value associated with a key, or the given default if absent
+    GetDefault(String, Vec<u8>),
+    /// Get the value of a key, setting it to the given default first if absent
+    GetOrSet(String, Vec<u8>),
+    /// Get the bytes `[start, end)` of a value
+    GetRange(String, usize, usize),
+    /// Get every key matching a glob pattern along with its value
+    GetAll(String),
+    /// Get a page of key-value pairs: cursor, page size and glob pattern
+    ScanValues(usize, usize, String),
+    /// Append a line to a newline-separated value
+    AppendLine(String, Vec<u8>),
+    /// Overwrite part of a value, starting at a byte offset
+    SetRange(String, usize, Vec<u8>),
+    /// Add a float to the value of a key
+    IncrByFloat(String, f64),
+    /// Decrement an integer value, deleting the key once it reaches zero
+    DecrDel(String),
+    /// Add to an integer value, returning the value from before the add
+    FetchAdd(String, i64),
+    /// Get a random existing key
+    RandomKey,
+    /// Get up to this many distinct random keys
+    RandomKeys(usize),
+    /// List up to this many keys, least recently accessed first
+    LruKeys(usize),
+    /// List the key segments directly under a prefix
+    Children(String),
+    /// Get a key's size and timestamps
+    Meta(String),
+    /// Get metadata for several keys at once
+    MMeta(Vec<String>),
+    /// Get the values of several keys at once
+    MGet(Vec<String>),
+    /// Get the length of a value in bytes
+    StrLen(String),
+    /// Report a key's hash and hash slot, as used for sharding
+    Slot(String),
+    /// List the changes made after a version
+    Changes(u64),
     /// Delete a key-value pair
     Delete(String),
+    /// Delete a key and return the value it held
+    GetDel(String),
+    /// Exchange the values of two keys
+    Swap(String, String),
+    /// Rename a key only if it holds the given value
+    RenameIf(String, String, Vec<u8>),
+    /// Run a sequence of optionally conditional steps as one atomic unit
+    Eval(Vec<EvalStep>),
+    /// Tag a key so it can be deleted along with the rest of its group
+    Tag(String, String),
+    /// Delete every key carrying a tag
+    DelTag(String),
     /// Compact the log file
     Compact,
+    /// Compact the log file and report the disk space reclaimed
+    TrimLog,
+    /// Check that the log directory is still writable
+    HealthCheck,
+    /// Check that the in-memory cache matches a replay of the log
+    Verify,
+    /// Report build and runtime information about the server
+    Info,
+    /// Follow the primary at the given address, or stop following on `None`
+    /// (`REPLICAOF NO ONE`)
+    ReplicaOf(Option<String>),
+    /// Stream this server's log to a replica; sent by `REPLICAOF`
+    Sync,
+    /// Report this server's replication role and lag
+    ReplStatus,
+    /// Report whether this server is a primary or a replica
+    Role,
+    /// Report the log file's path, size and entry count
+    DataInfo,
+    /// Report the limits this server enforces on clients
+    Limits,
+    /// List recent commands that exceeded the slow log threshold
+    SlowLog,
+    /// Reject writes until `RESUME`, for maintenance such as backups
+    Pause,
+    /// Accept writes again after a `PAUSE`
+    Resume,
+}
+
+/// One step of an `EVAL`: an action, run only if its condition holds.
+///
+/// `EVAL` takes steps separated by `;`, each of the form
+///
+/// ```text
+/// [IF <key> EQ|NE <value> THEN] SET <key> [<value>] | DELETE <key> | GET <key>
+/// ```
+///
+/// Keys and values are single tokens, so one holding spaces or `;` must be
+/// sent in `base64key:` or `base64:` form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalStep {
+    /// What must hold for the action to run; `None` always runs it
+    pub condition: Option<EvalCondition>,
+    /// What the step does
+    pub action: EvalAction,
+}
+
+/// The test guarding an [`EvalStep`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalCondition {
+    /// The key exists and holds exactly this value (`EQ`)
+    Equals(String, Vec<u8>),
+    /// The key is missing or holds some other value (`NE`)
+    NotEquals(String, Vec<u8>),
+}
+
+/// The operation an [`EvalStep`] performs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalAction {
+    /// Store a value
+    Set(String, Vec<u8>),
+    /// Delete a key
+    Delete(String),
+    /// Read a value, making it the reply if this is the last step run
+    Get(String),
+}
+
+impl fmt::Display for EvalStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.condition {
+            Some(EvalCondition::Equals(key, value)) => {
+                write!(f, "if {} eq {} then ", key, display_value(value))?
+            }
+            Some(EvalCondition::NotEquals(key, value)) => {
+                write!(f, "if {} ne {} then ", key, display_value(value))?
+            }
+            None => {}
+        }
+        match &self.action {
+            EvalAction::Set(key, value) => write!(f, "set {} {}", key, display_value(value)),
+            EvalAction::Delete(key) => write!(f, "delete {}", key),
+            EvalAction::Get(key) => write!(f, "get {}", key),
+        }
+    }
+}
+
+/// Wire encodings a client can ask for with `GET <key> AS <encoding>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueEncoding {
+    /// Lower-case hexadecimal, two digits per byte
+    Hex,
+    /// Standard base64, without the `base64:` prefix
+    Base64,
+    /// The bytes as stored; only usable with binary framing
+    Raw,
+}
+
+impl ValueEncoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "hex" => Some(ValueEncoding::Hex),
+            "base64" => Some(ValueEncoding::Base64),
+            "raw" => Some(ValueEncoding::Raw),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValueEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueEncoding::Hex => write!(f, "hex"),
+            ValueEncoding::Base64 => write!(f, "base64"),
+            ValueEncoding::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+impl Command {
+    /// Returns whether the command can change the database or its log.
+    ///
+    /// These are the commands rejected while writes are paused. `GETORSET`
+    /// counts as a write because it may store its default, and `COMPACT` and
+    /// `TRIMLOG` because they rewrite the log.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::SetIfVersion(..)
+                | Command::SetWithContentType(..)
+                | Command::SetXx(..)
+                | Command::SetGetIf(..)
+                | Command::GetOrSet(..)
+                | Command::AppendLine(..)
+                | Command::SetRange(..)
+                | Command::IncrByFloat(..)
+                | Command::DecrDel(_)
+                | Command::FetchAdd(..)
+                | Command::Delete(_)
+                | Command::GetDel(_)
+                | Command::Swap(..)
+                | Command::RenameIf(..)
+                | Command::Eval(_)
+                | Command::Tag(..)
+                | Command::DelTag(_)
+                | Command::Compact
+                | Command::TrimLog
+        )
+    }
+}
+
+/// Why a line couldn't be parsed as a command; see [`try_parse_command`].
+///
+/// The `Display` form is what the server sends after `ERROR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line was blank
+    Empty,
+    /// The first word isn't a command, as upper-cased
+    UnknownCommand(String),
+    /// The command was given too few or too many arguments
+    WrongArgCount(String),
+    /// An argument has the `base64key:` prefix but isn't valid base64
+    InvalidBase64(String),
+    /// The command and an argument it couldn't make sense of, such as a
+    /// number that doesn't parse
+    InvalidArgument(String, String),
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            ParseError::WrongArgCount(name) => {
+                write!(f, "wrong number of arguments for '{}'", name)
+            }
+            ParseError::InvalidBase64(arg) => write!(f, "invalid base64 in '{}'", arg),
+            ParseError::InvalidArgument(name, arg) => {
+                write!(f, "invalid argument '{}' for '{}'", arg, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 /// Responses that can be sent from the server to the client.
 ///
 /// # Examples
@@ -119,15 +390,49 @@ pub enum Response {
     Error(String),
 }
 
+impl Response {
+    /// Builds the `ERROR` response for a failed storage operation.
+    ///
+    /// Operating system errors such as a full disk get a short, stable
+    /// message instead of the raw OS text. Errors raised by the database
+    /// itself already read well and pass through unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::protocol::Response;
+    /// use std::io;
+    ///
+    /// let full = io::Error::from_raw_os_error(libc::ENOSPC);
+    /// assert_eq!(Response::from_io_error(&full).to_string(), "ERROR disk full");
+    ///
+    /// let own = io::Error::other("database full");
+    /// assert_eq!(Response::from_io_error(&own).to_string(), "ERROR database full");
+    /// ```
+    pub fn from_io_error(e: &io::Error) -> Response {
+        let message = match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded, _) => "disk full",
+            (io::ErrorKind::ReadOnlyFilesystem, _) => "read-only filesystem",
+            // The database raises these kinds too, with a more specific message
+            (io::ErrorKind::PermissionDenied, Some(_)) => "permission denied",
+            (io::ErrorKind::NotFound, Some(_)) => "log file not found",
+            (_, Some(libc::EIO)) => "disk I/O error",
+            _ => return Response::Error(e.to_string()),
+        };
+        Response::Error(message.to_string())
+    }
+}
+
 impl fmt::Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Response::Ok => write!(f, "OK"),
             Response::Value(val) => {
-                // Check if the value contains any non-printable characters
-                let is_binary = val
-                    .iter()
-                    .any(|&b| !b.is_ascii_graphic() && !b.is_ascii_whitespace());
+                // Check if the value contains any non-printable characters, or
+                // a line break that would end the response early
+                let is_binary = val.iter().any(|&b| {
+                    (!b.is_ascii_graphic() && !b.is_ascii_whitespace()) || b == b'\n' || b == b'\r'
+                });
                 if is_binary {
                     write!(f, "OK base64:{}", BASE64.encode(val))
                 } else {
@@ -147,28 +452,284 @@ impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Command::Get(key) => write!(f, "get {}", key),
-            Command::Set(key, value) => {
-                // Check if the value contains any non-printable characters
-                let is_binary = value
-                    .iter()
-                    .any(|&b| !b.is_ascii_graphic() && !b.is_ascii_whitespace());
-                if is_binary {
-                    write!(f, "set {} [binary data]", key)
-                } else {
-                    match String::from_utf8(value.clone()) {
-                        Ok(text) => write!(f, "set {} {}", key, text),
-                        Err(_) => write!(f, "set {} [binary data]", key),
-                    }
-                }
+            Command::GetWithVersion(key) => write!(f, "get {} withversion", key),
+            Command::GetAs(key, encoding) => write!(f, "get {} as {}", key, encoding),
+            Command::GetWithContentType(key) => write!(f, "get {} withct", key),
+            Command::GetAt(key, snapshot) => write!(f, "get {} at {}", key, snapshot),
+            Command::Snapshot => write!(f, "snapshot"),
+            Command::EndSnapshot(snapshot) => write!(f, "endsnapshot {}", snapshot),
+            Command::Set(key, value) => write!(f, "set {} {}", key, display_value(value)),
+            Command::SetIfVersion(key, value, version) => {
+                write!(f, "set {} {} ifver {}", key, display_value(value), version)
+            }
+            Command::SetWithContentType(key, value, content_type) => {
+                write!(
+                    f,
+                    "set {} {} ct {}",
+                    key,
+                    display_value(value),
+                    content_type
+                )
+            }
+            Command::SetXx(key, value) => write!(f, "setxx {} {}", key, display_value(value)),
+            Command::SetGetIf(key, value) => {
+                write!(f, "setgetif {} {}", key, display_value(value))
+            }
+            Command::GetDefault(key, default) => {
+                write!(f, "getdefault {} {}", key, display_value(default))
+            }
+            Command::GetOrSet(key, default) => {
+                write!(f, "getorset {} {}", key, display_value(default))
+            }
+            Command::GetRange(key, start, end) => write!(f, "getrange {} {} {}", key, start, end),
+            Command::GetAll(pattern) => write!(f, "getall {}", pattern),
+            Command::ScanValues(cursor, count, pattern) => {
+                write!(f, "scanvalues {} count {} match {}", cursor, count, pattern)
             }
+            Command::SetRange(key, offset, bytes) => {
+                write!(f, "setrange {} {} {}", key, offset, display_value(bytes))
+            }
+            Command::AppendLine(key, line) => {
+                write!(f, "appendline {} {}", key, display_value(line))
+            }
+            Command::IncrByFloat(key, delta) => write!(f, "incrbyfloat {} {}", key, delta),
+            Command::DecrDel(key) => write!(f, "decrdel {}", key),
+            Command::FetchAdd(key, delta) => write!(f, "fetchadd {} {}", key, delta),
+            Command::StrLen(key) => write!(f, "strlen {}", key),
+            Command::Slot(key) => write!(f, "slot {}", key),
+            Command::Changes(version) => write!(f, "changes since {}", version),
+            Command::RandomKey => write!(f, "randomkey"),
+            Command::RandomKeys(count) => write!(f, "randomkeys {}", count),
+            Command::LruKeys(count) => write!(f, "lrukeys {}", count),
+            Command::Children(prefix) => write!(f, "children {}", prefix),
+            Command::Meta(key) => write!(f, "meta {}", key),
+            Command::MMeta(keys) => write!(f, "mmeta {}", keys.join(" ")),
+            Command::MGet(keys) => write!(f, "mget {}", keys.join(" ")),
             Command::Delete(key) => write!(f, "delete {}", key),
+            Command::GetDel(key) => write!(f, "getdel {}", key),
+            Command::Swap(key1, key2) => write!(f, "swap {} {}", key1, key2),
+            Command::RenameIf(old, new, expected) => {
+                write!(f, "renameif {} {} {}", old, new, display_value(expected))
+            }
+            Command::Eval(steps) => {
+                let steps: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+                write!(f, "eval {}", steps.join("; "))
+            }
+            Command::Tag(key, tag) => write!(f, "tag {} {}", key, tag),
+            Command::DelTag(tag) => write!(f, "deltag {}", tag),
             Command::Compact => write!(f, "compact"),
+            Command::TrimLog => write!(f, "trimlog"),
+            Command::HealthCheck => write!(f, "healthcheck"),
+            Command::Verify => write!(f, "verify"),
+            Command::Info => write!(f, "info"),
+            Command::DataInfo => write!(f, "datainfo"),
+            Command::Limits => write!(f, "limits"),
+            Command::ReplicaOf(Some(primary)) => write!(f, "replicaof {}", primary),
+            Command::ReplicaOf(None) => write!(f, "replicaof no one"),
+            Command::Sync => write!(f, "sync"),
+            Command::ReplStatus => write!(f, "replstatus"),
+            Command::Role => write!(f, "role"),
+            Command::SlowLog => write!(f, "slowlog"),
+            Command::Pause => write!(f, "pause"),
+            Command::Resume => write!(f, "resume"),
         }
     }
 }
 
+/// Renders a value for display, hiding binary data.
+fn display_value(value: &[u8]) -> String {
+    // Check if the value contains any non-printable characters
+    let is_binary = value
+        .iter()
+        .any(|&b| !b.is_ascii_graphic() && !b.is_ascii_whitespace());
+    if is_binary {
+        return "[binary data]".to_string();
+    }
+    match String::from_utf8(value.to_vec()) {
+        Ok(text) => text,
+        Err(_) => "[binary data]".to_string(),
+    }
+}
+
+/// Splits a trailing `IFVER <version>` off the value of a SET.
+fn strip_ifver(rest: &str) -> Option<(&str, u64)> {
+    let mut tokens = rest.rsplitn(3, ' ');
+    let version = tokens.next()?.parse().ok()?;
+    if !tokens.next()?.eq_ignore_ascii_case("IFVER") {
+        return None;
+    }
+    Some((tokens.next().unwrap_or(""), version))
+}
+
+/// Splits a trailing `CT <type>/<subtype>` off the value of a SET.
+fn strip_content_type(rest: &str) -> Option<(&str, &str)> {
+    let mut tokens = rest.rsplitn(3, ' ');
+    let content_type = tokens.next().filter(|t| t.contains('/'))?;
+    if !tokens.next()?.eq_ignore_ascii_case("CT") {
+        return None;
+    }
+    Some((tokens.next().unwrap_or(""), content_type))
+}
+
+/// Decodes a value argument, honouring the `base64:` prefix for binary data.
+///
+/// Values that carry the prefix but aren't valid base64 are kept verbatim.
+fn decode_value(value: &str) -> Vec<u8> {
+    match value.strip_prefix("base64:") {
+        Some(encoded) => BASE64
+            .decode(encoded)
+            .unwrap_or_else(|_| value.as_bytes().to_vec()),
+        None => value.as_bytes().to_vec(),
+    }
+}
+
+/// Prefix marking a base64-encoded key.
+pub(crate) const BASE64_KEY_PREFIX: &str = "base64key:";
+
+/// Decodes a key argument, honouring the `base64key:` prefix.
+///
+/// A key that decodes to UTF-8 is used as is, which lets keys carry spaces
+/// and newlines. Any other bytes are kept in canonical `base64key:` form, so
+/// binary keys stay distinct and round-trip losslessly; keys starting with
+/// `base64key:` are therefore reserved for them. Returns `None` if the
+/// prefix is followed by invalid base64.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::{decode_key, encode_key};
+///
+/// assert_eq!(decode_key("plain").unwrap(), "plain");
+/// assert_eq!(decode_key("base64key:YSBi").unwrap(), "a b");
+/// assert_eq!(encode_key("a b"), "base64key:YSBi");
+/// assert!(decode_key("base64key:!!").is_none());
+/// ```
+pub fn decode_key(key: &str) -> Option<String> {
+    let Some(encoded) = key.strip_prefix(BASE64_KEY_PREFIX) else {
+        return Some(key.to_string());
+    };
+    let bytes = BASE64.decode(encoded).ok()?;
+    Some(match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => format!("{}{}", BASE64_KEY_PREFIX, BASE64.encode(e.into_bytes())),
+    })
+}
+
+/// Encodes a key so it survives as a single protocol token, the inverse of
+/// [`decode_key`].
+///
+/// Keys that are empty or contain whitespace or control characters are sent
+/// as `base64key:<base64>`; binary keys are already in that form.
+pub fn encode_key(key: &str) -> String {
+    let needs_encoding = !key.starts_with(BASE64_KEY_PREFIX)
+        && (key.is_empty() || key.chars().any(|c| c.is_whitespace() || c.is_control()));
+    if needs_encoding {
+        format!("{}{}", BASE64_KEY_PREFIX, BASE64.encode(key))
+    } else {
+        key.to_string()
+    }
+}
+
+/// Number of hash slots keys are grouped into; see [`hash_slot`].
+pub const HASH_SLOTS: u16 = 16384;
+
+/// Hashes a key for placing it on a
+/// [`ShardedClient`](crate::client::ShardedClient)'s ring.
+///
+/// This is 64-bit FNV-1a followed by a finalizing mix, so it is the same
+/// on every run and platform, unlike the standard library's hasher. FNV
+/// alone barely moves the high bits for strings that differ only at the
+/// end, such as `user:1` and `user:2`.
+pub fn key_hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Returns the hash slot of a key, below [`HASH_SLOTS`].
+///
+/// Slots split the ring into equal arcs, taken from the top bits of
+/// [`key_hash`], so keys in the same slot are neighbours on the ring and
+/// almost always share a server.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::{hash_slot, key_hash, HASH_SLOTS};
+///
+/// let slot = hash_slot("user:1");
+/// assert!(slot < HASH_SLOTS);
+/// assert_eq!(u64::from(slot), key_hash("user:1") >> 50);
+/// ```
+pub fn hash_slot(key: &str) -> u16 {
+    (key_hash(key) >> (64 - HASH_SLOTS.trailing_zeros())) as u16
+}
+
+/// Splits a trailing `NOREPLY` flag off a request line.
+///
+/// Returns the line without the flag and whether it was present. A flagged
+/// command is executed as usual, but the server sends nothing back for it,
+/// not even an error, so pipelined bulk loads avoid one read per request.
+/// A value that really ends in ` NOREPLY` must be sent base64-encoded.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::strip_noreply;
+///
+/// assert_eq!(strip_noreply("SET key value NOREPLY"), ("SET key value", true));
+/// assert_eq!(strip_noreply("SET key value"), ("SET key value", false));
+/// assert_eq!(strip_noreply("NOREPLY"), ("NOREPLY", false));
+/// ```
+pub fn strip_noreply(line: &str) -> (&str, bool) {
+    match line.rsplit_once(' ') {
+        Some((command, flag)) if flag.eq_ignore_ascii_case("NOREPLY") => (command.trim_end(), true),
+        _ => (line, false),
+    }
+}
+
+/// Splits a trailing `IDEM <token>` idempotency token off a request line.
+///
+/// Returns the line without the token and the token, if present. The server
+/// remembers the reply to a command sent with a token, and answers a repeat
+/// of the token with that reply instead of running the command again, so a
+/// client can safely retry a write after a timeout. Like ` NOREPLY`, a value
+/// that really ends in ` IDEM <word>` must be sent base64-encoded.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::strip_idempotency_token;
+///
+/// assert_eq!(
+///     strip_idempotency_token("SET key value IDEM req-1"),
+///     ("SET key value", Some("req-1"))
+/// );
+/// assert_eq!(strip_idempotency_token("SET key value"), ("SET key value", None));
+/// ```
+pub fn strip_idempotency_token(line: &str) -> (&str, Option<&str>) {
+    let Some((rest, token)) = line.rsplit_once(' ') else {
+        return (line, None);
+    };
+    match rest.trim_end().rsplit_once(' ') {
+        Some((command, flag)) if flag.eq_ignore_ascii_case("IDEM") && !token.is_empty() => {
+            (command.trim_end(), Some(token))
+        }
+        _ => (line, None),
+    }
+}
+
 /// Parse a command from a string.
 ///
+/// `SET <key>` with no value stores a zero-length value. The key then exists
+/// (a later `GET` replies `VALUE ` with an empty payload rather than
+/// `NOT_FOUND`), which is distinct from deleting it.
+///
 /// # Arguments
 ///
 /// * `line` - The input line to parse
@@ -213,49 +774,517 @@ impl fmt::Display for Command {
 ///     _ => panic!("Expected COMPACT command"),
 /// }
 ///
+/// // Built-in aliases are normalized to their canonical command
+/// let cmd = parse_command("DEL mykey").unwrap();
+/// match cmd {
+///     Command::Delete(key) => assert_eq!(key, "mykey"),
+///     _ => panic!("Expected DELETE command"),
+/// }
+///
 /// // Invalid commands return None
 /// assert!(parse_command("INVALID").is_none());
 /// assert!(parse_command("GET").is_none());
-/// assert!(parse_command("SET key").is_some()); // SET with empty value is valid
+///
+/// // SET with no value stores an empty value
+/// match parse_command("SET key").unwrap() {
+///     Command::Set(key, value) => {
+///         assert_eq!(key, "key");
+///         assert!(value.is_empty());
+///     },
+///     _ => panic!("Expected SET command"),
+/// }
+///
+/// // A trailing IFVER makes the SET conditional on the key's version
+/// match parse_command("SET key value IFVER 3").unwrap() {
+///     Command::SetIfVersion(key, value, version) => {
+///         assert_eq!(key, "key");
+///         assert_eq!(value, b"value");
+///         assert_eq!(version, 3);
+///     },
+///     _ => panic!("Expected conditional SET command"),
+/// }
+///
+/// // A trailing CT records the value's content type
+/// match parse_command("SET key {} CT application/json").unwrap() {
+///     Command::SetWithContentType(key, value, content_type) => {
+///         assert_eq!(key, "key");
+///         assert_eq!(value, b"{}");
+///         assert_eq!(content_type, "application/json");
+///     },
+///     _ => panic!("Expected SET with a content type"),
+/// }
 /// ```
 pub fn parse_command(line: &str) -> Option<Command> {
+    parse_command_with_aliases(line, &DEFAULT_ALIASES)
+}
+
+/// Parse a command from a string using a custom alias table.
+///
+/// The first token of the line is upper-cased and looked up in `aliases`
+/// before matching, so alias keys must be upper-case. Canonical command
+/// names always keep working.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::{Command, parse_command_with_aliases};
+/// use std::collections::HashMap;
+///
+/// let mut aliases = HashMap::new();
+/// aliases.insert("FETCH".to_string(), "GET".to_string());
+///
+/// let cmd = parse_command_with_aliases("fetch mykey", &aliases).unwrap();
+/// match cmd {
+///     Command::Get(key) => assert_eq!(key, "mykey"),
+///     _ => panic!("Expected GET command"),
+/// }
+///
+/// // Built-in aliases are not implied by a custom table
+/// assert!(parse_command_with_aliases("DEL mykey", &aliases).is_none());
+/// ```
+pub fn parse_command_with_aliases(
+    line: &str,
+    aliases: &HashMap<String, String>,
+) -> Option<Command> {
+    try_parse_command_with_aliases(line, aliases).ok()
+}
+
+/// Parse a command from a string, saying what was wrong if it can't be
+/// parsed.
+///
+/// This is [`parse_command`] with a [`ParseError`] in place of `None`.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::protocol::{try_parse_command, ParseError};
+///
+/// assert!(try_parse_command("GET mykey").is_ok());
+/// assert_eq!(
+///     try_parse_command("FOO mykey").unwrap_err(),
+///     ParseError::UnknownCommand("FOO".to_string())
+/// );
+/// assert_eq!(
+///     try_parse_command("GET").unwrap_err().to_string(),
+///     "wrong number of arguments for 'GET'"
+/// );
+/// ```
+pub fn try_parse_command(line: &str) -> Result<Command, ParseError> {
+    try_parse_command_with_aliases(line, &DEFAULT_ALIASES)
+}
+
+/// Parse a command from a string using a custom alias table, saying what was
+/// wrong if it can't be parsed.
+///
+/// See [`parse_command_with_aliases`] for how aliases are applied.
+pub fn try_parse_command_with_aliases(
+    line: &str,
+    aliases: &HashMap<String, String>,
+) -> Result<Command, ParseError> {
     let mut parts = line.trim().splitn(3, ' ');
-    let cmd = parts.next()?.to_uppercase();
+    let mut cmd = parts.next().unwrap_or_default().to_uppercase();
+    if cmd.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if let Some(canonical) = aliases.get(&cmd) {
+        cmd = canonical.to_uppercase();
+    }
+    let name = cmd.as_str();
 
-    match cmd.as_str() {
+    match name {
         "GET" => {
-            let key = parts.next()?;
-            if parts.next().is_some() {
-                return None;
-            } // GET should have exactly one argument
-            Some(Command::Get(key.to_string()))
+            let key = key_arg(required(name, parts.next())?)?;
+            match parts.next() {
+                None => Ok(Command::Get(key)),
+                Some(flag) if flag.eq_ignore_ascii_case("WITHVERSION") => {
+                    Ok(Command::GetWithVersion(key))
+                }
+                Some(flag) if flag.eq_ignore_ascii_case("WITHCT") => {
+                    Ok(Command::GetWithContentType(key))
+                }
+                Some(rest) => {
+                    // GET takes one argument plus WITHVERSION, AS <encoding>
+                    // or AT <snapshot>
+                    let mut tokens = rest.split_whitespace();
+                    let flag = required(name, tokens.next())?;
+                    let arg = required(name, tokens.next())?;
+                    no_more(name, tokens.next())?;
+                    match flag.to_uppercase().as_str() {
+                        "AS" => ValueEncoding::from_name(arg)
+                            .map(|encoding| Command::GetAs(key, encoding))
+                            .ok_or_else(|| invalid(name, arg)),
+                        "AT" => Ok(Command::GetAt(key, number_arg(name, arg)?)),
+                        _ => Err(invalid(name, flag)),
+                    }
+                }
+            }
         }
         "SET" => {
-            let key = parts.next()?;
-            let value = parts.next().unwrap_or("");
-            // Try to decode base64 if it starts with "base64:"
-            let value = if value.starts_with("base64:") {
-                BASE64
-                    .decode(&value[7..])
-                    .unwrap_or_else(|_| value.as_bytes().to_vec())
-            } else {
-                value.as_bytes().to_vec()
+            let key = key_arg(required(name, parts.next())?)?;
+            let rest = parts.next().unwrap_or("");
+            match strip_ifver(rest) {
+                Some((value, version)) => {
+                    Ok(Command::SetIfVersion(key, decode_value(value), version))
+                }
+                None => match strip_content_type(rest) {
+                    Some((value, content_type)) => Ok(Command::SetWithContentType(
+                        key,
+                        decode_value(value),
+                        content_type.to_string(),
+                    )),
+                    None => Ok(Command::Set(key, decode_value(rest))),
+                },
+            }
+        }
+        "SETXX" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let value = decode_value(parts.next().unwrap_or(""));
+            Ok(Command::SetXx(key, value))
+        }
+        "SETGETIF" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let value = decode_value(parts.next().unwrap_or(""));
+            Ok(Command::SetGetIf(key, value))
+        }
+        "GETDEFAULT" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let default = decode_value(parts.next().unwrap_or(""));
+            Ok(Command::GetDefault(key, default))
+        }
+        "GETORSET" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let default = decode_value(parts.next().unwrap_or(""));
+            Ok(Command::GetOrSet(key, default))
+        }
+        "GETRANGE" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let mut bounds = required(name, parts.next())?.split(' ');
+            let start = number_arg(name, required(name, bounds.next())?)?;
+            let end = number_arg(name, required(name, bounds.next())?)?;
+            no_more(name, bounds.next())?; // GETRANGE should have exactly three arguments
+            Ok(Command::GetRange(key, start, end))
+        }
+        "GETALL" => {
+            let pattern = required(name, parts.next())?;
+            no_more(name, parts.next())?; // GETALL should have exactly one argument
+            Ok(Command::GetAll(pattern.to_string()))
+        }
+        "SCANVALUES" => {
+            let cursor = number_arg(name, required(name, parts.next())?)?;
+            let mut options = required(name, parts.next())?.split(' ');
+            let flag = required(name, options.next())?;
+            if !flag.eq_ignore_ascii_case("COUNT") {
+                return Err(invalid(name, flag));
+            }
+            let count = required(name, options.next())?;
+            let count = number_arg(name, count)
+                .ok()
+                .filter(|&n: &usize| n > 0)
+                .ok_or_else(|| invalid(name, count))?;
+            let pattern = match options.next() {
+                None => "*",
+                Some(flag) if flag.eq_ignore_ascii_case("MATCH") => required(name, options.next())?,
+                Some(flag) => return Err(invalid(name, flag)),
             };
-            Some(Command::Set(key.to_string(), value))
+            // SCANVALUES takes a cursor, a COUNT and an optional MATCH
+            no_more(name, options.next())?;
+            Ok(Command::ScanValues(cursor, count, pattern.to_string()))
         }
         "DELETE" => {
-            let key = parts.next()?;
-            if parts.next().is_some() {
-                return None;
-            } // DELETE should have exactly one argument
-            Some(Command::Delete(key.to_string()))
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // DELETE should have exactly one argument
+            Ok(Command::Delete(key))
+        }
+        "APPENDLINE" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let line = decode_value(parts.next().unwrap_or(""));
+            Ok(Command::AppendLine(key, line))
+        }
+        "SETRANGE" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let (offset, bytes) = required(name, parts.next())?
+                .split_once(' ')
+                .ok_or_else(|| ParseError::WrongArgCount(name.to_string()))?;
+            let offset = number_arg(name, offset)?;
+            Ok(Command::SetRange(key, offset, decode_value(bytes)))
+        }
+        "INCRBYFLOAT" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let delta = required(name, parts.next())?;
+            if delta.contains(' ') {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // INCRBYFLOAT should have exactly two arguments
+            Ok(Command::IncrByFloat(key, number_arg(name, delta)?))
+        }
+        "DECRDEL" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // DECRDEL should have exactly one argument
+            Ok(Command::DecrDel(key))
+        }
+        "EVAL" => {
+            // Steps may hold any number of spaces, so take the whole rest
+            let script: Vec<&str> = parts.collect();
+            let steps = script
+                .join(" ")
+                .split(';')
+                .filter(|step| !step.trim().is_empty())
+                .map(|step| parse_eval_step(name, step))
+                .collect::<Result<Vec<_>, _>>()?;
+            if steps.is_empty() {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // EVAL needs at least one step
+            Ok(Command::Eval(steps))
+        }
+        "FETCHADD" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let delta = required(name, parts.next())?;
+            if delta.contains(' ') {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // FETCHADD should have exactly two arguments
+            Ok(Command::FetchAdd(key, number_arg(name, delta)?))
+        }
+        "STRLEN" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // STRLEN should have exactly one argument
+            Ok(Command::StrLen(key))
+        }
+        "SLOT" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // SLOT should have exactly one argument
+            Ok(Command::Slot(key))
+        }
+        "CHANGES" => {
+            let since = required(name, parts.next())?;
+            if !since.eq_ignore_ascii_case("SINCE") {
+                return Err(invalid(name, since));
+            }
+            let version = required(name, parts.next())?;
+            if version.contains(' ') {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            }
+            Ok(Command::Changes(number_arg(name, version)?))
+        }
+        "RANDOMKEY" => {
+            no_more(name, parts.next())?; // RANDOMKEY should have no arguments
+            Ok(Command::RandomKey)
+        }
+        "RANDOMKEYS" => {
+            let count = number_arg(name, required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // RANDOMKEYS should have exactly one argument
+            Ok(Command::RandomKeys(count))
+        }
+        "LRUKEYS" => {
+            let count = number_arg(name, required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // LRUKEYS should have exactly one argument
+            Ok(Command::LruKeys(count))
+        }
+        "CHILDREN" => {
+            // With no prefix, CHILDREN lists the top level
+            let prefix = key_arg(parts.next().unwrap_or(""))?;
+            no_more(name, parts.next())?; // CHILDREN takes at most one argument
+            Ok(Command::Children(prefix))
+        }
+        "META" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // META should have exactly one argument
+            Ok(Command::Meta(key))
+        }
+        "MMETA" => {
+            let keys: Vec<String> = parts
+                .flat_map(|part| part.split(' '))
+                .filter(|key| !key.is_empty())
+                .map(key_arg)
+                .collect::<Result<_, _>>()?;
+            if keys.is_empty() {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // MMETA needs at least one key
+            Ok(Command::MMeta(keys))
+        }
+        "MGET" => {
+            let keys: Vec<String> = parts
+                .flat_map(|part| part.split(' '))
+                .filter(|key| !key.is_empty())
+                .map(key_arg)
+                .collect::<Result<_, _>>()?;
+            if keys.is_empty() {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // MGET needs at least one key
+            Ok(Command::MGet(keys))
+        }
+        "GETDEL" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // GETDEL should have exactly one argument
+            Ok(Command::GetDel(key))
+        }
+        "SWAP" => {
+            let key1 = key_arg(required(name, parts.next())?)?;
+            let key2 = required(name, parts.next())?;
+            if key2.contains(' ') {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // SWAP should have exactly two arguments
+            Ok(Command::Swap(key1, key_arg(key2)?))
+        }
+        "RENAMEIF" => {
+            let old = key_arg(required(name, parts.next())?)?;
+            let (new, expected) = required(name, parts.next())?
+                .split_once(' ')
+                .ok_or_else(|| ParseError::WrongArgCount(name.to_string()))?;
+            Ok(Command::RenameIf(
+                old,
+                key_arg(new)?,
+                decode_value(expected),
+            ))
+        }
+        "TAG" => {
+            let key = key_arg(required(name, parts.next())?)?;
+            let tag = required(name, parts.next())?;
+            if tag.contains(' ') {
+                return Err(ParseError::WrongArgCount(name.to_string()));
+            } // TAG should have exactly two arguments
+            Ok(Command::Tag(key, key_arg(tag)?))
+        }
+        "DELTAG" => {
+            let tag = key_arg(required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // DELTAG should have exactly one argument
+            Ok(Command::DelTag(tag))
         }
         "COMPACT" => {
-            if parts.next().is_some() {
-                return None;
-            } // COMPACT should have no arguments
-            Some(Command::Compact)
+            no_more(name, parts.next())?; // COMPACT should have no arguments
+            Ok(Command::Compact)
+        }
+        "TRIMLOG" => {
+            no_more(name, parts.next())?; // TRIMLOG should have no arguments
+            Ok(Command::TrimLog)
+        }
+        "HEALTHCHECK" => {
+            no_more(name, parts.next())?; // HEALTHCHECK should have no arguments
+            Ok(Command::HealthCheck)
+        }
+        "VERIFY" => {
+            no_more(name, parts.next())?; // VERIFY should have no arguments
+            Ok(Command::Verify)
+        }
+        "INFO" => {
+            no_more(name, parts.next())?; // INFO should have no arguments
+            Ok(Command::Info)
         }
-        _ => None,
+        "REPLICAOF" => {
+            let primary = required(name, parts.next())?;
+            let primary = if primary.eq_ignore_ascii_case("NO") {
+                let one = required(name, parts.next())?;
+                if !one.eq_ignore_ascii_case("ONE") {
+                    return Err(invalid(name, one));
+                }
+                None
+            } else {
+                Some(primary.to_string())
+            };
+            no_more(name, parts.next())?; // REPLICAOF takes an address or NO ONE
+            Ok(Command::ReplicaOf(primary))
+        }
+        "SYNC" => {
+            no_more(name, parts.next())?; // SYNC should have no arguments
+            Ok(Command::Sync)
+        }
+        "REPLSTATUS" => {
+            no_more(name, parts.next())?; // REPLSTATUS should have no arguments
+            Ok(Command::ReplStatus)
+        }
+        "ROLE" => {
+            no_more(name, parts.next())?; // ROLE should have no arguments
+            Ok(Command::Role)
+        }
+        "DATAINFO" => {
+            no_more(name, parts.next())?; // DATAINFO should have no arguments
+            Ok(Command::DataInfo)
+        }
+        "LIMITS" => {
+            no_more(name, parts.next())?; // LIMITS should have no arguments
+            Ok(Command::Limits)
+        }
+        "SNAPSHOT" => {
+            no_more(name, parts.next())?; // SNAPSHOT should have no arguments
+            Ok(Command::Snapshot)
+        }
+        "ENDSNAPSHOT" => {
+            let snapshot = number_arg(name, required(name, parts.next())?)?;
+            no_more(name, parts.next())?; // ENDSNAPSHOT should have exactly one argument
+            Ok(Command::EndSnapshot(snapshot))
+        }
+        "SLOWLOG" => {
+            no_more(name, parts.next())?; // SLOWLOG should have no arguments
+            Ok(Command::SlowLog)
+        }
+        "PAUSE" => {
+            no_more(name, parts.next())?; // PAUSE should have no arguments
+            Ok(Command::Pause)
+        }
+        "RESUME" => {
+            no_more(name, parts.next())?; // RESUME should have no arguments
+            Ok(Command::Resume)
+        }
+        _ => Err(ParseError::UnknownCommand(cmd)),
+    }
+}
+
+/// Returns an argument of `name` that must be present.
+fn required<'a>(name: &str, arg: Option<&'a str>) -> Result<&'a str, ParseError> {
+    arg.ok_or_else(|| ParseError::WrongArgCount(name.to_string()))
+}
+
+/// Parses one `;`-separated step of an `EVAL`; see [`EvalStep`].
+fn parse_eval_step(name: &str, step: &str) -> Result<EvalStep, ParseError> {
+    let mut tokens: Vec<&str> = step.split_whitespace().collect();
+    let condition = if tokens[0].eq_ignore_ascii_case("IF") {
+        if tokens.len() < 6 {
+            return Err(invalid(name, step.trim()));
+        }
+        if !tokens[4].eq_ignore_ascii_case("THEN") {
+            return Err(invalid(name, tokens[4]));
+        }
+        let key = key_arg(tokens[1])?;
+        let value = decode_value(tokens[3]);
+        let condition = match tokens[2].to_uppercase().as_str() {
+            "EQ" => EvalCondition::Equals(key, value),
+            "NE" => EvalCondition::NotEquals(key, value),
+            _ => return Err(invalid(name, tokens[2])),
+        };
+        tokens.drain(..5);
+        Some(condition)
+    } else {
+        None
+    };
+
+    let action = tokens[0].to_uppercase();
+    let action = match (action.as_str(), &tokens[1..]) {
+        ("SET", [key]) => EvalAction::Set(key_arg(key)?, Vec::new()),
+        ("SET", [key, value]) => EvalAction::Set(key_arg(key)?, decode_value(value)),
+        ("DELETE", [key]) => EvalAction::Delete(key_arg(key)?),
+        ("GET", [key]) => EvalAction::Get(key_arg(key)?),
+        ("SET" | "DELETE" | "GET", _) => return Err(ParseError::WrongArgCount(action)),
+        _ => return Err(invalid(name, tokens[0])),
+    };
+    Ok(EvalStep { condition, action })
+}
+
+/// Checks that `name` was given nothing past its last argument.
+fn no_more(name: &str, rest: Option<&str>) -> Result<(), ParseError> {
+    match rest {
+        Some(_) => Err(ParseError::WrongArgCount(name.to_string())),
+        None => Ok(()),
     }
 }
+
+/// Decodes a key argument, rejecting a `base64key:` prefix on bad base64.
+fn key_arg(arg: &str) -> Result<String, ParseError> {
+    decode_key(arg).ok_or_else(|| ParseError::InvalidBase64(arg.to_string()))
+}
+
+/// Parses a numeric argument of `name`.
+fn number_arg<T: FromStr>(name: &str, arg: &str) -> Result<T, ParseError> {
+    arg.parse().map_err(|_| invalid(name, arg))
+}
+
+fn invalid(name: &str, arg: &str) -> ParseError {
+    ParseError::InvalidArgument(name.to_string(), arg.to_string())
+}
@@ -0,0 +1,121 @@
+//! Recording a server's wire traffic for debugging.
+//!
+//! With a capture file set, every request line and the response sent for it
+//! are appended to the file as a pair of length-prefixed records:
+//!
+//! ```text
+//! REQUEST 11
+//! SET a hello
+//! RESPONSE 3
+//! OK
+//!
+//! ```
+//!
+//! The number is the payload's length in bytes, and the payload is followed
+//! by a newline of its own. Payloads are stored exactly as they crossed the
+//! wire, so multi-line responses and `base64:` values need no escaping. A
+//! `NOREPLY` request has an empty response.
+//!
+//! Capturing is a debugging aid, not something to leave on: every command
+//! takes a shared lock and a write to the file, and the file grows without
+//! bound. [`read_capture`] reads a capture back, and
+//! [`Client::replay_capture`](crate::client::Client::replay_capture) sends it
+//! to another server.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A request as received by the server and the response it sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedExchange {
+    /// The request line, without its line ending
+    pub request: String,
+    /// Everything sent in reply, including line endings; empty for
+    /// `NOREPLY`
+    pub response: String,
+}
+
+/// The open capture file, shared by every connection.
+pub(super) struct CaptureLog {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl CaptureLog {
+    /// Opens the capture file at `path`, appending to it if it exists.
+    pub(super) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Appends one exchange, flushed so a crash right after loses nothing.
+    pub(super) fn record(&self, request: &str, response: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for (kind, payload) in [("REQUEST", request), ("RESPONSE", response)] {
+            writeln!(file, "{} {}", kind, payload.len())?;
+            file.write_all(payload.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()
+    }
+}
+
+/// Reads every exchange in the capture file at `path`, in the order the
+/// server handled them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use keystonelight::server::read_capture;
+///
+/// for exchange in read_capture("capture.log").unwrap() {
+///     print!("> {}\n{}", exchange.request, exchange.response);
+/// }
+/// ```
+pub fn read_capture<P: AsRef<Path>>(path: P) -> io::Result<Vec<CapturedExchange>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut exchanges = Vec::new();
+    while let Some(request) = read_record(&mut reader, "REQUEST")? {
+        let response = read_record(&mut reader, "RESPONSE")?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("capture ends before the response to `{}`", request),
+            )
+        })?;
+        exchanges.push(CapturedExchange { request, response });
+    }
+    Ok(exchanges)
+}
+
+/// Reads one `<kind> <len>` record, or `None` at the end of the file.
+fn read_record<R: BufRead>(reader: &mut R, kind: &str) -> io::Result<Option<String>> {
+    let mut header = String::new();
+    if reader.read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected a {} record, got `{}`", kind, header.trim_end()),
+        )
+    };
+    let len: usize = header
+        .trim_end()
+        .strip_prefix(kind)
+        .and_then(|len| len.strip_prefix(' '))
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(invalid)?;
+
+    // The payload plus the newline that ends the record
+    let mut payload = vec![0; len + 1];
+    reader.read_exact(&mut payload)?;
+    if payload.pop() != Some(b'\n') {
+        return Err(invalid());
+    }
+    String::from_utf8(payload)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
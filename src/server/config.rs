@@ -0,0 +1,247 @@
+//! Server settings loaded from a configuration file.
+//!
+//! The file format is a small subset of TOML: one `key = value` setting per
+//! line, with `#` starting a comment. String values may be wrapped in double
+//! quotes; numbers and `true`/`false` are written bare. Any setting left out
+//! keeps its default. `quota` may be given several times, once per prefix.
+//!
+//! ```text
+//! # keystonelight.conf
+//! addr = "127.0.0.1:7878"
+//! threads = 8
+//! log_file = "/var/lib/keystonelight/keystonelight.log"
+//! max_keys = 100000
+//! quota = "tenant1: 1048576"
+//! quota = "tenant2: 4194304"
+//! max_pipeline_depth = 64
+//! compact_on_shutdown = true
+//! require_utf8 = true
+//! key_separator = "/"
+//! mirror_log = "/mnt/standby/keystonelight.log"
+//! slow_log_threshold_ms = 50
+//! metrics_file = "/var/log/keystonelight/metrics.log"
+//! metrics_interval_secs = 60
+//! capture_file = "/tmp/keystonelight.capture"
+//! ```
+
+use super::{DEFAULT_KEY_SEPARATOR, DEFAULT_READ_BUFFER_SIZE, DEFAULT_THREAD_COUNT, SERVER_ADDR};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often stats are written when `metrics_file` is set
+const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings for [`Server::from_config`](super::Server::from_config).
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::server::ServerConfig;
+///
+/// let config = ServerConfig::parse("threads = 2\naddr = \"127.0.0.1:9000\"\n").unwrap();
+/// assert_eq!(config.threads, 2);
+/// assert_eq!(config.addr, "127.0.0.1:9000");
+/// assert_eq!(config.max_keys, None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// Where the server writes its PID (`pid_file`)
+    pub pid_file: PathBuf,
+    /// The append-only log holding the data (`log_file`)
+    pub log_file: PathBuf,
+    /// Number of worker threads (`threads`)
+    pub threads: usize,
+    /// Address to listen on (`addr`)
+    pub addr: String,
+    /// Cap on the number of keys (`max_keys`)
+    pub max_keys: Option<usize>,
+    /// Byte budgets for the values under key prefixes, from each
+    /// `quota = "<prefix> <bytes>"` line
+    pub quotas: Vec<(String, usize)>,
+    /// Longest request line a client may send (`max_request_bytes`)
+    pub max_request_bytes: Option<usize>,
+    /// Cap on the log's size on disk (`max_log_bytes`)
+    pub max_log_bytes: Option<usize>,
+    /// Commands a connection runs before yielding its worker
+    /// (`max_pipeline_depth`)
+    pub max_pipeline_depth: Option<usize>,
+    /// Capacity of each connection's read buffer (`read_buffer_size`)
+    pub read_buffer_size: usize,
+    /// Whether the log compacts itself as it grows (`auto_compact`)
+    pub auto_compact: bool,
+    /// Whether SETs of an unchanged value are skipped (`dedup_writes`)
+    pub dedup_writes: bool,
+    /// Whether non-UTF-8 keys and values are rejected (`require_utf8`)
+    pub require_utf8: bool,
+    /// Separator `CHILDREN` splits keys on (`key_separator`)
+    pub key_separator: String,
+    /// Whether the log is compacted on a graceful shutdown
+    /// (`compact_on_shutdown`)
+    pub compact_on_shutdown: bool,
+    /// Second log every write is also appended to (`mirror_log`)
+    pub mirror_log: Option<PathBuf>,
+    /// Commands slower than this are kept for `SLOWLOG`
+    /// (`slow_log_threshold_ms`)
+    pub slow_log_threshold: Option<Duration>,
+    /// File a stats line is appended to periodically (`metrics_file`)
+    pub metrics_file: Option<PathBuf>,
+    /// How often a stats line is written (`metrics_interval_secs`)
+    pub metrics_interval: Duration,
+    /// File every request and response is recorded to, for debugging only
+    /// (`capture_file`)
+    pub capture_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            pid_file: PathBuf::from("keystonelight.pid"),
+            log_file: PathBuf::from("keystonelight.log"),
+            threads: DEFAULT_THREAD_COUNT,
+            addr: SERVER_ADDR.to_string(),
+            max_keys: None,
+            quotas: Vec::new(),
+            max_request_bytes: None,
+            max_log_bytes: None,
+            max_pipeline_depth: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            auto_compact: true,
+            dedup_writes: false,
+            require_utf8: false,
+            key_separator: DEFAULT_KEY_SEPARATOR.to_string(),
+            compact_on_shutdown: false,
+            mirror_log: None,
+            slow_log_threshold: None,
+            metrics_file: None,
+            metrics_interval: DEFAULT_METRICS_INTERVAL,
+            capture_file: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads and parses a configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read config {}: {}", path.display(), e),
+            )
+        })?;
+        Self::parse(&text)
+    }
+
+    /// Parses configuration text, starting from the defaults.
+    ///
+    /// Unknown keys and malformed values are rejected with an `InvalidData`
+    /// error naming the offending line.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut config = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("config line {}: {}", index + 1, msg),
+                )
+            };
+
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected `key = value`, got `{}`", line)))?;
+            let key = key.trim();
+            let value = unquote(value.trim());
+
+            match key {
+                "pid_file" => config.pid_file = PathBuf::from(value),
+                "log_file" => config.log_file = PathBuf::from(value),
+                "threads" => config.threads = parse_value(key, value).map_err(invalid)?,
+                "addr" => config.addr = value.to_string(),
+                "max_keys" => config.max_keys = Some(parse_value(key, value).map_err(invalid)?),
+                "quota" => {
+                    let (prefix, max_bytes) = value.rsplit_once(' ').ok_or_else(|| {
+                        invalid(format!(
+                            "expected `<prefix> <bytes>` for `quota`, got `{}`",
+                            value
+                        ))
+                    })?;
+                    let max_bytes = parse_value(key, max_bytes).map_err(invalid)?;
+                    config.quotas.push((prefix.to_string(), max_bytes))
+                }
+                "max_request_bytes" => {
+                    config.max_request_bytes = Some(parse_value(key, value).map_err(invalid)?)
+                }
+                "max_log_bytes" => {
+                    config.max_log_bytes = Some(parse_value(key, value).map_err(invalid)?)
+                }
+                "max_pipeline_depth" => {
+                    config.max_pipeline_depth = Some(parse_value(key, value).map_err(invalid)?)
+                }
+                "read_buffer_size" => {
+                    config.read_buffer_size = parse_value(key, value).map_err(invalid)?
+                }
+                "auto_compact" => config.auto_compact = parse_value(key, value).map_err(invalid)?,
+                "dedup_writes" => config.dedup_writes = parse_value(key, value).map_err(invalid)?,
+                "require_utf8" => config.require_utf8 = parse_value(key, value).map_err(invalid)?,
+                "key_separator" => config.key_separator = value.to_string(),
+                "compact_on_shutdown" => {
+                    config.compact_on_shutdown = parse_value(key, value).map_err(invalid)?
+                }
+                "mirror_log" => config.mirror_log = Some(PathBuf::from(value)),
+                "slow_log_threshold_ms" => {
+                    let millis = parse_value(key, value).map_err(invalid)?;
+                    config.slow_log_threshold = Some(Duration::from_millis(millis))
+                }
+                "metrics_file" => config.metrics_file = Some(PathBuf::from(value)),
+                "metrics_interval_secs" => {
+                    let secs = parse_value(key, value).map_err(invalid)?;
+                    config.metrics_interval = Duration::from_secs(secs)
+                }
+                "capture_file" => config.capture_file = Some(PathBuf::from(value)),
+                _ => return Err(invalid(format!("unknown setting `{}`", key))),
+            }
+        }
+
+        if config.threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "config: threads must be at least 1",
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Drops a `#` comment, leaving `#` inside a quoted string alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' if !in_quotes => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Removes one pair of surrounding double quotes, if present.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{}` for `{}`", value, key))
+}
@@ -48,21 +48,31 @@
 //! fs::remove_file("custom.log").unwrap_or(());
 //! ```
 
-use crate::storage::Database;
-use crate::thread_pool::ThreadPool;
+use crate::protocol::{encode_key, ParseError, Response, ValueEncoding};
+use crate::storage::{CancelToken, Database};
+use crate::thread_pool::{PoolHandle, ThreadPool};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use libc;
 use signal_hook::iterator::Signals;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod capture;
+mod config;
+mod replication;
+
+use capture::CaptureLog;
+pub use capture::{read_capture, CapturedExchange};
+pub use config::ServerConfig;
+use replication::ReplicaLink;
 
 /// The address the server listens on
 const SERVER_ADDR: &str = "0.0.0.0:7878";
@@ -72,6 +82,437 @@ const BIND_TIMEOUT: Duration = Duration::from_secs(5);
 const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 /// Default number of worker threads
 const DEFAULT_THREAD_COUNT: usize = 4;
+/// Default capacity of each connection's read buffer
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+/// Smallest read buffer a connection gets, whatever is configured
+const MIN_READ_BUFFER_SIZE: usize = 64;
+/// Default separator between the segments of a hierarchical key
+const DEFAULT_KEY_SEPARATOR: &str = ":";
+/// Default longest time writes stay paused before resuming on their own
+const DEFAULT_MAX_PAUSE: Duration = Duration::from_secs(10 * 60);
+/// Number of slow commands kept for `SLOWLOG`
+const SLOW_LOG_CAPACITY: usize = 128;
+/// Most idempotency tokens remembered at once; the oldest are forgotten first
+const IDEMPOTENCY_CAPACITY: usize = 10_000;
+/// How long the reply to an idempotency token is remembered
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(5 * 60);
+/// Default time spent turning away queued connections on shutdown
+const DEFAULT_SHUTDOWN_DRAIN: Duration = Duration::from_secs(1);
+/// Reply sent to connections that arrive while the server shuts down
+const SHUTTING_DOWN_REPLY: &[u8] = b"ERROR shutting down\n";
+/// Signals that shut the server down by default
+const DEFAULT_SHUTDOWN_SIGNALS: [i32; 2] = [libc::SIGTERM, libc::SIGINT];
+/// Signals that make the server reopen its log file by default
+const DEFAULT_REOPEN_SIGNALS: [i32; 1] = [libc::SIGHUP];
+
+/// A hook run on every SET before the value is stored.
+///
+/// Besides the SET family, it sees the values stored by an EVAL script and
+/// the combined values APPENDLINE and SETRANGE build. The hook may rewrite
+/// the value in place; returning `Err(msg)` rejects the write and the client
+/// sees `ERROR <msg>`.
+pub type SetHook = Box<dyn Fn(&str, &mut Vec<u8>) -> Result<(), String> + Send + Sync>;
+
+/// Server-side hooks for transforming or validating values.
+///
+/// # Examples
+///
+/// ```no_run
+/// use keystonelight::server::Hooks;
+/// use keystonelight::Server;
+///
+/// let hooks = Hooks {
+///     on_set: Some(Box::new(|_key, value| {
+///         value.make_ascii_uppercase();
+///         Ok(())
+///     })),
+/// };
+/// let server = Server::new().unwrap().with_hooks(hooks);
+/// ```
+#[derive(Default)]
+pub struct Hooks {
+    /// Called with the key and value of every SET before it is stored
+    pub on_set: Option<SetHook>,
+}
+
+impl Hooks {
+    fn before_set(&self, key: &str, value: &mut Vec<u8>) -> Result<(), String> {
+        match &self.on_set {
+            Some(hook) => hook(key, value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A handler for a custom command registered with [`Server::register_command`].
+///
+/// The handler gets the whitespace-separated arguments that follow the
+/// command name, and the database, locked for the duration of the call.
+pub type CommandHandler = Box<dyn Fn(&[String], &Database) -> Response + Send + Sync>;
+
+/// A [`CommandHandler`] shared by every connection.
+type SharedCommandHandler = Arc<dyn Fn(&[String], &Database) -> Response + Send + Sync>;
+
+/// A registered custom command.
+struct CustomCommand {
+    handler: SharedCommandHandler,
+    /// Whether the handler changes the database, so it is refused while
+    /// writes are paused or on a replica
+    writes: bool,
+}
+
+/// What the accept loop does with a new connection when every worker is busy
+/// and the connection queue is full.
+///
+/// Only consulted once the queue is bounded with
+/// [`Server::with_max_queued_connections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaturationPolicy {
+    /// Reply `ERROR busy` and close the connection. Clients learn right away
+    /// that they should back off, at the cost of one write per rejection.
+    #[default]
+    Reject,
+    /// Stop accepting until a worker frees up. New connections wait in the
+    /// kernel's listen backlog, so clients see latency instead of errors, but
+    /// once the backlog fills their connects stall or fail, and a slow
+    /// connection holds up every listener's pending clients.
+    Block,
+    /// Close the connection without a reply. The cheapest option for the
+    /// server, but clients cannot tell an overload from a network failure.
+    Shed,
+}
+
+/// A handle that stops a running server from another thread.
+///
+/// Shutting down through the handle follows the same path as `SIGTERM`: the
+/// accept loops stop, a `COMPACT` or `VERIFY` still running is cancelled so
+/// shutdown doesn't wait for it, then the log is flushed (and compacted, if
+/// enabled) before [`Server::run`] returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+    cancel: CancelToken,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down.
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.cancel.cancel();
+    }
+}
+
+/// Settings every client handler needs, cloned into each connection.
+#[derive(Clone)]
+struct ConnectionOptions {
+    /// Hooks applied to incoming writes
+    hooks: Arc<Hooks>,
+    /// Custom commands, keyed by upper-cased name
+    commands: Arc<HashMap<String, Arc<CustomCommand>>>,
+    /// Longest request line, in bytes, a client may send
+    max_request_bytes: Option<usize>,
+    /// Capacity of each connection's read buffer
+    read_buffer_size: usize,
+    /// Commands a connection may run before giving up its worker
+    max_pipeline_depth: Option<usize>,
+    /// Separator between the segments of a hierarchical key, for `CHILDREN`
+    key_separator: Arc<str>,
+    /// Runtime details reported by `INFO`
+    info: Arc<ServerInfo>,
+    /// Whether writes are paused by `PAUSE`
+    pause: Arc<WritePause>,
+    /// Recent commands that took longer than the slow log threshold
+    slow_log: Arc<SlowLog>,
+    /// The primary this server follows, set by `REPLICAOF`; clients can't
+    /// write while it is set
+    replica: Arc<Mutex<Option<ReplicaLink>>>,
+    /// Replicas this server is currently streaming its log to
+    followers: Arc<AtomicUsize>,
+    /// Replies remembered for `IDEM` tokens
+    idempotency: Arc<IdempotencyCache>,
+    /// Commands handled since the server was created
+    commands_processed: Arc<AtomicU64>,
+    /// Where every request and its response are recorded, when capturing
+    capture: Option<Arc<CaptureLog>>,
+}
+
+/// A bounded record of commands that took longer than `threshold`, newest
+/// last, reported by `SLOWLOG`.
+struct SlowLog {
+    /// `None` disables the slow log
+    threshold: Option<Duration>,
+    entries: Mutex<VecDeque<SlowCommand>>,
+}
+
+/// One slow command: its name, when it finished and how long it took.
+struct SlowCommand {
+    name: String,
+    finished: SystemTime,
+    duration: Duration,
+}
+
+impl SlowLog {
+    fn new(threshold: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Records `name` if `duration` is over the threshold, evicting the
+    /// oldest entry once the log is full.
+    fn record(&self, name: &str, duration: Duration) {
+        match self.threshold {
+            Some(threshold) if duration > threshold => {}
+            _ => return,
+        }
+        println!("Slow command {} took {:?}", name, duration);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == SLOW_LOG_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(SlowCommand {
+            name: name.to_string(),
+            finished: SystemTime::now(),
+            duration,
+        });
+    }
+
+    /// Formats the `SLOWLOG` report, newest first, ending with `END`.
+    fn report(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut report = String::new();
+        for entry in entries.iter().rev() {
+            report.push_str(&format!(
+                "{} duration_us={} at={}\n",
+                entry.name,
+                entry.duration.as_micros(),
+                unix_millis(entry.finished)
+            ));
+        }
+        report.push_str("END\n");
+        report
+    }
+}
+
+/// Replies to recent commands sent with an `IDEM <token>`, so a retry with
+/// the same token is answered without running the command twice.
+///
+/// Tokens are kept for up to `ttl`, and only the newest `capacity` of them.
+/// Error replies are forgotten straight away so the command can be retried.
+struct IdempotencyCache {
+    capacity: usize,
+    ttl: Duration,
+    tokens: Mutex<IdempotencyTokens>,
+}
+
+#[derive(Default)]
+struct IdempotencyTokens {
+    /// The reply for each token, or `None` while its command is running
+    replies: HashMap<String, (Instant, Option<String>)>,
+    /// Tokens in the order they were first seen, for expiry
+    order: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            tokens: Mutex::new(IdempotencyTokens::default()),
+        }
+    }
+
+    /// Returns the reply to send for a token that has been seen before.
+    /// For a new token, returns `None` and reserves it until `finish`.
+    fn begin(&self, token: &str) -> Option<String> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Instant::now();
+        while let Some((seen, _)) = tokens.order.front() {
+            if now.duration_since(*seen) < self.ttl && tokens.order.len() < self.capacity {
+                break;
+            }
+            let (seen, token) = tokens.order.pop_front().unwrap();
+            // The token may have been dropped and reused since
+            if tokens
+                .replies
+                .get(&token)
+                .is_some_and(|(at, _)| *at == seen)
+            {
+                tokens.replies.remove(&token);
+            }
+        }
+
+        if let Some((_, reply)) = tokens.replies.get(token) {
+            return Some(
+                reply
+                    .clone()
+                    .unwrap_or_else(|| "ERROR request in progress\n".to_string()),
+            );
+        }
+        tokens.replies.insert(token.to_string(), (now, None));
+        tokens.order.push_back((now, token.to_string()));
+        None
+    }
+
+    /// Records the reply to a token reserved by `begin`.
+    fn finish(&self, token: &str, reply: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        if reply.starts_with("ERROR") {
+            tokens.replies.remove(token);
+        } else if let Some((_, slot)) = tokens.replies.get_mut(token) {
+            *slot = Some(reply.to_string());
+        }
+    }
+}
+
+/// The maintenance-mode flag set by `PAUSE` and cleared by `RESUME`.
+///
+/// A pause that outlives `max` is lifted the next time it is checked, so a
+/// forgotten `RESUME` can't block writes forever.
+struct WritePause {
+    paused: AtomicBool,
+    /// When the current pause began
+    since: Mutex<Instant>,
+    /// Longest a pause may last
+    max: Duration,
+}
+
+impl WritePause {
+    fn new(max: Duration) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            since: Mutex::new(Instant::now()),
+            max,
+        }
+    }
+
+    fn pause(&self) {
+        *self.since.lock().unwrap() = Instant::now();
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether writes are paused, lifting an expired pause.
+    fn is_paused(&self) -> bool {
+        if !self.paused.load(Ordering::SeqCst) {
+            return false;
+        }
+        if self.since.lock().unwrap().elapsed() >= self.max {
+            println!("Writes paused for over {:?}, resuming", self.max);
+            self.resume();
+            return false;
+        }
+        true
+    }
+}
+
+/// Details about a running server that don't change while it runs.
+struct ServerInfo {
+    started: Instant,
+    worker_threads: usize,
+    addrs: Vec<SocketAddr>,
+}
+
+/// Where and how often the server writes periodic snapshots.
+struct SnapshotSchedule {
+    dir: PathBuf,
+    interval: Duration,
+    /// How many of the newest snapshots to keep
+    keep: usize,
+}
+
+impl SnapshotSchedule {
+    /// Writes a timestamped snapshot, then removes all but the newest `keep`.
+    fn take(&self, storage: &Mutex<Database>) -> io::Result<PathBuf> {
+        let millis = unix_millis(SystemTime::now());
+        // Zero-padded so snapshot names sort in the order they were taken
+        let path = self.dir.join(format!("snapshot-{:020}.log", millis));
+        storage.lock().unwrap().snapshot_to(&path)?;
+
+        let mut snapshots: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("snapshot-") && name.ends_with(".log"))
+            })
+            .collect();
+        snapshots.sort();
+        let excess = snapshots.len().saturating_sub(self.keep.max(1));
+        for old in &snapshots[..excess] {
+            fs::remove_file(old)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Where and how often the server appends a stats line to its metrics file.
+struct MetricsSchedule {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl MetricsSchedule {
+    /// Appends one timestamped stats line, counting `ops` commands since the
+    /// previous one.
+    fn record(&self, storage: &Mutex<Database>, ops: u64) -> io::Result<()> {
+        let (keys, log_bytes) = {
+            let storage = storage.lock().unwrap();
+            (storage.key_count(), storage.log_size())
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "at={} keys={} log_bytes={} ops={}",
+            unix_millis(SystemTime::now()),
+            keys,
+            log_bytes,
+            ops
+        )
+    }
+}
+
+/// Logs a sampled fraction of accepted connections.
+struct AcceptLog {
+    /// Fraction of connections to log, from 0.0 (none) to 1.0 (all)
+    sample: f64,
+    /// Connections accepted so far
+    seen: u64,
+    sink: Box<dyn Write + Send>,
+}
+
+impl AcceptLog {
+    /// Records an accepted connection if it falls in the sample.
+    ///
+    /// Sampling is deterministic: connection `n` is logged whenever
+    /// `n * sample` crosses an integer, which spreads logged connections
+    /// evenly and makes 0.0 and 1.0 exact.
+    fn record(&mut self, peer: SocketAddr) {
+        self.seen += 1;
+        let logged_after = (self.seen as f64 * self.sample).floor();
+        let logged_before = ((self.seen - 1) as f64 * self.sample).floor();
+        if logged_after > logged_before {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let _ = writeln!(
+                self.sink,
+                "{}.{:03} accepted connection from {}",
+                now.as_secs(),
+                now.subsec_millis(),
+                peer
+            );
+            let _ = self.sink.flush();
+        }
+    }
+}
 
 /// A server instance that manages client connections and processes commands.
 ///
@@ -79,7 +520,7 @@ const DEFAULT_THREAD_COUNT: usize = 4;
 /// - Multi-threaded command processing
 /// - Persistent storage with automatic log compaction
 /// - PID file management
-/// - Signal handling (SIGTERM, SIGINT)
+/// - Signal handling (SIGTERM, SIGINT, and SIGHUP to reopen the log)
 /// - Graceful shutdown
 ///
 /// # Examples
@@ -101,32 +542,80 @@ const DEFAULT_THREAD_COUNT: usize = 4;
 pub struct Server {
     /// The underlying key-value store
     storage: Arc<Mutex<Database>>,
-    /// The TCP listener for accepting connections
-    listener: TcpListener,
+    /// The TCP listeners accepting connections, one per bound address
+    listeners: Vec<TcpListener>,
     /// Flag indicating if the server should continue running
     running: Arc<AtomicBool>,
+    /// The storage's cancel token, kept here since a long operation holds
+    /// the storage lock
+    cancel: CancelToken,
     /// Path to the PID file
     pid_file: PathBuf,
     /// Thread pool for handling client connections
     thread_pool: ThreadPool,
+    /// Settings shared with every connection handler
+    options: ConnectionOptions,
+    /// Signals that trigger a graceful shutdown
+    shutdown_signals: Vec<i32>,
+    /// Signals that make the server reopen its log file
+    reopen_signals: Vec<i32>,
+    /// Sampled log of accepted connections
+    accept_log: Mutex<AcceptLog>,
+    /// Whether to compact the log after the server stops accepting connections
+    compact_on_shutdown: bool,
+    /// What to do with connections that arrive while the queue is full
+    saturation_policy: SaturationPolicy,
+    /// Periodic snapshots, if enabled
+    snapshots: Option<SnapshotSchedule>,
+    /// Periodic stats lines, if enabled
+    metrics: Option<MetricsSchedule>,
+    /// Longest time spent rejecting backlogged connections on shutdown
+    shutdown_drain: Duration,
 }
 
 impl Server {
     fn cleanup_stale_pid_file(pid_file: &Path) -> io::Result<()> {
-        if let Ok(pid_str) = fs::read_to_string(pid_file) {
-            if let Ok(pid) = pid_str.trim().parse::<u32>() {
-                if !process_exists(pid) {
+        if let Ok(contents) = fs::read_to_string(pid_file) {
+            match parse_pid_file(&contents) {
+                Some((pid, started)) if !process_matches(pid, started) => {
                     println!("Cleaning up stale PID file from process {}", pid);
                     fs::remove_file(pid_file)?;
                 }
-            } else {
+                Some(_) => {}
                 // Invalid PID in file, clean it up
-                fs::remove_file(pid_file)?;
+                None => fs::remove_file(pid_file)?,
             }
         }
         Ok(())
     }
 
+    /// Returns the PID of the server recorded in `pid_file`, if it is still
+    /// running.
+    ///
+    /// The file holds the server's PID and, where the platform reports it,
+    /// the process's start time. A PID file left behind by a dead process,
+    /// or whose PID has since been reused by a process that started at a
+    /// different time, is removed, as is one that does not hold a valid PID,
+    /// and `None` is returned. A file holding only a PID is trusted as long
+    /// as some process has that PID.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::Server;
+    /// use std::path::Path;
+    ///
+    /// match Server::running_pid(Path::new("keystonelight.pid")) {
+    ///     Some(pid) => println!("Server running with PID {}", pid),
+    ///     None => println!("Server not running"),
+    /// }
+    /// ```
+    pub fn running_pid(pid_file: &Path) -> Option<u32> {
+        let _ = Self::cleanup_stale_pid_file(pid_file);
+        let (pid, started) = parse_pid_file(&fs::read_to_string(pid_file).ok()?)?;
+        process_matches(pid, started).then_some(pid)
+    }
+
     /// Creates a new server with default settings.
     ///
     /// This will:
@@ -186,6 +675,28 @@ impl Server {
         pid_file: P1,
         log_file: P2,
         num_threads: usize,
+    ) -> io::Result<Self> {
+        Self::with_addr(pid_file, log_file, num_threads, SERVER_ADDR)
+    }
+
+    /// Creates a new server bound to a specific address.
+    ///
+    /// Binding to port 0 picks an ephemeral port; use [`Server::local_addr`]
+    /// to find out which one.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::Server;
+    ///
+    /// let server = Server::with_addr("custom.pid", "custom.log", 4, "127.0.0.1:0").unwrap();
+    /// println!("Listening on {}", server.local_addr().unwrap());
+    /// ```
+    pub fn with_addr<P1: AsRef<Path>, P2: AsRef<Path>>(
+        pid_file: P1,
+        log_file: P2,
+        num_threads: usize,
+        addr: &str,
     ) -> io::Result<Self> {
         let pid_file = pid_file.as_ref().to_path_buf();
 
@@ -193,39 +704,78 @@ impl Server {
         Self::cleanup_stale_pid_file(&pid_file)?;
 
         // Check if PID file exists and process is running
-        if let Ok(pid_str) = fs::read_to_string(&pid_file) {
-            if let Ok(pid) = pid_str.trim().parse::<u32>() {
-                if process_exists(pid) {
-                    return Err(io::Error::new(
-                        io::ErrorKind::AddrInUse,
-                        format!("Server already running with PID {}", pid),
-                    ));
-                }
-            }
+        if let Some(pid) = Self::running_pid(&pid_file) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Server already running with PID {}", pid),
+            ));
         }
 
         // Write PID file
         let pid = process::id();
-        fs::write(&pid_file, format!("{}\n", pid))?;
+        let contents = match process_start_time(pid) {
+            Some(started) => format!("{} {}\n", pid, started),
+            None => format!("{}\n", pid),
+        };
+        fs::write(&pid_file, contents)?;
 
-        let storage = Arc::new(Mutex::new(Database::new()?));
+        let database = Database::with_log_path(log_file)?;
+        let cancel = database.cancel_token();
+        let storage = Arc::new(Mutex::new(database));
         let thread_pool = ThreadPool::new(num_threads);
         let start_time = Instant::now();
         let running = Arc::new(AtomicBool::new(true));
 
         loop {
-            match TcpListener::bind(SERVER_ADDR) {
+            match TcpListener::bind(addr) {
                 Ok(listener) => {
                     println!(
                         "Server listening on {} with {} worker threads",
-                        SERVER_ADDR, num_threads
+                        addr, num_threads
                     );
                     return Ok(Self {
                         storage,
-                        listener,
+                        listeners: vec![listener],
                         running,
+                        cancel,
                         pid_file,
                         thread_pool,
+                        options: ConnectionOptions {
+                            hooks: Arc::new(Hooks::default()),
+                            commands: Arc::new(HashMap::new()),
+                            max_request_bytes: None,
+                            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+                            max_pipeline_depth: None,
+                            key_separator: Arc::from(DEFAULT_KEY_SEPARATOR),
+                            // Refreshed when the server starts running
+                            info: Arc::new(ServerInfo {
+                                started: Instant::now(),
+                                worker_threads: num_threads,
+                                addrs: Vec::new(),
+                            }),
+                            pause: Arc::new(WritePause::new(DEFAULT_MAX_PAUSE)),
+                            slow_log: Arc::new(SlowLog::new(None)),
+                            replica: Arc::new(Mutex::new(None)),
+                            followers: Arc::new(AtomicUsize::new(0)),
+                            idempotency: Arc::new(IdempotencyCache::new(
+                                IDEMPOTENCY_CAPACITY,
+                                IDEMPOTENCY_TTL,
+                            )),
+                            commands_processed: Arc::new(AtomicU64::new(0)),
+                            capture: None,
+                        },
+                        shutdown_signals: DEFAULT_SHUTDOWN_SIGNALS.to_vec(),
+                        reopen_signals: DEFAULT_REOPEN_SIGNALS.to_vec(),
+                        compact_on_shutdown: false,
+                        saturation_policy: SaturationPolicy::default(),
+                        snapshots: None,
+                        metrics: None,
+                        shutdown_drain: DEFAULT_SHUTDOWN_DRAIN,
+                        accept_log: Mutex::new(AcceptLog {
+                            sample: 0.0,
+                            seen: 0,
+                            sink: Box::new(io::stdout()),
+                        }),
                     });
                 }
                 Err(e) => {
@@ -236,7 +786,7 @@ impl Server {
                             io::ErrorKind::AddrInUse,
                             format!(
                                 "Failed to bind to {} after {} seconds: {}",
-                                SERVER_ADDR,
+                                addr,
                                 BIND_TIMEOUT.as_secs(),
                                 e
                             ),
@@ -248,161 +798,1459 @@ impl Server {
         }
     }
 
-    /// Runs the server, accepting and handling client connections.
-    ///
-    /// This method blocks until the server is shut down via a signal
-    /// (SIGTERM or SIGINT) or encounters an error.
+    /// Creates a server from a [`ServerConfig`].
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// use keystonelight::Server;
-    /// use std::fs;
+    /// use keystonelight::server::{Server, ServerConfig};
     ///
-    /// let server = Server::new().unwrap();
+    /// let config = ServerConfig {
+    ///     threads: 8,
+    ///     max_keys: Some(10_000),
+    ///     ..ServerConfig::default()
+    /// };
+    /// let server = Server::from_config(&config).unwrap();
+    /// ```
+    pub fn from_config(config: &ServerConfig) -> io::Result<Self> {
+        let mut server = Self::with_addr(
+            &config.pid_file,
+            &config.log_file,
+            config.threads,
+            &config.addr,
+        )?
+        .with_auto_compact(config.auto_compact)
+        .with_dedup_writes(config.dedup_writes)
+        .with_require_utf8(config.require_utf8)
+        .with_read_buffer_size(config.read_buffer_size)
+        .with_key_separator(&config.key_separator)
+        .with_compact_on_shutdown(config.compact_on_shutdown);
+        if let Some(max_keys) = config.max_keys {
+            server = server.with_max_keys(max_keys);
+        }
+        for (prefix, max_bytes) in &config.quotas {
+            server = server.with_quota(prefix, *max_bytes);
+        }
+        if let Some(max_bytes) = config.max_request_bytes {
+            server = server.with_max_request_bytes(max_bytes);
+        }
+        if let Some(max_bytes) = config.max_log_bytes {
+            server = server.with_max_log_bytes(max_bytes);
+        }
+        if let Some(depth) = config.max_pipeline_depth {
+            server = server.with_max_pipeline_depth(depth);
+        }
+        if let Some(mirror_log) = &config.mirror_log {
+            server = server.with_mirror_log(mirror_log)?;
+        }
+        if let Some(threshold) = config.slow_log_threshold {
+            server = server.with_slow_log_threshold(threshold);
+        }
+        if let Some(metrics_file) = &config.metrics_file {
+            server = server.with_metrics_file(metrics_file, config.metrics_interval);
+        }
+        if let Some(capture_file) = &config.capture_file {
+            server = server.with_capture_file(capture_file)?;
+        }
+        Ok(server)
+    }
+
+    /// Creates a server from a configuration file; see [`ServerConfig`] for
+    /// the format.
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_config(&ServerConfig::from_file(path)?)
+    }
+
+    /// Caps the number of keys the server will store.
     ///
-    /// // Run the server (this will block)
-    /// // server.run().unwrap();
+    /// SETs of new keys past the cap reply `ERROR database full`; updates and
+    /// deletes keep working. See [`Database::set_max_keys`].
+    pub fn with_max_keys(self, max_keys: usize) -> Self {
+        self.storage.lock().unwrap().set_max_keys(Some(max_keys));
+        self
+    }
+
+    /// Caps the total bytes of value stored under keys starting with
+    /// `prefix`; SETs past it reply `ERROR quota exceeded`. Call once per
+    /// prefix. See [`Database::set_quota`].
+    pub fn with_quota(self, prefix: &str, max_bytes: usize) -> Self {
+        self.storage.lock().unwrap().set_quota(prefix, max_bytes);
+        self
+    }
+
+    /// Turns size-triggered log compaction on or off (on by default). See
+    /// [`Database::set_auto_compact`].
+    pub fn with_auto_compact(self, auto_compact: bool) -> Self {
+        self.storage.lock().unwrap().set_auto_compact(auto_compact);
+        self
+    }
+
+    /// Caps the log's size on disk; writes that would exceed it even after
+    /// compaction reply `ERROR disk budget exceeded`. See
+    /// [`Database::set_max_log_bytes`].
+    pub fn with_max_log_bytes(self, max_bytes: usize) -> Self {
+        self.storage
+            .lock()
+            .unwrap()
+            .set_max_log_bytes(Some(max_bytes));
+        self
+    }
+
+    /// Appends every write to a second log as well, for a hot standby to
+    /// tail. See [`Database::set_mirror_log`].
+    pub fn with_mirror_log<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
+        self.storage.lock().unwrap().set_mirror_log(Some(path))?;
+        Ok(self)
+    }
+
+    /// Skips SETs that would store the value a key already holds, so they
+    /// are not appended to the log. See [`Database::set_if_changed`].
+    pub fn with_dedup_writes(self, dedup_writes: bool) -> Self {
+        self.storage.lock().unwrap().set_dedup_writes(dedup_writes);
+        self
+    }
+
+    /// Rejects SETs of values that aren't valid UTF-8, and binary keys, with
+    /// `ERROR non-utf8 value` or `ERROR non-utf8 key`. See
+    /// [`Database::set_require_utf8`].
+    pub fn with_require_utf8(self, require_utf8: bool) -> Self {
+        self.storage.lock().unwrap().set_require_utf8(require_utf8);
+        self
+    }
+
+    /// Chooses which signals the server handles.
     ///
-    /// // Clean up
-    /// fs::remove_file("keystonelight.pid").unwrap_or(());
-    /// fs::remove_file("keystonelight.log").unwrap_or(());
-    /// ```
-    pub fn run(&self) -> io::Result<()> {
-        // Set up signal handlers
-        let mut signals = Signals::new(&[libc::SIGTERM, libc::SIGINT])?;
-        let running = Arc::clone(&self.running);
-        let pid_file = self.pid_file.clone();
+    /// Signals in `shutdown` stop the server gracefully (by default `SIGTERM`
+    /// and `SIGINT`). Signals in `reopen` make it reopen its log file without
+    /// dropping connections, for log rotation (by default `SIGHUP`). See
+    /// [`Database::reopen_log`].
+    pub fn with_signals(mut self, shutdown: &[i32], reopen: &[i32]) -> Self {
+        self.shutdown_signals = shutdown.to_vec();
+        self.reopen_signals = reopen.to_vec();
+        self
+    }
 
-        thread::spawn(move || {
-            for sig in signals.forever() {
-                match sig {
-                    libc::SIGTERM | libc::SIGINT => {
-                        println!("Received signal {}, shutting down...", sig);
-                        // Clean up PID file before setting running to false
-                        let _ = fs::remove_file(&pid_file);
-                        running.store(false, Ordering::SeqCst);
-                        break;
-                    }
-                    _ => unreachable!(),
-                }
-            }
+    /// Lets idle worker threads exit under low load.
+    ///
+    /// The pool keeps at least `min_threads` workers, grows back up to the
+    /// thread count the server was created with when connections arrive, and
+    /// releases workers that sit idle for `idle_timeout`.
+    pub fn with_idle_worker_timeout(mut self, min_threads: usize, idle_timeout: Duration) -> Self {
+        let max_threads = self.thread_pool.max_workers();
+        let max_queued = self.thread_pool.max_queued();
+        self.thread_pool =
+            ThreadPool::elastic(min_threads.min(max_threads), max_threads, idle_timeout);
+        self.thread_pool.set_max_queued(max_queued);
+        self
+    }
+
+    /// Limits how many accepted connections may wait for a worker thread.
+    ///
+    /// Since a worker serves one connection until the client disconnects,
+    /// this bounds how long new clients can be left waiting. Connections
+    /// beyond the limit are handled according to the
+    /// [`SaturationPolicy`]. The queue is unbounded by default.
+    pub fn with_max_queued_connections(mut self, max_queued: usize) -> Self {
+        self.thread_pool.set_max_queued(Some(max_queued));
+        self
+    }
+
+    /// Chooses how to handle connections while the queue is full; see
+    /// [`SaturationPolicy`] for the tradeoffs. Rejects them by default.
+    pub fn with_saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.saturation_policy = policy;
+        self
+    }
+
+    /// Writes a snapshot of the database into `dir` every `interval` while the
+    /// server runs, keeping only the newest `keep` of them.
+    ///
+    /// Snapshots are named `snapshot-<unix millis>.log` and are compacted
+    /// logs, so any of them can be opened with [`Database::with_log_path`]
+    /// to restore that point in time. They are independent of the live log
+    /// and its compaction.
+    pub fn with_snapshots<P: AsRef<Path>>(
+        mut self,
+        dir: P,
+        interval: Duration,
+        keep: usize,
+    ) -> Self {
+        self.snapshots = Some(SnapshotSchedule {
+            dir: dir.as_ref().to_path_buf(),
+            interval,
+            keep,
         });
+        self
+    }
 
-        // Set non-blocking mode for the listener
-        self.listener.set_nonblocking(true)?;
+    /// Records every request and the response sent for it to the file at
+    /// `path`, for debugging; see [`read_capture`] for the format.
+    ///
+    /// This is meant for chasing down a problem, not for normal running:
+    /// every command then takes a lock shared by all connections and a write
+    /// to the file, and the file is never rotated or truncated. Values are
+    /// recorded as they were sent, so the file holds all the data clients
+    /// wrote and read.
+    pub fn with_capture_file<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        println!(
+            "Capturing all traffic to {}; this slows every command, so turn it off once done",
+            path.display()
+        );
+        self.options.capture = Some(Arc::new(CaptureLog::open(path)?));
+        Ok(self)
+    }
 
-        while self.running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((stream, _)) => {
-                    let storage = Arc::clone(&self.storage);
-                    self.thread_pool.execute(move || {
-                        if let Err(e) = handle_client(stream, storage) {
-                            eprintln!("Error handling client: {}", e);
-                        }
-                    });
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // No incoming connection, sleep a bit and continue
-                    thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-                Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
-                    break;
-                }
-            }
-        }
+    /// Appends a stats line to the file at `path` every `interval` while the
+    /// server runs, for a cheap time series of its load.
+    ///
+    /// Each line reads `at=<unix ms> keys=<count> log_bytes=<size>
+    /// ops=<count>`, where `ops` is the number of commands handled since the
+    /// previous line. The file is created if needed and never truncated.
+    pub fn with_metrics_file<P: AsRef<Path>>(mut self, path: P, interval: Duration) -> Self {
+        self.metrics = Some(MetricsSchedule {
+            path: path.as_ref().to_path_buf(),
+            interval,
+        });
+        self
+    }
 
-        // Cleanup (in case we exit the loop without a signal)
-        let _ = fs::remove_file(&self.pid_file);
-        Ok(())
+    /// Logs a sampled fraction of accepted connections with the peer address
+    /// and a Unix timestamp.
+    ///
+    /// `sample` is clamped to `0.0..=1.0`; the default of 0.0 logs nothing and
+    /// 1.0 logs every connection. Lines go to stdout unless redirected with
+    /// [`Server::with_accept_log_sink`].
+    pub fn with_accept_log_sample(self, sample: f64) -> Self {
+        self.accept_log.lock().unwrap().sample = sample.clamp(0.0, 1.0);
+        self
     }
-}
 
-impl Drop for Server {
-    fn drop(&mut self) {
-        // Clean up PID file when server is dropped
-        let _ = fs::remove_file(&self.pid_file);
+    /// Sends accept log lines to `sink` instead of stdout.
+    pub fn with_accept_log_sink<W: Write + Send + 'static>(self, sink: W) -> Self {
+        self.accept_log.lock().unwrap().sink = Box::new(sink);
+        self
     }
-}
 
-fn process_exists(pid: u32) -> bool {
-    // On Unix-like systems, sending signal 0 to a process checks if it exists
-    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), None).is_ok()
-}
+    /// Installs hooks that run on incoming writes. See [`Hooks`].
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.options.hooks = Arc::new(hooks);
+        self
+    }
 
-fn handle_client(stream: TcpStream, storage: Arc<Mutex<Database>>) -> io::Result<()> {
-    // Set non-blocking mode for the stream
-    stream.set_nonblocking(false)?;
+    /// Registers a custom command, matched case-insensitively by `name`.
+    ///
+    /// Built-in commands take precedence: a custom command is only consulted
+    /// when the request's first word isn't a built-in command name.
+    /// Registering the same name twice replaces the earlier handler.
+    ///
+    /// The handler is treated as a read, so it still runs while writes are
+    /// paused and on a replica. Register a handler that changes the database
+    /// with [`Server::register_write_command`] instead.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::protocol::Response;
+    /// use keystonelight::Server;
+    ///
+    /// let mut server = Server::new().unwrap();
+    /// server.register_command(
+    ///     "EXISTS",
+    ///     Box::new(|args, db| match args {
+    ///         [key] if db.get(key).is_some() => Response::Ok,
+    ///         [_] => Response::NotFound,
+    ///         _ => Response::Error("usage: EXISTS <key>".to_string()),
+    ///     }),
+    /// );
+    /// ```
+    pub fn register_command(&mut self, name: &str, handler: CommandHandler) {
+        self.insert_command(name, handler, false);
+    }
 
-    let mut writer = stream.try_clone()?;
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
+    /// Registers a custom command that changes the database.
+    ///
+    /// Like [`Server::register_command`], except that, as with the built-in
+    /// writes, the command is refused with `ERROR paused` while writes are
+    /// paused and with `ERROR read-only replica` on a replica.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::protocol::Response;
+    /// use keystonelight::Server;
+    ///
+    /// let mut server = Server::new().unwrap();
+    /// server.register_write_command(
+    ///     "TOUCH",
+    ///     Box::new(|args, db| match args {
+    ///         [key] => match db.set(key, b"") {
+    ///             Ok(()) => Response::Ok,
+    ///             Err(e) => Response::from_io_error(&e),
+    ///         },
+    ///         _ => Response::Error("usage: TOUCH <key>".to_string()),
+    ///     }),
+    /// );
+    /// ```
+    pub fn register_write_command(&mut self, name: &str, handler: CommandHandler) {
+        self.insert_command(name, handler, true);
+    }
 
-    while reader.read_line(&mut line)? > 0 {
-        let command = line.trim();
-        println!("Received raw command: '{}'", command);
+    fn insert_command(&mut self, name: &str, handler: CommandHandler, writes: bool) {
+        let command = CustomCommand {
+            handler: Arc::from(handler),
+            writes,
+        };
+        Arc::make_mut(&mut self.options.commands).insert(name.to_uppercase(), Arc::new(command));
+    }
 
-        let response = match crate::protocol::parse_command(command) {
-            Some(cmd) => {
-                println!("Command parts: {:?}", cmd);
-                match cmd {
-                    crate::protocol::Command::Get(key) => {
-                        let storage = storage.lock().unwrap();
-                        match storage.get(&key) {
-                            Some(value) => {
-                                // Check if the value contains any non-printable characters
-                                let is_binary = value
-                                    .iter()
-                                    .any(|&b| !b.is_ascii_graphic() && !b.is_ascii_whitespace());
-                                if is_binary {
-                                    format!("VALUE base64:{}\n", BASE64.encode(&value))
-                                } else {
-                                    match String::from_utf8(value.clone()) {
-                                        Ok(text) => format!("VALUE {}\n", text),
-                                        Err(_) => {
-                                            format!("VALUE base64:{}\n", BASE64.encode(&value))
-                                        }
-                                    }
+    /// Sets the separator `CHILDREN` splits keys on, `:` by default.
+    pub fn with_key_separator(mut self, sep: &str) -> Self {
+        self.options.key_separator = Arc::from(sep);
+        self
+    }
+
+    /// Records commands that take longer than `threshold` to handle, so
+    /// latency outliers can be found with `SLOWLOG`.
+    ///
+    /// Only the command name and its duration are kept, never its arguments,
+    /// and only the newest 128 slow commands. The slow log is off by default.
+    pub fn with_slow_log_threshold(mut self, threshold: Duration) -> Self {
+        self.options.slow_log = Arc::new(SlowLog::new(Some(threshold)));
+        self
+    }
+
+    /// Limits how many bytes a client may send in a single request line.
+    ///
+    /// A client that sends more than `max_bytes` without a newline gets
+    /// `ERROR request too large` and is disconnected, without the server
+    /// buffering the rest of the line. There is no limit by default.
+    pub fn with_max_request_bytes(mut self, max_bytes: usize) -> Self {
+        self.options.max_request_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets the capacity of each connection's read buffer (8KB by default).
+    ///
+    /// Larger buffers cut down on reads from the socket for clients sending
+    /// large values. Sizes below 64 bytes are raised to 64, since an empty
+    /// buffer would read every connection as closed.
+    pub fn with_read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.options.read_buffer_size = read_buffer_size.max(MIN_READ_BUFFER_SIZE);
+        self
+    }
+
+    /// Limits how many commands a connection runs back to back before its
+    /// worker moves on.
+    ///
+    /// After `depth` commands the connection goes to the back of the pool's
+    /// queue, with any input it has already sent kept buffered, so a client
+    /// pipelining a long batch can't hold a worker while others wait. At
+    /// least one command runs each turn. There is no limit by default.
+    pub fn with_max_pipeline_depth(mut self, depth: usize) -> Self {
+        self.options.max_pipeline_depth = Some(depth);
+        self
+    }
+
+    /// Compacts the log as part of a graceful shutdown, so the next start
+    /// replays a minimal log.
+    pub fn with_compact_on_shutdown(mut self, compact_on_shutdown: bool) -> Self {
+        self.compact_on_shutdown = compact_on_shutdown;
+        self
+    }
+
+    /// Bounds how long `PAUSE` can hold off writes (10 minutes by default).
+    ///
+    /// A pause that lasts longer is lifted automatically, as if `RESUME` had
+    /// been sent.
+    pub fn with_max_pause(mut self, max: Duration) -> Self {
+        self.options.pause = Arc::new(WritePause::new(max));
+        self
+    }
+
+    /// Bounds how long shutdown spends turning away connections still
+    /// waiting in the listen backlog (1 second by default).
+    ///
+    /// Once the server stops accepting, each queued connection is accepted
+    /// and sent `ERROR shutting down`, so clients get a clear answer instead
+    /// of a reset. Draining ends as soon as the backlog is empty; a zero
+    /// grace period skips it.
+    pub fn with_shutdown_drain(mut self, grace: Duration) -> Self {
+        self.shutdown_drain = grace;
+        self
+    }
+
+    /// Returns a handle that can stop the server while it is running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            running: Arc::clone(&self.running),
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    /// Also accepts connections on `addr`.
+    ///
+    /// Every listener feeds the same thread pool and storage, and shutdown
+    /// stops all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::Server;
+    ///
+    /// let server = Server::with_addr("keystonelight.pid", "keystonelight.log", 4, "10.0.0.5:7878")
+    ///     .unwrap()
+    ///     .with_listener("127.0.0.1:7878")
+    ///     .unwrap();
+    /// assert_eq!(server.local_addrs().unwrap().len(), 2);
+    /// ```
+    pub fn with_listener(mut self, addr: &str) -> io::Result<Self> {
+        self.listeners.push(TcpListener::bind(addr)?);
+        println!("Server also listening on {}", addr);
+        Ok(self)
+    }
+
+    /// Returns the address the server is listening on, or the first one if it
+    /// listens on several.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Returns every address the server is listening on.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect()
+    }
+
+    /// Runs the server, accepting and handling client connections.
+    ///
+    /// This method blocks until the server is shut down via a signal
+    /// (SIGTERM or SIGINT) or encounters an error. SIGHUP reopens the log
+    /// file instead; see [`Server::with_signals`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use keystonelight::Server;
+    /// use std::fs;
+    ///
+    /// let server = Server::new().unwrap();
+    ///
+    /// // Run the server (this will block)
+    /// // server.run().unwrap();
+    ///
+    /// // Clean up
+    /// fs::remove_file("keystonelight.pid").unwrap_or(());
+    /// fs::remove_file("keystonelight.log").unwrap_or(());
+    /// ```
+    pub fn run(&self) -> io::Result<()> {
+        // Set up signal handlers
+        let mut signals = Signals::new(self.shutdown_signals.iter().chain(&self.reopen_signals))?;
+        let shutdown_signals = self.shutdown_signals.clone();
+        let storage = Arc::clone(&self.storage);
+        let running = Arc::clone(&self.running);
+        let cancel = self.cancel.clone();
+        let pid_file = self.pid_file.clone();
+        let signals_handle = signals.handle();
+
+        let signal_thread = thread::spawn(move || {
+            for sig in signals.forever() {
+                if shutdown_signals.contains(&sig) {
+                    println!("Received signal {}, shutting down...", sig);
+                    // Clean up PID file before setting running to false
+                    let _ = fs::remove_file(&pid_file);
+                    running.store(false, Ordering::SeqCst);
+                    cancel.cancel();
+                    break;
+                }
+
+                println!("Received signal {}, reopening log file", sig);
+                if let Err(e) = storage.lock().unwrap().reopen_log() {
+                    eprintln!("Error reopening log file: {}", e);
+                }
+            }
+        });
+
+        // Set non-blocking mode for the listeners
+        for listener in &self.listeners {
+            listener.set_nonblocking(true)?;
+        }
+
+        let options = ConnectionOptions {
+            info: Arc::new(ServerInfo {
+                started: Instant::now(),
+                worker_threads: self.thread_pool.max_workers(),
+                addrs: self.local_addrs()?,
+            }),
+            ..self.options.clone()
+        };
+        let options = &options;
+
+        if let Some(schedule) = &self.snapshots {
+            fs::create_dir_all(&schedule.dir)?;
+        }
+
+        // One accept loop per listener, all feeding the same pool
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                scope.spawn(move || self.accept_loop(listener, options));
+            }
+            if let Some(schedule) = &self.snapshots {
+                scope.spawn(move || self.snapshot_loop(schedule));
+            }
+            if let Some(schedule) = &self.metrics {
+                scope.spawn(move || self.metrics_loop(schedule));
+            }
+        });
+
+        // Cleanup (in case we exit the loop without a signal)
+        let _ = fs::remove_file(&self.pid_file);
+
+        // Stop listening for signals, so the signal thread lets go of storage
+        signals_handle.close();
+        let _ = signal_thread.join();
+
+        // Taking the storage lock waits for any command still executing, then
+        // make sure everything acknowledged is on disk before returning
+        let storage = self.storage.lock().unwrap();
+        storage.flush()?;
+        // Anything cancelled has stopped by now; the shutdown compaction
+        // itself should run to the end
+        self.cancel.reset();
+        if self.compact_on_shutdown {
+            println!("Compacting log before shutdown");
+            storage.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Hands an accepted connection to the thread pool, applying the
+    /// saturation policy if the queue is full.
+    fn dispatch(&self, stream: TcpStream, options: &ConnectionOptions) {
+        // Keep a way to reply once the stream has moved into the job
+        let reply = match self.saturation_policy {
+            SaturationPolicy::Reject | SaturationPolicy::Block => stream.try_clone().ok(),
+            SaturationPolicy::Shed => None,
+        };
+        let storage = Arc::clone(&self.storage);
+        let options = options.clone();
+        let pool = self.thread_pool.handle();
+        let mut job = move || match Connection::new(stream, options.read_buffer_size) {
+            Ok(connection) => run_connection(connection, storage, options, pool),
+            Err(e) => eprintln!("Error handling client: {}", e),
+        };
+
+        loop {
+            job = match self.thread_pool.try_execute(job) {
+                Ok(()) => return,
+                Err(job) => job,
+            };
+            match self.saturation_policy {
+                SaturationPolicy::Reject => {
+                    if let Some(mut reply) = reply {
+                        let _ = reply.write_all(b"ERROR busy\n");
+                    }
+                    return;
+                }
+                // Dropping the job closes the connection
+                SaturationPolicy::Shed => return,
+                SaturationPolicy::Block => {
+                    if !self.running.load(Ordering::SeqCst) {
+                        if let Some(mut reply) = reply {
+                            let _ = reply.write_all(SHUTTING_DOWN_REPLY);
+                        }
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+
+    fn snapshot_loop(&self, schedule: &SnapshotSchedule) {
+        let mut next = Instant::now() + schedule.interval;
+        while self.running.load(Ordering::SeqCst) {
+            if Instant::now() >= next {
+                match schedule.take(&self.storage) {
+                    Ok(path) => println!("Wrote snapshot {}", path.display()),
+                    Err(e) => eprintln!("Error writing snapshot: {}", e),
+                }
+                next = Instant::now() + schedule.interval;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn metrics_loop(&self, schedule: &MetricsSchedule) {
+        let commands = &self.options.commands_processed;
+        let mut last_count = commands.load(Ordering::SeqCst);
+        let mut next = Instant::now() + schedule.interval;
+        while self.running.load(Ordering::SeqCst) {
+            if Instant::now() >= next {
+                let count = commands.load(Ordering::SeqCst);
+                if let Err(e) = schedule.record(&self.storage, count - last_count) {
+                    eprintln!("Error writing metrics: {}", e);
+                }
+                last_count = count;
+                next = Instant::now() + schedule.interval;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Accepts connections on `listener` until the server stops running.
+    fn accept_loop(&self, listener: &TcpListener, options: &ConnectionOptions) {
+        while self.running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    self.accept_log.lock().unwrap().record(peer);
+                    self.dispatch(stream, options);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // No incoming connection, sleep a bit and continue
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    // Take the other listeners down too, as a single one would
+                    self.running.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+        self.drain_backlog(listener);
+    }
+
+    /// Turns away connections left in the listen backlog at shutdown.
+    fn drain_backlog(&self, listener: &TcpListener) {
+        let deadline = Instant::now() + self.shutdown_drain;
+        while Instant::now() < deadline {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    let _ = stream.set_nonblocking(false);
+                    let _ = stream.write_all(SHUTTING_DOWN_REPLY);
+                }
+                // The backlog is empty, or the listener is broken
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Clean up PID file when server is dropped
+        let _ = fs::remove_file(&self.pid_file);
+    }
+}
+
+fn process_exists(pid: u32) -> bool {
+    // On Unix-like systems, sending signal 0 to a process checks if it exists
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), None).is_ok()
+}
+
+/// Returns when process `pid` started, in clock ticks since boot, or `None`
+/// if it isn't running or the platform has no `/proc` to ask.
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may itself hold spaces and
+    // parentheses, so count fields from the last `)`: the start time is the
+    // 22nd field, and the 20th after the name
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Whether the process recorded in a PID file is still the one running as
+/// `pid`. Without a recorded start time, or where it can't be read back,
+/// any process with that PID counts.
+fn process_matches(pid: u32, started: Option<u64>) -> bool {
+    if !process_exists(pid) {
+        return false;
+    }
+    match (started, process_start_time(pid)) {
+        (Some(recorded), Some(actual)) => recorded == actual,
+        _ => true,
+    }
+}
+
+/// Parses a PID file: the PID, optionally followed by the process's start
+/// time. Returns `None` for anything else.
+fn parse_pid_file(contents: &str) -> Option<(u32, Option<u64>)> {
+    let mut fields = contents.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let started = match fields.next() {
+        Some(started) => Some(started.parse().ok()?),
+        None => None,
+    };
+    fields.next().is_none().then_some((pid, started))
+}
+
+/// Formats a `VALUE` response line, base64-encoding binary data.
+fn format_value(value: &[u8]) -> String {
+    format!("VALUE {}\n", encode_value(value))
+}
+
+/// Formats a `VALUE <version> <payload>` response for `GET ... WITHVERSION`.
+fn format_versioned_value(value: &[u8], version: u64) -> String {
+    format!("VALUE {} {}\n", version, encode_value(value))
+}
+
+/// Formats a `VALUE <payload>` response for `GET ... AS <encoding>`.
+///
+/// Responses are newline-framed text, so raw bytes can't be sent and `AS raw`
+/// is refused.
+fn format_encoded_value(value: &[u8], encoding: ValueEncoding) -> String {
+    match encoding {
+        ValueEncoding::Hex => {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("VALUE {}\n", hex)
+        }
+        ValueEncoding::Base64 => format!("VALUE {}\n", BASE64.encode(value)),
+        ValueEncoding::Raw => "ERROR raw encoding needs binary framing\n".to_string(),
+    }
+}
+
+/// Formats the sectioned `INFO` report, ending with `END`.
+fn format_info(info: &ServerInfo, storage: &Database) -> String {
+    let addrs: Vec<String> = info.addrs.iter().map(|addr| addr.to_string()).collect();
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    let mut report = String::new();
+    report.push_str("# Server\n");
+    report.push_str(&format!("version:{}\n", env!("CARGO_PKG_VERSION")));
+    report.push_str(&format!("os:{}\n", std::env::consts::OS));
+    report.push_str(&format!("process_id:{}\n", process::id()));
+    report.push_str(&format!(
+        "uptime_seconds:{}\n",
+        info.started.elapsed().as_secs()
+    ));
+    report.push_str(&format!("worker_threads:{}\n", info.worker_threads));
+    report.push_str(&format!("bind_addrs:{}\n", addrs.join(",")));
+    // Neither is supported yet; reported so tooling can rely on the fields
+    report.push_str("auth_enabled:no\n");
+    report.push_str("tls_enabled:no\n");
+    report.push('\n');
+    report.push_str("# Storage\n");
+    report.push_str(&format!("keys:{}\n", storage.key_count()));
+    report.push_str(&format!("log_bytes:{}\n", storage.log_size()));
+    report.push_str(&format!("compactions:{}\n", storage.compaction_count()));
+    report.push_str(&format!("follower:{}\n", yes_no(storage.is_follower())));
+    report.push_str("END\n");
+    report
+}
+
+/// Formats an optional limit for `LIMITS`, with `none` for no limit.
+fn format_limit(limit: Option<usize>) -> String {
+    limit.map_or_else(|| "none".to_string(), |limit| limit.to_string())
+}
+
+/// Formats a storage error as an `ERROR` response.
+fn format_error(e: &io::Error) -> String {
+    format!("{}\n", Response::from_io_error(e))
+}
+
+/// Milliseconds since the Unix epoch, or 0 for a time before it.
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Formats a [`Response`] returned by a custom command for the wire.
+fn format_response(response: &Response) -> String {
+    match response {
+        Response::Value(value) => format_value(value),
+        other => format!("{}\n", other),
+    }
+}
+
+/// Encodes a value for the wire, falling back to base64 for binary data.
+fn encode_value(value: &[u8]) -> String {
+    // Check if the value contains any non-printable characters, or a line
+    // break that would end the response early
+    let is_binary = value
+        .iter()
+        .any(|&b| (!b.is_ascii_graphic() && !b.is_ascii_whitespace()) || b == b'\n' || b == b'\r');
+    if is_binary {
+        return format!("base64:{}", BASE64.encode(value));
+    }
+    match std::str::from_utf8(value) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("base64:{}", BASE64.encode(value)),
+    }
+}
+
+/// A client connection between commands. Any pipelined input already read
+/// stays in `reader`, so the connection can move to another worker.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, read_buffer_size: usize) -> io::Result<Self> {
+        // Set non-blocking mode for the stream
+        stream.set_nonblocking(false)?;
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::with_capacity(read_buffer_size, stream),
+        })
+    }
+}
+
+/// Serves `connection` on the current worker. If it yields after
+/// `max_pipeline_depth` commands, queues it on `pool` to continue behind
+/// the connections already waiting.
+fn run_connection(
+    connection: Connection,
+    storage: Arc<Mutex<Database>>,
+    options: ConnectionOptions,
+    pool: PoolHandle,
+) {
+    match handle_client(connection, &storage, &options) {
+        Ok(Some(connection)) => {
+            // Kept to turn the client away if the pool has shut down, as the
+            // job then never runs and dropping it closes the connection
+            let reply = connection.writer.try_clone().ok();
+            let next = pool.clone();
+            let queued = pool.execute(move || run_connection(connection, storage, options, next));
+            if queued.is_err() {
+                if let Some(mut reply) = reply {
+                    let _ = reply.write_all(SHUTTING_DOWN_REPLY);
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("Error handling client: {}", e),
+    }
+}
+
+/// Runs commands from `connection` until the client disconnects, returning
+/// `None`, or until `max_pipeline_depth` commands have been served, handing
+/// the connection back.
+fn handle_client(
+    connection: Connection,
+    storage: &Arc<Mutex<Database>>,
+    options: &ConnectionOptions,
+) -> io::Result<Option<Connection>> {
+    let Connection {
+        mut reader,
+        mut writer,
+    } = connection;
+    let mut request = Vec::new();
+    let mut served = 0;
+    let hooks = &options.hooks;
+    // Read at most one byte past the limit, so an oversized line is detected
+    // without buffering all of it
+    let read_limit = options
+        .max_request_bytes
+        .map_or(u64::MAX, |max| max as u64 + 1);
+
+    // Read raw bytes, so a limit that falls inside a multibyte character is
+    // still reported as too large rather than as invalid UTF-8
+    while (&mut reader)
+        .take(read_limit)
+        .read_until(b'\n', &mut request)?
+        > 0
+    {
+        if request.len() as u64 >= read_limit && !request.ends_with(b"\n") {
+            writer.write_all(b"ERROR request too large\n")?;
+            writer.flush()?;
+            break;
+        }
+        let line = std::str::from_utf8(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let (command, noreply) = crate::protocol::strip_noreply(line.trim());
+        let (command, token) = crate::protocol::strip_idempotency_token(command);
+        println!("Received raw command: '{}'", command);
+        let started = Instant::now();
+        let replay = token.and_then(|token| options.idempotency.begin(token));
+
+        let response = match crate::protocol::try_parse_command(command) {
+            // A repeated idempotency token gets the first attempt's reply
+            _ if replay.is_some() => replay.clone().unwrap_or_default(),
+            Ok(cmd) if cmd.is_write() && options.pause.is_paused() => "ERROR paused\n".to_string(),
+            // A replica still compacts its own log
+            Ok(cmd)
+                if cmd.is_write()
+                    && !matches!(
+                        cmd,
+                        crate::protocol::Command::Compact | crate::protocol::Command::TrimLog
+                    )
+                    && options.replica.lock().unwrap().is_some() =>
+            {
+                "ERROR read-only replica\n".to_string()
+            }
+            Ok(cmd) => {
+                println!("Command parts: {:?}", cmd);
+                match cmd {
+                    crate::protocol::Command::Get(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get(&key) {
+                            Some(value) => format_value(&value),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::GetWithVersion(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get_with_version(&key) {
+                            Some((value, version)) => format_versioned_value(&value, version),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::GetAs(key, encoding) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get(&key) {
+                            Some(value) => format_encoded_value(&value, encoding),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::GetWithContentType(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get_with_content_type(&key) {
+                            Some((value, content_type)) => {
+                                format!("VALUE ct={} {}\n", content_type, encode_value(&value))
+                            }
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::GetAt(key, snapshot) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get_at(snapshot, &key) {
+                            Ok(Some(value)) => format_value(&value),
+                            Ok(None) => "NOT_FOUND\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::Snapshot => {
+                        let storage = storage.lock().unwrap();
+                        format!("SNAPSHOT {}\n", storage.begin_read_snapshot())
+                    }
+                    crate::protocol::Command::EndSnapshot(snapshot) => {
+                        let storage = storage.lock().unwrap();
+                        if storage.end_read_snapshot(snapshot) {
+                            "OK\n".to_string()
+                        } else {
+                            "NOT_FOUND\n".to_string()
+                        }
+                    }
+                    crate::protocol::Command::GetDefault(key, default) => {
+                        let storage = storage.lock().unwrap();
+                        format_value(&storage.get_or(&key, &default))
+                    }
+                    crate::protocol::Command::GetOrSet(key, mut default) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut default) {
+                            format!("ERROR {}\n", msg)
+                        } else {
+                            match storage.get_or_set(&key, &default) {
+                                Ok(value) => format_value(&value),
+                                Err(e) => format_error(&e),
+                            }
+                        }
+                    }
+                    crate::protocol::Command::GetRange(key, start, end) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get_range(&key, start, end) {
+                            Some(slice) => format_value(&slice),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::AppendLine(key, line) => {
+                        let storage = storage.lock().unwrap();
+                        let hook = |value: &mut Vec<u8>| {
+                            hooks.before_set(&key, value).map_err(io::Error::other)
+                        };
+                        match storage.append_line_with(&key, &line, hook) {
+                            Ok(count) => format!("LINES {}\n", count),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::SetRange(key, offset, bytes) => {
+                        let storage = storage.lock().unwrap();
+                        let hook = |value: &mut Vec<u8>| {
+                            hooks.before_set(&key, value).map_err(io::Error::other)
+                        };
+                        match storage.set_range_with(&key, offset, &bytes, hook) {
+                            Ok(len) => format!("LEN {}\n", len),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::IncrByFloat(key, delta) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.incr_by_float(&key, delta) {
+                            Ok(value) => format_value(value.to_string().as_bytes()),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::DecrDel(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.decr_del(&key) {
+                            Ok(Some(count)) if count <= 0 => format!("DELETED {}\n", count),
+                            Ok(Some(count)) => format!("VALUE {}\n", count),
+                            Ok(None) => "NOT_FOUND\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::FetchAdd(key, delta) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.fetch_add(&key, delta) {
+                            Ok(old) => format!("VALUE {}\n", old),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::RandomKey => {
+                        let storage = storage.lock().unwrap();
+                        match storage.random_key() {
+                            Some(key) => format!("KEY {}\n", encode_key(&key)),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::RandomKeys(count) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for key in storage.random_keys(count) {
+                            response.push_str(&format!("KEY {}\n", encode_key(&key)));
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::LruKeys(count) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for key in storage.lru_keys(count) {
+                            response.push_str(&format!("KEY {}\n", encode_key(&key)));
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::Children(prefix) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for child in storage.children(&prefix, &options.key_separator) {
+                            response.push_str(&format!("CHILD {}\n", encode_key(&child)));
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::Meta(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.metadata(&key) {
+                            // Keys never expire, so the TTL is always -1
+                            Some(metadata) => format!(
+                                "META size={} ttl=-1 created={} modified={}\n",
+                                metadata.size,
+                                unix_millis(metadata.created_at),
+                                unix_millis(metadata.last_modified)
+                            ),
+                            None => "NOT_FOUND\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::MGet(keys) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for value in storage.get_many(&keys) {
+                            match value {
+                                Some(value) => response.push_str(&format_value(&value)),
+                                None => response.push_str("NOT_FOUND\n"),
+                            }
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::MMeta(keys) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for (key, metadata) in keys.iter().zip(storage.metadata_many(&keys)) {
+                            // Keys never expire, so every TTL is -1
+                            match metadata {
+                                Some(metadata) => response.push_str(&format!(
+                                    "{} size={} ttl=-1\n",
+                                    encode_key(key),
+                                    metadata.size
+                                )),
+                                None => {
+                                    response.push_str(&format!("{} MISSING\n", encode_key(key)))
                                 }
                             }
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::StrLen(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.value_len(&key) {
+                            Some(len) => format!("LEN {}\n", len),
                             None => "NOT_FOUND\n".to_string(),
                         }
                     }
-                    crate::protocol::Command::Set(key, value) => {
-                        let mut storage = storage.lock().unwrap();
-                        if let Err(e) = storage.set(&key, &value) {
-                            format!("ERROR {}\n", e)
+                    crate::protocol::Command::Slot(key) => format!(
+                        "SLOT {} {}\n",
+                        crate::protocol::key_hash(&key),
+                        crate::protocol::hash_slot(&key)
+                    ),
+                    crate::protocol::Command::Changes(version) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.changes_since(version) {
+                            Ok((changes, current)) => {
+                                let mut response = String::new();
+                                for change in changes {
+                                    response.push_str(&change.to_string());
+                                    response.push('\n');
+                                }
+                                response.push_str(&format!("VERSION {}\nEND\n", current));
+                                response
+                            }
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::GetAll(pattern) => {
+                        let storage = storage.lock().unwrap();
+                        let mut response = String::new();
+                        for (key, value) in storage.scan_pairs(&pattern) {
+                            response.push_str(&format!(
+                                "{} => {}\n",
+                                encode_key(&key),
+                                encode_value(&value)
+                            ));
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::ScanValues(cursor, count, pattern) => {
+                        let storage = storage.lock().unwrap();
+                        let (next, page) = storage.scan_page(cursor, count, &pattern);
+                        let mut response = format!("CURSOR {}\n", next);
+                        for (key, value) in page {
+                            response.push_str(&format!(
+                                "{} => {}\n",
+                                encode_key(&key),
+                                encode_value(&value)
+                            ));
+                        }
+                        response.push_str("END\n");
+                        response
+                    }
+                    crate::protocol::Command::Set(key, mut value) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut value) {
+                            format!("ERROR {}\n", msg)
+                        } else if let Err(e) = storage.set(&key, &value) {
+                            format_error(&e)
+                        } else {
+                            "OK\n".to_string()
+                        }
+                    }
+                    crate::protocol::Command::SetXx(key, mut value) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut value) {
+                            format!("ERROR {}\n", msg)
+                        } else {
+                            match storage.set_xx(&key, &value) {
+                                Ok(written) => format!("SET {}\n", u8::from(written)),
+                                Err(e) => format_error(&e),
+                            }
+                        }
+                    }
+                    crate::protocol::Command::SetGetIf(key, mut value) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut value) {
+                            format!("ERROR {}\n", msg)
+                        } else {
+                            match storage.set_get_if_changed(&key, &value) {
+                                Ok((Some(old), written)) => format!(
+                                    "SET {} VALUE {}\n",
+                                    u8::from(written),
+                                    encode_value(&old)
+                                ),
+                                Ok((None, written)) => {
+                                    format!("SET {} NOT_FOUND\n", u8::from(written))
+                                }
+                                Err(e) => format_error(&e),
+                            }
+                        }
+                    }
+                    crate::protocol::Command::SetIfVersion(key, mut value, version) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut value) {
+                            format!("ERROR {}\n", msg)
+                        } else {
+                            match storage.set_if_version(&key, &value, version) {
+                                Ok(true) => "OK\n".to_string(),
+                                Ok(false) => "ERROR version mismatch\n".to_string(),
+                                Err(e) => format_error(&e),
+                            }
+                        }
+                    }
+                    crate::protocol::Command::SetWithContentType(key, mut value, content_type) => {
+                        let storage = storage.lock().unwrap();
+                        if let Err(msg) = hooks.before_set(&key, &mut value) {
+                            format!("ERROR {}\n", msg)
+                        } else if let Err(e) =
+                            storage.set_with_content_type(&key, &value, &content_type)
+                        {
+                            format_error(&e)
                         } else {
                             "OK\n".to_string()
                         }
                     }
                     crate::protocol::Command::Delete(key) => {
-                        let mut storage = storage.lock().unwrap();
+                        let storage = storage.lock().unwrap();
                         if let Err(e) = storage.delete(&key) {
-                            format!("ERROR {}\n", e)
+                            format_error(&e)
                         } else {
                             "OK\n".to_string()
                         }
                     }
-                    crate::protocol::Command::Compact => {
-                        let mut storage = storage.lock().unwrap();
-                        if let Err(e) = storage.compact() {
-                            format!("ERROR {}\n", e)
+                    crate::protocol::Command::GetDel(key) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.get_del(&key) {
+                            Ok(Some(value)) => format_value(&value),
+                            Ok(None) => "NOT_FOUND\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::Swap(key1, key2) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.swap(&key1, &key2) {
+                            Ok(true) => "OK\n".to_string(),
+                            Ok(false) => "NOT_FOUND\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::RenameIf(old, new, expected) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.rename_if(&old, &new, &expected) {
+                            Ok(true) => "OK\n".to_string(),
+                            Ok(false) => "ERROR value mismatch\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::Eval(mut steps) => {
+                        let storage = storage.lock().unwrap();
+                        // Every value the script may store goes through the
+                        // hook first, so one rejection fails the whole script
+                        let hooked = steps
+                            .iter_mut()
+                            .try_for_each(|step| match &mut step.action {
+                                crate::protocol::EvalAction::Set(key, value) => {
+                                    hooks.before_set(key, value)
+                                }
+                                _ => Ok(()),
+                            });
+                        if let Err(msg) = hooked {
+                            format!("ERROR {}\n", msg)
                         } else {
-                            "OK\n".to_string()
+                            match storage.eval(&steps) {
+                                Ok(Some(response)) => format_response(&response),
+                                Ok(None) => "SKIPPED\n".to_string(),
+                                Err(e) => format_error(&e),
+                            }
+                        }
+                    }
+                    crate::protocol::Command::Tag(key, tag) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.tag(&key, &tag) {
+                            Ok(true) => "OK\n".to_string(),
+                            Ok(false) => "NOT_FOUND\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::DelTag(tag) => {
+                        let storage = storage.lock().unwrap();
+                        match storage.delete_tag(&tag) {
+                            Ok(count) => format!("DELETED {}\n", count),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::Compact => {
+                        let storage = storage.lock().unwrap();
+                        match storage.try_compact() {
+                            Ok(true) => "OK\n".to_string(),
+                            Ok(false) => "OK (already in progress)\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::TrimLog => {
+                        let storage = storage.lock().unwrap();
+                        match storage.trim_log() {
+                            Ok(reclaimed) => format!("RECLAIMED {}\n", reclaimed),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::HealthCheck => {
+                        let storage = storage.lock().unwrap();
+                        match storage.health_check() {
+                            Ok(()) => "HEALTHY\n".to_string(),
+                            Err(e) => format!("UNHEALTHY {}\n", e),
+                        }
+                    }
+                    crate::protocol::Command::Verify => {
+                        let storage = storage.lock().unwrap();
+                        match storage.verify_consistency() {
+                            Ok(report) if report.is_clean() => "CONSISTENT\n".to_string(),
+                            Ok(report) => format!("INCONSISTENT {}\n", report),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::Info => {
+                        let storage = storage.lock().unwrap();
+                        format_info(&options.info, &storage)
+                    }
+                    crate::protocol::Command::ReplicaOf(primary) => {
+                        let mut replica = options.replica.lock().unwrap();
+                        // Dropping the old link disconnects from the old primary
+                        *replica = None;
+                        match primary {
+                            Some(primary) => {
+                                match ReplicaLink::start(&primary, Arc::clone(storage)) {
+                                    Ok(link) => {
+                                        println!("Replicating from {}", primary);
+                                        *replica = Some(link);
+                                        "OK\n".to_string()
+                                    }
+                                    Err(e) => format_error(&e),
+                                }
+                            }
+                            None => "OK\n".to_string(),
+                        }
+                    }
+                    crate::protocol::Command::Sync => {
+                        let info = storage.lock().unwrap().data_info();
+                        match info {
+                            // The connection belongs to the replica from here on
+                            Ok(Some(info)) => {
+                                println!("Streaming log to replica");
+                                return replication::serve_sync(
+                                    &mut writer,
+                                    &info.path,
+                                    &options.followers,
+                                )
+                                .map(|()| None);
+                            }
+                            Ok(None) => "ERROR no log file\n".to_string(),
+                            Err(e) => format_error(&e),
+                        }
+                    }
+                    crate::protocol::Command::ReplStatus => {
+                        // Don't hold the lock while the primary is asked
+                        // for its log size
+                        let progress = options
+                            .replica
+                            .lock()
+                            .unwrap()
+                            .as_ref()
+                            .map(ReplicaLink::progress);
+                        match progress {
+                            Some(progress) => progress.report(),
+                            None => {
+                                let info = storage.lock().unwrap().data_info();
+                                match info {
+                                    Ok(info) => format!(
+                                        "role=primary offset={} followers={}\n",
+                                        info.map_or(0, |info| info.size),
+                                        options.followers.load(Ordering::SeqCst)
+                                    ),
+                                    Err(e) => format_error(&e),
+                                }
+                            }
+                        }
+                    }
+                    crate::protocol::Command::Role => match &*options.replica.lock().unwrap() {
+                        Some(link) => format!("ROLE replica {}\n", link.primary()),
+                        None => "ROLE primary\n".to_string(),
+                    },
+                    crate::protocol::Command::DataInfo => {
+                        let storage = storage.lock().unwrap();
+                        match storage.data_info() {
+                            Ok(Some(info)) => format!(
+                                "path={} size={} entries={}\n",
+                                info.path.display(),
+                                info.size,
+                                info.entries
+                            ),
+                            Ok(None) => "ERROR no log file\n".to_string(),
+                            Err(e) => format_error(&e),
                         }
                     }
+                    crate::protocol::Command::Limits => {
+                        let max_keys = storage.lock().unwrap().max_keys();
+                        format!(
+                            "max_request_bytes={} max_pipeline_depth={} max_keys={}\n",
+                            format_limit(options.max_request_bytes),
+                            format_limit(options.max_pipeline_depth),
+                            format_limit(max_keys)
+                        )
+                    }
+                    crate::protocol::Command::SlowLog => options.slow_log.report(),
+                    crate::protocol::Command::Pause => {
+                        options.pause.pause();
+                        "OK\n".to_string()
+                    }
+                    crate::protocol::Command::Resume => {
+                        options.pause.resume();
+                        "OK\n".to_string()
+                    }
+                }
+            }
+            // Names the parser doesn't know may be custom commands
+            Err(e @ ParseError::UnknownCommand(_)) => {
+                let mut parts = command.split_whitespace();
+                let name = parts.next().unwrap_or_default().to_uppercase();
+                match options.commands.get(&name) {
+                    Some(custom) if custom.writes && options.pause.is_paused() => {
+                        "ERROR paused\n".to_string()
+                    }
+                    Some(custom) if custom.writes && options.replica.lock().unwrap().is_some() => {
+                        "ERROR read-only replica\n".to_string()
+                    }
+                    Some(custom) => {
+                        let args: Vec<String> = parts.map(str::to_string).collect();
+                        let storage = storage.lock().unwrap();
+                        format_response(&(custom.handler)(&args, &storage))
+                    }
+                    None => format!("{}\n", Response::Error(e.to_string())),
                 }
             }
-            None => "ERROR Invalid command\n".to_string(),
+            Err(e) => format!("{}\n", Response::Error(e.to_string())),
         };
 
-        writer.write_all(response.as_bytes())?;
-        writer.flush()?;
-        line.clear();
+        let name = command.split(' ').next().unwrap_or_default();
+        options
+            .slow_log
+            .record(&name.to_uppercase(), started.elapsed());
+        options.commands_processed.fetch_add(1, Ordering::SeqCst);
+
+        if let (Some(token), None) = (token, &replay) {
+            options.idempotency.finish(token, &response);
+        }
+
+        // Recorded before replying, so a client that has its reply can find
+        // the exchange in the capture
+        if let Some(capture) = &options.capture {
+            let sent = if noreply { "" } else { response.as_str() };
+            if let Err(e) = capture.record(line.trim_end_matches(['\r', '\n']), sent) {
+                eprintln!("Failed to capture request: {}", e);
+            }
+        }
+
+        if !noreply {
+            writer.write_all(response.as_bytes())?;
+            writer.flush()?;
+        }
+        request.clear();
+
+        served += 1;
+        if options
+            .max_pipeline_depth
+            .is_some_and(|depth| served >= depth)
+        {
+            return Ok(Some(Connection { reader, writer }));
+        }
     }
 
-    Ok(())
+    Ok(None)
 }
@@ -0,0 +1,281 @@
+//! Streaming a primary's log to replica servers over TCP.
+//!
+//! A replica sends `SYNC` to the primary, which takes over the connection and
+//! replies with a stream of frames:
+//!
+//! ```text
+//! FULLSYNC 2 40     the primary's complete log; replaces the replica's data
+//! SET key1 value1
+//! TAG key1 hot
+//! BATCH 1 53        entries appended since the previous frame
+//! DELETE key1
+//! PING              sent when idle, so a dead replica is noticed
+//! ```
+//!
+//! The second number in a frame header is the byte offset in the primary's
+//! log that the frame brings the replica up to. Comparing it with the
+//! primary's log size (`DATAINFO`) gives the replication lag.
+//!
+//! The feed is a [`LogTail`] on the primary's own log file, so entries are
+//! sent only once they are on disk. After the primary compacts its log the
+//! next frame is another `FULLSYNC`, and offsets start again from the new
+//! file.
+
+use crate::storage::{Database, LogEntry, LogTail, TailUpdate};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the primary checks its log for new entries to send
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest the primary stays silent before sending a `PING`
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest `REPLSTATUS` waits on the primary for its current log size
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Streams the log at `log_path` to a replica until the connection drops.
+/// `followers` counts the replicas being streamed to.
+pub(super) fn serve_sync(
+    writer: &mut TcpStream,
+    log_path: &Path,
+    followers: &AtomicUsize,
+) -> io::Result<()> {
+    let mut tail = LogTail::open(log_path)?;
+    followers.fetch_add(1, Ordering::SeqCst);
+    let result = stream_log(writer, &mut tail);
+    followers.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+fn stream_log(writer: &mut TcpStream, tail: &mut LogTail) -> io::Result<()> {
+    // The first read returns the whole file, which is the initial snapshot
+    let mut first = true;
+    let mut last_sent = Instant::now();
+    loop {
+        let (kind, entries) = match tail.read_new()? {
+            TailUpdate::Reset(entries) => ("FULLSYNC", entries),
+            TailUpdate::Appended(entries) if first => ("FULLSYNC", entries),
+            TailUpdate::Appended(entries) => ("BATCH", entries),
+        };
+        first = false;
+
+        if kind == "BATCH" && entries.is_empty() {
+            if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                writer.write_all(b"PING\n")?;
+                writer.flush()?;
+                last_sent = Instant::now();
+            }
+        } else {
+            let mut frame = format!("{} {} {}\n", kind, entries.len(), tail.offset());
+            for entry in &entries {
+                frame.push_str(&entry.to_string());
+                frame.push('\n');
+            }
+            writer.write_all(frame.as_bytes())?;
+            writer.flush()?;
+            last_sent = Instant::now();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// A replica's connection to its primary, set up by `REPLICAOF`.
+///
+/// A background thread applies the primary's frames to the local database.
+/// Dropping the link disconnects from the primary, which ends the thread.
+pub(super) struct ReplicaLink {
+    stream: TcpStream,
+    progress: Arc<ReplicaProgress>,
+}
+
+/// How far a replica has got through its primary's log, shared with the
+/// thread applying it.
+pub(super) struct ReplicaProgress {
+    /// Address of the primary, as given to `REPLICAOF`
+    primary: String,
+    /// Primary log offset of the last frame header read
+    received: AtomicU64,
+    /// Primary log offset of the last frame applied
+    applied: AtomicU64,
+    /// Cleared when the primary disconnects or the stream fails
+    connected: AtomicBool,
+}
+
+impl ReplicaLink {
+    /// Connects to `primary`, asks it for its log and starts applying it to
+    /// `storage`.
+    pub(super) fn start(primary: &str, storage: Arc<Mutex<Database>>) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(primary)?;
+        writeln!(stream, "SYNC")?;
+        stream.flush()?;
+
+        let reader = BufReader::new(stream.try_clone()?);
+        let progress = Arc::new(ReplicaProgress {
+            primary: primary.to_string(),
+            received: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            connected: AtomicBool::new(true),
+        });
+        let link = Self {
+            stream,
+            progress: Arc::clone(&progress),
+        };
+        thread::spawn(move || {
+            match follow(reader, &storage, &progress) {
+                Ok(()) => println!("Replication from {} ended", progress.primary),
+                Err(e) => eprintln!("Replication from {} failed: {}", progress.primary, e),
+            }
+            progress.connected.store(false, Ordering::SeqCst);
+        });
+        Ok(link)
+    }
+
+    /// Returns the address of the primary, as given to `REPLICAOF`.
+    pub(super) fn primary(&self) -> &str {
+        &self.progress.primary
+    }
+
+    /// Returns the link's progress, which outlives the link itself.
+    pub(super) fn progress(&self) -> Arc<ReplicaProgress> {
+        Arc::clone(&self.progress)
+    }
+}
+
+impl Drop for ReplicaLink {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl ReplicaProgress {
+    /// Formats the `REPLSTATUS` reply for a replica.
+    ///
+    /// The primary's current log size is fetched with `DATAINFO` over a new
+    /// connection; if that fails the primary offset and lag are reported as
+    /// `unknown`.
+    pub(super) fn report(&self) -> String {
+        let received = self.received.load(Ordering::SeqCst);
+        let applied = self.applied.load(Ordering::SeqCst);
+        let link = if self.connected.load(Ordering::SeqCst) {
+            "up"
+        } else {
+            "down"
+        };
+        let (primary_offset, lag) = match primary_log_size(&self.primary) {
+            Ok(size) => (size.to_string(), size.saturating_sub(applied).to_string()),
+            Err(e) => {
+                eprintln!("Failed to read log size of {}: {}", self.primary, e);
+                ("unknown".to_string(), "unknown".to_string())
+            }
+        };
+        format!(
+            "role=replica primary={} link={} received_offset={} applied_offset={} primary_offset={} lag_bytes={}\n",
+            self.primary, link, received, applied, primary_offset, lag
+        )
+    }
+}
+
+/// Asks the server at `addr` for the size of its log.
+fn primary_log_size(addr: &str) -> io::Result<u64> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for primary"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, STATUS_TIMEOUT)?;
+    stream.set_read_timeout(Some(STATUS_TIMEOUT))?;
+    writeln!(stream, "DATAINFO")?;
+    stream.flush()?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    reply
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("size="))
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected DATAINFO reply `{}`", reply.trim_end()),
+            )
+        })
+}
+
+/// Applies frames from the primary until it disconnects.
+fn follow(
+    mut reader: BufReader<TcpStream>,
+    storage: &Mutex<Database>,
+    progress: &ReplicaProgress,
+) -> io::Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let header = line.trim_end();
+        if header == "PING" {
+            continue;
+        }
+        if let Some(message) = header.strip_prefix("ERROR ") {
+            return Err(io::Error::other(message.to_string()));
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected replication frame `{}`", header),
+            )
+        };
+        let mut fields = header.split(' ');
+        let (Some(kind), Some(count), Some(offset), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let count: usize = count.parse().map_err(|_| invalid())?;
+        let offset: u64 = offset.parse().map_err(|_| invalid())?;
+        let reset = match kind {
+            "FULLSYNC" => true,
+            "BATCH" => false,
+            _ => return Err(invalid()),
+        };
+
+        progress.received.store(offset, Ordering::SeqCst);
+
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "primary disconnected mid-frame",
+                ));
+            }
+            // Only the line ending is stripped: `SET key ` is an empty value
+            let entry = LogEntry::from_string(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid replicated entry `{}`", line.trim_end()),
+                )
+            })?;
+            entries.push(entry);
+        }
+
+        let update = if reset {
+            TailUpdate::Reset(entries)
+        } else {
+            TailUpdate::Appended(entries)
+        };
+        // Recorded before the lock is released, so a client that can read
+        // the new entries never sees an older applied offset
+        let storage = storage.lock().unwrap();
+        let applied = storage.apply_update(update)?;
+        progress.applied.store(offset, Ordering::SeqCst);
+        drop(storage);
+        println!("Applied {} replicated entries", applied);
+    }
+}
@@ -1,62 +1,115 @@
+use super::CancelToken;
+use crate::protocol::{decode_key, encode_key};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use fs2::FileExt;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Seek, Write};
-use std::os::unix::fs::OpenOptionsExt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 
 const MAX_LOG_SIZE: usize = 1024 * 1024; // 1MB
 
-#[derive(Debug, Clone)]
+/// A single record in the append-only log.
+///
+/// Each entry is one line: `SET <key> <value>`, `DELETE <key>`,
+/// `TAG <key> <tag>`, `CTYPE <key> <content-type>` or `COMPACT`. Keys the
+/// log can't hold as a single word are written as `base64key:<base64>`, and
+/// values that aren't single-line text as `base64:<base64>`.
+///
+/// For hand-written logs, `SETL`, `DELETEL` and `TAGL` take the key with its
+/// length in bytes instead, so it may contain spaces:
+/// `SETL 9 two words value`. They read back as the same entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LogEntry {
+    /// `key` was set to the given value
     Set(String, Vec<u8>),
+    /// `key` was deleted
     Delete(String),
+    /// `key` was tagged with the given tag
+    Tag(String, String),
+    /// `key`'s current value was given a content type
+    ContentType(String, String),
+    /// Marker written at the end of a compacted log
     Compact,
 }
 
-impl LogEntry {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             LogEntry::Set(key, value) => {
-                match String::from_utf8(value.clone()) {
-                    Ok(text) => format!("SET {} {}", key, text),
-                    Err(_) => {
-                        // Only use base64 for binary data
-                        let encoded_value = BASE64.encode(value);
-                        format!("SET {} base64:{}", key, encoded_value)
+                // A line break would split the entry across log lines, so
+                // check the raw bytes before trying to read the value as text
+                let multi_line = value.iter().any(|&b| b == b'\n' || b == b'\r');
+                let text = (!multi_line)
+                    .then(|| std::str::from_utf8(value).ok())
+                    .flatten();
+                match text {
+                    Some(text) => write!(f, "SET {} {}", encode_key(key), text),
+                    None => {
+                        // Only use base64 for binary or multi-line data
+                        write!(f, "SET {} base64:{}", encode_key(key), BASE64.encode(value))
                     }
                 }
             }
-            LogEntry::Delete(key) => format!("DELETE {}", key),
-            LogEntry::Compact => "COMPACT".to_string(),
+            LogEntry::Delete(key) => write!(f, "DELETE {}", encode_key(key)),
+            LogEntry::Tag(key, tag) => write!(f, "TAG {} {}", encode_key(key), encode_key(tag)),
+            LogEntry::ContentType(key, content_type) => {
+                write!(f, "CTYPE {} {}", encode_key(key), encode_key(content_type))
+            }
+            LogEntry::Compact => write!(f, "COMPACT"),
         }
     }
+}
 
+impl LogEntry {
     pub fn from_string(line: &str) -> Option<LogEntry> {
-        let line = line.trim();
-        if line.is_empty() {
+        // Only strip the line ending: trailing spaces belong to the value, and
+        // `SET key ` is how an empty value is written
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() {
             return None;
         }
 
+        if let Some((kind, rest)) = line.split_once(' ') {
+            match kind {
+                "SETL" => {
+                    let (key, value) = split_sized_key(rest)?;
+                    return Some(LogEntry::Set(key, decode_value(value)?));
+                }
+                "DELETEL" => {
+                    let (key, rest) = split_sized_key(rest)?;
+                    return rest.is_empty().then_some(LogEntry::Delete(key));
+                }
+                "TAGL" => {
+                    let (key, tag) = split_sized_key(rest)?;
+                    return Some(LogEntry::Tag(key, decode_key(tag)?));
+                }
+                _ => {}
+            }
+        }
+
         let mut parts = line.splitn(3, ' ');
         match parts.next() {
             Some("SET") => {
-                let key = parts.next()?;
-                let value = parts.next()?;
-                if value.starts_with("base64:") {
-                    // Handle base64-encoded binary data
-                    let encoded = &value[7..]; // Skip "base64:" prefix
-                    let decoded_value = BASE64.decode(encoded).ok()?;
-                    Some(LogEntry::Set(key.to_string(), decoded_value))
-                } else {
-                    // Handle plain text
-                    Some(LogEntry::Set(key.to_string(), value.as_bytes().to_vec()))
-                }
+                let key = decode_key(parts.next()?)?;
+                let value = parts.next().unwrap_or("");
+                Some(LogEntry::Set(key, decode_value(value)?))
             }
             Some("DELETE") => {
-                let key = parts.next()?;
-                Some(LogEntry::Delete(key.to_string()))
+                let key = decode_key(parts.next()?)?;
+                Some(LogEntry::Delete(key))
+            }
+            Some("TAG") => {
+                let key = decode_key(parts.next()?)?;
+                let tag = decode_key(parts.next()?)?;
+                Some(LogEntry::Tag(key, tag))
+            }
+            Some("CTYPE") => {
+                let key = decode_key(parts.next()?)?;
+                let content_type = decode_key(parts.next()?)?;
+                Some(LogEntry::ContentType(key, content_type))
             }
             Some("COMPACT") => Some(LogEntry::Compact),
             _ => None,
@@ -64,16 +117,70 @@ impl LogEntry {
     }
 }
 
+/// Decodes a logged value, which is plain text or `base64:<base64>`.
+fn decode_value(value: &str) -> Option<Vec<u8>> {
+    match value.strip_prefix("base64:") {
+        Some(encoded) => BASE64.decode(encoded).ok(),
+        None => Some(value.as_bytes().to_vec()),
+    }
+}
+
+/// Splits `<len> <key>[ <rest>]` into the key, exactly `len` bytes long, and
+/// whatever follows the space after it.
+fn split_sized_key(fields: &str) -> Option<(String, &str)> {
+    let (len, rest) = fields.split_once(' ')?;
+    if !len.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let len: usize = len.parse().ok()?;
+    let key = rest.get(..len)?;
+    let rest = &rest[len..];
+    let rest = match rest.strip_prefix(' ') {
+        Some(rest) => rest,
+        None if rest.is_empty() => rest,
+        None => return None,
+    };
+    Some((key.to_string(), rest))
+}
+
 #[derive(Debug)]
 pub struct LogFile {
     file: File,
     current_size: usize,
     path: PathBuf,
+    /// Whether `append` compacts the log once it grows past `MAX_LOG_SIZE`
+    auto_compact: bool,
+    /// Hard ceiling on the log's size; writes that would cross it fail
+    max_total_bytes: Option<usize>,
+    /// Compactions completed since the log was opened
+    compactions: u64,
+    /// Secondary log that every appended batch is also written to
+    mirror: Option<(PathBuf, File)>,
+    /// Entries in the file as of the last replay or compaction, plus those
+    /// appended since; unparseable lines are not counted
+    entries: usize,
+    /// Free space a compaction must leave on the log's filesystem
+    compaction_reserve: u64,
+    /// Checked while replaying and compacting, to stop either early
+    cancel: CancelToken,
 }
 
 impl LogFile {
-    pub fn with_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    /// Opens the log at `path`, creating the file if needed. A missing
+    /// parent directory is created too when `create_dirs` is set, and is a
+    /// `NotFound` error naming the directory otherwise.
+    pub fn with_path<P: AsRef<Path>>(path: P, create_dirs: bool) -> io::Result<Self> {
         let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if create_dirs {
+                fs::create_dir_all(dir)?;
+            } else if !dir.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("log directory {} does not exist", dir.display()),
+                ));
+            }
+        }
         println!("Creating new log file at {}", path.display());
         let file = OpenOptions::new()
             .create(true)
@@ -99,41 +206,117 @@ impl LogFile {
             file,
             current_size,
             path,
+            auto_compact: true,
+            max_total_bytes: None,
+            compactions: 0,
+            mirror: None,
+            compaction_reserve: 0,
+            entries: 0,
+            cancel: CancelToken::new(),
         })
     }
 
+    /// Sets the token that cancels a replay or compaction in progress.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
     pub fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
-        let entry_str = entry.to_string();
-        println!("Appending log entry: {}", entry_str.trim());
-        self.file.write_all(entry_str.as_bytes())?;
-        self.file.write_all(b"\n")?;
-        self.current_size += entry_str.len() + 1;
+        self.append_all(std::slice::from_ref(entry))
+    }
+
+    /// Appends several entries with a single write and a single fsync, so a
+    /// group of related changes reaches the disk together.
+    pub fn append_all(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut batch = String::new();
+        for entry in entries {
+            let entry_str = entry.to_string();
+            println!("Appending log entry: {}", entry_str.trim());
+            batch.push_str(&entry_str);
+            batch.push('\n');
+        }
+        self.check_budget(entries, batch.len())?;
+        self.file.write_all(batch.as_bytes())?;
+        self.current_size += batch.len();
+        self.entries += entries.len();
         self.file.sync_all()?; // Ensure data is written to disk
         println!("Log entry appended and synced");
+        self.write_mirror(&batch);
 
         // Check if we need to compact
-        if self.current_size > MAX_LOG_SIZE {
+        if self.auto_compact && self.current_size > MAX_LOG_SIZE {
             println!(
                 "Log size ({}) exceeds maximum size ({}), triggering compaction",
                 self.current_size, MAX_LOG_SIZE
             );
-            self.compact()?;
-            // Update current size after compaction
-            self.current_size = self.file.metadata()?.len() as usize;
-            println!("Log compaction completed. New size: {}", self.current_size);
+            match self.compact() {
+                Ok(()) => println!("Log compaction completed. New size: {}", self.current_size),
+                // The write itself is already on disk; compaction can wait
+                // until there is room for it, or until after a shutdown
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::StorageFull | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    eprintln!("Skipping compaction: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
         }
 
         Ok(())
     }
 
+    /// Verifies that the log can still be written to disk.
+    ///
+    /// Syncs the open log file, then writes, fsyncs and removes a small probe
+    /// file next to it, so a read-only or full filesystem is reported even
+    /// though the log itself is already open.
+    pub fn probe_writable(&self) -> io::Result<()> {
+        self.file.sync_all()?;
+
+        let probe_path = self.path.with_extension("probe");
+        let mut probe = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&probe_path)?;
+        let result = probe.write_all(b"probe").and_then(|_| probe.sync_all());
+        drop(probe);
+        fs::remove_file(&probe_path)?;
+        result
+    }
+
+    /// Reopens the log after it was moved or removed, e.g. by a rotation tool.
+    ///
+    /// The old handle still sees the full log, so the recreated file at the
+    /// original path is seeded with a compacted snapshot of it. Does nothing
+    /// if the path still refers to the open file.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let open = self.file.metadata()?;
+        let moved = match fs::metadata(&self.path) {
+            Ok(meta) => meta.ino() != open.ino() || meta.dev() != open.dev(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if moved {
+            println!("Log file {} was moved, reopening", self.path.display());
+            self.compact()?;
+        }
+        Ok(())
+    }
+
     pub fn replay(&mut self) -> io::Result<Vec<LogEntry>> {
         println!("Replaying log file");
         let mut entries = Vec::new();
         // Seek to the beginning of the file
-        self.file.seek(std::io::SeekFrom::Start(0))?;
+        self.file.seek(SeekFrom::Start(0))?;
 
         let reader = BufReader::new(&self.file);
         for line in reader.lines() {
+            self.cancel.check("log replay")?;
             let line = line?;
             println!("Reading log line: {}", line);
             if let Some(entry) = LogEntry::from_string(&line) {
@@ -145,6 +328,7 @@ impl LogFile {
         }
 
         println!("Replay complete, found {} entries", entries.len());
+        self.entries = entries.len();
         Ok(entries)
     }
 
@@ -153,72 +337,500 @@ impl LogFile {
 
         // First, replay the log to get the current state
         let entries = self.replay()?;
-        let mut current_state = HashMap::new();
+        let mut current_state: HashMap<String, LiveKey> = HashMap::new();
 
-        // Build the current state, keeping only the latest entry for each key
+        // Build the current state, keeping only the latest value for each key
+        // along with the tags it still carries and its content type, which a
+        // new value clears
         for entry in entries {
             match entry {
-                LogEntry::Set(key, value) => {
-                    current_state.insert(key, Some(value));
-                }
+                LogEntry::Set(key, value) => match current_state.get_mut(&key) {
+                    Some(state) => {
+                        state.value = value;
+                        state.content_type = None;
+                    }
+                    None => {
+                        current_state.insert(
+                            key,
+                            LiveKey {
+                                value,
+                                tags: BTreeSet::new(),
+                                content_type: None,
+                            },
+                        );
+                    }
+                },
                 LogEntry::Delete(key) => {
-                    current_state.insert(key, None);
+                    current_state.remove(&key);
+                }
+                LogEntry::Tag(key, tag) => {
+                    if let Some(state) = current_state.get_mut(&key) {
+                        state.tags.insert(tag);
+                    }
+                }
+                LogEntry::ContentType(key, content_type) => {
+                    if let Some(state) = current_state.get_mut(&key) {
+                        state.content_type = Some(content_type);
+                    }
                 }
                 LogEntry::Compact => continue,
             }
         }
+        let entries: Vec<LogEntry> = current_state
+            .into_iter()
+            .flat_map(|(key, state)| key_entries(key, state.value, state.tags, state.content_type))
+            .collect();
+        let written = entries.len();
 
-        // Create a temporary file for the compacted log
+        // The temporary file has to live next to the log for the rename to be
+        // atomic, so make sure it fits before writing any of it
         let temp_path = self.path.with_extension("tmp");
-        let mut temp_file = OpenOptions::new()
+        let needed = entries
+            .iter()
+            .map(|entry| entry.to_string().len() as u64 + 1)
+            .sum();
+        self.precheck_space(needed)?;
+
+        // Create a temporary file for the compacted log
+        let temp_file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .mode(0o600)
             .open(&temp_path)?;
 
-        // Write only the current state to the temporary file
-        for (key, value_opt) in current_state {
-            if let Some(value) = value_opt {
-                let entry = LogEntry::Set(key, value);
-                writeln!(temp_file, "{}", entry.to_string())?;
+        // Write only the current state to the temporary file, and make sure it
+        // is on disk before it can replace the live log
+        if let Err(e) = write_compacted(temp_file, entries, Some(&self.cancel)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to write compacted log {}: {}",
+                    temp_path.display(),
+                    e
+                ),
+            ));
+        }
+
+        // Last chance to back out with the old log untouched
+        if let Err(e) = self.cancel.check("compaction") {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+
+        // Open and lock the compacted file while it is still the temporary
+        // one; after the rename this handle is the live log. Until then the
+        // old log stays open and locked, so a failure leaves it in charge
+        let installed = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .open(&temp_path)
+            .and_then(|file| {
+                file.try_lock_exclusive()?;
+                fs::rename(&temp_path, &self.path)?;
+                Ok(file)
+            });
+        let new_file = match installed {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
             }
+        };
+
+        // Dropping the old handle closes the last descriptor on the replaced
+        // file, so the filesystem frees its blocks now rather than whenever
+        // the log is next closed
+        let old_file = std::mem::replace(&mut self.file, new_file);
+        let _ = fs2::FileExt::unlock(&old_file);
+        drop(old_file);
+
+        // Sync the directory so the rename itself survives a power loss
+        sync_parent_dir(&self.path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to sync directory of {}: {}", self.path.display(), e),
+            )
+        })?;
+        self.current_size = self.file.metadata()?.len() as usize;
+        self.entries = written;
+        self.compactions += 1;
+
+        Ok(())
+    }
+
+    /// Fails with `StorageFull` unless the log's filesystem has room for
+    /// `needed` more bytes on top of the compaction reserve.
+    fn precheck_space(&self, needed: u64) -> io::Result<()> {
+        let available = fs2::available_space(parent_dir(&self.path))?;
+        let required = needed.saturating_add(self.compaction_reserve);
+        if available < required {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "Not enough free space to compact {}: need {} bytes, {} available",
+                    self.path.display(),
+                    required,
+                    available
+                ),
+            ));
         }
-        temp_file.sync_all()?;
+        Ok(())
+    }
 
-        // Release the lock on the old file
-        fs2::FileExt::unlock(&self.file)?;
+    /// Sets how much free space a compaction must leave on the log's
+    /// filesystem; a compaction that would eat into it is refused up front.
+    pub fn set_compaction_reserve(&mut self, bytes: u64) {
+        self.compaction_reserve = bytes;
+    }
 
-        // Close both files
-        drop(temp_file);
-        drop(std::mem::replace(
-            &mut self.file,
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .read(true)
-                .mode(0o600)
-                .open(&temp_path)?,
-        ));
+    /// Returns how many compactions have completed since the log was opened.
+    pub fn compactions(&self) -> u64 {
+        self.compactions
+    }
 
-        // Rename the temporary file to the main log file
-        fs::rename(&temp_path, &self.path)?;
+    /// Flushes the log to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
 
-        // Open and lock the new file
-        self.file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .mode(0o600)
-            .open(&self.path)?;
-        self.file.try_lock_exclusive()?;
+    /// Also appends every future write to the log at `path`; `None` stops
+    /// mirroring.
+    ///
+    /// The mirror only ever grows: compactions of the primary are not copied
+    /// to it, so it can be tailed by a follower.
+    pub fn set_mirror(&mut self, path: Option<&Path>) -> io::Result<()> {
+        self.mirror = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(path)?;
+                Some((path.to_path_buf(), file))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Writes a batch that already reached the primary log to the mirror.
+    /// The primary is the source of truth, so a failure here is only
+    /// reported.
+    fn write_mirror(&mut self, batch: &str) {
+        if let Some((path, file)) = &mut self.mirror {
+            if let Err(e) = file
+                .write_all(batch.as_bytes())
+                .and_then(|_| file.sync_all())
+            {
+                eprintln!(
+                    "Warning: failed to write mirror log {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Turns size-triggered compaction in `append` on or off. Explicit
+    /// `compact` calls work either way.
+    pub fn set_auto_compact(&mut self, auto_compact: bool) {
+        self.auto_compact = auto_compact;
+    }
+
+    /// Caps the size of the log; `None` removes the cap.
+    pub fn set_max_total_bytes(&mut self, max_total_bytes: Option<usize>) {
+        self.max_total_bytes = max_total_bytes;
+    }
+
+    /// Makes room for `len` more bytes within the size cap, compacting first
+    /// if needed. Batches made only of deletes are always let through, since
+    /// they are how space is reclaimed.
+    fn check_budget(&mut self, entries: &[LogEntry], len: usize) -> io::Result<()> {
+        let Some(max) = self.max_total_bytes else {
+            return Ok(());
+        };
+        let only_deletes = entries
+            .iter()
+            .all(|entry| matches!(entry, LogEntry::Delete(_)));
+        if only_deletes || self.current_size + len <= max {
+            return Ok(());
+        }
 
+        println!(
+            "Log size ({}) would exceed budget ({}), compacting",
+            self.current_size + len,
+            max
+        );
+        self.compact()?;
+        if self.current_size + len > max {
+            return Err(io::Error::other("disk budget exceeded"));
+        }
         Ok(())
     }
 
-    pub fn unlock(&self) -> io::Result<()> {
-        // Use fully qualified syntax to avoid naming conflicts
-        fs2::FileExt::unlock(&self.file)
+    /// Returns the current size of the log in bytes.
+    pub fn size(&self) -> usize {
+        self.current_size
+    }
+
+    /// Returns the path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns roughly how many entries the log holds. The count is exact
+    /// after a replay or compaction and only grows with appends in between.
+    pub fn entry_count(&self) -> usize {
+        self.entries
+    }
+}
+
+/// Streams the entries of the log at `path` to `f`, in log order.
+///
+/// The file is opened read-only and without taking the writer's lock, so this
+/// works on the log of a running server. Lines that fail to parse are skipped,
+/// like they are during a normal replay.
+pub fn read_entries<P, F>(path: P, mut f: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(&LogEntry),
+{
+    for entry in LogReader::open(path)? {
+        match entry {
+            Ok(entry) => f(&entry),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A lazy iterator over the entries of a log file.
+///
+/// Like [`read_entries`], the file is opened read-only without taking the
+/// writer's exclusive lock, so a running server's log can be read while it
+/// is being written. A shared lock would conflict with that exclusive lock,
+/// so none is taken; compaction replaces the log by renaming a new file over
+/// it, which leaves a reader on the old file undisturbed.
+///
+/// Blank lines are skipped. A line that doesn't parse yields an
+/// `InvalidData` error and iteration continues with the next line.
+///
+/// # Examples
+///
+/// ```
+/// use keystonelight::storage::{Database, LogEntry, LogReader};
+/// use std::fs;
+///
+/// let log_path = "test_log_reader.log";
+/// let db = Database::with_log_path(log_path).unwrap();
+/// db.set("key1", b"value1").unwrap();
+/// db.delete("key1").unwrap();
+///
+/// // The database still holds its lock
+/// let entries: Vec<LogEntry> = LogReader::open(log_path)
+///     .unwrap()
+///     .collect::<std::io::Result<_>>()
+///     .unwrap();
+/// assert_eq!(
+///     entries,
+///     vec![
+///         LogEntry::Set("key1".to_string(), b"value1".to_vec()),
+///         LogEntry::Delete("key1".to_string()),
+///     ]
+/// );
+///
+/// // Clean up
+/// fs::remove_file(log_path).unwrap_or(());
+/// ```
+pub struct LogReader {
+    lines: io::Lines<BufReader<File>>,
+    line_number: usize,
+}
+
+impl LogReader {
+    /// Opens the log at `path` for reading.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            lines: BufReader::new(File::open(path)?).lines(),
+            line_number: 0,
+        })
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(LogEntry::from_string(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid log entry on line {}", self.line_number),
+                )
+            }));
+        }
+    }
+}
+
+/// Entries read by a [`LogTail`] since the previous poll.
+#[derive(Debug)]
+pub enum TailUpdate {
+    /// New entries were appended to the file being followed
+    Appended(Vec<LogEntry>),
+    /// The file was replaced or truncated (e.g. by compaction), so these
+    /// entries are its complete contents and any previous state is stale
+    Reset(Vec<LogEntry>),
+}
+
+/// A read-only follower of a log file written by another process.
+///
+/// The tail never takes the primary's lock. It remembers how far it has
+/// read and notices when the primary swaps in a compacted file, which shows
+/// up as a new inode or a file shorter than the last read position.
+#[derive(Debug)]
+pub struct LogTail {
+    file: File,
+    path: PathBuf,
+    offset: u64,
+    inode: u64,
+}
+
+impl LogTail {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        println!("Following log file at {}", path.display());
+        let file = File::open(&path)?;
+        let inode = file.metadata()?.ino();
+        Ok(Self {
+            file,
+            path,
+            offset: 0,
+            inode,
+        })
+    }
+
+    /// Returns how many bytes of the current file have been read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads all complete entries written since the last call.
+    ///
+    /// A trailing partial line (the primary is mid-append) is left for the
+    /// next poll.
+    pub fn read_new(&mut self) -> io::Result<TailUpdate> {
+        let metadata = fs::metadata(&self.path)?;
+        let replaced = metadata.ino() != self.inode || metadata.len() < self.offset;
+        if replaced {
+            println!("Followed log file was replaced, replaying from the start");
+            self.file = File::open(&self.path)?;
+            self.inode = self.file.metadata()?.ino();
+            self.offset = 0;
+        }
+
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = Vec::new();
+        self.file.read_to_end(&mut buf)?;
+        let complete = match buf.iter().rposition(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None => 0,
+        };
+        self.offset += complete as u64;
+
+        let entries = String::from_utf8_lossy(&buf[..complete])
+            .lines()
+            .filter_map(LogEntry::from_string)
+            .collect();
+        Ok(if replaced {
+            TailUpdate::Reset(entries)
+        } else {
+            TailUpdate::Appended(entries)
+        })
+    }
+}
+
+/// Writes the live entries to `file`, flushing and fsyncing before returning.
+fn write_compacted<I: IntoIterator<Item = LogEntry>>(
+    file: File,
+    entries: I,
+    cancel: Option<&CancelToken>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        if let Some(cancel) = cancel {
+            cancel.check("compaction")?;
+        }
+        writeln!(writer, "{}", entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()
+}
+
+/// What compaction keeps of a key that is still live.
+struct LiveKey {
+    value: Vec<u8>,
+    tags: BTreeSet<String>,
+    content_type: Option<String>,
+}
+
+/// The entries that recreate one live key in a compacted log: its SET, its
+/// CTYPE if it has one, then one TAG per tag.
+pub fn key_entries(
+    key: String,
+    value: Vec<u8>,
+    tags: BTreeSet<String>,
+    content_type: Option<String>,
+) -> Vec<LogEntry> {
+    let mut entries = vec![LogEntry::Set(key.clone(), value)];
+    if let Some(content_type) = content_type {
+        entries.push(LogEntry::ContentType(key.clone(), content_type));
+    }
+    entries.extend(tags.into_iter().map(|tag| LogEntry::Tag(key.clone(), tag)));
+    entries
+}
+
+/// Writes `entries` to `path` as a compacted log.
+///
+/// The snapshot is written to a temporary file that replaces `path` only once
+/// it is fully on disk, so a crash never leaves a partial snapshot behind.
+pub fn write_snapshot(path: &Path, entries: Vec<LogEntry>) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let temp_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    if let Err(e) = write_compacted(temp_file, entries, None) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    fs::rename(&temp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Fsyncs the directory containing `path` so renames within it are durable.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    File::open(parent_dir(path))?.sync_all()
+}
+
+/// Returns the directory containing `path`, which is `.` for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     }
 }
 
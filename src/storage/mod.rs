@@ -50,18 +50,30 @@
 //! fs::remove_file(log_path).unwrap_or(());
 //! ```
 
-use crate::storage::log::{LogEntry, LogFile};
-use std::collections::HashMap;
+use crate::protocol::{EvalAction, EvalCondition, EvalStep, Response, BASE64_KEY_PREFIX};
+use crate::storage::log::LogFile;
+use rand::seq::IteratorRandom;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
 use std::io;
-use std::path::Path;
-use std::sync::{Arc, Mutex, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 // Currently unused file paths
 // const CACHE_PATH: &str = "cache.txt";
 // const DATA_PATH: &str = "data.txt";
 
+mod glob;
 mod log;
 
+pub use crate::storage::log::{LogEntry, LogReader, LogTail, TailUpdate};
+
+/// The content type reported for a value that was stored without one
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
 /// A persistent key-value database with in-memory cache and log-based storage.
 ///
 /// The database maintains an in-memory cache for fast access and a log file for persistence.
@@ -101,8 +113,68 @@ mod log;
 /// fs::remove_file("custom.log").unwrap_or(());
 /// ```
 pub struct Database {
-    log: Arc<Mutex<LogFile>>,
-    cache: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    /// The log this database appends to; `None` for a read-only follower
+    log: Option<Arc<Mutex<LogFile>>>,
+    /// Tail of another process's log, for followers opened with `open_follower`
+    follower: Option<Mutex<LogTail>>,
+    cache: Arc<RwLock<Cache>>,
+    /// Hard cap on the number of keys; new keys past it are rejected
+    max_keys: Option<usize>,
+    /// Whether `set` skips writes that would not change the stored value
+    dedup_writes: bool,
+    /// Whether writes of non-UTF-8 keys or values are rejected
+    require_utf8: bool,
+    /// Point-in-time read views opened by `begin_read_snapshot`
+    snapshots: Mutex<ReadSnapshots>,
+    /// Set while `compact` or `try_compact` is rewriting the log
+    compacting: AtomicBool,
+    /// Stops a compaction or verification in progress; shared with the log
+    cancel: CancelToken,
+}
+
+/// A flag that asks long-running operations to stop early, returned by
+/// [`Database::cancel_token`].
+///
+/// Compactions, including automatic ones, and [`Database::verify_consistency`]
+/// check the token as they go. Once it is cancelled they fail with
+/// `Interrupted`, and a compaction removes its temporary file and leaves the
+/// log as it was. Clones share the flag, which stays set until
+/// [`CancelToken::reset`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks operations checking the token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets operations run to completion again.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the token has been cancelled since it was last reset.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `Interrupted` if the token has been cancelled.
+    fn check(&self, operation: &str) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("{} cancelled", operation),
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Database {
@@ -126,6 +198,9 @@ impl Database {
 
     /// Creates a new database with a custom log file path.
     ///
+    /// Missing parent directories of the log are created; use
+    /// [`Database::open`] to treat them as an error instead.
+    ///
     /// # Examples
     ///
     /// ```
@@ -139,90 +214,276 @@ impl Database {
     /// fs::remove_file("custom.log").unwrap_or(());
     /// ```
     pub fn with_log_path<P: AsRef<Path>>(log_path: P) -> io::Result<Self> {
-        let mut log = LogFile::with_path(log_path)?;
-        let cache = Arc::new(RwLock::new(HashMap::new()));
+        Self::open(log_path, true)
+    }
+
+    /// Opens the database logged at `log_path`, like
+    /// [`Database::with_log_path`], choosing what happens when the log's
+    /// directory doesn't exist.
+    ///
+    /// With `create_dirs` the directory is created along with any missing
+    /// parents. Without it, opening fails with a `NotFound` error naming the
+    /// directory, for setups where a missing directory means a mount or
+    /// volume isn't there and a fresh empty database would hide that.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// assert!(Database::open("missing_dir/test_open.log", false).is_err());
+    ///
+    /// let db = Database::open("missing_dir/test_open.log", true).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    ///
+    /// // Clean up
+    /// fs::remove_dir_all("missing_dir").unwrap_or(());
+    /// ```
+    pub fn open<P: AsRef<Path>>(log_path: P, create_dirs: bool) -> io::Result<Self> {
+        let mut log = LogFile::with_path(log_path, create_dirs)?;
+        let cancel = CancelToken::new();
+        log.set_cancel_token(cancel.clone());
+        let cache = Arc::new(RwLock::new(Cache::default()));
 
         // Replay the log to build the cache
         let entries = log.replay()?;
         {
             let mut cache = cache.write().unwrap();
             for entry in entries {
-                match entry {
-                    LogEntry::Set(key, value) => {
-                        cache.insert(key, value);
-                    }
-                    LogEntry::Delete(key) => {
-                        cache.remove(&key);
-                    }
-                    LogEntry::Compact => {
-                        // Skip compact entries when replaying
-                        continue;
-                    }
-                }
+                apply_entry(&mut cache, entry);
             }
+            // Replayed entries predate this process, so they aren't changes
+            cache.forget_changes();
         }
 
         Ok(Self {
-            log: Arc::new(Mutex::new(log)),
+            log: Some(Arc::new(Mutex::new(log))),
+            follower: None,
             cache,
+            max_keys: None,
+            dedup_writes: false,
+            require_utf8: false,
+            snapshots: Mutex::new(ReadSnapshots::default()),
+            compacting: AtomicBool::new(false),
+            cancel,
         })
     }
 
-    // Currently unused file operations
-    /*
-    pub fn load_from_file(&self) -> io::Result<()> {
-        let mut storage = self.storage.write().unwrap();
-        let file = File::open(DATA_PATH)?;
-        let reader = BufReader::new(file);
+    /// Opens a read-only replica of a log file owned by another process.
+    ///
+    /// The follower does not lock the log, so it can run alongside the
+    /// primary. Call [`Database::poll_updates`] to apply entries the primary
+    /// has appended since; reads are eventually consistent. Writes return a
+    /// `PermissionDenied` error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_follower.log";
+    /// let primary = Database::with_log_path(log_path).unwrap();
+    /// let follower = Database::open_follower(log_path).unwrap();
+    ///
+    /// primary.set("key1", b"value1").unwrap();
+    /// assert_eq!(follower.poll_updates().unwrap(), 1);
+    /// assert_eq!(follower.get("key1").unwrap(), b"value1");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn open_follower<P: AsRef<Path>>(log_path: P) -> io::Result<Self> {
+        let db = Self {
+            log: None,
+            follower: Some(Mutex::new(LogTail::open(log_path)?)),
+            cache: Arc::new(RwLock::new(Cache::default())),
+            max_keys: None,
+            dedup_writes: false,
+            require_utf8: false,
+            snapshots: Mutex::new(ReadSnapshots::default()),
+            compacting: AtomicBool::new(false),
+            cancel: CancelToken::new(),
+        };
+        db.poll_updates()?;
+        db.cache.write().unwrap().forget_changes();
+        Ok(db)
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let parts: Vec<&str> = line.splitn(2, ' ').collect();
-            if parts.len() == 2 {
-                storage.insert(parts[0].to_string(), parts[1].as_bytes().to_vec());
+    /// Applies entries the primary has written since the last poll.
+    ///
+    /// If the primary compacted its log in the meantime, the cache is rebuilt
+    /// from the new file. Returns the number of entries applied. On a
+    /// database that isn't a follower this is a no-op returning 0.
+    pub fn poll_updates(&self) -> io::Result<usize> {
+        let Some(follower) = &self.follower else {
+            return Ok(0);
+        };
+        let update = follower.lock().unwrap().read_new()?;
+        let mut cache = self.cache.write().unwrap();
+        let entries = match update {
+            TailUpdate::Appended(entries) => entries,
+            TailUpdate::Reset(entries) => {
+                cache.clear();
+                entries
             }
+        };
+        let count = entries.len();
+        for entry in entries {
+            apply_entry(&mut cache, entry);
         }
-        Ok(())
+        Ok(count)
     }
 
-    pub fn save_to_file(&self) -> io::Result<()> {
-        let storage = self.storage.read().unwrap();
-        let mut file = File::create(DATA_PATH)?;
+    /// Writes entries read from another database's log, such as a
+    /// replication primary, to this database's own log and cache.
+    ///
+    /// A [`TailUpdate::Reset`] replaces the current contents: every existing
+    /// key is deleted in the same batch that writes the new entries, so
+    /// readers never see a mix of old and new state. `COMPACT` markers are
+    /// dropped. Returns the number of entries applied.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::{Database, LogEntry, TailUpdate};
+    /// use std::fs;
+    ///
+    /// let log_path = "test_apply_update.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("stale", b"old").unwrap();
+    ///
+    /// let snapshot = vec![LogEntry::Set("key1".to_string(), b"value1".to_vec())];
+    /// assert_eq!(db.apply_update(TailUpdate::Reset(snapshot)).unwrap(), 1);
+    /// assert_eq!(db.get("key1").unwrap(), b"value1");
+    /// assert!(db.get("stale").is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn apply_update(&self, update: TailUpdate) -> io::Result<usize> {
+        let mut cache = self.cache.write().unwrap();
+        let (reset, entries) = match update {
+            TailUpdate::Appended(entries) => (false, entries),
+            TailUpdate::Reset(entries) => (true, entries),
+        };
+        let entries: Vec<LogEntry> = entries
+            .into_iter()
+            .filter(|entry| !matches!(entry, LogEntry::Compact))
+            .collect();
 
-        for (key, value) in storage.iter() {
-            writeln!(file, "{} {}", key, String::from_utf8_lossy(value))?;
+        let mut batch = Vec::new();
+        if reset {
+            batch.extend(cache.entries.keys().cloned().map(LogEntry::Delete));
         }
-        Ok(())
+        batch.extend(entries.iter().cloned());
+        self.writable_log()?.append_all(&batch)?;
+
+        if reset {
+            cache.clear();
+        }
+        let count = entries.len();
+        for entry in entries {
+            apply_entry(&mut cache, entry);
+        }
+        Ok(count)
     }
-    */
 
-    /// Retrieves a value from the database.
+    /// Returns the changes made after `version`, oldest first, as log
+    /// entries, along with the current version to pass next time.
+    ///
+    /// Every change stamps a fresh version from the counter `SET`s use:
+    /// writes, deletes and tags alike. Pass a version from
+    /// [`Database::metadata`] or an earlier call to poll for what happened
+    /// since. Only the most recent changes are kept; if any after `version`
+    /// have been forgotten, including those from before this process opened
+    /// the log, this fails with `InvalidInput` and the caller has to read
+    /// the full data again.
     ///
     /// # Examples
     ///
     /// ```
-    /// use keystonelight::storage::Database;
+    /// use keystonelight::storage::{Database, LogEntry};
     /// use std::fs;
     ///
-    /// // Create a new database with a unique log file
-    /// let log_path = "test_get.log";
+    /// let log_path = "test_changes_since.log";
     /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    /// let version = db.metadata("key1").unwrap().version;
     ///
-    /// // Get non-existent key
-    /// assert!(db.get("missing").is_none());
+    /// db.delete("key1").unwrap();
+    /// let (changes, current) = db.changes_since(version).unwrap();
+    /// assert_eq!(changes, vec![LogEntry::Delete("key1".to_string())]);
+    /// assert!(db.changes_since(current).unwrap().0.is_empty());
     ///
-    /// // Get existing key
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn changes_since(&self, version: u64) -> io::Result<(Vec<LogEntry>, u64)> {
+        let cache = self.cache.read().unwrap();
+        if version < cache.changes_floor {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "changes before version {} are no longer kept",
+                    cache.changes_floor
+                ),
+            ));
+        }
+        let changes = cache
+            .changes
+            .iter()
+            .filter(|(changed_at, _)| *changed_at > version)
+            .map(|(_, change)| change.to_entry())
+            .collect();
+        Ok((changes, cache.last_version))
+    }
+
+    /// Streams every entry of the log at `path` to `f`, in order.
+    ///
+    /// No cache is built, so this is the building block for custom
+    /// projections of the log. Deletes and `COMPACT` markers are passed
+    /// through as-is. The log is read without taking its lock, so it is safe
+    /// to call against the log of a running server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::{Database, LogEntry};
+    /// use std::fs;
+    ///
+    /// let log_path = "test_replay_into.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
     /// db.set("key1", b"value1").unwrap();
-    /// assert_eq!(db.get("key1").unwrap(), b"value1");
+    /// db.delete("key1").unwrap();
+    ///
+    /// let mut deletes = 0;
+    /// Database::replay_into(log_path, |entry| {
+    ///     if let LogEntry::Delete(_) = entry {
+    ///         deletes += 1;
+    ///     }
+    /// })
+    /// .unwrap();
+    /// assert_eq!(deletes, 1);
     ///
     /// // Clean up
     /// fs::remove_file(log_path).unwrap_or(());
     /// ```
-    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
-        self.cache.read().unwrap().get(key).cloned()
+    pub fn replay_into<P, F>(path: P, f: F) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&LogEntry),
+    {
+        log::read_entries(path, f)
     }
 
-    /// Sets a key-value pair in the database.
+    /// Caps the number of keys the database will hold.
+    ///
+    /// Once the cap is reached, setting a brand-new key fails with a
+    /// `database full` error instead of evicting anything. Updating existing
+    /// keys still succeeds, and deleting keys frees room for new ones. `None`
+    /// removes the cap.
     ///
     /// # Examples
     ///
@@ -230,29 +491,91 @@ impl Database {
     /// use keystonelight::storage::Database;
     /// use std::fs;
     ///
-    /// let db = Database::new().unwrap();
+    /// let log_path = "test_max_keys.log";
+    /// let mut db = Database::with_log_path(log_path).unwrap();
+    /// db.set_max_keys(Some(1));
     ///
-    /// // Set and verify a value
     /// db.set("key1", b"value1").unwrap();
-    /// assert_eq!(db.get("key1").unwrap(), b"value1");
+    /// assert!(db.set("key2", b"value2").is_err());
+    /// db.set("key1", b"updated").unwrap();
     ///
-    /// // Update existing value
-    /// db.set("key1", b"new_value").unwrap();
-    /// assert_eq!(db.get("key1").unwrap(), b"new_value");
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_max_keys(&mut self, max_keys: Option<usize>) {
+        self.max_keys = max_keys;
+    }
+
+    /// Returns the cap set with [`Database::set_max_keys`].
+    pub fn max_keys(&self) -> Option<usize> {
+        self.max_keys
+    }
+
+    /// Caps the total size of the values stored under keys starting with
+    /// `prefix` at `max_bytes`, replacing any earlier quota for the prefix.
+    ///
+    /// A write that would take the prefix past its quota fails with a
+    /// `quota exceeded` error; shrinking or deleting values frees budget. A
+    /// key under several quota prefixes counts against each. Usage starts
+    /// from the values already stored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_quota.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set_quota("t1:", 8);
+    ///
+    /// db.set("t1:a", b"12345").unwrap();
+    /// let err = db.set("t1:b", b"6789").unwrap_err();
+    /// assert_eq!(err.to_string(), "quota exceeded");
+    /// db.set("t2:b", b"6789").unwrap();
+    /// assert_eq!(db.quota_usage("t1:"), Some(5));
     ///
     /// // Clean up
-    /// fs::remove_file("keystonelight.log").unwrap_or(());
+    /// fs::remove_file(log_path).unwrap_or(());
     /// ```
-    pub fn set(&self, key: &str, value: &[u8]) -> io::Result<()> {
+    pub fn set_quota(&self, prefix: &str, max_bytes: usize) {
         let mut cache = self.cache.write().unwrap();
-        let value = value.to_vec();
-        cache.insert(key.to_string(), value.clone());
-        let mut log = self.log.lock().unwrap();
-        log.append(&LogEntry::Set(key.to_string(), value))?;
-        Ok(())
+        let used_bytes = cache
+            .entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(_, entry)| entry.value.len())
+            .sum();
+        cache.quotas.retain(|quota| quota.prefix != prefix);
+        cache.quotas.push(Quota {
+            prefix: prefix.to_string(),
+            max_bytes,
+            used_bytes,
+        });
     }
 
-    /// Deletes a key-value pair from the database.
+    /// Returns the bytes of value stored under a quota prefix, or `None` if
+    /// the prefix has no quota.
+    pub fn quota_usage(&self, prefix: &str) -> Option<usize> {
+        let cache = self.cache.read().unwrap();
+        cache
+            .quotas
+            .iter()
+            .find(|quota| quota.prefix == prefix)
+            .map(|quota| quota.used_bytes)
+    }
+
+    /// Makes every `set` behave like [`Database::set_if_changed`].
+    pub fn set_dedup_writes(&mut self, dedup_writes: bool) {
+        self.dedup_writes = dedup_writes;
+    }
+
+    /// Rejects writes of values that aren't valid UTF-8, and of binary keys,
+    /// with an `InvalidData` error instead of storing them. Off by default.
+    ///
+    /// Binary keys are the ones the protocol carries as `base64key:<base64>`
+    /// because they don't decode to UTF-8; a key passed to the database in
+    /// that form counts as binary.
     ///
     /// # Examples
     ///
@@ -260,31 +583,102 @@ impl Database {
     /// use keystonelight::storage::Database;
     /// use std::fs;
     ///
-    /// // Create a new database with a unique log file
-    /// let log_path = "test_delete.log";
+    /// let log_path = "test_require_utf8.log";
+    /// let mut db = Database::with_log_path(log_path).unwrap();
+    /// db.set_require_utf8(true);
+    ///
+    /// db.set("greeting", "héllo".as_bytes()).unwrap();
+    /// let err = db.set("blob", &[0xff, 0xfe]).unwrap_err();
+    /// assert_eq!(err.to_string(), "non-utf8 value");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_require_utf8(&mut self, require_utf8: bool) {
+        self.require_utf8 = require_utf8;
+    }
+
+    /// Turns automatic compaction on or off.
+    ///
+    /// By default the log is compacted from inside a write once it grows past
+    /// 1MB. With auto-compaction off the log grows until
+    /// [`Database::compact`] is called, which keeps compaction's latency out
+    /// of ordinary writes. This is a no-op on a follower.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_auto_compact.log";
     /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set_auto_compact(false);
     ///
-    /// // Delete non-existent key
-    /// db.delete("missing").unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    /// db.compact().unwrap();
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_auto_compact(&self, auto_compact: bool) {
+        if let Some(log) = &self.log {
+            log.lock().unwrap().set_auto_compact(auto_compact);
+        }
+    }
+
+    /// Puts a hard ceiling on the log's size on disk.
+    ///
+    /// A write that would push the log past `max_bytes` first forces a
+    /// compaction; if the log would still be over budget, the write fails
+    /// with a `disk budget exceeded` error and nothing is changed. Deletes
+    /// are always accepted so space can be reclaimed. `None` removes the cap.
+    /// This is a no-op on a follower.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_max_log_bytes.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set_max_log_bytes(Some(32));
     ///
-    /// // Delete existing key
     /// db.set("key1", b"value1").unwrap();
-    /// db.delete("key1").unwrap();
-    /// assert!(db.get("key1").is_none());
+    /// let err = db.set("key2", &[b'x'; 64]).unwrap_err();
+    /// assert_eq!(err.to_string(), "disk budget exceeded");
     ///
     /// // Clean up
     /// fs::remove_file(log_path).unwrap_or(());
     /// ```
-    pub fn delete(&self, key: &str) -> io::Result<()> {
-        let mut cache = self.cache.write().unwrap();
-        if cache.remove(key).is_some() {
-            let mut log = self.log.lock().unwrap();
-            log.append(&LogEntry::Delete(key.to_string()))?;
+    pub fn set_max_log_bytes(&self, max_bytes: Option<usize>) {
+        if let Some(log) = &self.log {
+            log.lock().unwrap().set_max_total_bytes(max_bytes);
         }
-        Ok(())
     }
 
-    /// Compacts the log file by removing redundant entries.
+    /// Keeps `bytes` of the log's filesystem free during compaction.
+    ///
+    /// The compacted log is written next to the live one, so before starting
+    /// a compaction checks that the filesystem can hold it plus this reserve.
+    /// If not, it fails straight away with a `StorageFull` error and leaves
+    /// the log untouched, instead of running out of space halfway through.
+    /// Automatic compactions that can't fit are skipped. The default reserve
+    /// is 0. This is a no-op on a follower.
+    pub fn set_compaction_reserve(&self, bytes: u64) {
+        if let Some(log) = &self.log {
+            log.lock().unwrap().set_compaction_reserve(bytes);
+        }
+    }
+
+    /// Mirrors every write to a second log at `path`, e.g. on another mount,
+    /// for a hot standby to tail; `None` stops mirroring.
+    ///
+    /// Writes are appended to the mirror after they reach the primary log. A
+    /// failed mirror write is logged as a warning and does not fail the
+    /// operation. The mirror is never compacted. This is a no-op on a
+    /// follower.
     ///
     /// # Examples
     ///
@@ -292,26 +686,2464 @@ impl Database {
     /// use keystonelight::storage::Database;
     /// use std::fs;
     ///
-    /// let db = Database::new().unwrap();
+    /// let log_path = "test_mirror_primary.log";
+    /// let mirror_path = "test_mirror_secondary.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set_mirror_log(Some(mirror_path)).unwrap();
     ///
-    /// // Create some data
     /// db.set("key1", b"value1").unwrap();
-    /// db.set("key2", b"value2").unwrap();
-    /// db.delete("key1").unwrap();
+    /// assert_eq!(fs::read_to_string(mirror_path).unwrap(), "SET key1 value1\n");
     ///
-    /// // Compact the log
-    /// db.compact().unwrap();
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// fs::remove_file(mirror_path).unwrap_or(());
+    /// ```
+    pub fn set_mirror_log<P: AsRef<Path>>(&self, path: Option<P>) -> io::Result<()> {
+        match &self.log {
+            Some(log) => log
+                .lock()
+                .unwrap()
+                .set_mirror(path.as_ref().map(|path| path.as_ref())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the size of the log in bytes, or 0 for a follower.
+    pub fn log_size(&self) -> usize {
+        match &self.log {
+            Some(log) => log.lock().unwrap().size(),
+            None => 0,
+        }
+    }
+
+    /// Returns where the log lives, its size on disk and roughly how many
+    /// entries it holds, or `None` for a follower.
     ///
-    /// // Verify data is still intact
-    /// assert!(db.get("key1").is_none());
-    /// assert_eq!(db.get("key2").unwrap(), b"value2");
+    /// The size is read from the file's metadata rather than tracked, so it
+    /// also reflects anything written to the file behind the database's back.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let db = Database::with_log_path("data_info_doc.log").unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    ///
+    /// let info = db.data_info().unwrap().unwrap();
+    /// assert!(info.path.ends_with("data_info_doc.log"));
+    /// assert!(info.size > 0);
+    /// assert_eq!(info.entries, 1);
     ///
     /// // Clean up
-    /// fs::remove_file("keystonelight.log").unwrap_or(());
+    /// fs::remove_file("data_info_doc.log").unwrap_or(());
     /// ```
-    pub fn compact(&self) -> io::Result<()> {
-        let mut log = self.log.lock().unwrap();
-        log.compact()?;
+    pub fn data_info(&self) -> io::Result<Option<DataInfo>> {
+        let Some(log) = &self.log else {
+            return Ok(None);
+        };
+        let log = log.lock().unwrap();
+        Ok(Some(DataInfo {
+            path: log.path().to_path_buf(),
+            size: fs::metadata(log.path())?.len(),
+            entries: log.entry_count(),
+        }))
+    }
+
+    /// Returns how many times the log has been compacted since it was opened,
+    /// or 0 for a follower.
+    pub fn compaction_count(&self) -> u64 {
+        match &self.log {
+            Some(log) => log.lock().unwrap().compactions(),
+            None => 0,
+        }
+    }
+
+    /// Returns the number of keys in the database.
+    pub fn key_count(&self) -> usize {
+        self.cache.read().unwrap().entries.len()
+    }
+
+    /// Returns whether this database is a read-only follower.
+    pub fn is_follower(&self) -> bool {
+        self.log.is_none()
+    }
+
+    /// Rejects inserting `key` if it is new and the key cap has been reached.
+    fn check_capacity(&self, cache: &Cache, key: &str) -> io::Result<()> {
+        match self.max_keys {
+            Some(max) if cache.entries.len() >= max && !cache.entries.contains_key(key) => {
+                Err(io::Error::other("database full"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects storing `len` bytes under `key` if that would take one of its
+    /// quota prefixes past its budget.
+    fn check_quota(&self, cache: &Cache, key: &str, len: usize) -> io::Result<()> {
+        self.check_quotas(cache, &[(key, len)])
+    }
+
+    /// Like `check_quota`, for several distinct keys written together, where
+    /// a deleted key stores 0 bytes. Each quota is judged on the net change,
+    /// so bytes moved within a prefix don't count against it.
+    fn check_quotas(&self, cache: &Cache, changes: &[(&str, usize)]) -> io::Result<()> {
+        let over = cache.quotas.iter().any(|quota| {
+            let mut touched = false;
+            let mut used = quota.used_bytes;
+            for &(key, len) in changes {
+                if key.starts_with(&quota.prefix) {
+                    let old_len = cache.entries.get(key).map_or(0, |entry| entry.value.len());
+                    touched = true;
+                    used = used + len - old_len;
+                }
+            }
+            touched && used > quota.max_bytes
+        });
+        if over {
+            return Err(io::Error::other("quota exceeded"));
+        }
         Ok(())
     }
+
+    /// Rejects a binary key under `require_utf8`.
+    fn check_key(&self, key: &str) -> io::Result<()> {
+        if self.require_utf8 && key.starts_with(BASE64_KEY_PREFIX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "non-utf8 key"));
+        }
+        Ok(())
+    }
+
+    /// Stores `value` under `key` with the cache already write-locked.
+    /// Returns the new version of the key.
+    fn write(&self, cache: &mut Cache, key: &str, value: &[u8]) -> io::Result<u64> {
+        self.check_write(cache, key, value)?;
+        let value = value.to_vec();
+        // Persist first so a failed append never leaves the cache ahead of the log
+        self.writable_log()?
+            .append(&LogEntry::Set(key.to_string(), value.clone()))?;
+        Ok(cache.insert(key.to_string(), value))
+    }
+
+    /// Like `write`, also giving the new value `content_type`. Both are
+    /// appended in one batch, so the log never holds the value without it.
+    fn write_with_content_type(
+        &self,
+        cache: &mut Cache,
+        key: &str,
+        value: &[u8],
+        content_type: &str,
+    ) -> io::Result<u64> {
+        self.check_write(cache, key, value)?;
+        if content_type.is_empty()
+            || content_type.contains(|c: char| c.is_whitespace() || c.is_control())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid content type '{}'", content_type),
+            ));
+        }
+        let value = value.to_vec();
+        self.writable_log()?.append_all(&[
+            LogEntry::Set(key.to_string(), value.clone()),
+            LogEntry::ContentType(key.to_string(), content_type.to_string()),
+        ])?;
+        let version = cache.insert(key.to_string(), value);
+        cache.set_content_type(key, content_type);
+        Ok(version)
+    }
+
+    /// The checks every write of `value` to `key` must pass.
+    fn check_write(&self, cache: &Cache, key: &str, value: &[u8]) -> io::Result<()> {
+        self.check_key(key)?;
+        if self.require_utf8 && std::str::from_utf8(value).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "non-utf8 value"));
+        }
+        self.check_capacity(cache, key)?;
+        self.check_quota(cache, key, value.len())
+    }
+
+    /// Locks the log for writing, failing on a read-only follower.
+    fn writable_log(&self) -> io::Result<MutexGuard<'_, LogFile>> {
+        match &self.log {
+            Some(log) => Ok(log.lock().unwrap()),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "database is a read-only follower",
+            )),
+        }
+    }
+
+    // Currently unused file operations
+    /*
+    pub fn load_from_file(&self) -> io::Result<()> {
+        let mut storage = self.storage.write().unwrap();
+        let file = File::open(DATA_PATH)?;
+        let reader = BufReader::new(file);
+
+        for line in reader.lines() {
+            let line = line?;
+            let parts: Vec<&str> = line.splitn(2, ' ').collect();
+            if parts.len() == 2 {
+                storage.insert(parts[0].to_string(), parts[1].as_bytes().to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file(&self) -> io::Result<()> {
+        let storage = self.storage.read().unwrap();
+        let mut file = File::create(DATA_PATH)?;
+
+        for (key, value) in storage.iter() {
+            writeln!(file, "{} {}", key, String::from_utf8_lossy(value))?;
+        }
+        Ok(())
+    }
+    */
+
+    /// Retrieves a value from the database.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// // Create a new database with a unique log file
+    /// let log_path = "test_get.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// // Get non-existent key
+    /// assert!(db.get("missing").is_none());
+    ///
+    /// // Get existing key
+    /// db.set("key1", b"value1").unwrap();
+    /// assert_eq!(db.get("key1").unwrap(), b"value1");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let cache = self.cache.read().unwrap();
+        cache.access(key).map(|entry| entry.value.to_vec())
+    }
+
+    /// Opens a read-only, point-in-time view of the database and returns its
+    /// id, for reading several keys without seeing writes made in between.
+    ///
+    /// The view shares values with the cache, so taking it costs one
+    /// reference per key rather than a copy of the data. It stays readable
+    /// through [`Database::get_at`] until [`Database::end_read_snapshot`] or
+    /// until it is older than the snapshot lifetime (one minute by default).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_read_snapshot.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"before").unwrap();
+    /// let snapshot = db.begin_read_snapshot();
+    /// db.set("key1", b"after").unwrap();
+    ///
+    /// assert_eq!(db.get_at(snapshot, "key1").unwrap().unwrap(), b"before");
+    /// assert_eq!(db.get("key1").unwrap(), b"after");
+    /// assert!(db.end_read_snapshot(snapshot));
+    /// assert!(db.get_at(snapshot, "key1").is_err());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn begin_read_snapshot(&self) -> u64 {
+        let values = {
+            let cache = self.cache.read().unwrap();
+            cache
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.clone(), Arc::clone(&entry.value)))
+                .collect()
+        };
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.expire();
+        snapshots.next_id += 1;
+        let id = snapshots.next_id;
+        snapshots.views.insert(
+            id,
+            ReadSnapshot {
+                taken: Instant::now(),
+                values,
+            },
+        );
+        id
+    }
+
+    /// Reads `key` as it was when snapshot `id` was taken.
+    ///
+    /// Fails with a `NotFound` error if the snapshot was ended, has expired
+    /// or never existed.
+    pub fn get_at(&self, id: u64, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.expire();
+        match snapshots.views.get(&id) {
+            Some(snapshot) => Ok(snapshot.values.get(key).map(|value| value.to_vec())),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "unknown snapshot")),
+        }
+    }
+
+    /// Releases snapshot `id`. Returns `false` if it was already gone.
+    pub fn end_read_snapshot(&self, id: u64) -> bool {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.expire();
+        snapshots.views.remove(&id).is_some()
+    }
+
+    /// Sets how long a read snapshot lives before it is released on its own.
+    pub fn set_snapshot_lifetime(&self, lifetime: Duration) {
+        self.snapshots.lock().unwrap().max_lifetime = lifetime;
+    }
+
+    /// Returns every key matching the glob `pattern` with its value, sorted by
+    /// key.
+    ///
+    /// `*` matches any run of characters and `?` matches a single character.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_scan_pairs.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("user:1", b"alice").unwrap();
+    /// db.set("user:2", b"bob").unwrap();
+    /// db.set("session:1", b"token").unwrap();
+    ///
+    /// let pairs = db.scan_pairs("user:*");
+    /// assert_eq!(
+    ///     pairs,
+    ///     vec![
+    ///         ("user:1".to_string(), b"alice".to_vec()),
+    ///         ("user:2".to_string(), b"bob".to_vec()),
+    ///     ]
+    /// );
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn scan_pairs(&self, pattern: &str) -> Vec<(String, Vec<u8>)> {
+        let cache = self.cache.read().unwrap();
+        let mut pairs: Vec<_> = cache
+            .entries
+            .iter()
+            .filter(|(key, _)| glob::matches(pattern, key))
+            .map(|(key, entry)| (key.clone(), entry.value.to_vec()))
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs
+    }
+
+    /// Returns one page of the keys matching the glob `pattern`, with their
+    /// values, in key order.
+    ///
+    /// `cursor` is the position to resume from: `0` starts a scan, and the
+    /// returned cursor is passed back to fetch the next page, until it comes
+    /// back as `0`. Keys written or deleted between pages may shift the
+    /// positions of later keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_scan_page.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// for i in 0..5 {
+    ///     db.set(&format!("key{}", i), b"value").unwrap();
+    /// }
+    ///
+    /// let (cursor, page) = db.scan_page(0, 3, "*");
+    /// assert_eq!(cursor, 3);
+    /// assert_eq!(page.len(), 3);
+    ///
+    /// let (cursor, page) = db.scan_page(cursor, 3, "*");
+    /// assert_eq!(cursor, 0);
+    /// assert_eq!(page.len(), 2);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn scan_page(
+        &self,
+        cursor: usize,
+        count: usize,
+        pattern: &str,
+    ) -> (usize, Vec<(String, Vec<u8>)>) {
+        let cache = self.cache.read().unwrap();
+        // Only the keys are sorted, so just the values on this page are copied
+        let mut keys: Vec<&String> = cache
+            .entries
+            .keys()
+            .filter(|key| glob::matches(pattern, key))
+            .collect();
+        keys.sort();
+        let end = cursor.saturating_add(count);
+        let next = if end < keys.len() { end } else { 0 };
+        let page = keys
+            .into_iter()
+            .skip(cursor)
+            .take(count)
+            .map(|key| (key.clone(), cache.entries[key].value.to_vec()))
+            .collect();
+        (next, page)
+    }
+
+    /// Retrieves a value together with its current version.
+    ///
+    /// The version changes on every write to the key and can be passed to
+    /// [`Database::set_if_version`] for an optimistic read-modify-write.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_with_version.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// let (value, version) = db.get_with_version("key1").unwrap();
+    /// assert_eq!(value, b"value1");
+    ///
+    /// db.set("key1", b"value2").unwrap();
+    /// assert!(db.get_with_version("key1").unwrap().1 > version);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_with_version(&self, key: &str) -> Option<(Vec<u8>, u64)> {
+        let cache = self.cache.read().unwrap();
+        cache
+            .access(key)
+            .map(|entry| (entry.value.to_vec(), entry.version))
+    }
+
+    /// Returns the length of a value in bytes, without copying it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_value_len.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"hello").unwrap();
+    /// assert_eq!(db.value_len("key1"), Some(5));
+    /// assert_eq!(db.value_len("missing"), None);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let cache = self.cache.read().unwrap();
+        cache.entries.get(key).map(|entry| entry.value.len())
+    }
+
+    /// Returns a key chosen uniformly at random, or `None` if the database is
+    /// empty.
+    ///
+    /// There is no index over the keys, so this walks the key set and takes
+    /// O(n) time under the read lock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_random_key.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// assert_eq!(db.random_key(), None);
+    ///
+    /// db.set("only", b"value").unwrap();
+    /// assert_eq!(db.random_key().unwrap(), "only");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn random_key(&self) -> Option<String> {
+        let cache = self.cache.read().unwrap();
+        cache
+            .entries
+            .keys()
+            .choose(&mut rand::thread_rng())
+            .cloned()
+    }
+
+    /// Returns up to `count` distinct keys chosen uniformly at random, in no
+    /// particular order; all of them if the database holds fewer.
+    ///
+    /// Like [`Database::random_key`] this is a single O(n) pass under the
+    /// read lock, using reservoir sampling so only the chosen keys are
+    /// copied.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_random_keys.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// for key in ["a", "b", "c"] {
+    ///     db.set(key, b"value").unwrap();
+    /// }
+    ///
+    /// assert_eq!(db.random_keys(2).len(), 2);
+    /// let mut all = db.random_keys(10);
+    /// all.sort();
+    /// assert_eq!(all, ["a", "b", "c"]);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn random_keys(&self, count: usize) -> Vec<String> {
+        let cache = self.cache.read().unwrap();
+        cache
+            .entries
+            .keys()
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns up to `count` keys, least recently accessed first.
+    ///
+    /// Keys are accessed by being written or read by `get` and its variants
+    /// (`get_with_version`, `get_range`, `get_or`, `get_or_set`). Scans,
+    /// metadata and length lookups don't count. Keys loaded from the log
+    /// count as accessed in log order. This is one pass under the read lock,
+    /// keeping only the `count` oldest keys seen so far.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_lru_keys.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// for key in ["a", "b", "c"] {
+    ///     db.set(key, b"value").unwrap();
+    /// }
+    ///
+    /// db.get("a");
+    /// assert_eq!(db.lru_keys(2), ["b", "c"]);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn lru_keys(&self, count: usize) -> Vec<String> {
+        let cache = self.cache.read().unwrap();
+        // A max-heap of the oldest accesses so far, so the newest of them is
+        // the one to drop
+        let mut oldest = BinaryHeap::with_capacity(count.saturating_add(1).min(1024));
+        for (key, entry) in &cache.entries {
+            oldest.push((entry.last_access.load(Ordering::Relaxed), key));
+            if oldest.len() > count {
+                oldest.pop();
+            }
+        }
+        oldest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// Lists the distinct path segments directly under `prefix`, treating
+    /// keys as paths split by `sep`, like listing one level of a directory.
+    ///
+    /// A non-empty `prefix` that doesn't already end in `sep` gets one
+    /// appended, so `a` and `a:` list the same children. The segments are
+    /// returned sorted, each once, however many keys lie beneath it. An empty
+    /// `sep` lists the whole remainder of each key under `prefix`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_children.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// for key in ["a:b", "a:c", "a:c:d", "b:e"] {
+    ///     db.set(key, b"value").unwrap();
+    /// }
+    ///
+    /// assert_eq!(db.children("a:", ":"), ["b", "c"]);
+    /// assert_eq!(db.children("a:c", ":"), ["d"]);
+    /// assert_eq!(db.children("", ":"), ["a", "b"]);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn children(&self, prefix: &str, sep: &str) -> Vec<String> {
+        let prefix = if prefix.is_empty() || prefix.ends_with(sep) {
+            prefix.to_string()
+        } else {
+            format!("{}{}", prefix, sep)
+        };
+        let cache = self.cache.read().unwrap();
+        let children: BTreeSet<&str> = cache
+            .entries
+            .keys()
+            .filter_map(|key| key.strip_prefix(prefix.as_str()))
+            .map(|rest| match rest.split_once(sep) {
+                Some((child, _)) if !sep.is_empty() => child,
+                _ => rest,
+            })
+            .filter(|child| !child.is_empty())
+            .collect();
+        children.into_iter().map(str::to_string).collect()
+    }
+
+    /// Returns metadata for `key`, or `None` if it doesn't exist.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_metadata.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"hello").unwrap();
+    /// let created = db.metadata("key1").unwrap().created_at;
+    /// db.set("key1", b"updated").unwrap();
+    ///
+    /// let metadata = db.metadata("key1").unwrap();
+    /// assert_eq!(metadata.created_at, created);
+    /// assert!(metadata.last_modified >= created);
+    /// assert!(db.metadata("missing").is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn metadata(&self, key: &str) -> Option<KeyMetadata> {
+        let cache = self.cache.read().unwrap();
+        cache.entries.get(key).map(Entry::metadata)
+    }
+
+    /// Returns the value of each of `keys`, in order, with `None` for keys
+    /// that don't exist.
+    ///
+    /// All keys are read under a single read lock, so the values are a
+    /// consistent snapshot and the lock is taken once for the whole batch.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_many.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// assert_eq!(
+    ///     db.get_many(&["key1", "missing"]),
+    ///     vec![Some(b"value1".to_vec()), None]
+    /// );
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        let cache = self.cache.read().unwrap();
+        keys.iter()
+            .map(|key| cache.access(key.as_ref()).map(|entry| entry.value.to_vec()))
+            .collect()
+    }
+
+    /// Returns metadata for each of `keys`, in order, with `None` for keys
+    /// that don't exist.
+    ///
+    /// All keys are read under a single read lock, so the results are a
+    /// consistent snapshot.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_metadata_many.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"hello").unwrap();
+    /// let metadata = db.metadata_many(&["key1", "missing"]);
+    /// assert_eq!(metadata[0].as_ref().unwrap().size, 5);
+    /// assert!(metadata[1].is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn metadata_many<K: AsRef<str>>(&self, keys: &[K]) -> Vec<Option<KeyMetadata>> {
+        let cache = self.cache.read().unwrap();
+        keys.iter()
+            .map(|key| cache.entries.get(key.as_ref()).map(Entry::metadata))
+            .collect()
+    }
+
+    /// Retrieves the bytes `[start, end)` of a value.
+    ///
+    /// The range is clamped to the value's length, so a range past the end
+    /// yields an empty slice rather than an error. Returns `None` only if the
+    /// key does not exist.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_range.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"hello world").unwrap();
+    /// assert_eq!(db.get_range("key1", 0, 5).unwrap(), b"hello");
+    /// assert_eq!(db.get_range("key1", 6, 100).unwrap(), b"world");
+    /// assert!(db.get_range("key1", 20, 30).unwrap().is_empty());
+    /// assert!(db.get_range("missing", 0, 5).is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_range(&self, key: &str, start: usize, end: usize) -> Option<Vec<u8>> {
+        let cache = self.cache.read().unwrap();
+        let value = &cache.access(key)?.value;
+        let end = end.min(value.len());
+        let start = start.min(end);
+        Some(value[start..end].to_vec())
+    }
+
+    /// Retrieves a value from the database, falling back to `default` if the
+    /// key is absent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_or.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.get_or("missing", b"fallback"), b"fallback");
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// assert_eq!(db.get_or("key1", b"fallback"), b"value1");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_or(&self, key: &str, default: &[u8]) -> Vec<u8> {
+        let cache = self.cache.read().unwrap();
+        match cache.access(key) {
+            Some(entry) => entry.value.to_vec(),
+            None => default.to_vec(),
+        }
+    }
+
+    /// Sets a key-value pair in the database.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let db = Database::new().unwrap();
+    ///
+    /// // Set and verify a value
+    /// db.set("key1", b"value1").unwrap();
+    /// assert_eq!(db.get("key1").unwrap(), b"value1");
+    ///
+    /// // Update existing value
+    /// db.set("key1", b"new_value").unwrap();
+    /// assert_eq!(db.get("key1").unwrap(), b"new_value");
+    ///
+    /// // Clean up
+    /// fs::remove_file("keystonelight.log").unwrap_or(());
+    /// ```
+    pub fn set(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        if self.dedup_writes {
+            return self.set_if_changed(key, value).map(|_| ());
+        }
+        let mut cache = self.cache.write().unwrap();
+        self.write(&mut cache, key, value)?;
+        Ok(())
+    }
+
+    /// Sets a key-value pair, recording `content_type` as the value's MIME
+    /// type.
+    ///
+    /// The type belongs to this value: any later write to the key that
+    /// doesn't give one, such as a plain [`Database::set`], clears it again.
+    /// It must be a single word without whitespace, like `application/json`,
+    /// or this fails with `InvalidInput`. Unlike `set`, this always writes,
+    /// even with [`Database::set_dedup_writes`] on.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::{Database, DEFAULT_CONTENT_TYPE};
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_with_content_type.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set_with_content_type("doc", b"{}", "application/json").unwrap();
+    /// let (value, content_type) = db.get_with_content_type("doc").unwrap();
+    /// assert_eq!(value, b"{}");
+    /// assert_eq!(content_type, "application/json");
+    ///
+    /// db.set("doc", b"plain").unwrap();
+    /// assert_eq!(db.get_with_content_type("doc").unwrap().1, DEFAULT_CONTENT_TYPE);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_with_content_type(
+        &self,
+        key: &str,
+        value: &[u8],
+        content_type: &str,
+    ) -> io::Result<()> {
+        let mut cache = self.cache.write().unwrap();
+        self.write_with_content_type(&mut cache, key, value, content_type)?;
+        Ok(())
+    }
+
+    /// Returns the value of `key` along with its content type, which is
+    /// [`DEFAULT_CONTENT_TYPE`] if none was given when it was set.
+    pub fn get_with_content_type(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        let cache = self.cache.read().unwrap();
+        cache.access(key).map(|entry| {
+            let content_type = entry
+                .content_type
+                .as_deref()
+                .unwrap_or(DEFAULT_CONTENT_TYPE);
+            (entry.value.to_vec(), content_type.to_string())
+        })
+    }
+
+    /// Returns the value of `key`, first setting it to `default` if absent.
+    ///
+    /// The check and the write happen under one write lock, so concurrent
+    /// callers all see the same initial value and only one of them writes it
+    /// to the log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_or_set.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.get_or_set("counter", b"0").unwrap(), b"0");
+    /// // The key now exists, so a different default is ignored
+    /// assert_eq!(db.get_or_set("counter", b"100").unwrap(), b"0");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_or_set(&self, key: &str, default: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cache = self.cache.write().unwrap();
+        if let Some(entry) = cache.access(key) {
+            return Ok(entry.value.to_vec());
+        }
+        self.write(&mut cache, key, default)?;
+        Ok(default.to_vec())
+    }
+
+    /// Appends `line` to the newline-separated value at `key` and returns the
+    /// number of lines it now holds.
+    ///
+    /// A missing or empty value becomes `line` with no leading separator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_append_line.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.append_line("events", b"started").unwrap(), 1);
+    /// assert_eq!(db.append_line("events", b"stopped").unwrap(), 2);
+    /// assert_eq!(db.get("events").unwrap(), b"started\nstopped");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn append_line(&self, key: &str, line: &[u8]) -> io::Result<usize> {
+        self.append_line_with(key, line, |_| Ok(()))
+    }
+
+    /// Like [`Database::append_line`], but passes the combined value to
+    /// `check` before it is stored.
+    ///
+    /// `check` may change the value, and the line count is taken from what is
+    /// actually stored. If it fails, nothing is written and its error is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::{fs, io};
+    ///
+    /// let log_path = "test_append_line_with.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// let at_most_two = |value: &mut Vec<u8>| {
+    ///     if value.split(|&b| b == b'\n').count() > 2 {
+    ///         return Err(io::Error::other("too many lines"));
+    ///     }
+    ///     Ok(())
+    /// };
+    /// assert_eq!(db.append_line_with("events", b"started", at_most_two).unwrap(), 1);
+    /// assert_eq!(db.append_line_with("events", b"paused", at_most_two).unwrap(), 2);
+    /// assert!(db.append_line_with("events", b"stopped", at_most_two).is_err());
+    /// assert_eq!(db.get("events").unwrap(), b"started\npaused");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn append_line_with<F>(&self, key: &str, line: &[u8], check: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut cache = self.cache.write().unwrap();
+        let mut value = match cache.entries.get(key) {
+            Some(entry) if !entry.value.is_empty() => {
+                let mut value = entry.value.to_vec();
+                value.push(b'\n');
+                value
+            }
+            _ => Vec::new(),
+        };
+        value.extend_from_slice(line);
+        check(&mut value)?;
+        self.write(&mut cache, key, &value)?;
+        Ok(value.iter().filter(|&&b| b == b'\n').count() + 1)
+    }
+
+    /// Overwrites the bytes of the value at `key` starting at `offset` with
+    /// `bytes`, and returns the value's new length.
+    ///
+    /// A value shorter than `offset` is padded with zero bytes first, and a
+    /// missing key counts as an empty value. Writing no bytes changes nothing
+    /// and returns the current length, or 0 for a missing key. The whole new
+    /// value is logged. Fails with `InvalidInput` if the value would grow
+    /// past 512MB.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_range.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("record", b"hello world").unwrap();
+    /// assert_eq!(db.set_range("record", 6, b"there").unwrap(), 11);
+    /// assert_eq!(db.get("record").unwrap(), b"hello there");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_range(&self, key: &str, offset: usize, bytes: &[u8]) -> io::Result<usize> {
+        self.set_range_with(key, offset, bytes, |_| Ok(()))
+    }
+
+    /// Like [`Database::set_range`], but passes the new value to `check`
+    /// before it is stored.
+    ///
+    /// `check` may change the value, and the length returned is that of what
+    /// is actually stored. If it fails, nothing is written and its error is
+    /// returned. Writing no bytes stores nothing, so `check` isn't called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::{fs, io};
+    ///
+    /// let log_path = "test_set_range_with.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// let no_zeros = |value: &mut Vec<u8>| {
+    ///     if value.contains(&0) {
+    ///         return Err(io::Error::other("zero byte"));
+    ///     }
+    ///     Ok(())
+    /// };
+    /// db.set("record", b"hello world").unwrap();
+    /// assert_eq!(db.set_range_with("record", 6, b"there", no_zeros).unwrap(), 11);
+    /// assert!(db.set_range_with("record", 20, b"!", no_zeros).is_err());
+    /// assert_eq!(db.get("record").unwrap(), b"hello there");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_range_with<F>(
+        &self,
+        key: &str,
+        offset: usize,
+        bytes: &[u8],
+        check: F,
+    ) -> io::Result<usize>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut cache = self.cache.write().unwrap();
+        let current = cache.entries.get(key).map(|entry| &entry.value);
+        if bytes.is_empty() {
+            return Ok(current.map_or(0, |value| value.len()));
+        }
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|&end| end <= MAX_SET_RANGE_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset is out of range"))?;
+
+        let mut value = current.map_or_else(Vec::new, |value| value.to_vec());
+        if value.len() < end {
+            value.resize(end, 0);
+        }
+        value[offset..end].copy_from_slice(bytes);
+        check(&mut value)?;
+        self.write(&mut cache, key, &value)?;
+        Ok(value.len())
+    }
+
+    /// Adds `delta` to the float stored at `key` and returns the new value.
+    ///
+    /// A missing key counts as 0.0. The result is stored in its shortest
+    /// decimal form, so `1.5 + 1.5` is stored as `3`. Fails with `not a float`
+    /// if the current value isn't a finite number, and rejects a non-finite
+    /// `delta` or a result that overflows to infinity.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_incr_by_float.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.incr_by_float("total", 1.5).unwrap(), 1.5);
+    /// assert_eq!(db.incr_by_float("total", 1.5).unwrap(), 3.0);
+    /// assert_eq!(db.get("total").unwrap(), b"3");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn incr_by_float(&self, key: &str, delta: f64) -> io::Result<f64> {
+        if !delta.is_finite() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "increment is not a finite number",
+            ));
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        let current = match cache.entries.get(key) {
+            Some(entry) => std::str::from_utf8(&entry.value)
+                .ok()
+                .and_then(|text| text.trim().parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a float"))?,
+            None => 0.0,
+        };
+
+        let result = current + delta;
+        if !result.is_finite() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "increment would overflow",
+            ));
+        }
+        self.write(&mut cache, key, result.to_string().as_bytes())?;
+        Ok(result)
+    }
+
+    /// Decrements the integer stored at `key` by one, deleting the key if
+    /// that brings it to zero or below.
+    ///
+    /// Returns the new count, or `None` if the key doesn't exist. A count of
+    /// zero or less means the key was deleted. The check and the write happen
+    /// under one lock, so concurrent callers each see a distinct count and
+    /// exactly one of them deletes the key. Fails with `not an integer` if the
+    /// value isn't a whole number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_decr_del.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("refs", b"2").unwrap();
+    /// assert_eq!(db.decr_del("refs").unwrap(), Some(1));
+    /// assert_eq!(db.get("refs").unwrap(), b"1");
+    /// assert_eq!(db.decr_del("refs").unwrap(), Some(0));
+    /// assert_eq!(db.get("refs"), None);
+    /// assert_eq!(db.decr_del("refs").unwrap(), None);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn decr_del(&self, key: &str) -> io::Result<Option<i64>> {
+        let mut cache = self.cache.write().unwrap();
+        let Some(entry) = cache.entries.get(key) else {
+            return Ok(None);
+        };
+        let current = std::str::from_utf8(&entry.value)
+            .ok()
+            .and_then(|text| text.trim().parse::<i64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an integer"))?;
+        let result = current.checked_sub(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "decrement would overflow")
+        })?;
+
+        if result <= 0 {
+            self.writable_log()?
+                .append(&LogEntry::Delete(key.to_string()))?;
+            cache.remove(key);
+        } else {
+            self.write(&mut cache, key, result.to_string().as_bytes())?;
+        }
+        Ok(Some(result))
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the value from
+    /// before the add, like an atomic `fetch_add`.
+    ///
+    /// A missing key counts as zero, so the first call returns 0 and stores
+    /// `delta`. The read and the write happen under one lock, so concurrent
+    /// callers each get a distinct old value, which makes this suitable for
+    /// handing out IDs. Fails with `not an integer` if the value isn't a
+    /// whole number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_fetch_add.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.fetch_add("next_id", 1).unwrap(), 0);
+    /// assert_eq!(db.fetch_add("next_id", 1).unwrap(), 1);
+    /// assert_eq!(db.get("next_id").unwrap(), b"2");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn fetch_add(&self, key: &str, delta: i64) -> io::Result<i64> {
+        let mut cache = self.cache.write().unwrap();
+        let current = match cache.entries.get(key) {
+            Some(entry) => std::str::from_utf8(&entry.value)
+                .ok()
+                .and_then(|text| text.trim().parse::<i64>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an integer"))?,
+            None => 0,
+        };
+        let result = current.checked_add(delta).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "increment would overflow")
+        })?;
+        self.write(&mut cache, key, result.to_string().as_bytes())?;
+        Ok(current)
+    }
+
+    /// Runs the steps of an `EVAL` in order, as one atomic unit: the cache
+    /// stays write-locked throughout, so no other writer or reader sees the
+    /// keys between steps.
+    ///
+    /// Each step's condition is checked against the data as the earlier
+    /// steps left it, and a step whose condition doesn't hold is skipped.
+    /// Returns the result of the last step that ran, or `None` if none did.
+    /// A step that fails ends the sequence with its error; the steps before
+    /// it are not undone.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::protocol::{EvalAction, EvalCondition, EvalStep, Response};
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_eval.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("a", b"x").unwrap();
+    ///
+    /// // Set b only if a holds x
+    /// let steps = [EvalStep {
+    ///     condition: Some(EvalCondition::Equals("a".to_string(), b"x".to_vec())),
+    ///     action: EvalAction::Set("b".to_string(), b"1".to_vec()),
+    /// }];
+    /// assert_eq!(db.eval(&steps).unwrap(), Some(Response::Ok));
+    /// assert_eq!(db.get("b").unwrap(), b"1");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn eval(&self, steps: &[EvalStep]) -> io::Result<Option<Response>> {
+        let mut cache = self.cache.write().unwrap();
+        let mut last = None;
+        for step in steps {
+            let holds = |key: &str, value: &[u8]| {
+                cache
+                    .entries
+                    .get(key)
+                    .is_some_and(|entry| &*entry.value == value)
+            };
+            let run = match &step.condition {
+                Some(EvalCondition::Equals(key, value)) => holds(key, value),
+                Some(EvalCondition::NotEquals(key, value)) => !holds(key, value),
+                None => true,
+            };
+            if !run {
+                continue;
+            }
+
+            last = Some(match &step.action {
+                EvalAction::Set(key, value) => {
+                    self.write(&mut cache, key, value)?;
+                    Response::Ok
+                }
+                EvalAction::Delete(key) => {
+                    if cache.entries.contains_key(key) {
+                        self.writable_log()?
+                            .append(&LogEntry::Delete(key.clone()))?;
+                        cache.remove(key);
+                    }
+                    Response::Ok
+                }
+                EvalAction::Get(key) => match cache.entries.get(key) {
+                    Some(entry) => Response::Value(entry.value.to_vec()),
+                    None => Response::NotFound,
+                },
+            });
+        }
+        Ok(last)
+    }
+
+    /// Sets a key-value pair only if the key already exists.
+    ///
+    /// Returns whether anything was written; a missing key is left missing
+    /// and nothing is appended to the log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_xx.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert!(!db.set_xx("key1", b"value1").unwrap());
+    /// assert_eq!(db.get("key1"), None);
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// assert!(db.set_xx("key1", b"value2").unwrap());
+    /// assert_eq!(db.get("key1").unwrap(), b"value2");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_xx(&self, key: &str, value: &[u8]) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        if !cache.entries.contains_key(key) {
+            return Ok(false);
+        }
+        self.write(&mut cache, key, value)?;
+        Ok(true)
+    }
+
+    /// Sets a key-value pair unless the key already holds exactly `value`.
+    ///
+    /// Identical writes skip both the cache update and the log append, which
+    /// keeps clients that re-send unchanged values from growing the log.
+    /// Returns whether anything was written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_if_changed.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert!(db.set_if_changed("key1", b"value1").unwrap());
+    /// assert!(!db.set_if_changed("key1", b"value1").unwrap());
+    /// assert!(db.set_if_changed("key1", b"value2").unwrap());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_if_changed(&self, key: &str, value: &[u8]) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        let current = cache.entries.get(key).map(|entry| &*entry.value);
+        if current == Some(value) {
+            return Ok(false);
+        }
+        self.write(&mut cache, key, value)?;
+        Ok(true)
+    }
+
+    /// Sets a key-value pair unless the key already holds exactly `value`,
+    /// returning the value it held before along with whether anything was
+    /// written.
+    ///
+    /// This is [`Database::set_if_changed`] that also reads the old value,
+    /// under the same write lock, so a sync client learns both what was
+    /// there and whether it changed in one step. A missing key is always
+    /// written and returns `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_get_if_changed.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert_eq!(db.set_get_if_changed("key1", b"a").unwrap(), (None, true));
+    /// assert_eq!(
+    ///     db.set_get_if_changed("key1", b"a").unwrap(),
+    ///     (Some(b"a".to_vec()), false)
+    /// );
+    /// assert_eq!(
+    ///     db.set_get_if_changed("key1", b"b").unwrap(),
+    ///     (Some(b"a".to_vec()), true)
+    /// );
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_get_if_changed(
+        &self,
+        key: &str,
+        value: &[u8],
+    ) -> io::Result<(Option<Vec<u8>>, bool)> {
+        let mut cache = self.cache.write().unwrap();
+        let old = cache.access(key).map(|entry| entry.value.to_vec());
+        if old.as_deref() == Some(value) {
+            return Ok((old, false));
+        }
+        self.write(&mut cache, key, value)?;
+        Ok((old, true))
+    }
+
+    /// Sets a key-value pair only if the key is still at `expected` version.
+    ///
+    /// Version `0` never belongs to a stored key, so passing it means "only if
+    /// the key does not exist". Returns `Ok(false)` without writing if the key
+    /// has been written (or deleted) since `expected` was read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_set_if_version.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// assert!(db.set_if_version("key1", b"value1", 0).unwrap());
+    /// let (_, version) = db.get_with_version("key1").unwrap();
+    ///
+    /// assert!(db.set_if_version("key1", b"value2", version).unwrap());
+    /// // The first write bumped the version, so a second one against it fails
+    /// assert!(!db.set_if_version("key1", b"value3", version).unwrap());
+    /// assert_eq!(db.get("key1").unwrap(), b"value2");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn set_if_version(&self, key: &str, value: &[u8], expected: u64) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        let current = cache.entries.get(key).map_or(0, |entry| entry.version);
+        if current != expected {
+            return Ok(false);
+        }
+        self.write(&mut cache, key, value)?;
+        Ok(true)
+    }
+
+    /// Removes a key and returns the value it held, as one atomic step.
+    ///
+    /// Only one caller can ever receive a given value, which makes this
+    /// suitable for claiming work items. Returns `None`, and writes nothing,
+    /// if the key is absent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_get_del.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("job1", b"payload").unwrap();
+    /// assert_eq!(db.get_del("job1").unwrap().unwrap(), b"payload");
+    /// assert!(db.get_del("job1").unwrap().is_none());
+    /// assert!(db.get("job1").is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn get_del(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut cache = self.cache.write().unwrap();
+        if !cache.entries.contains_key(key) {
+            return Ok(None);
+        }
+        self.writable_log()?
+            .append(&LogEntry::Delete(key.to_string()))?;
+        Ok(cache.remove(key).map(|entry| entry.value.to_vec()))
+    }
+
+    /// Atomically exchanges the values of two keys.
+    ///
+    /// A missing key counts as absent, so swapping a present key with a
+    /// missing one moves the value across and deletes the source. The log
+    /// entries for both keys are appended as one write. Returns `false`, and
+    /// writes nothing, if both keys are absent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_swap.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("a", b"1").unwrap();
+    /// db.set("b", b"2").unwrap();
+    /// assert!(db.swap("a", "b").unwrap());
+    /// assert_eq!(db.get("a").unwrap(), b"2");
+    /// assert_eq!(db.get("b").unwrap(), b"1");
+    ///
+    /// // Swapping with a missing key moves the value
+    /// assert!(db.swap("a", "c").unwrap());
+    /// assert!(db.get("a").is_none());
+    /// assert_eq!(db.get("c").unwrap(), b"2");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn swap(&self, key1: &str, key2: &str) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        let value1 = cache.entries.get(key1).map(|entry| entry.value.to_vec());
+        let value2 = cache.entries.get(key2).map(|entry| entry.value.to_vec());
+        if value1.is_none() && value2.is_none() {
+            return Ok(false);
+        }
+        if key1 == key2 {
+            return Ok(true);
+        }
+
+        // Each key ends up with the other's value, or is deleted if the other
+        // was absent
+        let entry_for = |key: &str, value: &Option<Vec<u8>>| match value {
+            Some(value) => LogEntry::Set(key.to_string(), value.clone()),
+            None => LogEntry::Delete(key.to_string()),
+        };
+        // A key that is about to be created must be one `set` would accept
+        if value1.is_none() {
+            self.check_key(key1)?;
+        }
+        if value2.is_none() {
+            self.check_key(key2)?;
+        }
+        let len_of = |value: &Option<Vec<u8>>| value.as_ref().map_or(0, Vec::len);
+        self.check_quotas(&cache, &[(key1, len_of(&value2)), (key2, len_of(&value1))])?;
+
+        let entries = [entry_for(key1, &value2), entry_for(key2, &value1)];
+        self.writable_log()?.append_all(&entries)?;
+        for entry in entries {
+            apply_entry(&mut cache, entry);
+        }
+        Ok(true)
+    }
+
+    /// Renames `old` to `new`, but only if `old` currently holds `expected`.
+    ///
+    /// An existing `new` is overwritten. The SET of `new` and the DELETE of
+    /// `old` are appended as one write, under the same write lock as the
+    /// comparison. Returns `false`, and writes nothing, if `old` is missing
+    /// or holds a different value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_rename_if.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("pending:1", b"job").unwrap();
+    /// assert!(!db.rename_if("pending:1", "active:1", b"other").unwrap());
+    /// assert!(db.rename_if("pending:1", "active:1", b"job").unwrap());
+    /// assert!(db.get("pending:1").is_none());
+    /// assert_eq!(db.get("active:1").unwrap(), b"job");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn rename_if(&self, old: &str, new: &str, expected: &[u8]) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        match cache.entries.get(old) {
+            Some(entry) if *entry.value == *expected => {}
+            _ => return Ok(false),
+        }
+        if old == new {
+            return Ok(true);
+        }
+        self.check_key(new)?;
+        self.check_quotas(&cache, &[(new, expected.len()), (old, 0)])?;
+
+        let entries = [
+            LogEntry::Set(new.to_string(), expected.to_vec()),
+            LogEntry::Delete(old.to_string()),
+        ];
+        self.writable_log()?.append_all(&entries)?;
+        for entry in entries {
+            apply_entry(&mut cache, entry);
+        }
+        Ok(true)
+    }
+
+    /// Deletes a key-value pair from the database.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// // Create a new database with a unique log file
+    /// let log_path = "test_delete.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// // Delete non-existent key
+    /// db.delete("missing").unwrap();
+    ///
+    /// // Delete existing key
+    /// db.set("key1", b"value1").unwrap();
+    /// db.delete("key1").unwrap();
+    /// assert!(db.get("key1").is_none());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn delete(&self, key: &str) -> io::Result<()> {
+        let mut cache = self.cache.write().unwrap();
+        if cache.entries.contains_key(key) {
+            self.writable_log()?
+                .append(&LogEntry::Delete(key.to_string()))?;
+            cache.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Tags `key` with `tag`, so it can later be deleted along with every
+    /// other key carrying the tag by [`Database::delete_tag`].
+    ///
+    /// A key can carry any number of tags. Tags survive updates to the key
+    /// but are dropped when it is deleted. Returns `false`, and writes
+    /// nothing, if the key doesn't exist.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_tag.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("user:1:name", b"alice").unwrap();
+    /// db.set("user:1:email", b"alice@example.com").unwrap();
+    /// assert!(db.tag("user:1:name", "user:1").unwrap());
+    /// assert!(db.tag("user:1:email", "user:1").unwrap());
+    /// assert!(!db.tag("missing", "user:1").unwrap());
+    /// assert_eq!(db.keys_with_tag("user:1"), vec!["user:1:email", "user:1:name"]);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn tag(&self, key: &str, tag: &str) -> io::Result<bool> {
+        let mut cache = self.cache.write().unwrap();
+        match cache.entries.get(key) {
+            None => return Ok(false),
+            Some(entry) if entry.tags.contains(tag) => return Ok(true),
+            Some(_) => {}
+        }
+        self.writable_log()?
+            .append(&LogEntry::Tag(key.to_string(), tag.to_string()))?;
+        cache.tag(key, tag);
+        Ok(true)
+    }
+
+    /// Returns the keys carrying `tag`, sorted.
+    pub fn keys_with_tag(&self, tag: &str) -> Vec<String> {
+        let cache = self.cache.read().unwrap();
+        cache
+            .tagged
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Deletes every key carrying `tag` and returns how many were deleted.
+    ///
+    /// The deletes are appended to the log as one write, so either all of
+    /// them are persisted or none are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_delete_tag.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("session:a", b"1").unwrap();
+    /// db.set("session:b", b"2").unwrap();
+    /// db.tag("session:a", "sessions").unwrap();
+    /// db.tag("session:b", "sessions").unwrap();
+    ///
+    /// assert_eq!(db.delete_tag("sessions").unwrap(), 2);
+    /// assert!(db.get("session:a").is_none());
+    /// assert_eq!(db.delete_tag("sessions").unwrap(), 0);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn delete_tag(&self, tag: &str) -> io::Result<usize> {
+        let mut cache = self.cache.write().unwrap();
+        let Some(keys) = cache.tagged.get(tag).cloned() else {
+            return Ok(0);
+        };
+        let entries: Vec<LogEntry> = keys.iter().cloned().map(LogEntry::Delete).collect();
+        self.writable_log()?.append_all(&entries)?;
+        for key in &keys {
+            cache.remove(key);
+        }
+        Ok(keys.len())
+    }
+
+    /// Checks that the database can still persist writes.
+    ///
+    /// Performs a tiny write, fsync and delete of a probe file next to the
+    /// log. Returns the underlying I/O error if the disk is read-only, full
+    /// or otherwise unavailable.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_health.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// assert!(db.health_check().is_ok());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn health_check(&self) -> io::Result<()> {
+        self.writable_log()?.probe_writable()
+    }
+
+    /// Compacts the log file by removing redundant entries.
+    ///
+    /// Fails with `Interrupted`, leaving the log as it was, if the
+    /// [`CancelToken`] is cancelled before the compacted log is swapped in.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let db = Database::new().unwrap();
+    ///
+    /// // Create some data
+    /// db.set("key1", b"value1").unwrap();
+    /// db.set("key2", b"value2").unwrap();
+    /// db.delete("key1").unwrap();
+    ///
+    /// // Compact the log
+    /// db.compact().unwrap();
+    ///
+    /// // Verify data is still intact
+    /// assert!(db.get("key1").is_none());
+    /// assert_eq!(db.get("key2").unwrap(), b"value2");
+    ///
+    /// // Clean up
+    /// fs::remove_file("keystonelight.log").unwrap_or(());
+    /// ```
+    pub fn compact(&self) -> io::Result<()> {
+        let mut log = self.writable_log()?;
+        let _compacting = CompactionFlag::raise(&self.compacting);
+        log.compact()
+    }
+
+    /// Compacts the log to give disk space back right away, returning how
+    /// many bytes the log shrank by.
+    ///
+    /// This is [`Database::compact`] with the saving reported. The replaced
+    /// file's last descriptor is closed before this returns, so its blocks
+    /// are free as soon as it does. The one exception is a process following
+    /// the log with a [`LogTail`], which holds the old file until it next
+    /// polls.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_trim_log.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// db.delete("key1").unwrap();
+    /// assert!(db.trim_log().unwrap() > 0);
+    /// assert_eq!(db.log_size(), 0);
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn trim_log(&self) -> io::Result<u64> {
+        let mut log = self.writable_log()?;
+        let _compacting = CompactionFlag::raise(&self.compacting);
+        let before = log.size();
+        log.compact()?;
+        Ok(before.saturating_sub(log.size()) as u64)
+    }
+
+    /// Returns the token that cancels a compaction or verification in
+    /// progress.
+    ///
+    /// The token can be cancelled from any thread, including while this
+    /// database is locked by the operation it is stopping.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    /// use std::io;
+    ///
+    /// let log_path = "test_cancel_token.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    ///
+    /// let cancel = db.cancel_token();
+    /// cancel.cancel();
+    /// assert_eq!(db.compact().unwrap_err().kind(), io::ErrorKind::Interrupted);
+    /// cancel.reset();
+    /// db.compact().unwrap();
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Compacts the log unless a compaction is already running, in which
+    /// case it returns `Ok(false)` straight away instead of compacting again
+    /// once the first one finishes.
+    ///
+    /// Compactions never overlap either way, since they hold the log's lock;
+    /// this only spares a caller from waiting on work that is already being
+    /// done.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_try_compact.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    ///
+    /// db.set("key1", b"value1").unwrap();
+    /// db.set("key1", b"value2").unwrap();
+    /// assert!(db.try_compact().unwrap());
+    /// assert_eq!(db.get("key1").unwrap(), b"value2");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn try_compact(&self) -> io::Result<bool> {
+        if self.compacting.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let mut log = self.writable_log()?;
+        // Another caller may have finished a compaction while we waited for
+        // the lock; this one still runs, as its writes may not be included
+        let _compacting = CompactionFlag::raise(&self.compacting);
+        log.compact()?;
+        Ok(true)
+    }
+
+    /// Writes the current contents of the database to `path` as a compacted
+    /// log, which [`Database::with_log_path`] can open to restore them.
+    ///
+    /// The live log is left alone, and the snapshot only replaces `path`
+    /// once it has been fully written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_snapshot_to.log";
+    /// let snapshot_path = "test_snapshot_to.snapshot";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    /// db.snapshot_to(snapshot_path).unwrap();
+    ///
+    /// let restored = Database::with_log_path(snapshot_path).unwrap();
+    /// assert_eq!(restored.get("key1").unwrap(), b"value1");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// fs::remove_file(snapshot_path).unwrap_or(());
+    /// ```
+    pub fn snapshot_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let entries = {
+            let cache = self.cache.read().unwrap();
+            cache
+                .entries
+                .iter()
+                .flat_map(|(key, entry)| {
+                    log::key_entries(
+                        key.clone(),
+                        entry.value.to_vec(),
+                        entry.tags.clone(),
+                        entry.content_type.clone(),
+                    )
+                })
+                .collect()
+        };
+        log::write_snapshot(path.as_ref(), entries)
+    }
+
+    /// Copies the keys of the log or snapshot at `path` into this database,
+    /// with `policy` deciding what happens to keys that already exist here.
+    ///
+    /// The file is read in full first, so only each key's final value is
+    /// imported, along with its tags and content type. Under [`ImportPolicy::Error`] every key
+    /// is checked before anything is written, so a collision leaves the
+    /// database untouched and fails with `AlreadyExists`. Imported keys go
+    /// through the same checks as `set`, such as `max_keys` and quotas; if
+    /// one fails, the keys imported before it stay.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::{Database, ImportPolicy, ImportReport};
+    /// use std::fs;
+    ///
+    /// let log_path = "test_import_from.log";
+    /// let snapshot_path = "test_import_from.snapshot";
+    /// let source = Database::with_log_path(snapshot_path).unwrap();
+    /// source.set("key1", b"new").unwrap();
+    /// source.set("key2", b"new").unwrap();
+    /// drop(source);
+    ///
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"old").unwrap();
+    /// let report = db.import_from(snapshot_path, ImportPolicy::Skip).unwrap();
+    /// assert_eq!(
+    ///     report,
+    ///     ImportReport { imported: 1, skipped: 1, overwritten: 0 }
+    /// );
+    /// assert_eq!(db.get("key1").unwrap(), b"old");
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// fs::remove_file(snapshot_path).unwrap_or(());
+    /// ```
+    pub fn import_from<P: AsRef<Path>>(
+        &self,
+        path: P,
+        policy: ImportPolicy,
+    ) -> io::Result<ImportReport> {
+        let mut source = Cache::default();
+        log::read_entries(path, |entry| apply_entry(&mut source, entry.clone()))?;
+        let mut keys: Vec<&String> = source.entries.keys().collect();
+        keys.sort();
+
+        let mut cache = self.cache.write().unwrap();
+        if policy == ImportPolicy::Error {
+            if let Some(key) = keys
+                .iter()
+                .find(|key| cache.entries.contains_key(key.as_str()))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("key '{}' already exists", key),
+                ));
+            }
+        }
+
+        let mut report = ImportReport::default();
+        for key in keys {
+            let exists = cache.entries.contains_key(key);
+            if exists && policy == ImportPolicy::Skip {
+                report.skipped += 1;
+                continue;
+            }
+            let entry = &source.entries[key];
+            match &entry.content_type {
+                Some(content_type) => {
+                    self.write_with_content_type(&mut cache, key, &entry.value, content_type)?
+                }
+                None => self.write(&mut cache, key, &entry.value)?,
+            };
+            for tag in &entry.tags {
+                self.writable_log()?
+                    .append(&LogEntry::Tag(key.clone(), tag.clone()))?;
+                cache.tag(key, tag);
+            }
+            if exists {
+                report.overwritten += 1;
+            } else {
+                report.imported += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Flushes and fsyncs the log, so every acknowledged write is on disk.
+    ///
+    /// This is a no-op on a follower.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.log {
+            Some(log) => log.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Reopens the log file if it has been moved or removed since it was
+    /// opened, recreating it at its original path with the current contents.
+    ///
+    /// This is what the server does on `SIGHUP` so log rotation tools can move
+    /// the log aside. Followers have no log of their own, so this is a no-op
+    /// for them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_reopen_log.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    ///
+    /// fs::rename(log_path, "test_reopen_log.log.1").unwrap();
+    /// db.reopen_log().unwrap();
+    /// assert!(fs::read_to_string(log_path).unwrap().contains("key1"));
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// fs::remove_file("test_reopen_log.log.1").unwrap_or(());
+    /// ```
+    pub fn reopen_log(&self) -> io::Result<()> {
+        match &self.log {
+            Some(log) => log.lock().unwrap().reopen(),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the cache matches what replaying the log would produce.
+    ///
+    /// The log is replayed into a scratch map under the same locks writers
+    /// take, so no write can land in between, and the result is diffed
+    /// against the cache. This reads the whole log and blocks writes while it
+    /// runs, so it is meant as an occasional admin check. Followers lag their
+    /// primary by design and return a `PermissionDenied` error. Cancelling
+    /// the [`CancelToken`] stops it with an `Interrupted` error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::storage::Database;
+    /// use std::fs;
+    ///
+    /// let log_path = "test_verify_consistency.log";
+    /// let db = Database::with_log_path(log_path).unwrap();
+    /// db.set("key1", b"value1").unwrap();
+    ///
+    /// let report = db.verify_consistency().unwrap();
+    /// assert!(report.is_clean());
+    ///
+    /// // Clean up
+    /// fs::remove_file(log_path).unwrap_or(());
+    /// ```
+    pub fn verify_consistency(&self) -> io::Result<VerifyReport> {
+        let cache = self.cache.read().unwrap();
+        let mut replayed = Cache::default();
+        for entry in self.writable_log()?.replay()? {
+            apply_entry(&mut replayed, entry);
+        }
+
+        let mut report = VerifyReport::default();
+        for (key, entry) in &replayed.entries {
+            self.cancel.check("verification")?;
+            match cache.entries.get(key) {
+                None => report.missing.push(key.clone()),
+                Some(cached) if cached.value != entry.value => report.mismatched.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        for key in cache.entries.keys() {
+            if !replayed.entries.contains_key(key) {
+                report.extra.push(key.clone());
+            }
+        }
+        report.missing.sort();
+        report.extra.sort();
+        report.mismatched.sort();
+        Ok(report)
+    }
+}
+
+/// What [`Database::import_from`] does with a key that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportPolicy {
+    /// Replace the existing value with the imported one
+    Overwrite,
+    /// Keep the existing value and leave the key out of the import
+    Skip,
+    /// Import nothing, failing with the first key that collides
+    Error,
+}
+
+/// How many keys [`Database::import_from`] wrote or left alone.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Keys that didn't exist before
+    pub imported: usize,
+    /// Existing keys left as they were, under [`ImportPolicy::Skip`]
+    pub skipped: usize,
+    /// Existing keys replaced, under [`ImportPolicy::Overwrite`]
+    pub overwritten: usize,
+}
+
+/// Metadata about a single key, as returned by [`Database::metadata`] and
+/// [`Database::metadata_many`].
+///
+/// Like versions, the timestamps are not stored in the log: a key loaded by
+/// replaying the log is stamped with the time it was loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// Length of the value in bytes
+    pub size: usize,
+    /// Current version of the key, as used by [`Database::set_if_version`]
+    pub version: u64,
+    /// When the key was first set; deleting it and setting it again resets
+    /// this
+    pub created_at: SystemTime,
+    /// When the key was last written
+    pub last_modified: SystemTime,
+}
+
+/// Where a database's log lives and how big it is, as returned by
+/// [`Database::data_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataInfo {
+    /// Path of the log file
+    pub path: PathBuf,
+    /// Size of the log file in bytes
+    pub size: u64,
+    /// Approximate number of entries in the log
+    pub entries: usize,
+}
+
+/// Differences found by [`Database::verify_consistency`], each list sorted by
+/// key.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Keys the log holds but the cache does not
+    pub missing: Vec<String>,
+    /// Keys the cache holds but the log does not
+    pub extra: Vec<String>,
+    /// Keys whose cached value differs from the logged one
+    pub mismatched: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Returns whether the cache and the log agree.
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "missing=[{}] extra=[{}] mismatched=[{}]",
+            self.missing.join(","),
+            self.extra.join(","),
+            self.mismatched.join(",")
+        )
+    }
+}
+
+/// Marks a compaction as running until dropped.
+struct CompactionFlag<'a>(&'a AtomicBool);
+
+impl<'a> CompactionFlag<'a> {
+    fn raise(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Ordering::SeqCst);
+        Self(flag)
+    }
+}
+
+impl Drop for CompactionFlag<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Longest value `set_range` will grow a value to, so a stray offset can't
+/// allocate without bound
+const MAX_SET_RANGE_LEN: usize = 512 * 1024 * 1024;
+/// How many recent changes are kept for `changes_since`
+const MAX_KEPT_CHANGES: usize = 10_000;
+/// Most value bytes the kept changes may hold on to, since they keep
+/// overwritten and deleted values alive
+const MAX_KEPT_CHANGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long a read snapshot lives unless it is ended sooner
+const DEFAULT_SNAPSHOT_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A point-in-time copy of every key, sharing its values with the cache.
+struct ReadSnapshot {
+    taken: Instant,
+    values: HashMap<String, Arc<[u8]>>,
+}
+
+/// The open read snapshots of a database.
+struct ReadSnapshots {
+    views: HashMap<u64, ReadSnapshot>,
+    next_id: u64,
+    max_lifetime: Duration,
+}
+
+impl Default for ReadSnapshots {
+    fn default() -> Self {
+        Self {
+            views: HashMap::new(),
+            next_id: 0,
+            max_lifetime: DEFAULT_SNAPSHOT_LIFETIME,
+        }
+    }
+}
+
+impl ReadSnapshots {
+    /// Drops snapshots older than the lifetime.
+    fn expire(&mut self) {
+        let max_lifetime = self.max_lifetime;
+        self.views
+            .retain(|_, snapshot| snapshot.taken.elapsed() < max_lifetime);
+    }
+}
+
+/// A cached value, the version it was written at, when it was created and
+/// last written, and its tags.
+struct Entry {
+    /// Shared with any read snapshots taken while this value was current
+    value: Arc<[u8]>,
+    version: u64,
+    created_at: SystemTime,
+    last_modified: SystemTime,
+    tags: BTreeSet<String>,
+    /// MIME type given with the value, if any
+    content_type: Option<String>,
+    /// Tick of the cache's access clock when the key was last read or
+    /// written; updated by reads under the read lock
+    last_access: AtomicU64,
+}
+
+impl Entry {
+    fn metadata(&self) -> KeyMetadata {
+        KeyMetadata {
+            size: self.value.len(),
+            version: self.version,
+            created_at: self.created_at,
+            last_modified: self.last_modified,
+        }
+    }
+}
+
+/// The in-memory view of the log.
+///
+/// Every write stamps the key with a version from a single counter, so a key
+/// that is deleted and recreated never reuses an old version. Versions are
+/// assigned as the log is replayed and are only meaningful within one process.
+///
+/// Tags are kept both on each entry and in a reverse index from tag to keys;
+/// `insert` and `remove` keep the two in step, and keep each quota's usage
+/// current. Deletes and tags take a version too, and every change is kept,
+/// up to a limit, for [`Database::changes_since`].
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    tagged: HashMap<String, BTreeSet<String>>,
+    last_version: u64,
+    quotas: Vec<Quota>,
+    /// Recent changes with their versions, oldest first
+    changes: VecDeque<(u64, Change)>,
+    /// Changes up to this version are no longer in `changes`
+    changes_floor: u64,
+    /// Total length of the values in `changes`
+    changes_bytes: usize,
+    /// Ticks once per key access, ordering entries' `last_access`
+    access_clock: AtomicU64,
+}
+
+/// A change kept for [`Database::changes_since`]. Values are shared with the
+/// cache rather than copied.
+enum Change {
+    Set(String, Arc<[u8]>),
+    Delete(String),
+    Tag(String, String),
+    ContentType(String, String),
+}
+
+impl Change {
+    fn value_len(&self) -> usize {
+        match self {
+            Change::Set(_, value) => value.len(),
+            Change::Delete(_) | Change::Tag(..) | Change::ContentType(..) => 0,
+        }
+    }
+
+    fn to_entry(&self) -> LogEntry {
+        match self {
+            Change::Set(key, value) => LogEntry::Set(key.clone(), value.to_vec()),
+            Change::Delete(key) => LogEntry::Delete(key.clone()),
+            Change::Tag(key, tag) => LogEntry::Tag(key.clone(), tag.clone()),
+            Change::ContentType(key, content_type) => {
+                LogEntry::ContentType(key.clone(), content_type.clone())
+            }
+        }
+    }
+}
+
+/// A byte budget for the values under a key prefix; see
+/// [`Database::set_quota`].
+struct Quota {
+    prefix: String,
+    max_bytes: usize,
+    used_bytes: usize,
+}
+
+impl Cache {
+    /// Inserts `value` under a fresh version and returns that version.
+    ///
+    /// Overwriting an existing key keeps its creation time and tags, but not
+    /// its content type, which described the old value.
+    fn insert(&mut self, key: String, value: Vec<u8>) -> u64 {
+        self.last_version += 1;
+        let version = self.last_version;
+        let last_modified = SystemTime::now();
+        let (created_at, tags, old_len) = match self.entries.remove(&key) {
+            Some(entry) => (entry.created_at, entry.tags, entry.value.len()),
+            None => (last_modified, BTreeSet::new(), 0),
+        };
+        self.charge(&key, old_len, value.len());
+        let value: Arc<[u8]> = value.into();
+        self.record(version, Change::Set(key.clone(), Arc::clone(&value)));
+        let last_access = AtomicU64::new(self.tick());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                version,
+                created_at,
+                last_modified,
+                tags,
+                content_type: None,
+                last_access,
+            },
+        );
+        version
+    }
+
+    /// Looks up `key`, marking it as just accessed.
+    fn access(&self, key: &str) -> Option<&Entry> {
+        let entry = self.entries.get(key)?;
+        entry.last_access.store(self.tick(), Ordering::Relaxed);
+        Some(entry)
+    }
+
+    /// Advances the access clock and returns the new tick.
+    fn tick(&self) -> u64 {
+        self.access_clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Removes `key` along with its tags.
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.charge(key, entry.value.len(), 0);
+        self.last_version += 1;
+        self.record(self.last_version, Change::Delete(key.to_string()));
+        for tag in &entry.tags {
+            if let Some(keys) = self.tagged.get_mut(tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.tagged.remove(tag);
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    /// Tags an existing key. Returns `false` if the key doesn't exist.
+    fn tag(&mut self, key: &str, tag: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        entry.tags.insert(tag.to_string());
+        self.last_version += 1;
+        self.record(
+            self.last_version,
+            Change::Tag(key.to_string(), tag.to_string()),
+        );
+        self.tagged
+            .entry(tag.to_string())
+            .or_default()
+            .insert(key.to_string());
+        true
+    }
+
+    /// Gives the current value of `key` a content type, if the key exists.
+    fn set_content_type(&mut self, key: &str, content_type: &str) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        entry.content_type = Some(content_type.to_string());
+        self.last_version += 1;
+        self.record(
+            self.last_version,
+            Change::ContentType(key.to_string(), content_type.to_string()),
+        );
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.tagged.clear();
+        for quota in &mut self.quotas {
+            quota.used_bytes = 0;
+        }
+        // Emptying the cache isn't a change `changes_since` can express
+        self.forget_changes();
+    }
+
+    /// Keeps `change`, forgetting the oldest kept changes while there are
+    /// too many or they hold too many bytes.
+    fn record(&mut self, version: u64, change: Change) {
+        self.changes_bytes += change.value_len();
+        self.changes.push_back((version, change));
+        while self.changes.len() > MAX_KEPT_CHANGES || self.changes_bytes > MAX_KEPT_CHANGE_BYTES {
+            let Some((forgotten, change)) = self.changes.pop_front() else {
+                break;
+            };
+            self.changes_bytes -= change.value_len();
+            self.changes_floor = forgotten;
+        }
+    }
+
+    /// Drops every kept change, so `changes_since` only reports later ones.
+    fn forget_changes(&mut self) {
+        self.changes.clear();
+        self.changes_bytes = 0;
+        self.changes_floor = self.last_version;
+    }
+
+    /// Moves `key`'s share of its quotas from `old_len` bytes to `new_len`.
+    fn charge(&mut self, key: &str, old_len: usize, new_len: usize) {
+        for quota in &mut self.quotas {
+            if key.starts_with(&quota.prefix) {
+                quota.used_bytes = quota.used_bytes + new_len - old_len;
+            }
+        }
+    }
+}
+
+/// Applies a replayed log entry to the cache.
+fn apply_entry(cache: &mut Cache, entry: LogEntry) {
+    match entry {
+        LogEntry::Set(key, value) => {
+            cache.insert(key, value);
+        }
+        LogEntry::Delete(key) => {
+            cache.remove(&key);
+        }
+        LogEntry::Tag(key, tag) => {
+            cache.tag(&key, &tag);
+        }
+        LogEntry::ContentType(key, content_type) => {
+            cache.set_content_type(&key, &content_type);
+        }
+        LogEntry::Compact => {
+            // Skip compact entries when replaying
+        }
+    }
 }
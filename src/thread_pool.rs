@@ -1,10 +1,15 @@
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{channel, Receiver, RecvError, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
+use std::time::Duration;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Reported by a [`JobHandle`] whose task was dropped unrun
+const NOT_RUN: &str = "the pool shut down before the task ran";
+
 /// A thread pool for executing tasks concurrently.
 ///
 /// The `ThreadPool` maintains a set of worker threads that can execute tasks
@@ -45,6 +50,18 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 /// assert_eq!(counter.load(Ordering::SeqCst), 10);
 /// ```
 ///
+/// Elastic pool that releases idle workers:
+///
+/// ```
+/// use keystonelight::ThreadPool;
+/// use std::time::Duration;
+///
+/// // Keep one worker around, grow to four under load, and let extra
+/// // workers exit after 100ms without work
+/// let pool = ThreadPool::elastic(1, 4, Duration::from_millis(100));
+/// assert_eq!(pool.live_workers(), 1);
+/// ```
+///
 /// Graceful shutdown:
 ///
 /// ```
@@ -59,8 +76,52 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 /// // when it goes out of scope
 /// ```
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    /// Shared with [`PoolHandle`]s only weakly, so dropping the pool still
+    /// closes the channel
+    sender: Option<Arc<Sender<Job>>>,
+    shared: Arc<Shared>,
+    /// Jobs `try_execute` lets wait for a worker; `None` is unbounded
+    max_queued: Option<usize>,
+    next_id: AtomicUsize,
+}
+
+/// State shared between the pool and its workers.
+struct Shared {
+    receiver: Mutex<Receiver<Job>>,
+    /// Workers currently running, including ones busy with a job
+    live: AtomicUsize,
+    /// Workers currently waiting for a job
+    idle: AtomicUsize,
+    /// Jobs sent to the channel that no worker has picked up yet
+    pending: AtomicUsize,
+    /// The pool never shrinks below this many workers
+    min: usize,
+    /// Upper bound on live workers
+    max: usize,
+    /// How long a worker waits for a job before exiting; `None` keeps
+    /// workers alive forever
+    idle_timeout: Option<Duration>,
+}
+
+impl Shared {
+    /// Claims a slot for a new worker if the pool is below `max`.
+    fn try_grow(&self) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < self.max).then_some(live + 1)
+            })
+            .is_ok()
+    }
+
+    /// Gives up an idle worker's slot if the pool is above `min`.
+    fn try_retire(&self) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > self.min).then_some(live - 1)
+            })
+            .is_ok()
+    }
 }
 
 impl ThreadPool {
@@ -84,26 +145,123 @@ impl ThreadPool {
     /// ```
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
+        Self::build(size, size, None)
+    }
 
+    /// Create an elastic ThreadPool.
+    ///
+    /// The pool starts with `min` workers and spawns more on demand, up to
+    /// `max`, when a task arrives and no worker is idle. Workers beyond `min`
+    /// exit once they have waited `idle_timeout` without receiving a task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or `min` is greater than `max`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::ThreadPool;
+    /// use std::time::Duration;
+    ///
+    /// let pool = ThreadPool::elastic(1, 8, Duration::from_secs(30));
+    /// pool.execute(|| {
+    ///     println!("Hello from an elastic pool!");
+    /// });
+    /// ```
+    pub fn elastic(min: usize, max: usize, idle_timeout: Duration) -> ThreadPool {
+        assert!(max > 0);
+        assert!(min <= max);
+        Self::build(min, max, Some(idle_timeout))
+    }
+
+    fn build(min: usize, max: usize, idle_timeout: Option<Duration>) -> ThreadPool {
         let (sender, receiver) = channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            live: AtomicUsize::new(min),
+            idle: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            min,
+            max,
+            idle_timeout,
+        });
 
-        let mut workers = Vec::with_capacity(size);
+        let mut workers = Vec::with_capacity(max);
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for id in 0..min {
+            workers.push(Worker::new(id, Arc::clone(&shared)));
         }
 
         ThreadPool {
-            workers,
-            sender: Some(sender),
+            workers: Mutex::new(workers),
+            sender: Some(Arc::new(sender)),
+            shared,
+            max_queued: None,
+            next_id: AtomicUsize::new(min),
+        }
+    }
+
+    /// Returns a handle for queueing tasks from inside the pool's own tasks,
+    /// such as a task that wants to run again later without holding on to
+    /// its worker.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::ThreadPool;
+    /// use std::sync::mpsc::channel;
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// let handle = pool.handle();
+    /// let (done, finished) = channel();
+    ///
+    /// pool.execute(move || {
+    ///     // Continue in a fresh task, behind anything queued meanwhile
+    ///     handle.execute(move || done.send(()).unwrap()).ok();
+    /// });
+    /// finished.recv().unwrap();
+    /// ```
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            sender: self.sender.as_ref().map_or_else(Weak::new, Arc::downgrade),
+            shared: Arc::clone(&self.shared),
         }
     }
 
+    /// Returns the number of worker threads currently alive.
+    pub fn live_workers(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Returns the most worker threads the pool will run at once.
+    pub fn max_workers(&self) -> usize {
+        self.shared.max
+    }
+
+    /// Bounds how many tasks [`ThreadPool::try_execute`] lets queue up
+    /// waiting for a worker, or removes the bound with `None`.
+    ///
+    /// Tasks that an idle worker, or a worker an elastic pool can still
+    /// spawn, will pick up do not count against the bound. [`ThreadPool::execute`]
+    /// ignores it and always queues.
+    pub fn set_max_queued(&mut self, max_queued: Option<usize>) {
+        self.max_queued = max_queued;
+    }
+
+    /// Returns the bound set with [`ThreadPool::set_max_queued`].
+    pub fn max_queued(&self) -> Option<usize> {
+        self.max_queued
+    }
+
     /// Execute a task in the thread pool.
     ///
     /// The task will be executed by one of the worker threads in the pool.
-    /// If the pool has been shut down, the task will be silently dropped.
+    /// The pool only shuts down once it is dropped, which can't happen while
+    /// it is borrowed here, so the task is always queued; tasks still queued
+    /// at shutdown run before the workers exit. Tasks queued through a
+    /// [`PoolHandle`] can outlive the pool, so [`PoolHandle::execute`]
+    /// returns a `Result` instead.
     ///
     /// # Examples
     ///
@@ -128,9 +286,177 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let pending = self.shared.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        self.submit(Box::new(f), pending);
+    }
+
+    /// Execute a task in the thread pool unless its queue is full.
+    ///
+    /// Hands the task back as `Err` when no worker is free and the bound set
+    /// with [`ThreadPool::set_max_queued`] has been reached. Without a bound
+    /// this behaves like [`ThreadPool::execute`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::ThreadPool;
+    ///
+    /// let mut pool = ThreadPool::new(1);
+    /// pool.set_max_queued(Some(0));
+    ///
+    /// pool.execute(|| std::thread::sleep(std::time::Duration::from_millis(200)));
+    /// std::thread::sleep(std::time::Duration::from_millis(50));
+    ///
+    /// // The only worker is busy and nothing may queue behind it
+    /// assert!(pool.try_execute(|| {}).is_err());
+    /// ```
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let max_queued = match self.max_queued {
+            Some(max_queued) => max_queued,
+            None => {
+                self.execute(f);
+                return Ok(());
+            }
+        };
+
+        let spare = self.shared.idle.load(Ordering::SeqCst)
+            + self
+                .shared
+                .max
+                .saturating_sub(self.shared.live.load(Ordering::SeqCst));
+        let reserved =
+            self.shared
+                .pending
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                    (pending < max_queued + spare).then_some(pending + 1)
+                });
+        match reserved {
+            Ok(pending) => {
+                self.submit(Box::new(f), pending + 1);
+                Ok(())
+            }
+            Err(_) => Err(f),
+        }
+    }
+
+    /// Execute a task in the thread pool and return a handle to its result.
+    ///
+    /// A panic in the task is caught, so it doesn't take its worker down,
+    /// and is reported by [`JobHandle::join`] instead. Use
+    /// [`ThreadPool::execute`] when the result isn't needed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use keystonelight::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let handle = pool.spawn(|| 6 * 7);
+    /// assert_eq!(handle.join().unwrap(), 42);
+    /// ```
+    pub fn spawn<T, F>(&self, f: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // The caller may have dropped the handle, which is fine
+            let _ = sender.send(result);
+        });
+        JobHandle { receiver }
+    }
+
+    /// Sends a job already counted in `pending`.
+    fn submit(&self, job: Job, pending: usize) {
         if let Some(sender) = &self.sender {
             sender.send(job).unwrap();
+
+            // More queued jobs than idle workers, so grow the pool if it is allowed to
+            if pending > self.shared.idle.load(Ordering::SeqCst) && self.shared.try_grow() {
+                self.spawn_worker();
+            }
+        }
+    }
+
+    fn spawn_worker(&self) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut workers = self.workers.lock().unwrap();
+        // Forget workers that already exited after going idle
+        workers.retain(|worker| {
+            worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished())
+        });
+        workers.push(Worker::new(id, Arc::clone(&self.shared)));
+    }
+}
+
+/// A cloneable handle for queueing tasks on a [`ThreadPool`], returned by
+/// [`ThreadPool::handle`].
+///
+/// The handle doesn't keep the pool alive: once the pool has been dropped,
+/// [`PoolHandle::execute`] hands the task back instead of queueing it.
+#[derive(Clone)]
+pub struct PoolHandle {
+    sender: Weak<Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl PoolHandle {
+    /// Queues a task behind the ones already waiting, or returns it as `Err`
+    /// if the pool has shut down.
+    ///
+    /// Unlike [`ThreadPool::execute`] this never spawns a worker, and it
+    /// ignores the bound set with [`ThreadPool::set_max_queued`]. It is
+    /// meant for a task handing its work on, so the worker running it is
+    /// about to be free.
+    pub fn execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(sender) = self.sender.upgrade() else {
+            return Err(f);
+        };
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        // The receiver lives in `shared`, which this handle keeps alive
+        sender.send(Box::new(f)).unwrap();
+        Ok(())
+    }
+}
+
+/// The result of a task queued with [`ThreadPool::spawn`].
+///
+/// Dropping the handle detaches the task: it still runs, and its result is
+/// thrown away.
+pub struct JobHandle<T> {
+    receiver: Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the task to finish and returns its result.
+    ///
+    /// Returns `Err` with the panic payload if the task panicked, like
+    /// [`std::thread::JoinHandle::join`], or with a message if the pool shut
+    /// down before the task ran.
+    pub fn join(self) -> thread::Result<T> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new(NOT_RUN)))
+    }
+
+    /// Returns the task's result if it has finished, or hands the handle back
+    /// as `Err` so it can be polled again.
+    pub fn try_join(self) -> Result<thread::Result<T>, Self> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(Box::new(NOT_RUN))),
         }
     }
 }
@@ -141,7 +467,7 @@ impl Drop for ThreadPool {
         drop(self.sender.take());
 
         // Wait for all workers to finish
-        for worker in &mut self.workers {
+        for worker in self.workers.get_mut().unwrap().iter_mut() {
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
             }
@@ -150,25 +476,50 @@ impl Drop for ThreadPool {
 }
 
 struct Worker {
-    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    /// Spawns a worker whose thread is named after `id`, so it can be told
+    /// apart in panic messages and debuggers.
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        let thread = builder.spawn(move || {
             loop {
-                let job = match receiver.lock().unwrap().recv() {
-                    Ok(job) => job,
-                    Err(RecvError) => break, // Channel closed, exit thread
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                let message = {
+                    let receiver = shared.receiver.lock().unwrap();
+                    match shared.idle_timeout {
+                        Some(timeout) => receiver.recv_timeout(timeout),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    }
                 };
-                job();
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+
+                match message {
+                    Ok(job) => {
+                        shared.pending.fetch_sub(1, Ordering::SeqCst);
+                        job();
+                    }
+                    // Idle for too long, exit unless the pool is at its minimum
+                    Err(RecvTimeoutError::Timeout) => {
+                        // A job sent after the timeout may have found no idle
+                        // worker and no room to grow because this one still
+                        // counted as live; take the slot back to run it
+                        if shared.try_retire()
+                            && (shared.pending.load(Ordering::SeqCst) == 0 || !shared.try_grow())
+                        {
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break, // Channel closed, exit thread
+                }
             }
+            shared.live.fetch_sub(1, Ordering::SeqCst);
         });
 
         Worker {
-            id,
-            thread: Some(thread),
+            thread: Some(thread.expect("failed to spawn worker thread")),
         }
     }
 }
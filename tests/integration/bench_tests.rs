@@ -0,0 +1,26 @@
+use keystonelight::bench::{run_load, OpMix};
+use keystonelight::server::Server;
+use std::thread;
+use tempfile::tempdir;
+
+#[test]
+fn test_run_load_small() {
+    let temp_dir = tempdir().unwrap();
+    let server = Server::with_addr(
+        temp_dir.path().join("keystonelight.pid"),
+        temp_dir.path().join("keystonelight.log"),
+        2,
+        "127.0.0.1:0",
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let report = run_load(&addr.to_string(), 2, 100, OpMix::new(1, 1)).unwrap();
+    assert!(report.ops_per_sec > 0.0);
+    assert_eq!(report.errors, 0);
+    assert!(report.p50 <= report.p99);
+
+    assert!(run_load(&addr.to_string(), 0, 100, OpMix::default()).is_err());
+    assert!(run_load(&addr.to_string(), 1, 100, OpMix::new(0, 0)).is_err());
+}
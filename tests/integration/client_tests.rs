@@ -0,0 +1,349 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use keystonelight::client::{Client, ServerLimits, ShardedClient};
+use keystonelight::protocol::{hash_slot, HASH_SLOTS};
+use keystonelight::server::Server;
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
+
+fn start_server(temp_dir: &tempfile::TempDir) -> SocketAddr {
+    let server = Server::with_addr(
+        temp_dir.path().join("keystonelight.pid"),
+        temp_dir.path().join("keystonelight.log"),
+        2,
+        "127.0.0.1:0",
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+/// Runs the client binary with `args`, returning its stdout and exit code.
+fn run_client(addr: SocketAddr, args: &[&str]) -> (String, Option<i32>) {
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(args)
+        .env("KEYSTONELIGHT_SERVER_ADDR", addr.to_string())
+        .output()
+        .unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        output.status.code(),
+    )
+}
+
+#[test]
+fn test_client_runs_command_file() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let script = temp_dir.path().join("commands.txt");
+    fs::write(&script, "# seed data\nSET a 1\n\nGET a\nGETALL *\n").unwrap();
+
+    let (stdout, code) = run_client(addr, &["--file", script.to_str().unwrap()]);
+    assert_eq!(stdout, "OK\nVALUE 1\na => 1\n");
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+fn test_client_command_file_stops_on_error() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let script = temp_dir.path().join("commands.txt");
+    fs::write(&script, "SET a 1\nBOGUS\nSET b 2\n").unwrap();
+    let script = script.to_str().unwrap();
+
+    let (stdout, code) = run_client(addr, &["--file", script]);
+    assert_eq!(stdout, "OK\nERROR unknown command 'BOGUS'\n");
+    assert_eq!(code, Some(1));
+
+    let (stdout, code) = run_client(addr, &["--file", script, "--continue-on-error"]);
+    assert_eq!(stdout, "OK\nERROR unknown command 'BOGUS'\nOK\n");
+    assert_eq!(code, Some(1));
+
+    let (_, code) = run_client(addr, &["--file"]);
+    assert_eq!(code, Some(2));
+}
+
+#[test]
+fn test_interactive_client_disconnects_when_idle() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--idle-timeout", "1"])
+        .env("KEYSTONELIGHT_SERVER_ADDR", addr.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Send one command, then go quiet without closing stdin
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"SET a 1\n").unwrap();
+    stdin.flush().unwrap();
+    let started = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("client still connected after 10s idle");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+    assert!(started.elapsed() >= Duration::from_millis(900));
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("OK\n"), "{}", stdout);
+    assert!(
+        stdout.contains("No input for 1s, disconnecting"),
+        "{}",
+        stdout
+    );
+    drop(stdin);
+}
+
+#[test]
+fn test_interactive_client_round_trips_binary_values() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_client"))
+        .env("KEYSTONELIGHT_SERVER_ADDR", addr.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"SETHEX k 00ff\nGETHEX k\nSETB64 b AAEC/w==\nGETB64 b\nGETHEX b\nSETHEX bad 0g\nGETHEX missing\nquit\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    let replies: Vec<&str> = stdout.split("> ").skip(1).map(str::trim_end).collect();
+    assert_eq!(
+        replies,
+        [
+            "OK",
+            "00ff",
+            "OK",
+            "AAEC/w==",
+            "000102ff",
+            "Error: Invalid hex encoding",
+            "NOT_FOUND",
+            "Goodbye!"
+        ]
+    );
+
+    // The server holds exactly those bytes
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(client.get("k").unwrap(), Some(vec![0x00, 0xff]));
+    assert_eq!(client.get("b").unwrap(), Some(vec![0x00, 0x01, 0x02, 0xff]));
+    assert_eq!(client.get("bad").unwrap(), None);
+}
+
+#[test]
+fn test_get_to_streams_large_value_into_file() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+
+    // Binary, so it travels base64-encoded and has to be decoded in pieces
+    let original: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i * 31 % 256) as u8).collect();
+    let response = client
+        .send_command(&format!("SET big base64:{}", BASE64.encode(&original)))
+        .unwrap();
+    assert_eq!(response.trim(), "OK");
+
+    let path = temp_dir.path().join("big.bin");
+    let found = client
+        .get_to("big", fs::File::create(&path).unwrap())
+        .unwrap();
+    assert!(found);
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    // The connection is still in step for the next command
+    assert_eq!(client.send_command("SET text hello").unwrap().trim(), "OK");
+    let mut text = Vec::new();
+    assert!(client.get_to("text", &mut text).unwrap());
+    assert_eq!(text, b"hello");
+}
+
+#[test]
+fn test_get_to_missing_key_writes_nothing() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir);
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+
+    let mut sink = Vec::new();
+    assert!(!client.get_to("missing", &mut sink).unwrap());
+    assert!(sink.is_empty());
+}
+
+#[test]
+fn test_client_fetches_limits_and_rejects_oversized_values() {
+    let temp_dir = tempdir().unwrap();
+    let server = Server::with_addr(
+        temp_dir.path().join("keystonelight.pid"),
+        temp_dir.path().join("keystonelight.log"),
+        2,
+        "127.0.0.1:0",
+    )
+    .unwrap()
+    .with_max_request_bytes(64)
+    .with_max_pipeline_depth(8);
+    let addr = server.local_addr().unwrap().to_string();
+    thread::spawn(move || server.run());
+
+    let mut client = Client::connect(&addr).unwrap();
+    assert_eq!(
+        client.send_command("LIMITS").unwrap().trim_end(),
+        "max_request_bytes=64 max_pipeline_depth=8 max_keys=none"
+    );
+    assert_eq!(
+        client.limits(),
+        ServerLimits {
+            max_request_bytes: Some(64),
+            max_pipeline_depth: Some(8),
+            max_keys: None,
+        }
+    );
+
+    let err = client.set("big", &[b'x'; 64]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    // Had the value been sent, the server would have dropped the connection
+    client.set("small", b"ok").unwrap();
+    assert_eq!(client.get("big").unwrap(), None);
+
+    let other_dir = tempdir().unwrap();
+    let unlimited = Client::connect(&start_server(&other_dir).to_string()).unwrap();
+    assert_eq!(unlimited.limits(), ServerLimits::default());
+}
+
+#[test]
+fn test_sharded_client_spreads_keys_across_servers() {
+    let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+    let addrs: Vec<String> = dirs
+        .iter()
+        .map(|dir| start_server(dir).to_string())
+        .collect();
+    let mut sharded = ShardedClient::new(&addrs).unwrap();
+
+    let keys: Vec<String> = (0..50).map(|i| format!("user:{}", i)).collect();
+    for key in &keys {
+        sharded.set(key, key.as_bytes()).unwrap();
+    }
+
+    let mut direct: Vec<Client> = addrs
+        .iter()
+        .map(|addr| Client::connect(addr).unwrap())
+        .collect();
+    let mut per_server = [0; 2];
+    for key in &keys {
+        let server = sharded.server_for(key).to_string();
+        // Routing is a pure function of the key
+        assert_eq!(ShardedClient::new(&addrs).unwrap().server_for(key), server);
+        assert_eq!(sharded.get(key).unwrap(), Some(key.as_bytes().to_vec()));
+
+        // The key is on its server and nowhere else
+        for (i, client) in direct.iter_mut().enumerate() {
+            let stored = client.get(key).unwrap();
+            if addrs[i] == server {
+                assert_eq!(stored, Some(key.as_bytes().to_vec()));
+                per_server[i] += 1;
+            } else {
+                assert_eq!(stored, None);
+            }
+        }
+    }
+    assert!(
+        per_server.iter().all(|&count| count > 5),
+        "keys spread unevenly: {:?}",
+        per_server
+    );
+
+    sharded.delete("user:1").unwrap();
+    assert_eq!(sharded.get("user:1").unwrap(), None);
+}
+
+#[test]
+fn test_sharded_client_reports_unreachable_server() {
+    let temp_dir = tempdir().unwrap();
+    let live = start_server(&temp_dir).to_string();
+    // A port nothing listens on any more
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut sharded = ShardedClient::new(&[live.as_str(), dead.as_str()]).unwrap();
+
+    let keys: Vec<String> = (0..50).map(|i| format!("key{}", i)).collect();
+    let dead_key = keys
+        .iter()
+        .find(|key| sharded.server_for(key) == dead)
+        .unwrap();
+    let live_key = keys
+        .iter()
+        .find(|key| sharded.server_for(key) == live)
+        .unwrap();
+
+    let err = sharded.set(dead_key, b"v").unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains(&dead), "{}", message);
+    assert!(message.contains(dead_key.as_str()), "{}", message);
+
+    // Keys on the live server are unaffected
+    sharded.set(live_key, b"v").unwrap();
+    assert_eq!(sharded.get(live_key).unwrap(), Some(b"v".to_vec()));
+    assert!(sharded.get(dead_key).is_err());
+}
+
+#[test]
+fn test_slot_is_stable_and_matches_sharded_client() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_server(&temp_dir).to_string();
+    let mut client = Client::connect(&addr).unwrap();
+    let sharded = ShardedClient::new(&[addr.as_str()]).unwrap();
+
+    for key in ["user:1", "user:2", "a key with spaces"] {
+        let reply = client.slot(key).unwrap();
+        assert_eq!(client.slot(key).unwrap(), reply);
+        assert_eq!(sharded.slot(key), reply);
+        assert!(reply.1 < HASH_SLOTS);
+    }
+    assert_ne!(
+        client.slot("user:1").unwrap(),
+        client.slot("user:2").unwrap()
+    );
+}
+
+#[test]
+fn test_hash_slots_are_roughly_uniform() {
+    // Group the slots into 16 equal ranges and count keys in each
+    const GROUPS: usize = 16;
+    const KEYS: usize = 32_000;
+    let mut counts = [0usize; GROUPS];
+    for i in 0..KEYS {
+        let slot = hash_slot(&format!("user:{}", i)) as usize;
+        counts[slot * GROUPS / HASH_SLOTS as usize] += 1;
+    }
+
+    let expected = KEYS / GROUPS;
+    for count in counts {
+        assert!(
+            count.abs_diff(expected) < expected / 10,
+            "slot ranges unevenly loaded: {:?}",
+            counts
+        );
+    }
+}
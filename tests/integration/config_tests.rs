@@ -0,0 +1,113 @@
+use keystonelight::client::Client;
+use keystonelight::server::{Server, ServerConfig};
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use tempfile::tempdir;
+
+#[test]
+fn test_parse_config() {
+    let config = ServerConfig::parse(
+        r#"
+        # Comments and blank lines are ignored
+        threads = 6
+        addr = "127.0.0.1:9999"   # trailing comment
+        log_file = "/tmp/with # hash.log"
+        max_keys = 500
+        quota = "t1: 1024"
+        quota = "tenant two: 2048"
+        auto_compact = false
+        mirror_log = "/mnt/standby/keystonelight.log"
+        slow_log_threshold_ms = 50
+        max_pipeline_depth = 16
+        require_utf8 = true
+        key_separator = "/"
+        metrics_file = "/tmp/metrics.log"
+        metrics_interval_secs = 5
+        capture_file = "/tmp/keystonelight.capture"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.threads, 6);
+    assert_eq!(config.addr, "127.0.0.1:9999");
+    assert_eq!(config.log_file, PathBuf::from("/tmp/with # hash.log"));
+    assert_eq!(config.max_keys, Some(500));
+    assert_eq!(
+        config.quotas,
+        vec![("t1:".to_string(), 1024), ("tenant two:".to_string(), 2048)]
+    );
+    assert!(!config.auto_compact);
+    assert_eq!(config.max_pipeline_depth, Some(16));
+    assert!(config.require_utf8);
+    assert_eq!(config.key_separator, "/");
+    assert_eq!(
+        config.slow_log_threshold,
+        Some(std::time::Duration::from_millis(50))
+    );
+    assert_eq!(
+        config.mirror_log,
+        Some(PathBuf::from("/mnt/standby/keystonelight.log"))
+    );
+    assert_eq!(config.metrics_file, Some(PathBuf::from("/tmp/metrics.log")));
+    assert_eq!(config.metrics_interval, std::time::Duration::from_secs(5));
+    assert_eq!(
+        config.capture_file,
+        Some(PathBuf::from("/tmp/keystonelight.capture"))
+    );
+    // Anything left out keeps its default
+    let defaults = ServerConfig::default();
+    assert_eq!(config.pid_file, defaults.pid_file);
+    assert_eq!(config.max_request_bytes, None);
+    assert_eq!(config.read_buffer_size, defaults.read_buffer_size);
+}
+
+#[test]
+fn test_parse_config_errors() {
+    let err = ServerConfig::parse("threads = 2\nworkers = 4\n").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "config line 2: unknown setting `workers`");
+
+    let err = ServerConfig::parse("threads = many").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "config line 1: invalid value `many` for `threads`"
+    );
+
+    assert!(ServerConfig::parse("threads").is_err());
+    assert!(ServerConfig::parse("threads = 0").is_err());
+    assert!(ServerConfig::parse("auto_compact = yes").is_err());
+    assert!(ServerConfig::parse("quota = \"t1:\"").is_err());
+    assert!(ServerConfig::parse("quota = \"t1: lots\"").is_err());
+}
+
+#[test]
+fn test_server_from_config_file() {
+    let temp_dir = tempdir().unwrap();
+    let config_file = temp_dir.path().join("keystonelight.conf");
+    fs::write(
+        &config_file,
+        format!(
+            "pid_file = \"{}\"\nlog_file = \"{}\"\nthreads = 3\naddr = \"127.0.0.1:0\"\nmax_keys = 1\n",
+            temp_dir.path().join("keystonelight.pid").display(),
+            temp_dir.path().join("keystonelight.log").display(),
+        ),
+    )
+    .unwrap();
+
+    let server = Server::from_config_file(&config_file).unwrap();
+    let addr = server.local_addr().unwrap();
+    assert!(addr.ip().is_loopback());
+    thread::spawn(move || server.run());
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    let info = client.send_multiline_command("info").unwrap();
+    assert!(info.contains(&"worker_threads:3".to_string()));
+
+    // Storage settings are applied too
+    assert_eq!(client.send_command("set key1 value1").unwrap().trim(), "OK");
+    assert_eq!(
+        client.send_command("set key2 value2").unwrap().trim(),
+        "ERROR database full"
+    );
+}
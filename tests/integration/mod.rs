@@ -1,3 +1,8 @@
+mod bench_tests;
+mod client_tests;
+mod config_tests;
 mod protocol_tests;
+mod replication_tests;
 mod server_tests;
 mod storage_tests;
+mod thread_pool_tests;
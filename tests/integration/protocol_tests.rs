@@ -1,5 +1,11 @@
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use keystonelight::protocol::{parse_command, Command};
+use keystonelight::protocol::{
+    decode_key, encode_key, parse_command, parse_command_with_aliases, strip_idempotency_token,
+    strip_noreply, try_parse_command, Command, EvalAction, EvalCondition, EvalStep, ParseError,
+    Response, ValueEncoding,
+};
+use std::collections::HashMap;
+use std::io;
 
 #[test]
 fn test_parse_get_command() {
@@ -21,6 +27,12 @@ fn test_parse_set_command_binary() {
     assert!(matches!(cmd, Command::Set(key, value) if key == "mykey" && value == binary_data));
 }
 
+#[test]
+fn test_parse_set_command_empty_value() {
+    let cmd = parse_command("set mykey").unwrap();
+    assert!(matches!(cmd, Command::Set(key, value) if key == "mykey" && value.is_empty()));
+}
+
 #[test]
 fn test_parse_delete_command() {
     let cmd = parse_command("delete mykey").unwrap();
@@ -33,6 +45,46 @@ fn test_parse_compact_command() {
     assert!(matches!(cmd, Command::Compact));
 }
 
+#[test]
+fn test_parse_healthcheck_command() {
+    let cmd = parse_command("healthcheck").unwrap();
+    assert!(matches!(cmd, Command::HealthCheck));
+    assert!(parse_command("HEALTHCHECK now").is_none());
+}
+
+#[test]
+fn test_builtin_aliases() {
+    let cmd = parse_command("DEL mykey").unwrap();
+    assert!(matches!(cmd, Command::Delete(key) if key == "mykey"));
+    let cmd = parse_command("put k v").unwrap();
+    assert!(matches!(cmd, Command::Set(key, value) if key == "k" && value == b"v"));
+    // Canonical names keep working
+    let cmd = parse_command("DELETE mykey").unwrap();
+    assert!(matches!(cmd, Command::Delete(key) if key == "mykey"));
+    assert!(parse_command("del").is_none());
+}
+
+#[test]
+fn test_custom_aliases() {
+    let mut aliases = HashMap::new();
+    aliases.insert("REMOVE".to_string(), "DELETE".to_string());
+    let cmd = parse_command_with_aliases("remove mykey", &aliases).unwrap();
+    assert!(matches!(cmd, Command::Delete(key) if key == "mykey"));
+    assert!(parse_command_with_aliases("PUT k v", &aliases).is_none());
+}
+
+#[test]
+fn test_parse_getdefault_command() {
+    let cmd = parse_command("getdefault mykey fallback value").unwrap();
+    assert!(
+        matches!(cmd, Command::GetDefault(key, default) if key == "mykey" && default == b"fallback value")
+    );
+    let encoded = format!("GETDEFAULT mykey base64:{}", BASE64.encode([0, 1, 2]));
+    let cmd = parse_command(&encoded).unwrap();
+    assert!(matches!(cmd, Command::GetDefault(_, default) if default == [0, 1, 2]));
+    assert!(parse_command("getdefault").is_none());
+}
+
 #[test]
 fn test_case_insensitive() {
     let cmd = parse_command("GET mykey").unwrap();
@@ -79,3 +131,725 @@ fn test_invalid_commands() {
     assert!(parse_command("delete key extra").is_none());
     assert!(parse_command("compact extra").is_none());
 }
+
+#[test]
+fn test_getrange_command() {
+    match parse_command("getrange mykey 2 10").unwrap() {
+        Command::GetRange(key, start, end) => {
+            assert_eq!(key, "mykey");
+            assert_eq!(start, 2);
+            assert_eq!(end, 10);
+        }
+        _ => panic!("Expected GETRANGE command"),
+    }
+    assert!(parse_command("getrange mykey 2").is_none());
+    assert!(parse_command("getrange mykey a 10").is_none());
+    assert!(parse_command("getrange mykey 2 10 extra").is_none());
+    assert!(parse_command("getrange mykey -1 10").is_none());
+}
+
+#[test]
+fn test_get_as_command() {
+    match parse_command("get mykey as hex").unwrap() {
+        Command::GetAs(key, encoding) => {
+            assert_eq!(key, "mykey");
+            assert_eq!(encoding, ValueEncoding::Hex);
+        }
+        _ => panic!("Expected GET AS command"),
+    }
+    assert!(matches!(
+        parse_command("GET mykey AS BASE64").unwrap(),
+        Command::GetAs(_, ValueEncoding::Base64)
+    ));
+    assert!(matches!(
+        parse_command("get mykey as raw").unwrap(),
+        Command::GetAs(_, ValueEncoding::Raw)
+    ));
+    assert!(parse_command("get mykey as").is_none());
+    assert!(parse_command("get mykey as utf16").is_none());
+    assert!(parse_command("get mykey as hex extra").is_none());
+}
+
+#[test]
+fn test_snapshot_commands() {
+    assert!(matches!(
+        parse_command("snapshot").unwrap(),
+        Command::Snapshot
+    ));
+    assert!(parse_command("snapshot now").is_none());
+
+    match parse_command("get mykey at 7").unwrap() {
+        Command::GetAt(key, snapshot) => {
+            assert_eq!(key, "mykey");
+            assert_eq!(snapshot, 7);
+        }
+        _ => panic!("Expected GET AT command"),
+    }
+    assert!(parse_command("get mykey at").is_none());
+    assert!(parse_command("get mykey at latest").is_none());
+
+    assert!(matches!(
+        parse_command("ENDSNAPSHOT 7").unwrap(),
+        Command::EndSnapshot(7)
+    ));
+    assert!(parse_command("endsnapshot").is_none());
+    assert!(parse_command("endsnapshot x").is_none());
+    assert!(!parse_command("snapshot").unwrap().is_write());
+}
+
+#[test]
+fn test_versioned_commands() {
+    match parse_command("get mykey withversion").unwrap() {
+        Command::GetWithVersion(key) => assert_eq!(key, "mykey"),
+        _ => panic!("Expected GET WITHVERSION command"),
+    }
+    assert!(parse_command("get mykey extra").is_none());
+
+    match parse_command("set mykey hello world ifver 7").unwrap() {
+        Command::SetIfVersion(key, value, version) => {
+            assert_eq!(key, "mykey");
+            assert_eq!(value, b"hello world");
+            assert_eq!(version, 7);
+        }
+        _ => panic!("Expected conditional SET command"),
+    }
+
+    // Without a numeric version, IFVER is just part of the value
+    match parse_command("set mykey hello IFVER x").unwrap() {
+        Command::Set(_, value) => assert_eq!(value, b"hello IFVER x"),
+        _ => panic!("Expected SET command"),
+    }
+}
+
+#[test]
+fn test_content_type_commands() {
+    match parse_command("set doc {\"a\": 1} ct application/json").unwrap() {
+        Command::SetWithContentType(key, value, content_type) => {
+            assert_eq!(key, "doc");
+            assert_eq!(value, b"{\"a\": 1}");
+            assert_eq!(content_type, "application/json");
+        }
+        _ => panic!("Expected SET with a content type"),
+    }
+    assert!(parse_command("set doc x CT text/plain").unwrap().is_write());
+
+    // Without a slash the type isn't a MIME type, so CT is part of the value
+    match parse_command("set doc hello CT json").unwrap() {
+        Command::Set(_, value) => assert_eq!(value, b"hello CT json"),
+        _ => panic!("Expected SET command"),
+    }
+
+    match parse_command("get doc WITHCT").unwrap() {
+        Command::GetWithContentType(key) => assert_eq!(key, "doc"),
+        _ => panic!("Expected GET WITHCT command"),
+    }
+    assert!(!parse_command("get doc withct").unwrap().is_write());
+}
+
+#[test]
+fn test_getall_command() {
+    match parse_command("getall user:*").unwrap() {
+        Command::GetAll(pattern) => assert_eq!(pattern, "user:*"),
+        _ => panic!("Expected GETALL command"),
+    }
+    assert!(parse_command("getall").is_none());
+    assert!(parse_command("getall a b").is_none());
+}
+
+#[test]
+fn test_scanvalues_command() {
+    match parse_command("scanvalues 0 count 50").unwrap() {
+        Command::ScanValues(cursor, count, pattern) => {
+            assert_eq!((cursor, count, pattern.as_str()), (0, 50, "*"));
+        }
+        _ => panic!("Expected SCANVALUES command"),
+    }
+    match parse_command("SCANVALUES 100 COUNT 10 MATCH user:*").unwrap() {
+        Command::ScanValues(cursor, count, pattern) => {
+            assert_eq!((cursor, count, pattern.as_str()), (100, 10, "user:*"));
+        }
+        _ => panic!("Expected SCANVALUES command"),
+    }
+    assert!(parse_command("scanvalues 0").is_none());
+    assert!(parse_command("scanvalues 0 count 0").is_none());
+    assert!(parse_command("scanvalues x count 10").is_none());
+    assert!(parse_command("scanvalues 0 limit 10").is_none());
+    assert!(parse_command("scanvalues 0 count 10 match").is_none());
+    assert!(parse_command("scanvalues 0 count 10 match a b").is_none());
+}
+
+#[test]
+fn test_swap_command() {
+    match parse_command("swap a b").unwrap() {
+        Command::Swap(key1, key2) => {
+            assert_eq!(key1, "a");
+            assert_eq!(key2, "b");
+        }
+        _ => panic!("Expected SWAP command"),
+    }
+    assert!(parse_command("swap a").is_none());
+    assert!(parse_command("swap a b c").is_none());
+}
+
+#[test]
+fn test_getdel_command() {
+    match parse_command("getdel job1").unwrap() {
+        Command::GetDel(key) => assert_eq!(key, "job1"),
+        _ => panic!("Expected GETDEL command"),
+    }
+    assert!(parse_command("getdel").is_none());
+    assert!(parse_command("getdel a b").is_none());
+}
+
+#[test]
+fn test_setxx_command() {
+    match parse_command("setxx key1 new value").unwrap() {
+        Command::SetXx(key, value) => {
+            assert_eq!(key, "key1");
+            assert_eq!(value, b"new value");
+        }
+        _ => panic!("Expected SETXX command"),
+    }
+    assert!(parse_command("setxx").is_none());
+}
+
+#[test]
+fn test_setgetif_command() {
+    match parse_command("setgetif key1 new value").unwrap() {
+        Command::SetGetIf(key, value) => {
+            assert_eq!(key, "key1");
+            assert_eq!(value, b"new value");
+        }
+        _ => panic!("Expected SETGETIF command"),
+    }
+    assert!(parse_command("setgetif key1 v").unwrap().is_write());
+    assert!(parse_command("setgetif").is_none());
+}
+
+#[test]
+fn test_appendline_command() {
+    match parse_command("appendline log disk full").unwrap() {
+        Command::AppendLine(key, line) => {
+            assert_eq!(key, "log");
+            assert_eq!(line, b"disk full");
+        }
+        _ => panic!("Expected APPENDLINE command"),
+    }
+    assert!(parse_command("appendline").is_none());
+}
+
+#[test]
+fn test_eval_command() {
+    match parse_command("eval IF a EQ x THEN SET b 1; get b; if c ne base64:AA== then delete c;")
+        .unwrap()
+    {
+        Command::Eval(steps) => assert_eq!(
+            steps,
+            vec![
+                EvalStep {
+                    condition: Some(EvalCondition::Equals("a".to_string(), b"x".to_vec())),
+                    action: EvalAction::Set("b".to_string(), b"1".to_vec()),
+                },
+                EvalStep {
+                    condition: None,
+                    action: EvalAction::Get("b".to_string()),
+                },
+                EvalStep {
+                    condition: Some(EvalCondition::NotEquals("c".to_string(), vec![0])),
+                    action: EvalAction::Delete("c".to_string()),
+                },
+            ]
+        ),
+        _ => panic!("Expected EVAL command"),
+    }
+
+    let err = |line: &str| try_parse_command(line).unwrap_err().to_string();
+    assert_eq!(err("eval"), "wrong number of arguments for 'EVAL'");
+    assert_eq!(err("eval ;"), "wrong number of arguments for 'EVAL'");
+    assert_eq!(
+        err("eval if a gt 1 then get a"),
+        "invalid argument 'gt' for 'EVAL'"
+    );
+    assert_eq!(
+        err("eval if a eq 1 then"),
+        "invalid argument 'if a eq 1 then' for 'EVAL'"
+    );
+    assert_eq!(
+        err("eval if a eq 1 get a"),
+        "invalid argument 'get' for 'EVAL'"
+    );
+    assert_eq!(
+        err("eval if a eq 1 else get a"),
+        "invalid argument 'else' for 'EVAL'"
+    );
+    assert_eq!(err("eval incr a"), "invalid argument 'incr' for 'EVAL'");
+    assert_eq!(err("eval get a b"), "wrong number of arguments for 'GET'");
+}
+
+#[test]
+fn test_fetchadd_command() {
+    match parse_command("fetchadd next_id 5").unwrap() {
+        Command::FetchAdd(key, delta) => {
+            assert_eq!(key, "next_id");
+            assert_eq!(delta, 5);
+        }
+        _ => panic!("Expected FETCHADD command"),
+    }
+    assert!(matches!(
+        parse_command("FETCHADD n -2").unwrap(),
+        Command::FetchAdd(_, -2)
+    ));
+    assert!(parse_command("fetchadd next_id").is_none());
+    assert!(parse_command("fetchadd next_id 1.5").is_none());
+    assert!(parse_command("fetchadd next_id 1 2").is_none());
+}
+
+#[test]
+fn test_changes_command() {
+    assert!(matches!(
+        parse_command("changes since 42").unwrap(),
+        Command::Changes(42)
+    ));
+    assert!(matches!(
+        parse_command("CHANGES SINCE 0").unwrap(),
+        Command::Changes(0)
+    ));
+    assert!(parse_command("changes").is_none());
+    assert!(parse_command("changes since").is_none());
+    assert!(parse_command("changes after 3").is_none());
+    assert!(parse_command("changes since -1").is_none());
+    assert!(parse_command("changes since 3 4").is_none());
+}
+
+#[test]
+fn test_limits_command() {
+    assert!(matches!(parse_command("limits").unwrap(), Command::Limits));
+    assert!(parse_command("LIMITS all").is_none());
+}
+
+#[test]
+fn test_setrange_command() {
+    match parse_command("setrange record 4 abc").unwrap() {
+        Command::SetRange(key, offset, bytes) => {
+            assert_eq!(key, "record");
+            assert_eq!(offset, 4);
+            assert_eq!(bytes, b"abc");
+        }
+        _ => panic!("Expected SETRANGE command"),
+    }
+    match parse_command("SETRANGE record 0 base64:AAE=").unwrap() {
+        Command::SetRange(_, 0, bytes) => assert_eq!(bytes, vec![0, 1]),
+        _ => panic!("Expected SETRANGE command"),
+    }
+    assert!(parse_command("setrange record").is_none());
+    assert!(parse_command("setrange record 4").is_none());
+    assert!(parse_command("setrange record -1 abc").is_none());
+}
+
+#[test]
+fn test_lrukeys_command() {
+    assert!(matches!(
+        parse_command("lrukeys 5").unwrap(),
+        Command::LruKeys(5)
+    ));
+    assert!(parse_command("LRUKEYS").is_none());
+    assert!(parse_command("lrukeys many").is_none());
+    assert!(parse_command("lrukeys 5 6").is_none());
+}
+
+#[test]
+fn test_decrdel_command() {
+    match parse_command("decrdel refs").unwrap() {
+        Command::DecrDel(key) => assert_eq!(key, "refs"),
+        _ => panic!("Expected DECRDEL command"),
+    }
+    assert!(parse_command("decrdel").is_none());
+    assert!(parse_command("decrdel refs 2").is_none());
+}
+
+#[test]
+fn test_incrbyfloat_command() {
+    match parse_command("incrbyfloat total -1.5").unwrap() {
+        Command::IncrByFloat(key, delta) => {
+            assert_eq!(key, "total");
+            assert_eq!(delta, -1.5);
+        }
+        _ => panic!("Expected INCRBYFLOAT command"),
+    }
+    assert!(parse_command("incrbyfloat total").is_none());
+    assert!(parse_command("incrbyfloat total abc").is_none());
+    assert!(parse_command("incrbyfloat total 1 2").is_none());
+}
+
+#[test]
+fn test_slowlog_command() {
+    assert!(matches!(
+        parse_command("slowlog").unwrap(),
+        Command::SlowLog
+    ));
+    assert!(parse_command("slowlog 10").is_none());
+}
+
+#[test]
+fn test_pause_commands() {
+    assert!(matches!(parse_command("pause").unwrap(), Command::Pause));
+    assert!(matches!(parse_command("RESUME").unwrap(), Command::Resume));
+    assert!(parse_command("pause 10").is_none());
+
+    for write in ["set k v", "delete k", "getorset k v", "swap a b", "compact"] {
+        assert!(parse_command(write).unwrap().is_write(), "{}", write);
+    }
+    for read in ["get k", "getall *", "mmeta k", "info", "pause", "resume"] {
+        assert!(!parse_command(read).unwrap().is_write(), "{}", read);
+    }
+}
+
+#[test]
+fn test_renameif_command() {
+    match parse_command("renameif old new some value").unwrap() {
+        Command::RenameIf(old, new, expected) => {
+            assert_eq!(old, "old");
+            assert_eq!(new, "new");
+            assert_eq!(expected, b"some value");
+        }
+        _ => panic!("Expected RENAMEIF command"),
+    }
+    match parse_command("RENAMEIF old new base64:AAE=").unwrap() {
+        Command::RenameIf(_, _, expected) => assert_eq!(expected, vec![0, 1]),
+        _ => panic!("Expected RENAMEIF command"),
+    }
+    assert!(parse_command("renameif old new").is_none());
+    assert!(parse_command("renameif old").is_none());
+}
+
+#[test]
+fn test_tag_commands() {
+    match parse_command("tag mykey mytag").unwrap() {
+        Command::Tag(key, tag) => {
+            assert_eq!(key, "mykey");
+            assert_eq!(tag, "mytag");
+        }
+        _ => panic!("Expected TAG command"),
+    }
+    assert!(parse_command("tag mykey").is_none());
+    assert!(parse_command("tag mykey two tags").is_none());
+
+    match parse_command("DELTAG mytag").unwrap() {
+        Command::DelTag(tag) => assert_eq!(tag, "mytag"),
+        _ => panic!("Expected DELTAG command"),
+    }
+    assert!(parse_command("deltag").is_none());
+    assert!(parse_command("deltag a b").is_none());
+}
+
+#[test]
+fn test_strip_noreply() {
+    assert_eq!(strip_noreply("SET k v NOREPLY"), ("SET k v", true));
+    assert_eq!(strip_noreply("delete k noreply"), ("delete k", true));
+    assert_eq!(strip_noreply("GET k"), ("GET k", false));
+    // The flag must be its own trailing token
+    assert_eq!(strip_noreply("SET k vNOREPLY"), ("SET k vNOREPLY", false));
+    assert_eq!(strip_noreply("NOREPLY"), ("NOREPLY", false));
+}
+
+#[test]
+fn test_randomkey_command() {
+    assert!(matches!(
+        parse_command("randomkey").unwrap(),
+        Command::RandomKey
+    ));
+    assert!(parse_command("randomkey extra").is_none());
+}
+
+#[test]
+fn test_meta_command() {
+    match parse_command("meta mykey").unwrap() {
+        Command::Meta(key) => assert_eq!(key, "mykey"),
+        _ => panic!("Expected META command"),
+    }
+    assert!(parse_command("meta").is_none());
+    assert!(parse_command("meta key1 key2").is_none());
+}
+
+#[test]
+fn test_mget_command() {
+    match parse_command("mget key1 key2  key3").unwrap() {
+        Command::MGet(keys) => assert_eq!(keys, vec!["key1", "key2", "key3"]),
+        _ => panic!("Expected MGET command"),
+    }
+    assert!(!parse_command("MGET only").unwrap().is_write());
+    assert!(parse_command("mget").is_none());
+    assert!(parse_command("mget   ").is_none());
+}
+
+#[test]
+fn test_mmeta_command() {
+    match parse_command("mmeta key1 key2  key3").unwrap() {
+        Command::MMeta(keys) => assert_eq!(keys, vec!["key1", "key2", "key3"]),
+        _ => panic!("Expected MMETA command"),
+    }
+    match parse_command("MMETA only").unwrap() {
+        Command::MMeta(keys) => assert_eq!(keys, vec!["only"]),
+        _ => panic!("Expected MMETA command"),
+    }
+    assert!(parse_command("mmeta").is_none());
+    assert!(parse_command("mmeta key1 base64key:!!").is_none());
+}
+
+#[test]
+fn test_strlen_command() {
+    match parse_command("strlen mykey").unwrap() {
+        Command::StrLen(key) => assert_eq!(key, "mykey"),
+        _ => panic!("Expected STRLEN command"),
+    }
+    assert!(parse_command("strlen").is_none());
+    assert!(parse_command("strlen a b").is_none());
+}
+
+#[test]
+fn test_getorset_command() {
+    match parse_command("getorset counter 0").unwrap() {
+        Command::GetOrSet(key, default) => {
+            assert_eq!(key, "counter");
+            assert_eq!(default, b"0");
+        }
+        _ => panic!("Expected GETORSET command"),
+    }
+    assert!(parse_command("getorset").is_none());
+}
+
+#[test]
+fn test_io_error_responses() {
+    let cases = [
+        (libc::ENOSPC, "ERROR disk full"),
+        (libc::EDQUOT, "ERROR disk full"),
+        (libc::EROFS, "ERROR read-only filesystem"),
+        (libc::EACCES, "ERROR permission denied"),
+        (libc::EPERM, "ERROR permission denied"),
+        (libc::ENOENT, "ERROR log file not found"),
+        (libc::EIO, "ERROR disk I/O error"),
+    ];
+    for (errno, expected) in cases {
+        let e = io::Error::from_raw_os_error(errno);
+        assert_eq!(Response::from_io_error(&e).to_string(), expected);
+    }
+
+    // Compaction wraps OS errors with context, which keeps the kind
+    let full = io::Error::from_raw_os_error(libc::ENOSPC);
+    let wrapped = io::Error::new(full.kind(), format!("Failed to write log: {}", full));
+    assert_eq!(
+        Response::from_io_error(&wrapped).to_string(),
+        "ERROR disk full"
+    );
+
+    // Errors the database raises itself keep their message
+    let follower = io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "database is a read-only follower",
+    );
+    assert_eq!(
+        Response::from_io_error(&follower).to_string(),
+        "ERROR database is a read-only follower"
+    );
+    assert_eq!(
+        Response::from_io_error(&io::Error::other("disk budget exceeded")).to_string(),
+        "ERROR disk budget exceeded"
+    );
+}
+
+#[test]
+fn test_base64_keys() {
+    let key = "line one\nline two";
+    let encoded = format!("base64key:{}", BASE64.encode(key));
+    assert_eq!(encode_key(key), encoded);
+    assert_eq!(decode_key(&encoded).unwrap(), key);
+
+    match parse_command(&format!("GET {}", encoded)).unwrap() {
+        Command::Get(parsed) => assert_eq!(parsed, key),
+        _ => panic!("Expected GET command"),
+    }
+    match parse_command(&format!("SET {} some value", encoded)).unwrap() {
+        Command::Set(parsed, value) => {
+            assert_eq!(parsed, key);
+            assert_eq!(value, b"some value");
+        }
+        _ => panic!("Expected SET command"),
+    }
+    match parse_command(&format!("swap plain {}", encoded)).unwrap() {
+        Command::Swap(key1, key2) => assert_eq!((key1.as_str(), key2.as_str()), ("plain", key)),
+        _ => panic!("Expected SWAP command"),
+    }
+    assert!(parse_command("GET base64key:not-base64!").is_none());
+
+    // Plain keys are untouched, and binary keys stay in canonical encoded form
+    assert_eq!(encode_key("plain"), "plain");
+    let binary = format!("base64key:{}", BASE64.encode([0xffu8, 0x00, 0xfe]));
+    assert_eq!(decode_key(&binary).unwrap(), binary);
+    assert_eq!(encode_key(&binary), binary);
+}
+
+#[test]
+fn test_datainfo_command() {
+    assert!(matches!(
+        parse_command("datainfo").unwrap(),
+        Command::DataInfo
+    ));
+    assert!(parse_command("datainfo extra").is_none());
+}
+
+#[test]
+fn test_replicaof_command() {
+    assert!(matches!(
+        parse_command("replicaof 127.0.0.1:7878").unwrap(),
+        Command::ReplicaOf(Some(primary)) if primary == "127.0.0.1:7878"
+    ));
+    assert!(matches!(
+        parse_command("REPLICAOF no one").unwrap(),
+        Command::ReplicaOf(None)
+    ));
+    assert!(parse_command("replicaof").is_none());
+    assert!(parse_command("replicaof no").is_none());
+    assert!(parse_command("replicaof no body").is_none());
+    assert!(parse_command("replicaof host:1 extra").is_none());
+    assert!(matches!(parse_command("sync").unwrap(), Command::Sync));
+    assert!(parse_command("sync now").is_none());
+    assert!(matches!(
+        parse_command("replstatus").unwrap(),
+        Command::ReplStatus
+    ));
+    assert!(parse_command("replstatus now").is_none());
+    assert!(matches!(parse_command("role").unwrap(), Command::Role));
+    assert!(parse_command("role primary").is_none());
+    match parse_command("slot user:1").unwrap() {
+        Command::Slot(key) => assert_eq!(key, "user:1"),
+        _ => panic!("Expected SLOT command"),
+    }
+    assert!(parse_command("slot").is_none());
+    assert!(parse_command("slot a b").is_none());
+}
+
+#[test]
+fn test_randomkeys_command() {
+    assert!(matches!(
+        parse_command("randomkeys 5").unwrap(),
+        Command::RandomKeys(5)
+    ));
+    assert!(parse_command("randomkeys").is_none());
+    assert!(parse_command("randomkeys -1").is_none());
+    assert!(parse_command("randomkeys 5 6").is_none());
+}
+
+#[test]
+fn test_children_command() {
+    match parse_command("children a:b").unwrap() {
+        Command::Children(prefix) => assert_eq!(prefix, "a:b"),
+        _ => panic!("Expected CHILDREN command"),
+    }
+    match parse_command("CHILDREN").unwrap() {
+        Command::Children(prefix) => assert!(prefix.is_empty()),
+        _ => panic!("Expected CHILDREN command"),
+    }
+    assert!(parse_command("children a b").is_none());
+}
+
+#[test]
+fn test_strip_idempotency_token() {
+    assert_eq!(
+        strip_idempotency_token("INCRBYFLOAT n 1 IDEM abc"),
+        ("INCRBYFLOAT n 1", Some("abc"))
+    );
+    assert_eq!(
+        strip_idempotency_token("set k v idem abc"),
+        ("set k v", Some("abc"))
+    );
+    assert_eq!(strip_idempotency_token("SET k v"), ("SET k v", None));
+    assert_eq!(strip_idempotency_token("SET k IDEM"), ("SET k IDEM", None));
+    assert_eq!(strip_idempotency_token("IDEM abc"), ("IDEM abc", None));
+}
+
+#[test]
+fn test_parse_errors() {
+    let wrong_args = |name: &str| ParseError::WrongArgCount(name.to_string());
+    let invalid =
+        |name: &str, arg: &str| ParseError::InvalidArgument(name.to_string(), arg.to_string());
+
+    assert_eq!(try_parse_command("").unwrap_err(), ParseError::Empty);
+    assert_eq!(try_parse_command("   ").unwrap_err(), ParseError::Empty);
+
+    assert_eq!(
+        try_parse_command("foo bar").unwrap_err(),
+        ParseError::UnknownCommand("FOO".to_string())
+    );
+
+    // Too few arguments, and too many
+    assert_eq!(try_parse_command("get").unwrap_err(), wrong_args("GET"));
+    assert_eq!(
+        try_parse_command("delete a b").unwrap_err(),
+        wrong_args("DELETE")
+    );
+    assert_eq!(try_parse_command("swap a").unwrap_err(), wrong_args("SWAP"));
+    assert_eq!(
+        try_parse_command("swap a b c").unwrap_err(),
+        wrong_args("SWAP")
+    );
+    assert_eq!(
+        try_parse_command("getrange k 1").unwrap_err(),
+        wrong_args("GETRANGE")
+    );
+    assert_eq!(try_parse_command("mmeta").unwrap_err(), wrong_args("MMETA"));
+    assert_eq!(
+        try_parse_command("compact now").unwrap_err(),
+        wrong_args("COMPACT")
+    );
+    // Aliases are reported under their canonical name
+    assert_eq!(try_parse_command("del").unwrap_err(), wrong_args("DELETE"));
+
+    assert_eq!(
+        try_parse_command("get base64key:%%%").unwrap_err(),
+        ParseError::InvalidBase64("base64key:%%%".to_string())
+    );
+    assert_eq!(
+        try_parse_command("mmeta a base64key:!").unwrap_err(),
+        ParseError::InvalidBase64("base64key:!".to_string())
+    );
+
+    assert_eq!(
+        try_parse_command("incrbyfloat total abc").unwrap_err(),
+        invalid("INCRBYFLOAT", "abc")
+    );
+    assert_eq!(
+        try_parse_command("getrange k 1 x").unwrap_err(),
+        invalid("GETRANGE", "x")
+    );
+    assert_eq!(
+        try_parse_command("get k as octal").unwrap_err(),
+        invalid("GET", "octal")
+    );
+    assert_eq!(
+        try_parse_command("scanvalues 0 count 0").unwrap_err(),
+        invalid("SCANVALUES", "0")
+    );
+
+    // The Option-returning parser agrees with the detailed one
+    for line in ["get", "foo", "get base64key:%%%", "randomkeys x"] {
+        assert!(parse_command(line).is_none());
+        assert!(try_parse_command(line).is_err());
+    }
+    assert!(try_parse_command("get k").is_ok());
+}
+
+#[test]
+fn test_parse_error_messages() {
+    let message = |line: &str| try_parse_command(line).unwrap_err().to_string();
+    assert_eq!(message("FOO"), "unknown command 'FOO'");
+    assert_eq!(message("get"), "wrong number of arguments for 'GET'");
+    assert_eq!(
+        message("get base64key:%"),
+        "invalid base64 in 'base64key:%'"
+    );
+    assert_eq!(
+        message("randomkeys many"),
+        "invalid argument 'many' for 'RANDOMKEYS'"
+    );
+    assert_eq!(message(""), "empty command");
+}
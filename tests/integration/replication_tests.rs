@@ -0,0 +1,206 @@
+use keystonelight::protocol::Response;
+use keystonelight::server::Server;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
+
+fn start_server(temp_dir: &tempfile::TempDir, name: &str) -> SocketAddr {
+    let server = Server::with_addr(
+        temp_dir.path().join(format!("{}.pid", name)),
+        temp_dir.path().join(format!("{}.log", name)),
+        4,
+        "127.0.0.1:0",
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+fn send(addr: SocketAddr, command: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    writeln!(stream, "{}", command).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    response.trim().to_string()
+}
+
+/// Waits up to five seconds for `command` on `addr` to reply `expected`.
+fn wait_for_reply(addr: SocketAddr, command: &str, expected: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut response = send(addr, command);
+    while response != expected && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+        response = send(addr, command);
+    }
+    assert_eq!(response, expected, "{}", command);
+}
+
+#[test]
+fn test_replica_follows_primary() {
+    let temp_dir = tempdir().unwrap();
+    let primary = start_server(&temp_dir, "primary");
+    let replica = start_server(&temp_dir, "replica");
+
+    // Data the primary had before the replica connected arrives in the full
+    // sync, and replaces whatever the replica held
+    assert_eq!(send(primary, "set before 1"), "OK");
+    assert_eq!(send(replica, "set stale 1"), "OK");
+    assert_eq!(send(replica, &format!("replicaof {}", primary)), "OK");
+    wait_for_reply(replica, "get before", "VALUE 1");
+    assert_eq!(send(replica, "get stale"), "NOT_FOUND");
+
+    // Live writes stream across as they are committed
+    assert_eq!(send(primary, "set after 2"), "OK");
+    assert_eq!(send(primary, "delete before"), "OK");
+    wait_for_reply(replica, "get after", "VALUE 2");
+    wait_for_reply(replica, "get before", "NOT_FOUND");
+
+    // A compaction on the primary triggers another full sync
+    assert_eq!(send(primary, "compact"), "OK");
+    assert_eq!(send(primary, "set compacted 3"), "OK");
+    wait_for_reply(replica, "get compacted", "VALUE 3");
+    assert_eq!(send(replica, "get after"), "VALUE 2");
+
+    // Clients can't write to a replica until it stops following
+    assert_eq!(send(replica, "set local 1"), "ERROR read-only replica");
+    assert_eq!(send(replica, "replicaof no one"), "OK");
+    assert_eq!(send(replica, "set local 1"), "OK");
+
+    assert_eq!(send(primary, "set later 4"), "OK");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(send(replica, "get later"), "NOT_FOUND");
+}
+
+#[test]
+fn test_replica_refuses_custom_write_commands() {
+    let temp_dir = tempdir().unwrap();
+    let primary = start_server(&temp_dir, "primary");
+    let mut server = Server::with_addr(
+        temp_dir.path().join("replica.pid"),
+        temp_dir.path().join("replica.log"),
+        4,
+        "127.0.0.1:0",
+    )
+    .unwrap();
+    server.register_write_command(
+        "TOUCH",
+        Box::new(|args, db| match db.set(&args[0], b"touched") {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::from_io_error(&e),
+        }),
+    );
+    let replica = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    assert_eq!(send(replica, &format!("replicaof {}", primary)), "OK");
+    assert_eq!(send(replica, "touch key1"), "ERROR read-only replica");
+    assert_eq!(send(replica, "get key1"), "NOT_FOUND");
+    assert_eq!(send(replica, "replicaof no one"), "OK");
+    assert_eq!(send(replica, "touch key1"), "OK");
+    assert_eq!(send(replica, "get key1"), "VALUE touched");
+}
+
+#[test]
+fn test_replicaof_unreachable_primary() {
+    let temp_dir = tempdir().unwrap();
+    let replica = start_server(&temp_dir, "replica");
+
+    // Grab a free port, then close it so nothing is listening there
+    let unused = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    assert!(send(replica, &format!("replicaof {}", unused)).starts_with("ERROR"));
+    assert_eq!(send(replica, "set key1 value1"), "OK");
+}
+
+/// Returns the value of `name=` in a `REPLSTATUS` reply.
+fn field<'a>(status: &'a str, name: &str) -> &'a str {
+    status
+        .split(' ')
+        .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+        .unwrap_or_else(|| panic!("missing {} in {}", name, status))
+}
+
+#[test]
+fn test_replstatus_reports_lag() {
+    let temp_dir = tempdir().unwrap();
+    let primary = start_server(&temp_dir, "primary");
+    let replica = start_server(&temp_dir, "replica");
+
+    let status = send(primary, "replstatus");
+    assert_eq!(field(&status, "role"), "primary");
+    assert_eq!(field(&status, "followers"), "0");
+
+    assert_eq!(send(primary, "set key1 value1"), "OK");
+    assert_eq!(send(replica, &format!("replicaof {}", primary)), "OK");
+    wait_for_reply(replica, "get key1", "VALUE value1");
+
+    // Once synced, the replica has applied everything the primary holds
+    let status = send(replica, "replstatus");
+    assert_eq!(field(&status, "role"), "replica");
+    assert_eq!(field(&status, "primary"), primary.to_string());
+    assert_eq!(field(&status, "link"), "up");
+    assert_eq!(field(&status, "lag_bytes"), "0");
+    assert_eq!(
+        field(&status, "applied_offset"),
+        field(&status, "primary_offset")
+    );
+    assert_eq!(field(&send(primary, "replstatus"), "followers"), "1");
+
+    // Lag shows up while a burst of writes is streaming across
+    let burst = thread::spawn(move || {
+        let mut stream = TcpStream::connect(primary).unwrap();
+        let batch: String = (0..3000)
+            .map(|i| format!("set burst{} {}\n", i, i))
+            .collect();
+        stream.write_all(batch.as_bytes()).unwrap();
+        let mut replies = BufReader::new(stream);
+        let mut line = String::new();
+        for _ in 0..3000 {
+            line.clear();
+            replies.read_line(&mut line).unwrap();
+        }
+    });
+    let mut max_lag = 0;
+    while !burst.is_finished() {
+        let status = send(replica, "replstatus");
+        max_lag = max_lag.max(field(&status, "lag_bytes").parse::<u64>().unwrap());
+    }
+    burst.join().unwrap();
+    assert!(max_lag > 0);
+
+    // ...and drains once the burst is over
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut status = send(replica, "replstatus");
+    while field(&status, "lag_bytes") != "0" && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+        status = send(replica, "replstatus");
+    }
+    assert_eq!(field(&status, "lag_bytes"), "0", "{}", status);
+    assert_eq!(send(replica, "get burst2999"), "VALUE 2999");
+}
+
+#[test]
+fn test_role_reports_primary_or_replica() {
+    let temp_dir = tempdir().unwrap();
+    let primary = start_server(&temp_dir, "primary");
+    let replica = start_server(&temp_dir, "replica");
+
+    assert_eq!(send(primary, "role"), "ROLE primary");
+    assert_eq!(send(replica, "ROLE"), "ROLE primary");
+
+    assert_eq!(send(replica, &format!("replicaof {}", primary)), "OK");
+    assert_eq!(send(replica, "role"), format!("ROLE replica {}", primary));
+    // Streaming to a replica doesn't change the primary's role
+    assert_eq!(send(primary, "role"), "ROLE primary");
+
+    assert_eq!(send(replica, "replicaof no one"), "OK");
+    assert_eq!(send(replica, "role"), "ROLE primary");
+}
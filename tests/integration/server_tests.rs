@@ -1,8 +1,12 @@
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use keystonelight::server::Server;
+use keystonelight::client::Client;
+use keystonelight::protocol::Response;
+use keystonelight::server::{read_capture, Hooks, SaturationPolicy, Server};
+use keystonelight::storage::Database;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -76,12 +80,11 @@ fn send_command(command: &str) -> std::io::Result<String> {
 }
 
 fn decode_response(response: &str) -> Option<String> {
-    if response.starts_with("VALUE base64:") {
-        let encoded = &response["VALUE base64:".len()..];
+    if let Some(encoded) = response.strip_prefix("VALUE base64:") {
         let decoded = BASE64.decode(encoded).ok()?;
         String::from_utf8(decoded).ok()
-    } else if response.starts_with("VALUE ") {
-        Some(response["VALUE ".len()..].to_string())
+    } else if let Some(value) = response.strip_prefix("VALUE ") {
+        Some(value.to_string())
     } else {
         Some(response.to_string())
     }
@@ -116,6 +119,52 @@ fn start_server(temp_dir: &tempfile::TempDir, num_threads: usize) -> Arc<AtomicB
     running
 }
 
+/// Starts a server on an ephemeral port with its own PID and log files,
+/// returning the address to connect to.
+fn start_isolated_server(temp_dir: &tempfile::TempDir, num_threads: usize) -> SocketAddr {
+    start_configured_server(temp_dir, num_threads, |server| server)
+}
+
+/// Like `start_isolated_server`, applying `configure` before the server runs.
+fn start_configured_server<F>(
+    temp_dir: &tempfile::TempDir,
+    num_threads: usize,
+    configure: F,
+) -> SocketAddr
+where
+    F: FnOnce(Server) -> Server,
+{
+    let test_id = Uuid::new_v4();
+    let pid_file = temp_dir
+        .path()
+        .join(format!("keystonelight-{}.pid", test_id));
+    let log_file = temp_dir
+        .path()
+        .join(format!("keystonelight-{}.log", test_id));
+
+    let server =
+        configure(Server::with_addr(&pid_file, &log_file, num_threads, "127.0.0.1:0").unwrap());
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        if let Err(e) = server.run() {
+            eprintln!("Server error: {}", e);
+        }
+    });
+    addr
+}
+
+fn send_command_to(addr: SocketAddr, command: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    writeln!(stream, "{}", command)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut response = String::new();
+    reader.read_line(&mut response)?;
+    Ok(response.trim().to_string())
+}
+
 #[test]
 fn test_server_basic_operations() {
     let temp_dir = tempdir().unwrap();
@@ -188,17 +237,26 @@ fn test_server_error_handling() {
 
     // Test invalid command
     let response = send_command("invalid command").unwrap();
-    assert_eq!(response, "ERROR Invalid command");
+    assert_eq!(response, "ERROR unknown command 'INVALID'");
 
     // Test missing arguments
     let response = send_command("get").unwrap();
-    assert_eq!(response, "ERROR Invalid command");
+    assert_eq!(response, "ERROR wrong number of arguments for 'GET'");
 
     // Clean up
     running.store(false, Ordering::SeqCst);
     thread::sleep(Duration::from_millis(500));
 }
 
+#[test]
+fn test_server_healthcheck() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let response = send_command_to(addr, "healthcheck").unwrap();
+    assert_eq!(response, "HEALTHY");
+}
+
 #[test]
 fn test_server_binary_data() {
     let temp_dir = tempdir().unwrap();
@@ -308,3 +366,2115 @@ fn test_server_thread_pool_stress() {
     running.store(false, Ordering::SeqCst);
     thread::sleep(Duration::from_millis(500));
 }
+
+#[test]
+fn test_server_getdefault() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let response = send_command_to(addr, "set present value").unwrap();
+    assert_eq!(response, "OK");
+
+    let response = send_command_to(addr, "getdefault present fallback").unwrap();
+    assert_eq!(response, "VALUE value");
+
+    // Absent keys return the default rather than NOT_FOUND
+    let response = send_command_to(addr, "getdefault absent fallback").unwrap();
+    assert_eq!(response, "VALUE fallback");
+
+    let binary = vec![0, 1, 2, 3];
+    let response = send_command_to(
+        addr,
+        &format!("getdefault absent base64:{}", BASE64.encode(&binary)),
+    )
+    .unwrap();
+    assert_eq!(response, format!("VALUE base64:{}", BASE64.encode(&binary)));
+}
+
+#[test]
+fn test_server_empty_value() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let response = send_command_to(addr, "set empty").unwrap();
+    assert_eq!(response, "OK");
+
+    // An empty value is reported as a VALUE with no payload, not NOT_FOUND
+    let mut stream = TcpStream::connect(addr).unwrap();
+    writeln!(stream, "get empty").unwrap();
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).unwrap();
+    assert_eq!(response, "VALUE \n");
+
+    let response = send_command_to(addr, "get missing").unwrap();
+    assert_eq!(response, "NOT_FOUND");
+}
+
+#[test]
+fn test_server_max_keys() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_max_keys(1));
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "set key2 value2").unwrap(),
+        "ERROR database full"
+    );
+    assert_eq!(send_command_to(addr, "set key1 updated").unwrap(), "OK");
+}
+
+#[test]
+fn test_server_quota_per_prefix() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_quota("t1:", 8));
+
+    assert_eq!(send_command_to(addr, "set t1:a 1234").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set t1:b 5678").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "set t1:c 9").unwrap(),
+        "ERROR quota exceeded"
+    );
+    assert_eq!(
+        send_command_to(addr, "set t1:a 12345").unwrap(),
+        "ERROR quota exceeded"
+    );
+    assert_eq!(send_command_to(addr, "set t2:c 123456789").unwrap(), "OK");
+
+    assert_eq!(send_command_to(addr, "delete t1:b").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set t1:c 9").unwrap(), "OK");
+
+    // Moving a value into the prefix is charged like a SET
+    assert_eq!(
+        send_command_to(addr, "swap t1:a t2:c").unwrap(),
+        "ERROR quota exceeded"
+    );
+    assert_eq!(
+        send_command_to(addr, "renameif t2:c t1:d 123456789").unwrap(),
+        "ERROR quota exceeded"
+    );
+    assert_eq!(send_command_to(addr, "get t1:a").unwrap(), "VALUE 1234");
+    assert_eq!(send_command_to(addr, "get t1:d").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(addr, "get t2:c").unwrap(),
+        "VALUE 123456789"
+    );
+    // Moves within the prefix, or that fit the budget, still work
+    assert_eq!(send_command_to(addr, "swap t1:a t1:c").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set t2:d 12").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "swap t1:a t2:d").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "renameif t2:d t1:e 9").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "set t1:f 123").unwrap(),
+        "ERROR quota exceeded"
+    );
+}
+
+#[test]
+fn test_server_set_hook_transforms_values() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| {
+        server.with_hooks(Hooks {
+            on_set: Some(Box::new(|_key, value| {
+                value.make_ascii_uppercase();
+                Ok(())
+            })),
+        })
+    });
+
+    assert_eq!(send_command_to(addr, "set key1 hello world").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "get key1").unwrap(),
+        "VALUE HELLO WORLD"
+    );
+}
+
+#[test]
+fn test_server_custom_command() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |mut server| {
+        server.register_command(
+            "REVERSE",
+            Box::new(|args, db| match args {
+                [key] => match db.get(key) {
+                    Some(mut value) => {
+                        value.reverse();
+                        Response::Value(value)
+                    }
+                    None => Response::NotFound,
+                },
+                _ => Response::Error("usage: REVERSE <key>".to_string()),
+            }),
+        );
+        server
+    });
+
+    assert_eq!(send_command_to(addr, "set key1 hello").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "reverse key1").unwrap(),
+        "VALUE olleh"
+    );
+    assert_eq!(
+        send_command_to(addr, "REVERSE missing").unwrap(),
+        "NOT_FOUND"
+    );
+    assert_eq!(
+        send_command_to(addr, "REVERSE").unwrap(),
+        "ERROR usage: REVERSE <key>"
+    );
+    // Built-ins are unaffected, and unknown commands are still rejected
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE hello");
+    assert_eq!(
+        send_command_to(addr, "UPCASE key1").unwrap(),
+        "ERROR unknown command 'UPCASE'"
+    );
+}
+
+#[test]
+fn test_server_custom_write_command_respects_pause() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |mut server| {
+        server.register_write_command(
+            "TOUCH",
+            Box::new(|args, db| match db.set(&args[0], b"touched") {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::from_io_error(&e),
+            }),
+        );
+        server.register_command(
+            "PEEK",
+            Box::new(|args, db| match db.get(&args[0]) {
+                Some(value) => Response::Value(value),
+                None => Response::NotFound,
+            }),
+        );
+        server
+    });
+
+    assert_eq!(send_command_to(addr, "pause").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "touch key1").unwrap(), "ERROR paused");
+    // Read-only custom commands still run
+    assert_eq!(send_command_to(addr, "peek key1").unwrap(), "NOT_FOUND");
+
+    assert_eq!(send_command_to(addr, "resume").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "touch key1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "peek key1").unwrap(), "VALUE touched");
+}
+
+#[test]
+fn test_server_set_hook_rejects_values() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| {
+        server.with_hooks(Hooks {
+            on_set: Some(Box::new(|_key, value| {
+                if value.starts_with(b"{") {
+                    Ok(())
+                } else {
+                    Err("value must be a JSON object".to_string())
+                }
+            })),
+        })
+    });
+
+    assert_eq!(
+        send_command_to(addr, "set key1 plain").unwrap(),
+        "ERROR value must be a JSON object"
+    );
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "set key1 {}").unwrap(), "OK");
+
+    // EVAL can't sneak a value past the hook, and one bad value stops it all
+    assert_eq!(
+        send_command_to(addr, "eval set key2 {}; set key3 plain").unwrap(),
+        "ERROR value must be a JSON object"
+    );
+    assert_eq!(send_command_to(addr, "get key2").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "get key3").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(addr, "eval set key2 {}; get key2").unwrap(),
+        "VALUE {}"
+    );
+
+    // APPENDLINE is checked on the combined value
+    assert_eq!(
+        send_command_to(addr, "appendline key4 plain").unwrap(),
+        "ERROR value must be a JSON object"
+    );
+    assert_eq!(send_command_to(addr, "get key4").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(addr, "appendline key1 plain").unwrap(),
+        "LINES 2"
+    );
+
+    // So is SETRANGE, on the value it would leave behind
+    assert_eq!(
+        send_command_to(addr, "setrange key1 0 plain").unwrap(),
+        "ERROR value must be a JSON object"
+    );
+    assert_eq!(
+        send_command_to(addr, "setrange key5 0 plain").unwrap(),
+        "ERROR value must be a JSON object"
+    );
+    assert_eq!(send_command_to(addr, "get key5").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "setrange key1 1 x").unwrap(), "LEN 8");
+    assert_eq!(
+        send_command_to(addr, "get key1").unwrap(),
+        "VALUE base64:e3gKcGxhaW4="
+    );
+}
+
+#[test]
+fn test_server_getrange() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set key1 hello world").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "getrange key1 0 11").unwrap(),
+        "VALUE hello world"
+    );
+    assert_eq!(
+        send_command_to(addr, "getrange key1 6 11").unwrap(),
+        "VALUE world"
+    );
+    assert_eq!(
+        send_command_to(addr, "getrange key1 20 30").unwrap(),
+        "VALUE"
+    );
+    assert_eq!(
+        send_command_to(addr, "getrange missing 0 5").unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_running_pid_detects_live_process() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    fs::write(&pid_file, format!("{}\n", std::process::id())).unwrap();
+
+    assert_eq!(Server::running_pid(&pid_file), Some(std::process::id()));
+    assert!(pid_file.exists());
+}
+
+#[test]
+fn test_running_pid_cleans_up_stale_file() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+
+    // A child that has already been reaped leaves a PID nothing is using
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    fs::write(&pid_file, format!("{}\n", dead_pid)).unwrap();
+
+    assert_eq!(Server::running_pid(&pid_file), None);
+    assert!(!pid_file.exists());
+
+    // Garbage in the PID file is treated as stale too
+    fs::write(&pid_file, "not a pid\n").unwrap();
+    assert_eq!(Server::running_pid(&pid_file), None);
+    assert!(!pid_file.exists());
+
+    // No PID file at all
+    assert_eq!(Server::running_pid(&pid_file), None);
+}
+
+#[test]
+fn test_running_pid_detects_reused_pid() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    // A running server records its start time next to its PID
+    let server = Server::with_addr(&pid_file, &log_file, 1, "127.0.0.1:0").unwrap();
+    let contents = fs::read_to_string(&pid_file).unwrap();
+    let fields: Vec<&str> = contents.split_whitespace().collect();
+    assert_eq!(fields.len(), 2, "{}", contents);
+    assert_eq!(fields[0], std::process::id().to_string());
+    assert_eq!(Server::running_pid(&pid_file), Some(std::process::id()));
+    drop(server);
+
+    // The PID belongs to a live process, but not to the one that wrote the
+    // file, so the file is stale and a new server may start
+    fs::write(&pid_file, format!("{} 1\n", std::process::id())).unwrap();
+    assert_eq!(Server::running_pid(&pid_file), None);
+    assert!(!pid_file.exists());
+    fs::write(&pid_file, format!("{} 1\n", std::process::id())).unwrap();
+    let server = Server::with_addr(&pid_file, &log_file, 1, "127.0.0.1:0").unwrap();
+    assert_eq!(Server::running_pid(&pid_file), Some(std::process::id()));
+    drop(server);
+
+    // A dead PID with a start time is stale too, and so is a bad start time
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    fs::write(&pid_file, format!("{} 12345\n", dead_pid)).unwrap();
+    assert_eq!(Server::running_pid(&pid_file), None);
+    assert!(!pid_file.exists());
+    fs::write(&pid_file, format!("{} soon\n", std::process::id())).unwrap();
+    assert_eq!(Server::running_pid(&pid_file), None);
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn test_reopen_signal_reopens_moved_log() {
+    let temp_dir = tempdir().unwrap();
+    // Signals go to the whole test process, so use one no other server in
+    // this binary handles instead of the default SIGHUP
+    let addr = start_configured_server(&temp_dir, 2, |server| {
+        server.with_signals(&[libc::SIGTERM, libc::SIGINT], &[libc::SIGUSR2])
+    });
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+
+    let log_file = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    let rotated = log_file.with_extension("log.1");
+
+    // Rotate the log away and ask the server to reopen it
+    fs::rename(&log_file, &rotated).unwrap();
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGUSR2);
+    }
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !log_file.exists() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "log file was not recreated"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // The recreated log carries the existing data, and new writes land in it
+    assert_eq!(send_command_to(addr, "set key2 value2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value1");
+    let contents = fs::read_to_string(&log_file).unwrap();
+    assert!(contents.contains("SET key1 value1"));
+    assert!(contents.contains("SET key2 value2"));
+    assert!(fs::read_to_string(&rotated)
+        .unwrap()
+        .contains("SET key1 value1"));
+}
+
+#[test]
+fn test_server_optimistic_locking() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set counter 1").unwrap(), "OK");
+
+    // Read with version
+    let response = send_command_to(addr, "get counter withversion").unwrap();
+    let mut parts = response.splitn(3, ' ');
+    assert_eq!(parts.next(), Some("VALUE"));
+    let version: u64 = parts.next().unwrap().parse().unwrap();
+    assert_eq!(parts.next(), Some("1"));
+
+    // Successful conditional set
+    assert_eq!(
+        send_command_to(addr, &format!("set counter 2 ifver {}", version)).unwrap(),
+        "OK"
+    );
+
+    // The successful set bumped the version, so reusing the old one conflicts
+    assert_eq!(
+        send_command_to(addr, &format!("set counter 3 ifver {}", version)).unwrap(),
+        "ERROR version mismatch"
+    );
+    assert_eq!(send_command_to(addr, "get counter").unwrap(), "VALUE 2");
+
+    assert_eq!(
+        send_command_to(addr, "get missing withversion").unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_server_get_as_encoding() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    let original = [0u8, 1, 127, 128, 255, b'\n'];
+    assert_eq!(
+        send_command_to(
+            addr,
+            &format!("set blob base64:{}", BASE64.encode(original))
+        )
+        .unwrap(),
+        "OK"
+    );
+
+    let hex = send_command_to(addr, "get blob as hex").unwrap();
+    let hex = hex.strip_prefix("VALUE ").unwrap();
+    assert_eq!(hex, "00017f80ff0a");
+    let decoded: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    assert_eq!(decoded, original);
+
+    let base64 = send_command_to(addr, "get blob as base64").unwrap();
+    let base64 = base64.strip_prefix("VALUE ").unwrap();
+    assert_eq!(BASE64.decode(base64).unwrap(), original);
+
+    // Text values are encoded too, and the override only lasts one request
+    assert_eq!(send_command_to(addr, "set text hi").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "get text as hex").unwrap(),
+        "VALUE 6869"
+    );
+    assert_eq!(send_command_to(addr, "get text").unwrap(), "VALUE hi");
+
+    assert_eq!(
+        send_command_to(addr, "get blob as raw").unwrap(),
+        "ERROR raw encoding needs binary framing"
+    );
+    assert_eq!(
+        send_command_to(addr, "get missing as hex").unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_server_getall() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    for command in [
+        "set user:1 alice",
+        "set user:2 bob smith",
+        "set session:1 token",
+        &format!("set user:3 base64:{}", BASE64.encode([0u8, 1, 2])),
+    ] {
+        assert_eq!(send_command_to(addr, command).unwrap(), "OK");
+    }
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    writeln!(stream, "getall user:*").unwrap();
+    let reader = BufReader::new(&stream);
+    let lines: Vec<String> = reader
+        .lines()
+        .map(|line| line.unwrap())
+        .take_while(|line| line != "END")
+        .collect();
+
+    assert_eq!(
+        lines,
+        vec![
+            "user:1 => alice".to_string(),
+            "user:2 => bob smith".to_string(),
+            format!("user:3 => base64:{}", BASE64.encode([0u8, 1, 2])),
+        ]
+    );
+}
+
+#[test]
+fn test_server_scanvalues_pages() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+
+    for i in 0..500 {
+        let prefix = if i % 2 == 0 { "even" } else { "odd" };
+        let response = client
+            .send_command(&format!("set {}:{:03} value{}", prefix, i, i))
+            .unwrap();
+        assert_eq!(response.trim(), "OK");
+    }
+
+    let scan_all = |client: &mut Client, filter: &str| {
+        let mut pairs = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let lines = client
+                .send_multiline_command(&format!("scanvalues {} count 50{}", cursor, filter))
+                .unwrap();
+            cursor = lines[0].strip_prefix("CURSOR ").unwrap().parse().unwrap();
+            assert!(lines.len() - 1 <= 50);
+            pairs.extend(lines[1..].iter().cloned());
+            if cursor == 0 {
+                return pairs;
+            }
+        }
+    };
+
+    let all = scan_all(&mut client, "");
+    let mut expected: Vec<String> = (0..500)
+        .map(|i| {
+            let prefix = if i % 2 == 0 { "even" } else { "odd" };
+            format!("{}:{:03} => value{}", prefix, i, i)
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(all, expected);
+
+    let odd = scan_all(&mut client, " match odd:*");
+    assert_eq!(odd.len(), 250);
+    let expected_odd: Vec<String> = expected
+        .into_iter()
+        .filter(|line| line.starts_with("odd:"))
+        .collect();
+    assert_eq!(odd, expected_odd);
+}
+
+/// Starts a single-worker server that queues no connections, and occupies
+/// its worker with an open connection.
+fn start_saturated_server(
+    temp_dir: &tempfile::TempDir,
+    policy: SaturationPolicy,
+) -> (SocketAddr, TcpStream) {
+    let addr = start_configured_server(temp_dir, 1, |server| {
+        server
+            .with_max_queued_connections(0)
+            .with_saturation_policy(policy)
+    });
+
+    let mut busy = TcpStream::connect(addr).unwrap();
+    busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    writeln!(busy, "set key1 value1").unwrap();
+    let mut response = String::new();
+    BufReader::new(&busy).read_line(&mut response).unwrap();
+    assert_eq!(response, "OK\n");
+    (addr, busy)
+}
+
+#[test]
+fn test_server_saturation_reject() {
+    let temp_dir = tempdir().unwrap();
+    let (addr, busy) = start_saturated_server(&temp_dir, SaturationPolicy::Reject);
+
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(&stream);
+    let mut response = String::new();
+    reader.read_line(&mut response).unwrap();
+    assert_eq!(response, "ERROR busy\n");
+    // The connection is closed after the error
+    response.clear();
+    assert_eq!(reader.read_line(&mut response).unwrap(), 0);
+
+    // Capacity frees up once the busy client leaves
+    drop(busy);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value1");
+}
+
+#[test]
+fn test_server_saturation_shed() {
+    let temp_dir = tempdir().unwrap();
+    let (addr, _busy) = start_saturated_server(&temp_dir, SaturationPolicy::Shed);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut response = String::new();
+    // Closed without a reply; the write may or may not land before the close
+    let _ = writeln!(stream, "get key1");
+    let read = BufReader::new(&stream).read_line(&mut response);
+    assert!(matches!(read, Ok(0)) || read.is_err(), "got {:?}", response);
+}
+
+#[test]
+fn test_server_saturation_block() {
+    let temp_dir = tempdir().unwrap();
+    let (addr, busy) = start_saturated_server(&temp_dir, SaturationPolicy::Block);
+
+    // The kernel accepts the connection, but the server won't serve it yet
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    writeln!(stream, "get key1").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut response = String::new();
+    let err = reader.read_line(&mut response).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ));
+
+    // Once the busy client leaves, the waiting one is picked up
+    drop(busy);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    reader.read_line(&mut response).unwrap();
+    assert_eq!(response, "VALUE value1\n");
+}
+
+#[test]
+fn test_server_info() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 3);
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(client.send_command("set key1 value1").unwrap().trim(), "OK");
+    assert_eq!(client.send_command("compact").unwrap().trim(), "OK");
+
+    let lines = client.send_multiline_command("info").unwrap();
+    assert_eq!(lines[0], "# Server");
+    assert!(lines.contains(&"# Storage".to_string()));
+
+    let field = |name: &str| {
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix(&format!("{}:", name)))
+            .unwrap_or_else(|| panic!("missing field {}", name))
+            .to_string()
+    };
+    field("uptime_seconds").parse::<u64>().unwrap();
+    assert_eq!(field("version"), env!("CARGO_PKG_VERSION"));
+    assert_eq!(field("worker_threads"), "3");
+    assert_eq!(field("bind_addrs"), addr.to_string());
+    assert_eq!(field("keys"), "1");
+    assert_eq!(field("compactions"), "1");
+    assert!(field("log_bytes").parse::<usize>().unwrap() > 0);
+}
+
+#[test]
+fn test_server_verify() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "verify").unwrap(), "CONSISTENT");
+}
+
+/// A cloneable in-memory sink for capturing server log output.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[test]
+fn test_accept_log_sampling() {
+    for (sample, expected) in [(1.0, 6), (0.5, 3), (0.0, 0)] {
+        let temp_dir = tempdir().unwrap();
+        let sink = SharedBuffer::default();
+        let server_sink = sink.clone();
+        let addr = start_configured_server(&temp_dir, 2, move |server| {
+            server
+                .with_accept_log_sample(sample)
+                .with_accept_log_sink(server_sink)
+        });
+
+        for _ in 0..6 {
+            assert_eq!(send_command_to(addr, "healthcheck").unwrap(), "HEALTHY");
+        }
+
+        let lines = sink.lines();
+        assert_eq!(lines.len(), expected, "sample {}", sample);
+        for line in lines {
+            assert!(line.contains("accepted connection from 127.0.0.1:"));
+        }
+    }
+}
+
+#[test]
+fn test_server_swap() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set a 1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set b 2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "swap a b").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get a").unwrap(), "VALUE 2");
+    assert_eq!(send_command_to(addr, "get b").unwrap(), "VALUE 1");
+    assert_eq!(send_command_to(addr, "swap x y").unwrap(), "NOT_FOUND");
+}
+
+#[test]
+fn test_server_max_request_bytes() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_max_request_bytes(64));
+
+    // Requests within the budget work as usual
+    let value = "x".repeat(50);
+    assert_eq!(
+        send_command_to(addr, &format!("set key1 {}", value)).unwrap(),
+        "OK"
+    );
+
+    // A huge request with no newline is rejected once the budget is spent,
+    // and the connection is closed
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(b"SET key2 ").unwrap();
+    stream.write_all(&[b'y'; 1024]).unwrap();
+    stream.flush().unwrap();
+
+    let mut reader = BufReader::new(&stream);
+    let mut response = String::new();
+    reader.read_line(&mut response).unwrap();
+    assert_eq!(response.trim(), "ERROR request too large");
+    let mut rest = String::new();
+    assert_eq!(reader.read_line(&mut rest).unwrap_or(0), 0);
+
+    assert_eq!(send_command_to(addr, "get key2").unwrap(), "NOT_FOUND");
+}
+
+/// Sends `request` without a newline and returns the reply lines up to the
+/// connection closing.
+fn send_unterminated(addr: SocketAddr, request: &[u8]) -> Vec<String> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(request).unwrap();
+    stream.flush().unwrap();
+    BufReader::new(&stream)
+        .lines()
+        .map_while(Result::ok)
+        .collect()
+}
+
+#[test]
+fn test_server_max_request_bytes_mid_character() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_max_request_bytes(64));
+
+    // The byte past the limit is the first half of a two-byte character
+    let mut request = format!("SET key3 {}", "x".repeat(55)).into_bytes();
+    assert_eq!(request.len(), 64);
+    request.extend("é".repeat(100).as_bytes());
+    assert_eq!(
+        send_unterminated(addr, &request),
+        vec!["ERROR request too large"]
+    );
+    assert_eq!(send_command_to(addr, "get key3").unwrap(), "NOT_FOUND");
+}
+
+#[test]
+fn test_server_max_request_bytes_ignores_claimed_length() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_max_request_bytes(64));
+
+    // Requests are newline-framed, so a header announcing a huge length is
+    // only text: the budget still cuts the request off after 64 bytes
+    let mut request = b"SET key4 LEN 18446744073709551615 ".to_vec();
+    request.extend([b'z'; 4096]);
+    assert_eq!(
+        send_unterminated(addr, &request),
+        vec!["ERROR request too large"]
+    );
+    assert_eq!(send_command_to(addr, "get key4").unwrap(), "NOT_FOUND");
+}
+
+#[test]
+fn test_server_multiple_listeners_share_storage() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let server = Server::with_addr(&pid_file, &log_file, 2, "127.0.0.1:0")
+        .unwrap()
+        .with_listener("127.0.0.1:0")
+        .unwrap();
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+    assert_eq!(server.local_addr().unwrap(), addrs[0]);
+    thread::spawn(move || server.run().unwrap());
+
+    assert_eq!(
+        send_command_to(addrs[0], "set shared value1").unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addrs[1], "get shared").unwrap(),
+        "VALUE value1"
+    );
+    assert_eq!(
+        send_command_to(addrs[1], "set shared value2").unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addrs[0], "get shared").unwrap(),
+        "VALUE value2"
+    );
+}
+
+#[test]
+fn test_server_getdel() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set job1 payload").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "getdel job1").unwrap(),
+        "VALUE payload"
+    );
+    assert_eq!(send_command_to(addr, "get job1").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "getdel job1").unwrap(), "NOT_FOUND");
+}
+
+#[test]
+fn test_server_base64_keys() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let key = format!("base64key:{}", BASE64.encode("user 1\nprofile"));
+    let binary_key = format!("base64key:{}", BASE64.encode([0xffu8, 0x10, 0x00]));
+    assert_eq!(
+        send_command_to(addr, &format!("set {} alice", key)).unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("set {} hash", binary_key)).unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("get {}", key)).unwrap(),
+        "VALUE alice"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("get {}", binary_key)).unwrap(),
+        "VALUE hash"
+    );
+
+    // Listings echo the keys back in encoded form
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    let mut lines = client.send_multiline_command("getall *").unwrap();
+    lines.sort();
+    let mut expected = vec![
+        format!("{} => alice", key),
+        format!("{} => hash", binary_key),
+    ];
+    expected.sort();
+    assert_eq!(lines, expected);
+
+    assert_eq!(
+        send_command_to(addr, &format!("delete {}", key)).unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("get {}", key)).unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_server_setxx() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "setxx key1 value1").unwrap(), "SET 0");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "setxx key1 value2").unwrap(), "SET 1");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value2");
+}
+
+#[test]
+fn test_server_setgetif() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "setgetif key1 value1").unwrap(),
+        "SET 1 NOT_FOUND"
+    );
+    assert_eq!(
+        send_command_to(addr, "setgetif key1 value2").unwrap(),
+        "SET 1 VALUE value1"
+    );
+    assert_eq!(
+        send_command_to(addr, "setgetif key1 value2").unwrap(),
+        "SET 0 VALUE value2"
+    );
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value2");
+}
+
+#[test]
+fn test_server_appendline() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "appendline log first line").unwrap(),
+        "LINES 1"
+    );
+    assert_eq!(
+        send_command_to(addr, "appendline log second").unwrap(),
+        "LINES 2"
+    );
+    // Multi-line values are base64-encoded so they fit on one response line
+    assert_eq!(
+        send_command_to(addr, "get log").unwrap(),
+        format!("VALUE base64:{}", BASE64.encode("first line\nsecond"))
+    );
+    assert_eq!(send_command_to(addr, "strlen log").unwrap(), "LEN 17");
+}
+
+#[test]
+fn test_server_incrbyfloat() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "incrbyfloat total 2.5").unwrap(),
+        "VALUE 2.5"
+    );
+    assert_eq!(
+        send_command_to(addr, "incrbyfloat total -0.5").unwrap(),
+        "VALUE 2"
+    );
+    assert_eq!(send_command_to(addr, "get total").unwrap(), "VALUE 2");
+
+    assert_eq!(send_command_to(addr, "set name alice").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "incrbyfloat name 1").unwrap(),
+        "ERROR not a float"
+    );
+    assert!(send_command_to(addr, "incrbyfloat total nan")
+        .unwrap()
+        .starts_with("ERROR"));
+}
+
+#[test]
+fn test_server_reports_parse_errors() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "FOO bar").unwrap(),
+        "ERROR unknown command 'FOO'"
+    );
+    assert_eq!(
+        send_command_to(addr, "delete a b").unwrap(),
+        "ERROR wrong number of arguments for 'DELETE'"
+    );
+    assert_eq!(
+        send_command_to(addr, "get base64key:%%").unwrap(),
+        "ERROR invalid base64 in 'base64key:%%'"
+    );
+    assert_eq!(
+        send_command_to(addr, "randomkeys lots").unwrap(),
+        "ERROR invalid argument 'lots' for 'RANDOMKEYS'"
+    );
+}
+
+#[test]
+fn test_server_children() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    for key in ["a:b", "a:c", "a:c:d"] {
+        assert_eq!(
+            send_command_to(addr, &format!("set {} v", key)).unwrap(),
+            "OK"
+        );
+    }
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(
+        client.send_multiline_command("CHILDREN a:").unwrap(),
+        ["CHILD b", "CHILD c"]
+    );
+    assert_eq!(
+        client.send_multiline_command("CHILDREN").unwrap(),
+        ["CHILD a"]
+    );
+    assert!(client
+        .send_multiline_command("CHILDREN nothing")
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_server_children_with_custom_separator() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_key_separator("/"));
+    for key in ["users/1/name", "users/2/name", "users:3"] {
+        assert_eq!(
+            send_command_to(addr, &format!("set {} v", key)).unwrap(),
+            "OK"
+        );
+    }
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(
+        client.send_multiline_command("CHILDREN users").unwrap(),
+        ["CHILD 1", "CHILD 2"]
+    );
+}
+
+#[test]
+fn test_server_trimlog() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key1 value2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 value3").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "delete key2").unwrap(), "OK");
+    // Three superseded lines go: two SETs of 16 bytes and a DELETE of 12
+    assert_eq!(send_command_to(addr, "trimlog").unwrap(), "RECLAIMED 44");
+    assert_eq!(send_command_to(addr, "trimlog").unwrap(), "RECLAIMED 0");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value2");
+    assert_eq!(
+        send_command_to(addr, "trimlog now").unwrap(),
+        "ERROR wrong number of arguments for 'TRIMLOG'"
+    );
+}
+
+#[test]
+fn test_server_decrdel() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set refs 2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "decrdel refs").unwrap(), "VALUE 1");
+    assert_eq!(send_command_to(addr, "get refs").unwrap(), "VALUE 1");
+    assert_eq!(send_command_to(addr, "decrdel refs").unwrap(), "DELETED 0");
+    assert_eq!(send_command_to(addr, "get refs").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "decrdel refs").unwrap(), "NOT_FOUND");
+
+    assert_eq!(send_command_to(addr, "set name alice").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "decrdel name").unwrap(),
+        "ERROR not an integer"
+    );
+}
+
+#[test]
+fn test_server_changes_since() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set a 1").unwrap(), "OK");
+    let reply = send_command_to(addr, "get a withversion").unwrap();
+    let version: u64 = reply.split(' ').nth(1).unwrap().parse().unwrap();
+
+    assert_eq!(send_command_to(addr, "set b 2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "delete a").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set b 3").unwrap(), "OK");
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(
+        client
+            .send_multiline_command(&format!("changes since {}", version))
+            .unwrap(),
+        [
+            "SET b 2".to_string(),
+            "DELETE a".to_string(),
+            "SET b 3".to_string(),
+            format!("VERSION {}", version + 3),
+        ]
+    );
+    assert_eq!(
+        client.send_multiline_command("changes since 0").unwrap(),
+        [
+            "SET a 1".to_string(),
+            "SET b 2".to_string(),
+            "DELETE a".to_string(),
+            "SET b 3".to_string(),
+            format!("VERSION {}", version + 3),
+        ]
+    );
+}
+
+#[test]
+fn test_server_setrange() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "set greeting hello").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "setrange greeting 1 ipp").unwrap(),
+        "LEN 5"
+    );
+    assert_eq!(
+        send_command_to(addr, "get greeting").unwrap(),
+        "VALUE hippo"
+    );
+    assert_eq!(
+        send_command_to(addr, "setrange greeting 5 s").unwrap(),
+        "LEN 6"
+    );
+    assert_eq!(
+        send_command_to(addr, "get greeting").unwrap(),
+        "VALUE hippos"
+    );
+    assert_eq!(
+        send_command_to(addr, "setrange blank 2 x").unwrap(),
+        "LEN 3"
+    );
+    assert_eq!(
+        send_command_to(addr, "setrange greeting many s").unwrap(),
+        "ERROR invalid argument 'many' for 'SETRANGE'"
+    );
+}
+
+#[test]
+fn test_server_lrukeys() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    for key in ["a", "b", "c", "d"] {
+        assert_eq!(
+            send_command_to(addr, &format!("set {} v", key)).unwrap(),
+            "OK"
+        );
+    }
+    assert_eq!(send_command_to(addr, "get a").unwrap(), "VALUE v");
+    assert_eq!(send_command_to(addr, "get c").unwrap(), "VALUE v");
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(
+        client.send_multiline_command("LRUKEYS 2").unwrap(),
+        ["KEY b", "KEY d"]
+    );
+}
+
+#[test]
+fn test_server_fetchadd() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(send_command_to(addr, "fetchadd id 1").unwrap(), "VALUE 0");
+    assert_eq!(send_command_to(addr, "fetchadd id 1").unwrap(), "VALUE 1");
+    assert_eq!(send_command_to(addr, "fetchadd id 100").unwrap(), "VALUE 2");
+    assert_eq!(send_command_to(addr, "get id").unwrap(), "VALUE 102");
+
+    assert_eq!(send_command_to(addr, "set name alice").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "fetchadd name 1").unwrap(),
+        "ERROR not an integer"
+    );
+    assert_eq!(
+        send_command_to(addr, "fetchadd id lots").unwrap(),
+        "ERROR invalid argument 'lots' for 'FETCHADD'"
+    );
+}
+
+#[test]
+fn test_server_eval() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set a x").unwrap(), "OK");
+
+    // Condition met: b is set, and the reply is the last step's
+    assert_eq!(
+        send_command_to(addr, "eval if a eq x then set b 1; get b").unwrap(),
+        "VALUE 1"
+    );
+    // Later steps see earlier ones
+    assert_eq!(
+        send_command_to(addr, "eval set a y; if a eq y then delete b").unwrap(),
+        "OK"
+    );
+    assert_eq!(send_command_to(addr, "get b").unwrap(), "NOT_FOUND");
+
+    // Condition failed: nothing runs
+    assert_eq!(
+        send_command_to(addr, "eval if a eq x then set b 2").unwrap(),
+        "SKIPPED"
+    );
+    assert_eq!(send_command_to(addr, "get b").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(addr, "eval if a ne y then set b 2; if b ne 2 then get a").unwrap(),
+        "VALUE y"
+    );
+
+    assert_eq!(
+        send_command_to(addr, "eval if a like x then set b 2").unwrap(),
+        "ERROR invalid argument 'like' for 'EVAL'"
+    );
+}
+
+#[test]
+fn test_server_randomkey() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "randomkey").unwrap(), "NOT_FOUND");
+
+    let keys = ["key1", "key2", "key3"];
+    for key in keys {
+        assert_eq!(
+            send_command_to(addr, &format!("set {} value", key)).unwrap(),
+            "OK"
+        );
+    }
+    for _ in 0..10 {
+        let response = send_command_to(addr, "randomkey").unwrap();
+        let key = response.strip_prefix("KEY ").unwrap();
+        assert!(keys.contains(&key), "unexpected key {}", key);
+    }
+}
+
+#[test]
+fn test_server_mmeta() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set key1 hello").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 hello world").unwrap(), "OK");
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    let lines = client
+        .send_multiline_command("mmeta key1 missing key2")
+        .unwrap();
+    assert_eq!(
+        lines,
+        vec![
+            "key1 size=5 ttl=-1".to_string(),
+            "missing MISSING".to_string(),
+            "key2 size=11 ttl=-1".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_server_mget() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set key1 hello").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 base64:AAEC").unwrap(), "OK");
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    let lines = client
+        .send_multiline_command("mget key1 missing key2")
+        .unwrap();
+    assert_eq!(
+        lines,
+        vec![
+            "VALUE hello".to_string(),
+            "NOT_FOUND".to_string(),
+            "VALUE base64:AAEC".to_string(),
+        ]
+    );
+    assert_eq!(
+        client.mget(&["key2", "missing", "key1"]).unwrap(),
+        vec![Some(vec![0, 1, 2]), None, Some(b"hello".to_vec())]
+    );
+
+    // At least one key is needed
+    let reply = send_command_to(addr, "mget").unwrap();
+    assert!(reply.starts_with("ERROR"), "{}", reply);
+}
+
+#[test]
+fn test_server_meta() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "meta key1").unwrap(), "NOT_FOUND");
+    assert_eq!(send_command_to(addr, "set key1 hello").unwrap(), "OK");
+
+    let timestamps = |response: &str| -> (u128, u128) {
+        let fields: Vec<&str> = response.split(' ').collect();
+        assert_eq!(&fields[..3], ["META", "size=5", "ttl=-1"]);
+        let created = fields[3].strip_prefix("created=").unwrap();
+        let modified = fields[4].strip_prefix("modified=").unwrap();
+        (created.parse().unwrap(), modified.parse().unwrap())
+    };
+    let (created, modified) = timestamps(&send_command_to(addr, "meta key1").unwrap());
+    assert_eq!(created, modified);
+
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(send_command_to(addr, "set key1 world").unwrap(), "OK");
+    let (created_after, modified_after) = timestamps(&send_command_to(addr, "meta key1").unwrap());
+    assert_eq!(created_after, created);
+    assert!(modified_after > modified);
+}
+
+#[test]
+fn test_server_strlen() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let binary = BASE64.encode([0u8, 159, 146, 150]);
+    assert_eq!(send_command_to(addr, "set text hello").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, &format!("set binary base64:{}", binary)).unwrap(),
+        "OK"
+    );
+    assert_eq!(send_command_to(addr, "set empty").unwrap(), "OK");
+
+    assert_eq!(send_command_to(addr, "strlen text").unwrap(), "LEN 5");
+    assert_eq!(send_command_to(addr, "strlen binary").unwrap(), "LEN 4");
+    assert_eq!(send_command_to(addr, "strlen empty").unwrap(), "LEN 0");
+    assert_eq!(
+        send_command_to(addr, "strlen missing").unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_large_read_buffers_transfer_large_values() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| {
+        server.with_read_buffer_size(1024 * 1024)
+    });
+
+    let mut client = Client::connect_with_buffer_size(&addr.to_string(), 1024 * 1024).unwrap();
+    let value: String = (0..2 * 1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+
+    let response = client.send_command(&format!("SET big {}", value)).unwrap();
+    assert_eq!(response.trim(), "OK");
+    let response = client.send_command("GET big").unwrap();
+    assert_eq!(response.trim_end(), format!("VALUE {}", value));
+}
+
+#[test]
+fn test_zero_read_buffer_size() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| server.with_read_buffer_size(0));
+
+    // The server falls back to a usable buffer instead of closing connections
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value1");
+
+    // The client refuses outright
+    let err = Client::connect_with_buffer_size(&addr.to_string(), 0)
+        .err()
+        .expect("a zero-size read buffer was accepted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_server_getorset() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "getorset counter 0").unwrap(),
+        "VALUE 0"
+    );
+    assert_eq!(
+        send_command_to(addr, "getorset counter 5").unwrap(),
+        "VALUE 0"
+    );
+    assert_eq!(send_command_to(addr, "get counter").unwrap(), "VALUE 0");
+}
+
+#[test]
+fn test_graceful_shutdown_flushes_and_compacts() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let server = Server::with_addr(&pid_file, &log_file, 2, "127.0.0.1:0")
+        .unwrap()
+        .with_compact_on_shutdown(true);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    for i in 0..20 {
+        assert_eq!(
+            send_command_to(addr, &format!("set key{} value{}", i % 5, i)).unwrap(),
+            "OK"
+        );
+    }
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+    assert!(!pid_file.exists());
+
+    // The log was compacted down to the live keys on the way out
+    let contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(contents.lines().count(), 5);
+
+    let db = Database::with_log_path(&log_file).unwrap();
+    for i in 15..20 {
+        assert_eq!(
+            db.get(&format!("key{}", i % 5)),
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+}
+
+#[test]
+fn test_connection_yielding_during_shutdown_is_told() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    // Every command yields the worker, so each one re-queues the connection
+    let server = Server::with_addr(&pid_file, &log_file, 2, "127.0.0.1:0")
+        .unwrap()
+        .with_max_pipeline_depth(1);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    writeln!(stream, "set key1 value1").unwrap();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "OK\n");
+
+    // Wait for the server to stop and start shutting its pool down, which
+    // waits on the worker reading from this connection
+    shutdown.shutdown();
+    for _ in 0..500 {
+        if !pid_file.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // The command is still served, but it can't queue the connection again
+    writeln!(stream, "get key1").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "VALUE value1\n");
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "ERROR shutting down\n");
+    line.clear();
+    assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_periodic_snapshots() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let snapshot_dir = temp_dir.path().join("snapshots");
+    let server = Server::with_addr(&pid_file, &log_file, 2, "127.0.0.1:0")
+        .unwrap()
+        .with_snapshots(&snapshot_dir, Duration::from_millis(50), 2);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 value2").unwrap(), "OK");
+
+    let snapshots = || -> Vec<std::path::PathBuf> {
+        let mut paths: Vec<_> = fs::read_dir(&snapshot_dir)
+            .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+            .unwrap_or_default();
+        paths.sort();
+        paths
+    };
+
+    // Let several intervals pass, enough for older snapshots to be pruned
+    thread::sleep(Duration::from_millis(400));
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+
+    let paths = snapshots();
+    assert!(!paths.is_empty());
+    assert!(paths.len() <= 2, "kept {:?}", paths);
+    for path in &paths {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("snapshot-") && name.ends_with(".log"));
+    }
+
+    let db = Database::with_log_path(paths.last().unwrap()).unwrap();
+    assert_eq!(db.get("key1"), Some(b"value1".to_vec()));
+    assert_eq!(db.get("key2"), Some(b"value2".to_vec()));
+}
+
+#[test]
+fn test_metrics_file_accumulates_stats_lines() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let metrics_file = temp_dir.path().join("metrics.log");
+    let server = Server::with_addr(&pid_file, &log_file, 2, "127.0.0.1:0")
+        .unwrap()
+        .with_metrics_file(&metrics_file, Duration::from_millis(100));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 value2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE value1");
+
+    // Long enough for several intervals
+    thread::sleep(Duration::from_millis(450));
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+
+    let text = fs::read_to_string(&metrics_file).unwrap();
+    let lines: Vec<HashMap<&str, u128>> = text
+        .lines()
+        .map(|line| {
+            line.split(' ')
+                .map(|field| {
+                    let (name, value) = field.split_once('=').unwrap();
+                    (name, value.parse().unwrap())
+                })
+                .collect()
+        })
+        .collect();
+    assert!(lines.len() >= 2, "metrics file held {:?}", text);
+    assert!(lines.windows(2).all(|pair| pair[0]["at"] <= pair[1]["at"]));
+    // Each command is counted once, in the interval it ran in
+    assert_eq!(lines.iter().map(|line| line["ops"]).sum::<u128>(), 3);
+    let last = lines.last().unwrap();
+    assert_eq!(last["keys"], 2);
+    assert!(last["log_bytes"] > 0);
+}
+
+#[test]
+fn test_shutdown_rejects_backlogged_connections() {
+    let temp_dir = tempdir().unwrap();
+    let pid_file = temp_dir.path().join("keystonelight.pid");
+    let log_file = temp_dir.path().join("keystonelight.log");
+    // One worker and a blocking accept loop, so later clients stay queued
+    let server = Server::with_addr(&pid_file, &log_file, 1, "127.0.0.1:0")
+        .unwrap()
+        .with_max_queued_connections(0)
+        .with_saturation_policy(SaturationPolicy::Block);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let mut busy = Client::connect(&addr.to_string()).unwrap();
+    assert_eq!(busy.send_command("set key1 value1").unwrap().trim(), "OK");
+    // Held by the accept loop, waiting for the worker
+    let waiting = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    // Still in the listen backlog
+    let queued = TcpStream::connect(addr).unwrap();
+
+    shutdown.shutdown();
+    for stream in [&waiting, &queued] {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        assert_eq!(response, "ERROR shutting down\n");
+    }
+
+    drop(busy);
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_server_noreply_pipeline() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut batch = String::new();
+    for i in 0..100 {
+        batch.push_str(&format!("set key{} value{} NOREPLY\n", i, i));
+    }
+    // Errors are suppressed too, so the stream stays aligned
+    batch.push_str("bogus NOREPLY\n");
+    batch.push_str("get key99\n");
+    stream.write_all(batch.as_bytes()).unwrap();
+    stream.flush().unwrap();
+
+    let mut reader = BufReader::new(&stream);
+    let mut response = String::new();
+    reader.read_line(&mut response).unwrap();
+    assert_eq!(response, "VALUE value99\n");
+
+    // Nothing else was sent for the flagged commands
+    stream
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    response.clear();
+    assert!(reader.read_line(&mut response).is_err());
+
+    for i in [0, 42, 98] {
+        assert_eq!(
+            send_command_to(addr, &format!("get key{}", i)).unwrap(),
+            format!("VALUE value{}", i)
+        );
+    }
+}
+
+#[test]
+fn test_server_pause_and_resume() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set key1 before").unwrap(), "OK");
+
+    assert_eq!(send_command_to(addr, "pause").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "set key1 during").unwrap(),
+        "ERROR paused"
+    );
+    assert_eq!(
+        send_command_to(addr, "delete key1").unwrap(),
+        "ERROR paused"
+    );
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE before");
+
+    assert_eq!(send_command_to(addr, "resume").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key1 after").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE after");
+}
+
+#[test]
+fn test_server_pause_expires() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |server| {
+        server.with_max_pause(Duration::from_millis(200))
+    });
+
+    assert_eq!(send_command_to(addr, "pause").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "set key1 value").unwrap(),
+        "ERROR paused"
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(send_command_to(addr, "set key1 value").unwrap(), "OK");
+}
+
+#[test]
+fn test_server_tags() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    for key in ["user:1:name", "user:1:email", "user:2:name"] {
+        assert_eq!(
+            send_command_to(addr, &format!("set {} value", key)).unwrap(),
+            "OK"
+        );
+    }
+    assert_eq!(
+        send_command_to(addr, "tag user:1:name user:1").unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, "tag user:1:email user:1").unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, "tag missing user:1").unwrap(),
+        "NOT_FOUND"
+    );
+
+    assert_eq!(send_command_to(addr, "deltag user:1").unwrap(), "DELETED 2");
+    assert_eq!(
+        send_command_to(addr, "get user:1:name").unwrap(),
+        "NOT_FOUND"
+    );
+    assert_eq!(
+        send_command_to(addr, "get user:1:email").unwrap(),
+        "NOT_FOUND"
+    );
+    assert_eq!(
+        send_command_to(addr, "get user:2:name").unwrap(),
+        "VALUE value"
+    );
+    assert_eq!(send_command_to(addr, "deltag user:1").unwrap(), "DELETED 0");
+}
+
+#[test]
+fn test_server_read_snapshot() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set key1 old1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 old2").unwrap(), "OK");
+
+    let response = send_command_to(addr, "snapshot").unwrap();
+    let id: u64 = response.strip_prefix("SNAPSHOT ").unwrap().parse().unwrap();
+
+    assert_eq!(send_command_to(addr, "set key1 new1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "delete key2").unwrap(), "OK");
+
+    // The snapshot is usable from any connection
+    assert_eq!(
+        send_command_to(addr, &format!("get key1 at {}", id)).unwrap(),
+        "VALUE old1"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("get key2 at {}", id)).unwrap(),
+        "VALUE old2"
+    );
+    assert_eq!(send_command_to(addr, "get key1").unwrap(), "VALUE new1");
+
+    assert_eq!(
+        send_command_to(addr, &format!("endsnapshot {}", id)).unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("get key1 at {}", id)).unwrap(),
+        "ERROR unknown snapshot"
+    );
+    assert_eq!(
+        send_command_to(addr, &format!("endsnapshot {}", id)).unwrap(),
+        "NOT_FOUND"
+    );
+}
+
+#[test]
+fn test_server_renameif() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    assert_eq!(send_command_to(addr, "set lock:a owner 1").unwrap(), "OK");
+
+    assert_eq!(
+        send_command_to(addr, "renameif lock:a lock:b owner 2").unwrap(),
+        "ERROR value mismatch"
+    );
+    assert_eq!(
+        send_command_to(addr, "renameif missing lock:b owner 1").unwrap(),
+        "ERROR value mismatch"
+    );
+    assert_eq!(
+        send_command_to(addr, "renameif lock:a lock:b owner 1").unwrap(),
+        "OK"
+    );
+    assert_eq!(send_command_to(addr, "get lock:a").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(addr, "get lock:b").unwrap(),
+        "VALUE owner 1"
+    );
+}
+
+#[test]
+fn test_server_slowlog() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_configured_server(&temp_dir, 2, |mut server| {
+        server.register_command(
+            "NAP",
+            Box::new(|_args, _db| {
+                thread::sleep(Duration::from_millis(100));
+                Response::Ok
+            }),
+        );
+        server.with_slow_log_threshold(Duration::from_millis(50))
+    });
+
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    assert!(client.send_multiline_command("slowlog").unwrap().is_empty());
+
+    assert_eq!(client.send_command("set key1 value1").unwrap().trim(), "OK");
+    assert_eq!(client.send_command("nap secret-arg").unwrap().trim(), "OK");
+    assert_eq!(
+        client.send_command("get key1").unwrap().trim(),
+        "VALUE value1"
+    );
+
+    // Only the slow command is logged, by name and without its arguments
+    let lines = client.send_multiline_command("slowlog").unwrap();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    let fields: Vec<&str> = lines[0].split(' ').collect();
+    assert_eq!(fields[0], "NAP");
+    let micros: u128 = fields[1]
+        .strip_prefix("duration_us=")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(
+        (100_000..5_000_000).contains(&micros),
+        "implausible duration {}us",
+        micros
+    );
+    assert!(fields[2].starts_with("at="));
+}
+
+#[test]
+fn test_server_concurrent_compact_requests() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 8);
+    for i in 0..20 {
+        assert_eq!(
+            send_command_to(addr, &format!("set key{} value{}", i, i)).unwrap(),
+            "OK"
+        );
+    }
+
+    let handles: Vec<_> = (0..16)
+        .map(|_| thread::spawn(move || send_command_to(addr, "compact").unwrap()))
+        .collect();
+    for handle in handles {
+        let response = handle.join().unwrap();
+        assert!(
+            response == "OK" || response == "OK (already in progress)",
+            "{}",
+            response
+        );
+    }
+
+    assert_eq!(send_command_to(addr, "verify").unwrap(), "CONSISTENT");
+    for i in 0..20 {
+        assert_eq!(
+            send_command_to(addr, &format!("get key{}", i)).unwrap(),
+            format!("VALUE value{}", i)
+        );
+    }
+}
+
+#[test]
+fn test_datainfo_command() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("datainfo.log");
+    let server = Server::with_addr(
+        temp_dir.path().join("datainfo.pid"),
+        &log_file,
+        2,
+        "127.0.0.1:0",
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let field = |reply: &str, name: &str| {
+        reply
+            .split(' ')
+            .find_map(|field| field.strip_prefix(&format!("{}=", name)))
+            .unwrap_or_else(|| panic!("missing field {} in {}", name, reply))
+            .to_string()
+    };
+
+    let before = send_command_to(addr, "datainfo").unwrap();
+    assert_eq!(field(&before, "path"), log_file.display().to_string());
+
+    assert_eq!(send_command_to(addr, "set key1 value1").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set key2 value2").unwrap(), "OK");
+    let after = send_command_to(addr, "datainfo").unwrap();
+    let size_before: u64 = field(&before, "size").parse().unwrap();
+    let size_after: u64 = field(&after, "size").parse().unwrap();
+    assert!(size_after > size_before);
+    assert_eq!(size_after, fs::metadata(&log_file).unwrap().len());
+    assert_eq!(
+        field(&after, "entries").parse::<usize>().unwrap(),
+        field(&before, "entries").parse::<usize>().unwrap() + 2
+    );
+}
+
+#[test]
+fn test_max_pipeline_depth_prevents_starvation() {
+    let temp_dir = tempdir().unwrap();
+    // A single worker, so the pipelining client and the other one share it
+    let addr = start_configured_server(&temp_dir, 1, |server| server.with_max_pipeline_depth(8));
+    const PIPELINED: usize = 5000;
+
+    let mut pipeline = TcpStream::connect(addr).unwrap();
+    let replies = BufReader::new(pipeline.try_clone().unwrap());
+    let received = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = Arc::clone(&received);
+    let reader = thread::spawn(move || {
+        for line in replies.lines() {
+            assert_eq!(line.unwrap(), "OK");
+            if counter.fetch_add(1, Ordering::SeqCst) + 1 == PIPELINED {
+                break;
+            }
+        }
+    });
+    let batch: String = (0..PIPELINED)
+        .map(|i| format!("set pipelined{} {}\n", i, i))
+        .collect();
+    let writer = thread::spawn(move || pipeline.write_all(batch.as_bytes()).unwrap());
+
+    // Wait until the pipeline is being worked through, then cut in
+    while received.load(Ordering::SeqCst) == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(send_command_to(addr, "set other 1").unwrap(), "OK");
+    assert!(
+        received.load(Ordering::SeqCst) < PIPELINED,
+        "the other client waited for the whole pipeline"
+    );
+
+    writer.join().unwrap();
+    reader.join().unwrap();
+    assert_eq!(
+        send_command_to(addr, &format!("get pipelined{}", PIPELINED - 1)).unwrap(),
+        format!("VALUE {}", PIPELINED - 1)
+    );
+}
+
+#[test]
+fn test_require_utf8_rejects_binary_values() {
+    let temp_dir = tempdir().unwrap();
+    let strict = start_configured_server(&temp_dir, 2, |server| server.with_require_utf8(true));
+    let lenient = start_isolated_server(&temp_dir, 2);
+    let binary = format!("base64:{}", BASE64.encode([0xff, 0x00, 0xfe]));
+
+    assert_eq!(send_command_to(strict, "set text héllo").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(strict, &format!("set blob {}", binary)).unwrap(),
+        "ERROR non-utf8 value"
+    );
+    assert_eq!(send_command_to(strict, "get blob").unwrap(), "NOT_FOUND");
+    assert_eq!(
+        send_command_to(strict, "set base64key:/w== value").unwrap(),
+        "ERROR non-utf8 key"
+    );
+
+    assert_eq!(send_command_to(lenient, "set text héllo").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(lenient, &format!("set blob {}", binary)).unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(lenient, "get blob").unwrap(),
+        format!("VALUE {}", binary)
+    );
+}
+#[test]
+fn test_randomkeys_command() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+    let mut client = Client::connect(&addr.to_string()).unwrap();
+    for i in 0..20 {
+        let response = client.send_command(&format!("set key{} v", i)).unwrap();
+        assert_eq!(response.trim(), "OK");
+    }
+
+    let lines = client.send_multiline_command("randomkeys 5").unwrap();
+    assert_eq!(lines.len(), 5);
+    assert!(lines.iter().all(|line| line.starts_with("KEY key")));
+
+    // Asking for more keys than exist returns them all
+    let mut lines = client.send_multiline_command("randomkeys 50").unwrap();
+    lines.sort();
+    lines.dedup();
+    assert_eq!(lines.len(), 20);
+}
+
+#[test]
+fn test_idempotency_token_prevents_double_apply() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    // A retry with the same token gets the first reply without running again
+    let first = send_command_to(addr, "incrbyfloat counter 1 IDEM req-1").unwrap();
+    assert_eq!(first, "VALUE 1");
+    let retry = send_command_to(addr, "incrbyfloat counter 1 IDEM req-1").unwrap();
+    assert_eq!(retry, "VALUE 1");
+    assert_eq!(send_command_to(addr, "get counter").unwrap(), "VALUE 1");
+
+    // A new token runs the command again
+    let second = send_command_to(addr, "incrbyfloat counter 1 IDEM req-2").unwrap();
+    assert_eq!(second, "VALUE 2");
+
+    // The token isn't part of the stored value
+    assert_eq!(send_command_to(addr, "set k v1 IDEM req-3").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set k v2").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "set k v1 IDEM req-3").unwrap(), "OK");
+    assert_eq!(send_command_to(addr, "get k").unwrap(), "VALUE v2");
+
+    // Errors are not remembered, so a failed attempt can be retried
+    assert_eq!(send_command_to(addr, "set text abc").unwrap(), "OK");
+    let failed = send_command_to(addr, "incrbyfloat text 1 IDEM req-4").unwrap();
+    assert!(failed.starts_with("ERROR"), "{}", failed);
+    assert_eq!(send_command_to(addr, "set text 5").unwrap(), "OK");
+    let retried = send_command_to(addr, "incrbyfloat text 1 IDEM req-4").unwrap();
+    assert_eq!(retried, "VALUE 6");
+}
+
+#[test]
+fn test_capture_file_records_replayable_exchanges() {
+    let temp_dir = tempdir().unwrap();
+    let capture_path = temp_dir.path().join("keystonelight.capture");
+    let path = capture_path.clone();
+    let addr = start_configured_server(&temp_dir, 2, move |server| {
+        server.with_capture_file(&path).unwrap()
+    });
+
+    // One connection, so the capture's order is the order sent
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"SET a hello\r\nSET b base64:AAEC NOREPLY\nGET b\nGETALL *\nbogus\n")
+        .unwrap();
+    let mut reader = BufReader::new(&stream);
+    let mut last = String::new();
+    while !last.starts_with("ERROR") {
+        last.clear();
+        reader.read_line(&mut last).unwrap();
+    }
+
+    let exchanges = read_capture(&capture_path).unwrap();
+    let pairs: Vec<(&str, &str)> = exchanges
+        .iter()
+        .map(|e| (e.request.as_str(), e.response.as_str()))
+        .collect();
+    assert_eq!(
+        pairs,
+        vec![
+            ("SET a hello", "OK\n"),
+            ("SET b base64:AAEC NOREPLY", ""),
+            ("GET b", "VALUE base64:AAEC\n"),
+            ("GETALL *", "a => hello\nb => base64:AAEC\nEND\n"),
+            ("bogus", last.as_str()),
+        ]
+    );
+
+    // Replayed against a fresh server, every response matches
+    let other_dir = tempdir().unwrap();
+    let other = start_isolated_server(&other_dir, 2);
+    let mut client = Client::connect(&other.to_string()).unwrap();
+    let mut report = Vec::new();
+    assert_eq!(client.replay_capture(&exchanges, &mut report).unwrap(), 0);
+    assert!(report.is_empty());
+    assert_eq!(send_command_to(other, "get a").unwrap(), "VALUE hello");
+
+    // A response that differs from the recorded one is reported
+    fs::write(
+        &capture_path,
+        "REQUEST 5\nGET a\nRESPONSE 10\nVALUE bye\n\n",
+    )
+    .unwrap();
+    let exchanges = read_capture(&capture_path).unwrap();
+    assert_eq!(client.replay_capture(&exchanges, &mut report).unwrap(), 1);
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.starts_with("MISMATCH GET a\n"), "{}", report);
+}
+
+#[test]
+fn test_content_type_round_trip() {
+    let temp_dir = tempdir().unwrap();
+    let addr = start_isolated_server(&temp_dir, 2);
+
+    assert_eq!(
+        send_command_to(addr, "set doc {\"a\":1} CT application/json").unwrap(),
+        "OK"
+    );
+    assert_eq!(
+        send_command_to(addr, "get doc withct").unwrap(),
+        "VALUE ct=application/json {\"a\":1}"
+    );
+    // The type isn't part of the value
+    assert_eq!(send_command_to(addr, "get doc").unwrap(), "VALUE {\"a\":1}");
+
+    assert_eq!(send_command_to(addr, "set blob base64:AAEC").unwrap(), "OK");
+    assert_eq!(
+        send_command_to(addr, "get blob WITHCT").unwrap(),
+        "VALUE ct=application/octet-stream base64:AAEC"
+    );
+    assert_eq!(
+        send_command_to(addr, "get missing WITHCT").unwrap(),
+        "NOT_FOUND"
+    );
+}
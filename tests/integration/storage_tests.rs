@@ -1,12 +1,16 @@
-use keystonelight::storage::Database;
+use keystonelight::protocol::{EvalAction, EvalCondition, EvalStep, Response};
+use keystonelight::storage::{
+    Database, ImportPolicy, ImportReport, LogEntry, LogReader, TailUpdate, DEFAULT_CONTENT_TYPE,
+};
 use std::fs;
+use std::io::{self, Write};
 use std::thread;
 use std::time::Duration;
 use tempfile::tempdir;
 
 fn cleanup(log_file: &str) {
     for _ in 0..5 {
-        if let Ok(_) = fs::remove_file(log_file) {
+        if fs::remove_file(log_file).is_ok() {
             break;
         }
         thread::sleep(Duration::from_millis(200));
@@ -240,3 +244,1736 @@ fn test_log_compaction_comprehensive() {
         assert_eq!(db.get(&key), None);
     }
 }
+
+#[test]
+fn test_health_check() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let db = Database::with_log_path(log_file.to_str().unwrap()).unwrap();
+    assert!(db.health_check().is_ok());
+    // The probe file must not be left behind
+    assert!(!temp_dir.path().join("keystonelight.probe").exists());
+
+    // Removing the directory out from under the open log makes it unwritable
+    fs::remove_dir_all(temp_dir.path()).unwrap();
+    assert!(db.health_check().is_err());
+}
+
+#[test]
+fn test_compaction_leaves_no_temp_file() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let db = Database::with_log_path(log_file.to_str().unwrap()).unwrap();
+    for i in 0..20 {
+        db.set("key", format!("value{}", i).as_bytes()).unwrap();
+    }
+    db.compact().unwrap();
+
+    assert!(!temp_dir.path().join("keystonelight.tmp").exists());
+    let contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(contents, "SET key value19\n");
+}
+
+#[test]
+fn test_get_or() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let db = Database::with_log_path(log_file.to_str().unwrap()).unwrap();
+    db.set("present", b"value").unwrap();
+
+    assert_eq!(db.get_or("present", b"default"), b"value");
+    assert_eq!(db.get_or("absent", b"default"), b"default");
+}
+
+#[test]
+fn test_follower_tails_primary() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let primary = Database::with_log_path(&log_file).unwrap();
+    primary.set("key1", b"value1").unwrap();
+
+    // The follower replays what already exists when it opens
+    let follower = Database::open_follower(&log_file).unwrap();
+    assert!(follower.is_follower());
+    assert_eq!(follower.get("key1"), Some(b"value1".to_vec()));
+
+    // New writes only show up after a poll
+    primary.set("key2", b"value2").unwrap();
+    primary.delete("key1").unwrap();
+    assert_eq!(follower.get("key2"), None);
+    assert_eq!(follower.poll_updates().unwrap(), 2);
+    assert_eq!(follower.get("key1"), None);
+    assert_eq!(follower.get("key2"), Some(b"value2".to_vec()));
+    assert_eq!(follower.poll_updates().unwrap(), 0);
+
+    // Followers are read-only
+    assert!(follower.set("key3", b"value3").is_err());
+    assert_eq!(follower.get("key3"), None);
+}
+
+#[test]
+fn test_follower_recovers_from_compaction() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let primary = Database::with_log_path(&log_file).unwrap();
+    for i in 0..10 {
+        primary.set("counter", i.to_string().as_bytes()).unwrap();
+    }
+    primary.set("doomed", b"value").unwrap();
+    let follower = Database::open_follower(&log_file).unwrap();
+    assert_eq!(follower.get("counter"), Some(b"9".to_vec()));
+
+    primary.delete("doomed").unwrap();
+    primary.compact().unwrap();
+    primary.set("after", b"compaction").unwrap();
+
+    // The compacted file replaces the one being followed, so the follower
+    // rebuilds its cache from scratch
+    follower.poll_updates().unwrap();
+    assert_eq!(follower.get("counter"), Some(b"9".to_vec()));
+    assert_eq!(follower.get("doomed"), None);
+    assert_eq!(follower.get("after"), Some(b"compaction".to_vec()));
+}
+
+#[test]
+fn test_empty_value_is_distinct_from_missing() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("empty", b"").unwrap();
+    db.set("spaced", b"trailing ").unwrap();
+    assert_eq!(db.get("empty"), Some(Vec::new()));
+    assert_eq!(db.get("missing"), None);
+    drop(db);
+
+    // Empty values and trailing spaces survive a replay...
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("empty"), Some(Vec::new()));
+    assert_eq!(db.get("spaced"), Some(b"trailing ".to_vec()));
+
+    // ...and a compaction
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("empty"), Some(Vec::new()));
+
+    db.delete("empty").unwrap();
+    assert_eq!(db.get("empty"), None);
+}
+
+#[test]
+fn test_quota_limits_bytes_under_prefix() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("t1:old", b"abc").unwrap();
+    // Existing values count from the start
+    db.set_quota("t1:", 10);
+    assert_eq!(db.quota_usage("t1:"), Some(3));
+    assert_eq!(db.quota_usage("t2:"), None);
+
+    db.set("t1:a", b"1234567").unwrap();
+    assert_eq!(db.quota_usage("t1:"), Some(10));
+    let err = db.set("t1:b", b"x").unwrap_err();
+    assert_eq!(err.to_string(), "quota exceeded");
+    assert_eq!(db.get("t1:b"), None);
+
+    // Other prefixes are unaffected
+    db.set("t2:b", &[0; 100]).unwrap();
+
+    // Shrinking a value or deleting a key frees budget
+    db.set("t1:a", b"12345").unwrap();
+    assert_eq!(db.quota_usage("t1:"), Some(8));
+    db.set("t1:b", b"xy").unwrap();
+    assert!(db.set("t1:b", b"xyz").is_err());
+    db.delete("t1:old").unwrap();
+    db.set("t1:b", b"xyz").unwrap();
+    assert_eq!(db.quota_usage("t1:"), Some(8));
+
+    // The rejected writes never reached the log
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("t1:b"), Some(b"xyz".to_vec()));
+    db.set_quota("t1:", 10);
+    assert_eq!(db.quota_usage("t1:"), Some(8));
+}
+
+/// Writes a snapshot holding `shared` (tagged `hot`) and `fresh`, and opens
+/// a database that already has its own `shared` and `local`.
+fn import_fixture(temp_dir: &tempfile::TempDir) -> (Database, std::path::PathBuf) {
+    let snapshot = temp_dir.path().join("snapshot.log");
+    let source = Database::with_log_path(temp_dir.path().join("source.log")).unwrap();
+    source.set("shared", b"imported").unwrap();
+    source.tag("shared", "hot").unwrap();
+    source.set("fresh", b"imported").unwrap();
+    source.set("gone", b"imported").unwrap();
+    source.delete("gone").unwrap();
+    source.snapshot_to(&snapshot).unwrap();
+
+    let db = Database::with_log_path(temp_dir.path().join("keystonelight.log")).unwrap();
+    db.set("shared", b"existing").unwrap();
+    db.set("local", b"existing").unwrap();
+    (db, snapshot)
+}
+
+#[test]
+fn test_import_overwrite_replaces_existing_keys() {
+    let temp_dir = tempdir().unwrap();
+    let (db, snapshot) = import_fixture(&temp_dir);
+
+    let report = db.import_from(&snapshot, ImportPolicy::Overwrite).unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 1,
+            skipped: 0,
+            overwritten: 1
+        }
+    );
+    assert_eq!(db.get("shared").unwrap(), b"imported");
+    assert_eq!(db.get("fresh").unwrap(), b"imported");
+    assert_eq!(db.get("local").unwrap(), b"existing");
+    assert_eq!(db.get("gone"), None);
+    assert_eq!(db.keys_with_tag("hot"), vec!["shared".to_string()]);
+
+    // The import was logged
+    drop(db);
+    let db = Database::with_log_path(temp_dir.path().join("keystonelight.log")).unwrap();
+    assert_eq!(db.get("shared").unwrap(), b"imported");
+    assert_eq!(db.keys_with_tag("hot"), vec!["shared".to_string()]);
+}
+
+#[test]
+fn test_import_skip_keeps_existing_keys() {
+    let temp_dir = tempdir().unwrap();
+    let (db, snapshot) = import_fixture(&temp_dir);
+
+    let report = db.import_from(&snapshot, ImportPolicy::Skip).unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 1,
+            skipped: 1,
+            overwritten: 0
+        }
+    );
+    assert_eq!(db.get("shared").unwrap(), b"existing");
+    assert_eq!(db.get("fresh").unwrap(), b"imported");
+    assert!(db.keys_with_tag("hot").is_empty());
+}
+
+#[test]
+fn test_import_error_aborts_on_collision() {
+    let temp_dir = tempdir().unwrap();
+    let (db, snapshot) = import_fixture(&temp_dir);
+
+    let err = db.import_from(&snapshot, ImportPolicy::Error).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(err.to_string(), "key 'shared' already exists");
+    // Nothing was imported, not even the keys that didn't collide
+    assert_eq!(db.get("shared").unwrap(), b"existing");
+    assert_eq!(db.get("fresh"), None);
+
+    db.delete("shared").unwrap();
+    let report = db.import_from(&snapshot, ImportPolicy::Error).unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 2,
+            skipped: 0,
+            overwritten: 0
+        }
+    );
+}
+
+#[test]
+fn test_max_keys_rejects_new_keys() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+
+    let mut db = Database::with_log_path(&log_file).unwrap();
+    db.set_max_keys(Some(2));
+    db.set("key1", b"value1").unwrap();
+    db.set("key2", b"value2").unwrap();
+
+    let err = db.set("key3", b"value3").unwrap_err();
+    assert_eq!(err.to_string(), "database full");
+    assert_eq!(db.get("key3"), None);
+
+    // Updating an existing key is not an insert
+    db.set("key1", b"updated").unwrap();
+    assert_eq!(db.get("key1"), Some(b"updated".to_vec()));
+
+    // Deleting frees room for a new key
+    db.delete("key2").unwrap();
+    db.set("key3", b"value3").unwrap();
+    assert_eq!(db.get("key3"), Some(b"value3".to_vec()));
+
+    // The rejected insert never reached the log
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("key1"), Some(b"updated".to_vec()));
+    assert_eq!(db.get("key2"), None);
+    assert_eq!(db.get("key3"), Some(b"value3".to_vec()));
+}
+
+#[test]
+fn test_replay_into_sees_exact_entry_sequence() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    fs::write(
+        &log_file,
+        "SET key1 value1\nSET key2 value2\nDELETE key1\nCOMPACT\nSET key3 base64:AAEC\n",
+    )
+    .unwrap();
+
+    let mut entries = Vec::new();
+    Database::replay_into(&log_file, |entry| entries.push(entry.clone())).unwrap();
+
+    assert_eq!(
+        entries,
+        vec![
+            LogEntry::Set("key1".to_string(), b"value1".to_vec()),
+            LogEntry::Set("key2".to_string(), b"value2".to_vec()),
+            LogEntry::Delete("key1".to_string()),
+            LogEntry::Compact,
+            LogEntry::Set("key3".to_string(), vec![0, 1, 2]),
+        ]
+    );
+}
+
+#[test]
+fn test_get_range() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("key1", b"hello world").unwrap();
+
+    // Full range
+    assert_eq!(db.get_range("key1", 0, 11), Some(b"hello world".to_vec()));
+    // Partial slice
+    assert_eq!(db.get_range("key1", 6, 11), Some(b"world".to_vec()));
+    // End past the value is clamped
+    assert_eq!(db.get_range("key1", 6, 100), Some(b"world".to_vec()));
+    // Start beyond the value is empty
+    assert_eq!(db.get_range("key1", 20, 30), Some(Vec::new()));
+    // Start after end is empty
+    assert_eq!(db.get_range("key1", 5, 2), Some(Vec::new()));
+    // Missing key
+    assert_eq!(db.get_range("missing", 0, 5), None);
+}
+
+#[test]
+fn test_set_if_changed_skips_identical_writes() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    let writes = (0..100)
+        .filter(|_| db.set_if_changed("key1", b"value1").unwrap())
+        .count();
+    assert_eq!(writes, 1);
+
+    let log_contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(log_contents.lines().count(), 1);
+    assert_eq!(db.get("key1"), Some(b"value1".to_vec()));
+}
+
+#[test]
+fn test_set_get_if_changed() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // A missing key is written
+    assert_eq!(db.set_get_if_changed("key1", b"old").unwrap(), (None, true));
+
+    // A new value is written and the old one returned
+    assert_eq!(
+        db.set_get_if_changed("key1", b"new").unwrap(),
+        (Some(b"old".to_vec()), true)
+    );
+    assert_eq!(db.get("key1").unwrap(), b"new");
+
+    // The same value still comes back, but isn't written again
+    let version = db.metadata("key1").unwrap().version;
+    assert_eq!(
+        db.set_get_if_changed("key1", b"new").unwrap(),
+        (Some(b"new".to_vec()), false)
+    );
+    assert_eq!(db.metadata("key1").unwrap().version, version);
+    let log_contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(log_contents.lines().count(), 2);
+}
+
+#[test]
+fn test_dedup_writes_flag() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let mut db = Database::with_log_path(&log_file).unwrap();
+    db.set_dedup_writes(true);
+
+    for _ in 0..100 {
+        db.set("key1", b"value1").unwrap();
+    }
+    db.set("key1", b"value2").unwrap();
+
+    let log_contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(log_contents.lines().count(), 2);
+    assert_eq!(db.get("key1"), Some(b"value2".to_vec()));
+}
+
+#[test]
+fn test_versions_track_writes() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    assert_eq!(db.get_with_version("key1"), None);
+    db.set("key1", b"value1").unwrap();
+    let (_, v1) = db.get_with_version("key1").unwrap();
+
+    // Writes to other keys leave the version alone
+    db.set("key2", b"value2").unwrap();
+    assert_eq!(db.get_with_version("key1").unwrap().1, v1);
+
+    // A deleted and recreated key never gets an old version back
+    db.delete("key1").unwrap();
+    assert!(!db.set_if_version("key1", b"stale", v1).unwrap());
+    db.set("key1", b"value1").unwrap();
+    assert!(db.get_with_version("key1").unwrap().1 > v1);
+}
+
+#[test]
+fn test_scan_pairs_glob() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    for key in ["user:1", "user:22", "users", "session:1", "user:"] {
+        db.set(key, key.as_bytes()).unwrap();
+    }
+
+    let keys = |pattern: &str| -> Vec<String> {
+        db.scan_pairs(pattern)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    };
+    assert_eq!(keys("user:*"), vec!["user:", "user:1", "user:22"]);
+    assert_eq!(keys("user:?"), vec!["user:1"]);
+    assert_eq!(keys("*:1"), vec!["session:1", "user:1"]);
+    assert_eq!(keys("users"), vec!["users"]);
+    assert_eq!(keys("*").len(), 5);
+    assert!(keys("nothing*").is_empty());
+}
+
+#[test]
+fn test_verify_consistency() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("key1", b"value1").unwrap();
+    db.set("key2", b"value2").unwrap();
+    db.delete("key2").unwrap();
+    assert!(db.verify_consistency().unwrap().is_clean());
+
+    // Write behind the database's back so the cache falls out of sync
+    let mut log = fs::OpenOptions::new().append(true).open(&log_file).unwrap();
+    writeln!(log, "SET key1 tampered").unwrap();
+    writeln!(log, "SET key3 value3").unwrap();
+    drop(log);
+
+    let report = db.verify_consistency().unwrap();
+    assert!(!report.is_clean());
+    assert_eq!(report.missing, vec!["key3"]);
+    assert_eq!(report.mismatched, vec!["key1"]);
+    assert!(report.extra.is_empty());
+
+    // Losing the log entirely leaves every cached key unaccounted for
+    fs::write(&log_file, "").unwrap();
+    let report = db.verify_consistency().unwrap();
+    assert_eq!(report.extra, vec!["key1"]);
+    assert!(report.missing.is_empty());
+}
+
+#[test]
+fn test_swap() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // Present <-> present
+    db.set("a", b"1").unwrap();
+    db.set("b", b"2").unwrap();
+    assert!(db.swap("a", "b").unwrap());
+    assert_eq!(db.get("a"), Some(b"2".to_vec()));
+    assert_eq!(db.get("b"), Some(b"1".to_vec()));
+
+    // Present <-> absent moves the value and deletes the source
+    assert!(db.swap("a", "c").unwrap());
+    assert_eq!(db.get("a"), None);
+    assert_eq!(db.get("c"), Some(b"2".to_vec()));
+
+    // Absent <-> absent is a no-op
+    let log_len = fs::read_to_string(&log_file).unwrap().lines().count();
+    assert!(!db.swap("x", "y").unwrap());
+    assert_eq!(
+        fs::read_to_string(&log_file).unwrap().lines().count(),
+        log_len
+    );
+
+    // The swaps survive a restart
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("a"), None);
+    assert_eq!(db.get("b"), Some(b"1".to_vec()));
+    assert_eq!(db.get("c"), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_get_del_claims_value_once() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+
+    for round in 0..50 {
+        let key = format!("job{}", round);
+        db.set(&key, b"payload").unwrap();
+
+        let claimers: Vec<_> = (0..2)
+            .map(|_| {
+                let db = std::sync::Arc::clone(&db);
+                let key = key.clone();
+                thread::spawn(move || db.get_del(&key).unwrap())
+            })
+            .collect();
+        let claimed: Vec<_> = claimers
+            .into_iter()
+            .filter_map(|claimer| claimer.join().unwrap())
+            .collect();
+
+        assert_eq!(claimed, vec![b"payload".to_vec()]);
+        assert_eq!(db.get(&key), None);
+    }
+
+    // The deletes were logged
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("job0"), None);
+}
+
+#[test]
+fn test_auto_compact_disabled() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set_auto_compact(false);
+
+    // Rewrite a handful of keys until the log is well past the 1MB threshold
+    let value = vec![b'x'; 1024];
+    for i in 0..1500 {
+        db.set(&format!("key{}", i % 10), &value).unwrap();
+    }
+
+    let size = fs::metadata(&log_file).unwrap().len() as usize;
+    assert!(size > 1024 * 1024 + 256 * 1024);
+    assert_eq!(db.log_size(), size);
+    let contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(contents.lines().count(), 1500);
+    assert!(!contents.contains("COMPACT"));
+
+    // An explicit compaction still works and resets the size counter
+    db.compact().unwrap();
+    let size = fs::metadata(&log_file).unwrap().len() as usize;
+    assert!(size < 20 * 1024);
+    assert_eq!(db.log_size(), size);
+    assert_eq!(db.get("key3"), Some(value));
+}
+
+#[test]
+fn test_max_log_bytes_backpressure() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    let budget = 256;
+    db.set_max_log_bytes(Some(budget));
+
+    // Overwrites keep fitting, because hitting the budget forces a compaction
+    for i in 0..100 {
+        db.set("counter", format!("{:04}", i).as_bytes()).unwrap();
+        assert!(fs::metadata(&log_file).unwrap().len() as usize <= budget);
+    }
+    assert_eq!(db.get("counter"), Some(b"0099".to_vec()));
+
+    // Distinct keys eventually exhaust the budget and fail cleanly
+    let mut stored = 0;
+    let err = loop {
+        match db.set(&format!("key{}", stored), b"some value") {
+            Ok(()) => stored += 1,
+            Err(e) => break e,
+        }
+        assert!(stored < 100, "budget was never enforced");
+    };
+    assert_eq!(err.to_string(), "disk budget exceeded");
+    assert!(stored > 0);
+    assert_eq!(db.get(&format!("key{}", stored)), None);
+    assert!(fs::metadata(&log_file).unwrap().len() as usize <= budget);
+
+    // Deleting reclaims space for new writes
+    db.delete("key0").unwrap();
+    db.delete("key1").unwrap();
+    db.set(&format!("key{}", stored), b"some value").unwrap();
+}
+
+#[test]
+fn test_value_len() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("text", b"hello world").unwrap();
+    db.set("binary", &[0, 159, 146, 150, 255]).unwrap();
+    db.set("empty", b"").unwrap();
+
+    assert_eq!(db.value_len("text"), Some(11));
+    assert_eq!(db.value_len("binary"), Some(5));
+    assert_eq!(db.value_len("empty"), Some(0));
+    assert_eq!(db.value_len("missing"), None);
+}
+
+#[test]
+fn test_get_or_set_concurrent_initialization() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+
+    let callers: Vec<_> = (0..16)
+        .map(|i| {
+            let db = std::sync::Arc::clone(&db);
+            thread::spawn(move || {
+                db.get_or_set("config", format!("init{}", i).as_bytes())
+                    .unwrap()
+            })
+        })
+        .collect();
+    let values: Vec<Vec<u8>> = callers
+        .into_iter()
+        .map(|caller| caller.join().unwrap())
+        .collect();
+
+    // Everyone agrees on the winner's value
+    assert!(values.iter().all(|value| value == &values[0]));
+    assert_eq!(db.get("config"), Some(values[0].clone()));
+
+    // And it was written to the log exactly once
+    let log_contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(log_contents.lines().count(), 1);
+}
+
+#[test]
+fn test_incr_by_float() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // A missing key starts from zero
+    assert_eq!(db.incr_by_float("total", 10.5).unwrap(), 10.5);
+    assert_eq!(db.incr_by_float("total", 0.1).unwrap(), 10.6);
+    assert_eq!(db.incr_by_float("total", -5.6).unwrap(), 5.0);
+    assert_eq!(db.get("total").unwrap(), b"5");
+
+    // Integers and existing float text are both accepted
+    db.set("count", b"3").unwrap();
+    assert_eq!(db.incr_by_float("count", 1.25).unwrap(), 4.25);
+    assert_eq!(db.get("count").unwrap(), b"4.25");
+
+    db.set("name", b"alice").unwrap();
+    let err = db.incr_by_float("name", 1.0).unwrap_err();
+    assert_eq!(err.to_string(), "not a float");
+    assert_eq!(db.get("name").unwrap(), b"alice");
+
+    assert!(db.incr_by_float("total", f64::NAN).is_err());
+    assert!(db.incr_by_float("total", f64::INFINITY).is_err());
+    assert!(db.incr_by_float("total", f64::MAX).is_ok());
+    assert!(db.incr_by_float("total", f64::MAX).is_err());
+
+    // Results survive a restart
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("count").unwrap(), b"4.25");
+}
+
+#[test]
+fn test_decr_del() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // Above zero the key stays with its new count
+    db.set("refs", b"3").unwrap();
+    assert_eq!(db.decr_del("refs").unwrap(), Some(2));
+    assert_eq!(db.get("refs").unwrap(), b"2");
+    assert_eq!(db.decr_del("refs").unwrap(), Some(1));
+
+    // Reaching zero deletes it
+    assert_eq!(db.decr_del("refs").unwrap(), Some(0));
+    assert_eq!(db.get("refs"), None);
+
+    // A missing key is left missing
+    assert_eq!(db.decr_del("refs").unwrap(), None);
+    assert_eq!(db.decr_del("never").unwrap(), None);
+    assert_eq!(db.get("never"), None);
+
+    // A count already at or below zero is deleted too
+    db.set("negative", b"-4").unwrap();
+    assert_eq!(db.decr_del("negative").unwrap(), Some(-5));
+    assert_eq!(db.get("negative"), None);
+
+    db.set("name", b"alice").unwrap();
+    let err = db.decr_del("name").unwrap_err();
+    assert_eq!(err.to_string(), "not an integer");
+    assert_eq!(db.get("name").unwrap(), b"alice");
+
+    db.set("kept", b"10").unwrap();
+    db.decr_del("kept").unwrap();
+
+    // Both the decremented value and the delete are logged
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("refs"), None);
+    assert_eq!(db.get("kept").unwrap(), b"9");
+}
+
+#[test]
+fn test_decr_del_concurrent_callers_delete_once() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+    db.set("refs", b"40").unwrap();
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let db = std::sync::Arc::clone(&db);
+            thread::spawn(move || {
+                (0..10)
+                    .map(|_| db.decr_del("refs").unwrap().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut counts: Vec<i64> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    counts.sort();
+    // Every caller saw a distinct count, and only the last one deleted
+    assert_eq!(counts, (0..40).collect::<Vec<_>>());
+    assert_eq!(db.get("refs"), None);
+}
+
+#[test]
+fn test_changes_since_returns_later_changes_in_order() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("a", b"1").unwrap();
+    let (_, version) = db.get_with_version("a").unwrap();
+
+    db.set("b", b"2").unwrap();
+    db.tag("b", "hot").unwrap();
+    db.delete("a").unwrap();
+    db.set("b", b"3").unwrap();
+
+    let (changes, current) = db.changes_since(version).unwrap();
+    assert_eq!(
+        changes,
+        vec![
+            LogEntry::Set("b".to_string(), b"2".to_vec()),
+            LogEntry::Tag("b".to_string(), "hot".to_string()),
+            LogEntry::Delete("a".to_string()),
+            LogEntry::Set("b".to_string(), b"3".to_vec()),
+        ]
+    );
+    assert_eq!(current, version + 4);
+    assert!(db.changes_since(current).unwrap().0.is_empty());
+
+    // Changes from before the log was opened aren't kept
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    let err = db.changes_since(0).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let (_, current) = db.changes_since(current).unwrap();
+    db.set("c", b"4").unwrap();
+    assert_eq!(
+        db.changes_since(current).unwrap().0,
+        vec![LogEntry::Set("c".to_string(), b"4".to_vec())]
+    );
+}
+
+#[test]
+fn test_set_range_overwrites_within_value() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("record", b"aaaaaaaa").unwrap();
+    assert_eq!(db.set_range("record", 2, b"XY").unwrap(), 8);
+    assert_eq!(db.get("record").unwrap(), b"aaXYaaaa");
+    assert_eq!(db.set_range("record", 0, b"").unwrap(), 8);
+
+    // The patched value was logged
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("record").unwrap(), b"aaXYaaaa");
+}
+
+#[test]
+fn test_set_range_past_the_end_zero_fills() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("record", b"abc").unwrap();
+    // Overlapping the end extends the value
+    assert_eq!(db.set_range("record", 2, b"XYZ").unwrap(), 5);
+    assert_eq!(db.get("record").unwrap(), b"abXYZ");
+    // Starting past the end leaves a gap of zeros
+    assert_eq!(db.set_range("record", 7, b"!").unwrap(), 8);
+    assert_eq!(db.get("record").unwrap(), b"abXYZ\0\0!");
+
+    let err = db.set_range("record", usize::MAX, b"!").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(db.get("record").unwrap(), b"abXYZ\0\0!");
+}
+
+#[test]
+fn test_set_range_creates_missing_key() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    assert_eq!(db.set_range("fresh", 3, b"hi").unwrap(), 5);
+    assert_eq!(db.get("fresh").unwrap(), b"\0\0\0hi");
+    // Writing nothing doesn't create a key
+    assert_eq!(db.set_range("absent", 3, b"").unwrap(), 0);
+    assert_eq!(db.get("absent"), None);
+}
+
+#[test]
+fn test_cancelled_compaction_leaves_log_intact() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let mut log = String::new();
+    for i in 0..200_000 {
+        log.push_str(&format!("SET key{} value-{}\n", i % 1000, i));
+    }
+    fs::write(&log_file, &log).unwrap();
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+
+    let compacting = {
+        let db = std::sync::Arc::clone(&db);
+        thread::spawn(move || db.compact())
+    };
+    thread::sleep(Duration::from_millis(50));
+    let cancelled_at = std::time::Instant::now();
+    db.cancel_token().cancel();
+    let err = compacting.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+
+    // The log wasn't replaced, and the temporary file is gone
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), log);
+    assert!(!temp_dir.path().join("keystonelight.tmp").exists());
+    assert!(db.verify_consistency().is_err());
+
+    db.cancel_token().reset();
+    assert!(db.verify_consistency().unwrap().is_clean());
+    db.compact().unwrap();
+    assert_eq!(db.get("key7").unwrap(), b"value-199007");
+}
+
+#[test]
+fn test_write_past_size_limit_after_cancel_still_succeeds() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.cancel_token().cancel();
+
+    // This write crosses the size limit, so its auto-compaction is cancelled
+    let big = vec![b'x'; 1024 * 1024 + 1];
+    db.set("big", &big).unwrap();
+    assert_eq!(db.get("big").unwrap(), big);
+
+    db.cancel_token().reset();
+    assert!(db.verify_consistency().unwrap().is_clean());
+}
+
+#[test]
+fn test_lru_keys_lists_untouched_keys_first() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    for i in 0..6 {
+        db.set(&format!("key{}", i), b"value").unwrap();
+    }
+
+    // Read the even keys, most recently key0
+    for i in [4, 2, 0] {
+        db.get(&format!("key{}", i)).unwrap();
+    }
+    // Neither counts as an access
+    db.value_len("key1");
+    db.metadata("key3");
+
+    assert_eq!(db.lru_keys(3), ["key1", "key3", "key5"]);
+    assert_eq!(
+        db.lru_keys(10),
+        ["key1", "key3", "key5", "key4", "key2", "key0"]
+    );
+    assert!(db.lru_keys(0).is_empty());
+
+    // Writing a key counts as accessing it
+    db.set("key1", b"updated").unwrap();
+    assert_eq!(db.lru_keys(1), ["key3"]);
+}
+
+#[test]
+fn test_fetch_add_returns_old_value() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // A missing key starts at zero
+    assert_eq!(db.fetch_add("ids", 10).unwrap(), 0);
+    assert_eq!(db.get("ids").unwrap(), b"10");
+    assert_eq!(db.fetch_add("ids", 10).unwrap(), 10);
+    assert_eq!(db.fetch_add("ids", -5).unwrap(), 20);
+    assert_eq!(db.get("ids").unwrap(), b"15");
+
+    db.set("name", b"alice").unwrap();
+    let err = db.fetch_add("name", 1).unwrap_err();
+    assert_eq!(err.to_string(), "not an integer");
+    assert_eq!(db.get("name").unwrap(), b"alice");
+
+    db.set("max", i64::MAX.to_string().as_bytes()).unwrap();
+    assert!(db.fetch_add("max", 1).is_err());
+
+    // The new values were logged
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("ids").unwrap(), b"15");
+}
+
+#[test]
+fn test_fetch_add_concurrent_callers_get_distinct_values() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let db = std::sync::Arc::clone(&db);
+            thread::spawn(move || {
+                (0..10)
+                    .map(|_| db.fetch_add("next_id", 1).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut ids: Vec<i64> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, (0..40).collect::<Vec<_>>());
+    assert_eq!(db.get("next_id").unwrap(), b"40");
+}
+
+#[test]
+fn test_eval_applies_steps_atomically() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let mut db = Database::with_log_path(&log_file).unwrap();
+    db.set("a", b"x").unwrap();
+
+    let set_b_if_a = |expected: &[u8]| {
+        vec![EvalStep {
+            condition: Some(EvalCondition::Equals("a".to_string(), expected.to_vec())),
+            action: EvalAction::Set("b".to_string(), b"1".to_vec()),
+        }]
+    };
+    assert_eq!(db.eval(&set_b_if_a(b"other")).unwrap(), None);
+    assert_eq!(db.get("b"), None);
+    assert_eq!(db.eval(&set_b_if_a(b"x")).unwrap(), Some(Response::Ok));
+    assert_eq!(db.get("b").unwrap(), b"1");
+
+    // A failing step stops the sequence, keeping what ran before it
+    db.set_max_keys(Some(2));
+    let steps = vec![
+        EvalStep {
+            condition: None,
+            action: EvalAction::Set("a".to_string(), b"y".to_vec()),
+        },
+        EvalStep {
+            condition: None,
+            action: EvalAction::Set("c".to_string(), b"1".to_vec()),
+        },
+        EvalStep {
+            condition: None,
+            action: EvalAction::Delete("b".to_string()),
+        },
+    ];
+    assert_eq!(db.eval(&steps).unwrap_err().to_string(), "database full");
+    assert_eq!(db.get("a").unwrap(), b"y");
+    assert_eq!(db.get("b").unwrap(), b"1");
+
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("a").unwrap(), b"y");
+    assert_eq!(db.get("b").unwrap(), b"1");
+}
+
+#[test]
+fn test_children_lists_one_level() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    for key in ["a:b", "a:c", "a:c:d", "a:c:e", "ab:x", "a:", "top"] {
+        db.set(key, b"value").unwrap();
+    }
+
+    // Only the next segment, each once, and not the sibling `ab`
+    assert_eq!(db.children("a:", ":"), ["b", "c"]);
+    assert_eq!(db.children("a", ":"), ["b", "c"]);
+    assert_eq!(db.children("a:c:", ":"), ["d", "e"]);
+    assert_eq!(db.children("", ":"), ["a", "ab", "top"]);
+    assert!(db.children("a:b:", ":").is_empty());
+    assert!(db.children("missing:", ":").is_empty());
+
+    // Other separators, and none at all
+    db.set("x/y/z", b"value").unwrap();
+    assert_eq!(db.children("x", "/"), ["y"]);
+    assert_eq!(db.children("a:c", ""), [":d", ":e"]);
+}
+
+#[test]
+fn test_append_line() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // The first line gets no leading separator, and neither does an empty value
+    assert_eq!(db.append_line("events", b"one").unwrap(), 1);
+    assert_eq!(db.append_line("events", b"two").unwrap(), 2);
+    assert_eq!(db.append_line("events", b"three").unwrap(), 3);
+    assert_eq!(db.get("events").unwrap(), b"one\ntwo\nthree");
+    db.set("empty", b"").unwrap();
+    assert_eq!(db.append_line("empty", b"first").unwrap(), 1);
+    assert_eq!(db.get("empty").unwrap(), b"first");
+
+    // Multi-line values keep one log line per write and survive a restart
+    let log_contents = fs::read_to_string(&log_file).unwrap();
+    assert_eq!(log_contents.lines().count(), 5);
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("events").unwrap(), b"one\ntwo\nthree");
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("events").unwrap(), b"one\ntwo\nthree");
+}
+
+#[test]
+fn test_set_xx() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    // A missing key stays missing and nothing reaches the log
+    assert!(!db.set_xx("key1", b"value1").unwrap());
+    assert_eq!(db.get("key1"), None);
+    assert_eq!(fs::read_to_string(&log_file).unwrap(), "");
+
+    db.set("key1", b"value1").unwrap();
+    assert!(db.set_xx("key1", b"value2").unwrap());
+    assert_eq!(db.get("key1").unwrap(), b"value2");
+    assert_eq!(fs::read_to_string(&log_file).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn test_keys_with_spaces_and_newlines_survive_the_log() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("two words", b"value1").unwrap();
+    db.set("two\nlines", b"value2").unwrap();
+    db.set("gone key", b"value3").unwrap();
+    db.delete("gone key").unwrap();
+    // One log line per entry, despite the separators in the keys
+    assert_eq!(fs::read_to_string(&log_file).unwrap().lines().count(), 4);
+
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("two words").unwrap(), b"value1");
+    assert_eq!(db.get("two\nlines").unwrap(), b"value2");
+    assert_eq!(db.get("gone key"), None);
+
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("two words").unwrap(), b"value1");
+    assert_eq!(db.get("two\nlines").unwrap(), b"value2");
+}
+
+#[test]
+fn test_length_prefixed_keys_replay_exactly() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    fs::write(
+        &log_file,
+        "SETL 9 two words value with  spaces \n\
+         SETL 5 spacekey-with-no-separator\n\
+         SETL 8 gone key doomed\n\
+         DELETEL 8 gone key\n\
+         SETL 6 empty \n\
+         SETL 4 tail\n\
+         SETL x1 bad length\n\
+         SETL 99 too short\n\
+         SETL 3 abcdef\n\
+         TAGL 9 two words hot\n",
+    )
+    .unwrap();
+
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("two words").unwrap(), b"value with  spaces ");
+    assert_eq!(db.get("empty ").unwrap(), b"");
+    assert_eq!(db.get("tail").unwrap(), b"");
+    assert_eq!(db.get("gone key"), None);
+    // The byte count must land on the end of the key
+    assert_eq!(db.get("space"), None);
+    assert_eq!(db.get("abc"), None);
+    assert_eq!(db.keys_with_tag("hot"), vec!["two words".to_string()]);
+
+    // Replayed keys are written back in the usual encoding
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("two words").unwrap(), b"value with  spaces ");
+    assert!(LogEntry::from_string("SETL 9 two words v").is_some());
+}
+
+#[test]
+fn test_compaction_refused_without_free_space() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("key1", b"value1").unwrap();
+    db.set("key1", b"value2").unwrap();
+    db.set("key2", b"value3").unwrap();
+    let before = fs::read(&log_file).unwrap();
+
+    // No filesystem has this much to spare
+    db.set_compaction_reserve(u64::MAX);
+    let err = db.compact().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    assert!(
+        err.to_string()
+            .starts_with("Not enough free space to compact"),
+        "{}",
+        err
+    );
+
+    // Nothing was written: the log is as it was and no temp file is left
+    assert_eq!(fs::read(&log_file).unwrap(), before);
+    assert!(!log_file.with_extension("tmp").exists());
+    assert_eq!(db.compaction_count(), 0);
+    db.set("key3", b"value4").unwrap();
+    assert_eq!(db.get("key1").unwrap(), b"value2");
+
+    db.set_compaction_reserve(0);
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("key1").unwrap(), b"value2");
+    assert_eq!(db.get("key3").unwrap(), b"value4");
+}
+
+#[test]
+fn test_trim_log_releases_disk_space() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set_auto_compact(false);
+    let value = vec![b'x'; 8 * 1024];
+    for i in 0..200 {
+        db.set(&format!("key{}", i), &value).unwrap();
+    }
+    for i in 5..200 {
+        db.delete(&format!("key{}", i)).unwrap();
+    }
+
+    let before = fs::metadata(&log_file).unwrap();
+    let reclaimed = db.trim_log().unwrap();
+    let after = fs::metadata(&log_file).unwrap();
+    assert!(reclaimed > 195 * 8 * 1024, "reclaimed {}", reclaimed);
+    assert_eq!(after.len(), db.log_size() as u64);
+    assert!(
+        after.blocks() * 10 < before.blocks(),
+        "{} blocks before, {} after",
+        before.blocks(),
+        after.blocks()
+    );
+
+    // Nothing in this process still holds the replaced file open, which
+    // would keep its blocks allocated
+    for fd in fs::read_dir("/proc/self/fd").unwrap() {
+        if let Ok(open) = fs::metadata(fd.unwrap().path()) {
+            assert!(
+                open.ino() != before.ino() || open.dev() != before.dev(),
+                "replaced log file is still open"
+            );
+        }
+    }
+
+    for i in 0..5 {
+        assert_eq!(db.get(&format!("key{}", i)).unwrap(), value);
+    }
+    // The trimmed log is the live one: new writes land in it
+    db.set("after", b"trim").unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("after").unwrap(), b"trim");
+    assert_eq!(db.key_count(), 6);
+}
+
+#[test]
+fn test_get_many() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("a", b"1").unwrap();
+    db.set("b", &[0u8, 1, 2]).unwrap();
+    db.set("empty", b"").unwrap();
+
+    let keys = vec![
+        "b".to_string(),
+        "missing".to_string(),
+        "a".to_string(),
+        "empty".to_string(),
+        "a".to_string(),
+    ];
+    assert_eq!(
+        db.get_many(&keys),
+        vec![
+            Some(vec![0, 1, 2]),
+            None,
+            Some(b"1".to_vec()),
+            Some(Vec::new()),
+            Some(b"1".to_vec()),
+        ]
+    );
+    assert!(db.get_many::<String>(&[]).is_empty());
+}
+
+#[test]
+fn test_metadata_many() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("short", b"abc").unwrap();
+    db.set("long", &[b'x'; 100]).unwrap();
+    db.set("empty", b"").unwrap();
+
+    let metadata = db.metadata_many(&["long", "missing", "short", "empty"]);
+    let sizes: Vec<Option<usize>> = metadata.iter().map(|m| m.map(|m| m.size)).collect();
+    assert_eq!(sizes, vec![Some(100), None, Some(3), Some(0)]);
+
+    // Versions match the ones GET WITHVERSION reports
+    let (_, version) = db.get_with_version("short").unwrap();
+    assert_eq!(metadata[2].unwrap().version, version);
+}
+
+#[test]
+fn test_log_reader_while_writer_is_open() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("key1", b"value1").unwrap();
+    db.set("key2", &[0u8, 1, 2]).unwrap();
+    db.delete("key1").unwrap();
+
+    let entries: Vec<LogEntry> = LogReader::open(&log_file)
+        .unwrap()
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            LogEntry::Set("key1".to_string(), b"value1".to_vec()),
+            LogEntry::Set("key2".to_string(), vec![0, 1, 2]),
+            LogEntry::Delete("key1".to_string()),
+        ]
+    );
+
+    // Entries are read lazily, so later appends are seen by a fresh reader
+    // and a malformed line is reported without ending the iteration
+    let mut file = fs::OpenOptions::new().append(true).open(&log_file).unwrap();
+    writeln!(file, "GARBAGE").unwrap();
+    db.set("key3", b"value3").unwrap();
+    let mut reader = LogReader::open(&log_file).unwrap();
+    assert_eq!(reader.by_ref().take(3).count(), 3);
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "invalid log entry on line 4");
+    assert_eq!(
+        reader.next().unwrap().unwrap(),
+        LogEntry::Set("key3".to_string(), b"value3".to_vec())
+    );
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn test_random_key() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.random_key(), None);
+
+    let keys = ["alpha", "beta", "gamma"];
+    for key in keys {
+        db.set(key, b"value").unwrap();
+    }
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..200 {
+        let key = db.random_key().unwrap();
+        assert!(keys.contains(&key.as_str()), "unexpected key {}", key);
+        seen.insert(key);
+    }
+    // With 200 draws every key shows up, barring astronomically bad luck
+    assert_eq!(seen.len(), keys.len());
+}
+
+#[test]
+fn test_mirror_log_receives_every_write() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let mirror_file = temp_dir.path().join("mirror.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set_mirror_log(Some(&mirror_file)).unwrap();
+
+    db.set("key1", b"value1").unwrap();
+    db.set("key2", &[0u8, 1, 2]).unwrap();
+    db.delete("key1").unwrap();
+
+    let read = |path: &std::path::Path| -> Vec<LogEntry> {
+        LogReader::open(path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap()
+    };
+    assert_eq!(read(&mirror_file), read(&log_file));
+    assert_eq!(read(&mirror_file).len(), 3);
+
+    // Once mirroring stops, the mirror is left as it was
+    db.set_mirror_log(None::<&str>).unwrap();
+    db.set("key3", b"value3").unwrap();
+    assert_eq!(read(&mirror_file).len(), 3);
+    assert_eq!(read(&log_file).len(), 4);
+}
+
+#[test]
+fn test_metadata_timestamps() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("key1", b"value1").unwrap();
+    let first = db.metadata("key1").unwrap();
+    assert_eq!(first.created_at, first.last_modified);
+
+    thread::sleep(Duration::from_millis(10));
+    db.append_line("key1", b"more").unwrap();
+    let updated = db.metadata("key1").unwrap();
+    assert_eq!(updated.created_at, first.created_at);
+    assert!(updated.last_modified > first.last_modified);
+
+    thread::sleep(Duration::from_millis(10));
+    db.delete("key1").unwrap();
+    assert!(db.metadata("key1").is_none());
+    db.set("key1", b"again").unwrap();
+    let recreated = db.metadata("key1").unwrap();
+    assert!(recreated.created_at > updated.last_modified);
+    assert_eq!(recreated.created_at, recreated.last_modified);
+}
+
+#[test]
+fn test_delete_by_tag() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    {
+        let db = Database::with_log_path(&log_file).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            db.set(key, b"value").unwrap();
+        }
+        assert!(db.tag("a", "group").unwrap());
+        assert!(db.tag("b", "group").unwrap());
+        assert!(db.tag("b", "other").unwrap());
+        assert!(db.tag("c", "other").unwrap());
+        assert!(!db.tag("missing", "group").unwrap());
+
+        // Updates keep tags; deletes drop them
+        db.set("a", b"updated").unwrap();
+        db.delete("c").unwrap();
+        db.set("c", b"recreated").unwrap();
+        assert_eq!(db.keys_with_tag("group"), vec!["a", "b"]);
+        assert_eq!(db.keys_with_tag("other"), vec!["b"]);
+    }
+
+    // Tags are replayed from the log, and survive compaction
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.keys_with_tag("group"), vec!["a", "b"]);
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.keys_with_tag("group"), vec!["a", "b"]);
+
+    assert_eq!(db.delete_tag("group").unwrap(), 2);
+    assert!(db.get("a").is_none());
+    assert!(db.get("b").is_none());
+    assert_eq!(db.get("c").unwrap(), b"recreated");
+    assert_eq!(db.get("d").unwrap(), b"value");
+    assert!(db.keys_with_tag("group").is_empty());
+    // "b" carried both tags, so "other" lost its only key
+    assert!(db.keys_with_tag("other").is_empty());
+    assert_eq!(db.delete_tag("group").unwrap(), 0);
+}
+
+#[test]
+fn test_read_snapshot_is_isolated_from_writes() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    db.set("balance:a", b"100").unwrap();
+    db.set("balance:b", b"0").unwrap();
+    db.set("doomed", b"here").unwrap();
+    let snapshot = db.begin_read_snapshot();
+
+    db.set("balance:a", b"50").unwrap();
+    db.set("balance:b", b"50").unwrap();
+    db.delete("doomed").unwrap();
+    db.set("new", b"later").unwrap();
+
+    assert_eq!(db.get_at(snapshot, "balance:a").unwrap().unwrap(), b"100");
+    assert_eq!(db.get_at(snapshot, "balance:b").unwrap().unwrap(), b"0");
+    assert_eq!(db.get_at(snapshot, "doomed").unwrap().unwrap(), b"here");
+    assert!(db.get_at(snapshot, "new").unwrap().is_none());
+    assert_eq!(db.get("balance:a").unwrap(), b"50");
+
+    // Snapshots are independent and end individually
+    let second = db.begin_read_snapshot();
+    assert_ne!(second, snapshot);
+    assert_eq!(db.get_at(second, "balance:a").unwrap().unwrap(), b"50");
+    assert!(db.end_read_snapshot(snapshot));
+    assert!(!db.end_read_snapshot(snapshot));
+    let err = db.get_at(snapshot, "balance:a").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(db.get_at(second, "balance:a").is_ok());
+
+    // Snapshots past their lifetime are released on their own
+    db.set_snapshot_lifetime(Duration::from_millis(50));
+    thread::sleep(Duration::from_millis(100));
+    assert!(db.get_at(second, "balance:a").is_err());
+}
+
+#[test]
+fn test_rename_if() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    {
+        let db = Database::with_log_path(&log_file).unwrap();
+        db.set("old", b"expected value").unwrap();
+        db.set("other", b"untouched").unwrap();
+
+        // Mismatched value: nothing changes
+        assert!(!db.rename_if("old", "new", b"something else").unwrap());
+        assert_eq!(db.get("old").unwrap(), b"expected value");
+        assert!(db.get("new").is_none());
+
+        // Missing source
+        assert!(!db.rename_if("missing", "new", b"expected value").unwrap());
+        assert!(db.get("new").is_none());
+
+        // Matching value renames, overwriting an existing destination
+        db.set("new", b"stale").unwrap();
+        assert!(db.rename_if("old", "new", b"expected value").unwrap());
+        assert!(db.get("old").is_none());
+        assert_eq!(db.get("new").unwrap(), b"expected value");
+        assert_eq!(db.get("other").unwrap(), b"untouched");
+    }
+
+    // The rename is persisted
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert!(db.get("old").is_none());
+    assert_eq!(db.get("new").unwrap(), b"expected value");
+}
+
+#[test]
+fn test_concurrent_compactions() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = std::sync::Arc::new(Database::with_log_path(&log_file).unwrap());
+    for i in 0..50 {
+        db.set(&format!("key{}", i), format!("value{}", i).as_bytes())
+            .unwrap();
+    }
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let db = std::sync::Arc::clone(&db);
+            thread::spawn(move || {
+                let mut ran = 0;
+                for i in 0..10 {
+                    // Interleave writes so compactions have work to do
+                    db.set(&format!("key{}", i), format!("thread{}", t).as_bytes())
+                        .unwrap();
+                    if db.try_compact().unwrap() {
+                        ran += 1;
+                    }
+                    db.compact().unwrap();
+                }
+                ran
+            })
+        })
+        .collect();
+    let ran: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert!(ran > 0);
+
+    assert!(db.verify_consistency().unwrap().is_clean());
+    assert!(!log_file.with_extension("tmp").exists());
+    let expected: Vec<(String, Option<Vec<u8>>)> = (0..50)
+        .map(|i| {
+            let key = format!("key{}", i);
+            let value = db.get(&key);
+            (key, value)
+        })
+        .collect();
+    drop(db);
+
+    // The log reopens with every key intact
+    let db = Database::with_log_path(&log_file).unwrap();
+    for (key, value) in expected {
+        assert!(value.is_some());
+        assert_eq!(db.get(&key), value, "{}", key);
+    }
+}
+
+#[test]
+fn test_data_info_tracks_log() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("data_info.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+
+    let info = db.data_info().unwrap().unwrap();
+    assert_eq!(info.path, log_file);
+    assert_eq!((info.size, info.entries), (0, 0));
+
+    db.set("key1", b"value1").unwrap();
+    db.set("key1", b"value2").unwrap();
+    db.tag("key1", "hot").unwrap();
+    let info = db.data_info().unwrap().unwrap();
+    assert_eq!(info.size, fs::metadata(&log_file).unwrap().len());
+    assert_eq!(info.entries, 3);
+
+    // Compaction drops the overwritten SET
+    db.compact().unwrap();
+    let info = db.data_info().unwrap().unwrap();
+    assert_eq!(info.size, fs::metadata(&log_file).unwrap().len());
+    assert_eq!(info.entries, 2);
+    drop(db);
+
+    // Reopening counts what the replay found
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.data_info().unwrap().unwrap().entries, 2);
+
+    let follower = Database::open_follower(&log_file).unwrap();
+    assert!(follower.data_info().unwrap().is_none());
+}
+
+#[test]
+fn test_apply_update_persists_replicated_entries() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("apply_update.log");
+    let db = Database::with_log_path(&log_file).unwrap();
+    db.set("local", b"1").unwrap();
+
+    let snapshot = vec![
+        LogEntry::Set("key1".to_string(), b"value1".to_vec()),
+        LogEntry::Tag("key1".to_string(), "hot".to_string()),
+        LogEntry::Compact,
+    ];
+    assert_eq!(db.apply_update(TailUpdate::Reset(snapshot)).unwrap(), 2);
+    let appended = vec![
+        LogEntry::Set("key2".to_string(), Vec::new()),
+        LogEntry::Delete("key1".to_string()),
+    ];
+    assert_eq!(db.apply_update(TailUpdate::Appended(appended)).unwrap(), 2);
+    assert_eq!(db.get("key2").unwrap(), b"");
+    assert!(db.get("key1").is_none());
+    assert!(db.get("local").is_none());
+    drop(db);
+
+    // The replicated entries went through this database's own log
+    let db = Database::with_log_path(&log_file).unwrap();
+    assert_eq!(db.get("key2").unwrap(), b"");
+    assert!(db.get("key1").is_none());
+    assert!(db.get("local").is_none());
+}
+
+#[test]
+fn test_require_utf8() {
+    let temp_dir = tempdir().unwrap();
+    let mut db = Database::with_log_path(temp_dir.path().join("utf8.log")).unwrap();
+    let invalid: &[u8] = &[0x66, 0x6f, 0xff, 0x6f];
+
+    // Binary data is stored as-is by default
+    db.set("text", "naïve".as_bytes()).unwrap();
+    db.set("binary", invalid).unwrap();
+    db.set("base64key:/w==", b"binary key").unwrap();
+    assert_eq!(db.get("binary").unwrap(), invalid);
+
+    db.set_require_utf8(true);
+    db.set("text", "café".as_bytes()).unwrap();
+    assert_eq!(db.get("text").unwrap(), "café".as_bytes());
+
+    let err = db.set("binary", &[0xc3]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "non-utf8 value");
+    assert_eq!(db.get("binary").unwrap(), invalid);
+    assert!(db.append_line("text", invalid).is_err());
+
+    let err = db.set("base64key:/w==", b"value").unwrap_err();
+    assert_eq!(err.to_string(), "non-utf8 key");
+    let err = db
+        .rename_if("text", "base64key:/w==", "café".as_bytes())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "non-utf8 key");
+    let err = db.swap("text", "base64key:/g==").unwrap_err();
+    assert_eq!(err.to_string(), "non-utf8 key");
+    let err = db.swap("base64key:/g==", "text").unwrap_err();
+    assert_eq!(err.to_string(), "non-utf8 key");
+    assert_eq!(db.get("text").unwrap(), "café".as_bytes());
+    assert!(db.get("base64key:/g==").is_none());
+    // Binary keys that already exist can still be swapped
+    assert!(db.swap("text", "base64key:/w==").unwrap());
+    assert_eq!(db.get("text").unwrap(), b"binary key");
+}
+
+#[test]
+fn test_random_keys() {
+    let temp_dir = tempdir().unwrap();
+    let db = Database::with_log_path(temp_dir.path().join("random_keys.log")).unwrap();
+    assert!(db.random_keys(5).is_empty());
+
+    let inserted: std::collections::HashSet<String> =
+        (0..1000).map(|i| format!("key{}", i)).collect();
+    for key in &inserted {
+        db.set(key, b"value").unwrap();
+    }
+
+    let sample = db.random_keys(50);
+    assert_eq!(sample.len(), 50);
+    let distinct: std::collections::HashSet<&String> = sample.iter().collect();
+    assert_eq!(distinct.len(), 50);
+    assert!(sample.iter().all(|key| inserted.contains(key)));
+
+    assert_eq!(db.random_keys(5000).len(), 1000);
+    assert!(db.random_keys(0).is_empty());
+}
+
+#[test]
+fn test_open_creates_missing_log_directory() {
+    let temp_dir = tempdir().unwrap();
+    let log_path = temp_dir.path().join("data").join("nested").join("test.log");
+
+    let err = Database::open(&log_path, false).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(err.to_string().contains("log directory"), "{}", err);
+    assert!(!temp_dir.path().join("data").exists());
+
+    let db = Database::with_log_path(&log_path).unwrap();
+    assert!(log_path.is_file());
+    db.set("key1", b"value1").unwrap();
+    drop(db);
+
+    // Once the directory is there, strict opening works and sees the data
+    let db = Database::open(&log_path, false).unwrap();
+    assert_eq!(db.get("key1").unwrap(), b"value1");
+}
+
+#[test]
+fn test_content_type_is_persisted() {
+    let temp_dir = tempdir().unwrap();
+    let log_path = temp_dir.path().join("test.log");
+
+    let db = Database::with_log_path(&log_path).unwrap();
+    db.set_with_content_type("doc", b"{\"a\": 1}", "application/json")
+        .unwrap();
+    db.set("plain", b"bytes").unwrap();
+    db.set_with_content_type("page", b"<p>", "text/html")
+        .unwrap();
+    db.set("page", b"<p>new</p>").unwrap();
+    assert_eq!(
+        db.get_with_content_type("doc").unwrap(),
+        (b"{\"a\": 1}".to_vec(), "application/json".to_string())
+    );
+    assert_eq!(
+        db.get_with_content_type("plain").unwrap().1,
+        DEFAULT_CONTENT_TYPE
+    );
+    // A new value without a type drops the old value's type
+    assert_eq!(
+        db.get_with_content_type("page").unwrap().1,
+        DEFAULT_CONTENT_TYPE
+    );
+    assert!(db.get_with_content_type("missing").is_none());
+
+    let err = db
+        .set_with_content_type("doc", b"x", "text/plain; charset=utf-8")
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(db.get("doc").unwrap(), b"{\"a\": 1}");
+    drop(db);
+
+    // The type survives a restart, and a compaction
+    let db = Database::with_log_path(&log_path).unwrap();
+    assert_eq!(
+        db.get_with_content_type("doc").unwrap().1,
+        "application/json"
+    );
+    db.compact().unwrap();
+    drop(db);
+    let db = Database::with_log_path(&log_path).unwrap();
+    assert_eq!(
+        db.get_with_content_type("doc").unwrap().1,
+        "application/json"
+    );
+    assert_eq!(
+        db.get_with_content_type("page").unwrap(),
+        (b"<p>new</p>".to_vec(), DEFAULT_CONTENT_TYPE.to_string())
+    );
+}
@@ -0,0 +1,167 @@
+use keystonelight::ThreadPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Waits up to five seconds for `condition` to hold.
+fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+fn submit_sleepers(pool: &ThreadPool, count: usize, done: &Arc<AtomicUsize>) {
+    for _ in 0..count {
+        let done = Arc::clone(done);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(200));
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+}
+
+#[test]
+fn test_spawn_returns_job_result() {
+    let pool = ThreadPool::new(2);
+    let handle = pool.spawn(|| (1..=10).sum::<u32>());
+    assert_eq!(handle.join().unwrap(), 55);
+
+    // Polling hands the handle back until the job is done
+    let mut handle = pool.spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        "done".to_string()
+    });
+    let result = loop {
+        match handle.try_join() {
+            Ok(result) => break result,
+            Err(pending) => handle = pending,
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(result.unwrap(), "done");
+}
+
+#[test]
+fn test_spawn_reports_panics() {
+    let pool = ThreadPool::new(1);
+    let handle = pool.spawn(|| -> u32 { panic!("job failed") });
+    let payload = handle.join().unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+
+    // The worker survived the panic
+    assert_eq!(pool.live_workers(), 1);
+    assert_eq!(pool.spawn(|| 7).join().unwrap(), 7);
+}
+
+#[test]
+fn test_no_task_is_lost_at_shutdown() {
+    let pool = ThreadPool::new(2);
+    let handle = pool.handle();
+    let done = Arc::new(AtomicUsize::new(0));
+    submit_sleepers(&pool, 6, &done);
+
+    // Dropping the pool waits for every queued task, not just running ones
+    drop(pool);
+    assert_eq!(done.load(Ordering::SeqCst), 6);
+
+    // Later submissions are handed back rather than dropped
+    let done_later = Arc::clone(&done);
+    let rejected = handle
+        .execute(move || {
+            done_later.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap_err();
+    rejected();
+    assert_eq!(done.load(Ordering::SeqCst), 7);
+}
+
+#[test]
+fn test_bounded_queue_rejects_when_full() {
+    let mut pool = ThreadPool::new(1);
+    pool.set_max_queued(Some(1));
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // One job runs and one waits; a third is handed back
+    submit_sleepers(&pool, 1, &done);
+    assert!(wait_for(|| pool.try_execute(|| {}).is_ok()));
+    let rejected = pool.try_execute(|| {});
+    assert!(rejected.is_err());
+
+    // Once the queue drains there is room again
+    assert!(wait_for(|| done.load(Ordering::SeqCst) == 1));
+    let counter = Arc::clone(&done);
+    assert!(wait_for(|| {
+        let counter = Arc::clone(&counter);
+        pool.try_execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .is_ok()
+    }));
+    assert!(wait_for(|| done.load(Ordering::SeqCst) == 2));
+}
+
+#[test]
+fn test_fixed_pool_keeps_all_workers() {
+    let pool = ThreadPool::new(3);
+    assert_eq!(pool.live_workers(), 3);
+    assert_eq!(pool.max_workers(), 3);
+}
+
+#[test]
+fn test_elastic_pool_shrinks_and_grows() {
+    let pool = ThreadPool::elastic(1, 4, Duration::from_millis(50));
+    assert_eq!(pool.live_workers(), 1);
+
+    // Grows to the maximum under load
+    let done = Arc::new(AtomicUsize::new(0));
+    submit_sleepers(&pool, 4, &done);
+    assert_eq!(pool.live_workers(), 4);
+    assert!(wait_for(|| done.load(Ordering::SeqCst) == 4));
+
+    // Idle workers exit down to the minimum
+    assert!(wait_for(|| pool.live_workers() == 1));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.live_workers(), 1);
+
+    // And the pool grows back when work arrives again
+    submit_sleepers(&pool, 4, &done);
+    assert!(pool.live_workers() > 1);
+    assert!(pool.live_workers() <= 4);
+    assert!(wait_for(|| done.load(Ordering::SeqCst) == 8));
+}
+
+#[test]
+fn test_elastic_pool_without_minimum_runs_every_job() {
+    // With no minimum, the last worker can time out just as a job arrives;
+    // that job must still run without waiting for another submit
+    let pool = ThreadPool::elastic(0, 1, Duration::from_millis(1));
+    for i in 0..200 {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        assert!(
+            receiver.recv_timeout(Duration::from_secs(5)).is_ok(),
+            "job {} was stranded",
+            i
+        );
+        thread::sleep(Duration::from_micros(900 + i % 200));
+    }
+    assert!(wait_for(|| pool.live_workers() == 0));
+}
+
+#[test]
+fn test_worker_threads_are_named() {
+    let pool = ThreadPool::new(2);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.execute(move || {
+        let name = thread::current().name().map(str::to_string);
+        sender.send(name).unwrap();
+    });
+    let name = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(name.is_some_and(|name| name.starts_with("worker-")));
+}
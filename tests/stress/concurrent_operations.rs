@@ -1,14 +1,11 @@
 use keystonelight::storage::Database;
 use log::{info, warn};
-use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 use tempfile::tempdir;
 
 const NUM_CLIENTS: usize = 10;
 const OPERATIONS_PER_CLIENT: usize = 100;
-const TIMEOUT_SECONDS: u64 = 5;
 
 pub fn test_concurrent_operations(db: Arc<Database>) {
     info!("Starting concurrent operations test");
@@ -19,7 +16,6 @@ pub fn test_concurrent_operations(db: Arc<Database>) {
         let db_clone = db.clone();
         let handle = thread::spawn(move || {
             let mut successful_sets = vec![];
-            let mut errors = 0;
 
             for i in 0..OPERATIONS_PER_CLIENT {
                 let key = format!("client{}_key{}", client_id, i);
@@ -32,7 +28,7 @@ pub fn test_concurrent_operations(db: Arc<Database>) {
                         }
                     }
                     1 => {
-                        if let None = db_clone.get(&key) {
+                        if db_clone.get(&key).is_none() {
                             successful_sets.push((key, value));
                         }
                     }
@@ -45,20 +41,14 @@ pub fn test_concurrent_operations(db: Arc<Database>) {
                 }
             }
 
-            (successful_sets, errors)
+            successful_sets
         });
         handles.push(handle);
     }
 
     for handle in handles {
         match handle.join() {
-            Ok((successful_sets, errors)) => {
-                all_successful_sets.extend(successful_sets);
-                assert_eq!(
-                    errors, 0,
-                    "No errors should occur during concurrent operations"
-                );
-            }
+            Ok(successful_sets) => all_successful_sets.extend(successful_sets),
             Err(e) => panic!("Thread panicked: {:?}", e),
         }
     }
@@ -73,3 +63,11 @@ pub fn test_concurrent_operations(db: Arc<Database>) {
 
     info!("Concurrent operations test completed");
 }
+
+#[test]
+fn stress_test_concurrent_operations() {
+    let temp_dir = tempdir().unwrap();
+    let log_file = temp_dir.path().join("keystonelight.log");
+    let db = Arc::new(Database::with_log_path(log_file.to_str().unwrap()).unwrap());
+    test_concurrent_operations(db);
+}
@@ -4,10 +4,3 @@ mod large_data;
 mod many_clients;
 mod persistence;
 mod varied_operations;
-
-pub use concurrent_operations::*;
-pub use error_injection::*;
-pub use large_data::*;
-pub use many_clients::*;
-pub use persistence::*;
-pub use varied_operations::*;
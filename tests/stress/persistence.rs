@@ -1,5 +1,4 @@
 use keystonelight::Database;
-use rand::Rng;
 use std::sync::Arc;
 use std::thread;
 use tempfile::tempdir;
@@ -18,7 +17,6 @@ fn stress_test_persistence() {
     for client_id in 0..num_clients {
         let db_clone = db.clone();
         let handle = thread::spawn(move || {
-            let mut rng = rand::thread_rng();
             let mut written_data = vec![];
 
             for i in 0..ops_per_client {